@@ -0,0 +1,192 @@
+"use strict";
+
+// Served at `<BASE_PATH>/admin`; the API lives next to it.
+const base = location.pathname.replace(/\/admin\/?$/, "");
+const $ = (id) => document.getElementById(id);
+let users = [];
+// Proves to admin routes that a request came from this page.
+let csrfToken = null;
+
+async function call(method, path, body) {
+  const options = { method, headers: {}, credentials: "same-origin" };
+  if (csrfToken && method !== "GET" && path.startsWith("/admin/")) {
+    options.headers["X-CSRF-Token"] = csrfToken;
+  }
+  if (body instanceof FormData) {
+    // The browser sets the multipart Content-Type, boundary included.
+    options.body = body;
+  } else if (body !== undefined) {
+    options.headers["Content-Type"] = "application/json";
+    options.body = JSON.stringify(body);
+  }
+  // Bodies are read as sent, whatever RESPONSE_ENVELOPE says.
+  const unwrapped = path + (path.includes("?") ? "&" : "?") + "envelope=false";
+  const response = await fetch(base + unwrapped, options);
+  const text = await response.text();
+  if (!response.ok) {
+    throw new Error(describe(response.status, text));
+  }
+  return text;
+}
+
+// Validation failures list their fields; other errors are plain text.
+function describe(status, text) {
+  try {
+    const body = JSON.parse(text);
+    if (Array.isArray(body.details)) {
+      return body.details
+        .map((detail) => (detail.field ? detail.field + ": " : "") + detail.message)
+        .join("; ");
+    }
+    if (body.error) {
+      return body.message ? body.error + ": " + body.message : body.error;
+    }
+  } catch (_) {
+    // Not JSON.
+  }
+  return status + " " + (text || "Request Failed");
+}
+
+function show(section) {
+  $("login").hidden = section !== "login";
+  $("main").hidden = section !== "main";
+  $("logout").hidden = section !== "main";
+}
+
+function notice(message, error) {
+  $("notice").textContent = message;
+  $("notice").className = error ? "error" : "";
+}
+
+async function start() {
+  const response = await fetch(base + "/admin/session?envelope=false", {
+    credentials: "same-origin",
+  });
+  if (response.ok) {
+    csrfToken = (await response.json()).csrf_token;
+    show("main");
+    await load();
+  } else {
+    show("login");
+  }
+}
+
+async function load() {
+  try {
+    users = JSON.parse(await call("GET", "/users"));
+    render();
+  } catch (e) {
+    notice(e.message, true);
+  }
+}
+
+function render() {
+  const query = $("search").value.trim().toLowerCase();
+  const shown = users.filter(
+    (user) =>
+      !query ||
+      user.name.toLowerCase().includes(query) ||
+      user.email.toLowerCase().includes(query)
+  );
+  const rows = shown.map((user) => {
+    const row = document.createElement("tr");
+    for (const value of [user.id, user.name, user.email, user.created_at || ""]) {
+      const cell = document.createElement("td");
+      cell.textContent = value;
+      row.append(cell);
+    }
+    const actions = document.createElement("td");
+    actions.append(button("Edit", () => edit(user)), button("Delete", () => remove(user)));
+    row.append(actions);
+    return row;
+  });
+  $("users").replaceChildren(...rows);
+  $("count").textContent = shown.length + " of " + users.length + " users";
+}
+
+function button(label, onClick) {
+  const element = document.createElement("button");
+  element.type = "button";
+  element.textContent = label;
+  element.addEventListener("click", onClick);
+  return element;
+}
+
+function edit(user) {
+  $("form-title").textContent = "Edit user " + user.id;
+  $("user-id").value = user.id;
+  $("name").value = user.name;
+  $("email").value = user.email;
+  $("cancel").hidden = false;
+  $("name").focus();
+}
+
+function resetForm() {
+  $("form-title").textContent = "New user";
+  $("user-form").reset();
+  $("user-id").value = "";
+  $("cancel").hidden = true;
+}
+
+async function remove(user) {
+  if (!confirm("Delete " + user.name + " <" + user.email + ">?")) {
+    return;
+  }
+  try {
+    await call("DELETE", "/users/" + user.id);
+    notice("User " + user.id + " deleted");
+    await load();
+  } catch (e) {
+    notice(e.message, true);
+  }
+}
+
+$("login-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  try {
+    const login = { token: $("token").value };
+    if ($("code").value.trim()) {
+      login.code = $("code").value.trim();
+    }
+    const session = JSON.parse(await call("POST", "/admin/session", login));
+    csrfToken = session.csrf_token;
+    $("token").value = "";
+    $("code").value = "";
+    notice("");
+    show("main");
+    await load();
+  } catch (e) {
+    notice("Login failed: " + e.message, true);
+  }
+});
+
+$("logout").addEventListener("click", async () => {
+  await call("DELETE", "/admin/session").catch(() => {});
+  csrfToken = null;
+  users = [];
+  notice("");
+  show("login");
+});
+
+$("user-form").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const id = $("user-id").value;
+  const user = new FormData($("user-form"));
+  try {
+    if (id) {
+      await call("PUT", "/users/" + id, user);
+      notice("User " + id + " updated");
+    } else {
+      await call("POST", "/users", user);
+      notice("User created");
+    }
+    resetForm();
+    await load();
+  } catch (e) {
+    notice(e.message, true);
+  }
+});
+
+$("search").addEventListener("input", render);
+$("cancel").addEventListener("click", resetForm);
+start();
@@ -0,0 +1,70 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/*
+*  Build metadata
+*
+*  Embeds what `GET /version` reports: the commit, the time of the build and
+*  the enabled cargo features. `GIT_SHA` overrides the commit for builds
+*  without a checkout, such as the Dockerfile's, and `SOURCE_DATE_EPOCH` the
+*  time for reproducible builds.
+*/
+
+fn main() {
+    let sha = env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| DateTime::<Utc>::from_timestamp(epoch, 0))
+        .unwrap_or_else(Utc::now);
+    println!(
+        "cargo:rustc-env=BUILD_TIMESTAMP={}",
+        built_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+
+    // Naming anything to watch stops cargo rerunning this on every change, so
+    // the sources are watched along with the commit.
+    for path in ["build.rs", "Cargo.toml", "src"] {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        let head = git(&["symbolic-ref", "-q", "HEAD"]);
+        let refs = ["HEAD", head.as_deref().unwrap_or("HEAD"), "packed-refs"];
+        // A missing file would count as changed on every build.
+        for path in refs.map(|name| git_dir.join(name)) {
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// The trimmed output of a successful git command.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,334 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as Tokens;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, Ident, LitInt, LitStr, Path,
+    PathArguments, Type,
+};
+
+/*
+*  CrudModel derive
+*
+*  Implements `crate::crud::CrudModel` for a struct with named fields, one
+*  column per field, in declaration order:
+*
+*      #[derive(CrudModel)]
+*      #[crud(table = "addresses")]
+*      struct Address {
+*          #[crud(id)]
+*          id: Option<i32>,
+*          #[crud(parent)]
+*          user_id: Option<i32>,
+*          #[crud(length(min = 1, max = 255))]
+*          line1: String,
+*          ...
+*      }
+*
+*  Field attributes say how a column is written: `id` is the key, assigned by
+*  the database; `parent` scopes updates and deletes to its owner and is only
+*  written on insert; `generated` is only ever read; `unique` is listed in
+*  `UNIQUE`. The rest describe valid request bodies for `schema()`:
+*  `length(min = .., max = ..)`, `email`, and `one_of = PATH` for a list of
+*  allowed strings.
+*
+*  The trait is this crate's own, so the generated code names it through
+*  `crate::`; the derive is meant for the models of `rust_api` only.
+*/
+
+#[proc_macro_derive(CrudModel, attributes(crud))]
+pub fn derive_crud_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// What the attributes say about a field.
+#[derive(Default)]
+struct Column {
+    id: bool,
+    parent: bool,
+    generated: bool,
+    unique: bool,
+    email: bool,
+    min_length: Option<LitInt>,
+    max_length: Option<LitInt>,
+    one_of: Option<Path>,
+}
+
+impl Column {
+    /// Written by inserts.
+    fn inserted(&self) -> bool {
+        !self.id && !self.generated
+    }
+
+    /// Written by updates.
+    fn updated(&self) -> bool {
+        self.inserted() && !self.parent
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<Tokens> {
+    let table = table(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    input,
+                    "CrudModel needs named fields",
+                ))
+            }
+        },
+        _ => return Err(syn::Error::new_spanned(input, "CrudModel needs a struct")),
+    };
+    let mut columns = Vec::new();
+    for field in fields {
+        let column = column(field)?;
+        columns.push((field.ident.clone().expect("named field"), &field.ty, column));
+    }
+    let key = |wanted: fn(&Column) -> bool, name: &str| -> syn::Result<Option<&Ident>> {
+        let mut found = columns.iter().filter(|(_, _, column)| wanted(column));
+        let first = found.next().map(|(ident, _, _)| ident);
+        if let Some((ident, _, _)) = found.next() {
+            return Err(syn::Error::new_spanned(
+                ident,
+                format!("only one field can be `{}`", name),
+            ));
+        }
+        Ok(first)
+    };
+    let id = key(|column| column.id, "id")?
+        .ok_or_else(|| syn::Error::new_spanned(input, "CrudModel needs a #[crud(id)] field"))?;
+    let parent = key(|column| column.parent, "parent")?;
+
+    let names = |wanted: fn(&Column) -> bool| -> Vec<&Ident> {
+        columns
+            .iter()
+            .filter(|(_, _, column)| wanted(column))
+            .map(|(ident, _, _)| ident)
+            .collect()
+    };
+    let all = names(|_| true);
+    let inserted = names(Column::inserted);
+    let updated = names(Column::updated);
+    let unique = names(|column| column.unique);
+
+    let column_list = list(&all);
+    let insert = format!(
+        "INSERT INTO {} ({}) VALUES ({}) RETURNING {}",
+        table,
+        list(&inserted),
+        placeholders(1, inserted.len()),
+        column_list
+    );
+    let sets: Vec<String> = (1..)
+        .zip(&updated)
+        .map(|(n, ident)| format!("{} = ${}", ident, n))
+        .collect();
+    let update = format!(
+        "UPDATE {} SET {} WHERE {}",
+        table,
+        sets.join(", "),
+        keys(id, parent, updated.len() + 1)
+    );
+    let delete = format!("DELETE FROM {} WHERE {}", table, keys(id, parent, 1));
+    let unique: Vec<String> = unique.iter().map(ToString::to_string).collect();
+
+    let reads = (0usize..)
+        .zip(&all)
+        .map(|(n, ident)| quote!(#ident: row.get(#n)));
+    let required = columns
+        .iter()
+        .filter(|(_, ty, _)| option_of(ty).is_none())
+        .map(|(ident, _, _)| ident.to_string());
+    let properties = columns.iter().map(|(ident, ty, column)| {
+        let name = ident.to_string();
+        let schema = schema(ty, column);
+        quote!(#name: #schema)
+    });
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::crud::CrudModel for #name #type_generics #where_clause {
+            const TABLE: &'static str = #table;
+            const COLUMNS: &'static str = #column_list;
+            const UNIQUE: &'static [&'static str] = &[#(#unique),*];
+            const INSERT: &'static str = #insert;
+            const UPDATE: &'static str = #update;
+            const DELETE: &'static str = #delete;
+
+            fn from_row(row: &::postgres::Row) -> Self {
+                #name { #(#reads),* }
+            }
+
+            fn insert_params(&self) -> Vec<&(dyn ::postgres::types::ToSql + Sync)> {
+                vec![#(&self.#inserted),*]
+            }
+
+            fn update_params(&self) -> Vec<&(dyn ::postgres::types::ToSql + Sync)> {
+                vec![#(&self.#updated),*]
+            }
+
+            fn schema() -> ::serde_json::Value {
+                ::serde_json::json!({
+                    "type": "object",
+                    "required": [#(#required),*],
+                    "properties": { #(#properties),* },
+                    "additionalProperties": false
+                })
+            }
+        }
+    })
+}
+
+/// The table named by `#[crud(table = "..")]` on the struct.
+fn table(input: &DeriveInput) -> syn::Result<String> {
+    let mut table = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("crud"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("unknown crud attribute; expected `table`"))
+            }
+        })?;
+    }
+    table.ok_or_else(|| syn::Error::new_spanned(input, "CrudModel needs #[crud(table = \"..\")]"))
+}
+
+fn column(field: &syn::Field) -> syn::Result<Column> {
+    let mut column = Column::default();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("crud"))
+    {
+        attr.parse_nested_meta(|meta| {
+            let flag = [
+                ("id", &mut column.id),
+                ("parent", &mut column.parent),
+                ("generated", &mut column.generated),
+                ("unique", &mut column.unique),
+                ("email", &mut column.email),
+            ]
+            .into_iter()
+            .find(|(name, _)| meta.path.is_ident(name));
+            if let Some((_, flag)) = flag {
+                *flag = true;
+            } else if meta.path.is_ident("length") {
+                meta.parse_nested_meta(|bound| {
+                    let limit = if bound.path.is_ident("min") {
+                        &mut column.min_length
+                    } else if bound.path.is_ident("max") {
+                        &mut column.max_length
+                    } else {
+                        return Err(bound.error("expected `min` or `max`"));
+                    };
+                    *limit = Some(bound.value()?.parse()?);
+                    Ok(())
+                })?;
+            } else if meta.path.is_ident("one_of") {
+                column.one_of = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("unknown crud attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    if column.id && column.parent {
+        return Err(syn::Error::new_spanned(
+            field,
+            "a field cannot be both `id` and `parent`",
+        ));
+    }
+    Ok(column)
+}
+
+/// The schema of a field's values in request bodies.
+fn schema(ty: &Type, column: &Column) -> Tokens {
+    if let Some(allowed) = &column.one_of {
+        return quote!(::serde_json::json!({ "enum": &#allowed[..] }));
+    }
+    let optional = option_of(ty);
+    let mut entries = Vec::new();
+    match (json_type(optional.unwrap_or(ty)), optional) {
+        (Some(kind), Some(_)) => entries.push(quote!("type": [#kind, "null"])),
+        (Some(kind), None) => entries.push(quote!("type": #kind)),
+        (None, _) => {}
+    }
+    if column.email {
+        entries.push(quote!("format": "email"));
+    }
+    if let Some(min) = &column.min_length {
+        entries.push(quote!("minLength": #min));
+    }
+    if let Some(max) = &column.max_length {
+        entries.push(quote!("maxLength": #max));
+    }
+    quote!(::serde_json::json!({ #(#entries),* }))
+}
+
+/// `T` for `Option<T>`.
+fn option_of(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else { return None };
+    let last = path.path.segments.last()?;
+    if last.ident != "Option" {
+        return None;
+    }
+    match &last.arguments {
+        PathArguments::AngleBracketed(arguments) => match arguments.args.first()? {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The JSON type values of `ty` serialize to; None when it is not obvious.
+fn json_type(ty: &Type) -> Option<&'static str> {
+    let Type::Path(path) = ty else { return None };
+    let name = path.path.segments.last()?.ident.to_string();
+    Some(match name.as_str() {
+        "String" | "DateTime" | "NaiveDate" | "NaiveDateTime" => "string",
+        "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "u64" | "isize" | "usize" => {
+            "integer"
+        }
+        "f32" | "f64" => "number",
+        "bool" => "boolean",
+        "Vec" => "array",
+        _ => return None,
+    })
+}
+
+fn list(idents: &[&Ident]) -> String {
+    idents
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `$first, $first + 1, ...`, `count` of them.
+fn placeholders(first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|n| format!("${}", n))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The condition picking one row by id, and owner when there is a parent;
+/// their placeholders are numbered from `first` on.
+fn keys(id: &Ident, parent: Option<&Ident>, first: usize) -> String {
+    (first..)
+        .zip(std::iter::once(id).chain(parent))
+        .map(|(n, key)| format!("{} = ${}", key, n))
+        .collect::<Vec<_>>()
+        .join(" AND ")
+}
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_api::http::{parse_date, Request};
+use rust_api::multipart::Limits;
+
+// Whatever bytes arrive, parsing them and reading the parts handlers read must
+// not panic.
+fuzz_target!(|data: &[u8]| {
+    let Some(mut request) = Request::parse(data) else {
+        return;
+    };
+    request.content_length();
+    request.form();
+    request.multipart(&Limits::default()).ok();
+    request.cookie("id");
+    request.query("page");
+    request.header("If-Modified-Since").and_then(parse_date);
+    request.strip_prefix("/api/crud");
+    request.segment(1);
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_api::http::read_head;
+
+// However the bytes are split into reads, the head ends in the same place.
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk_size, raw)) = data.split_first() else {
+        return;
+    };
+    let expected = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|end| end + 4)
+        .filter(|&end| end <= 4096);
+    let mut buffer = Vec::new();
+    let end = read_head(
+        &mut &raw[..],
+        &mut buffer,
+        usize::from(chunk_size).max(1),
+        4096,
+    )
+    .unwrap();
+    assert_eq!(end, expected);
+});
@@ -0,0 +1,563 @@
+use crate::auth::constant_time_eq;
+use crate::http::Request;
+use crate::listener::RouteGroup;
+use crate::lockout::{LoginGuard, Verdict};
+use crate::signature::SignedRequests;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64URL};
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, KeyInit, Mac};
+use log::warn;
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+*  Scoped access tokens
+*
+*  Integrations authenticate with a bearer token that carries scopes: an API
+*  key from `API_KEYS`, or a JWT signed with `JWT_SECRET` (HS256) by whoever
+*  issues them, with the scopes space separated in its `scope` claim as in
+*  RFC 8693. They may also sign the request with a key from `SIGNING_KEYS`
+*  instead, see `crate::signature`. Every route declares the scope it needs
+*  and the router checks it before the handler runs, so an integration that
+*  only reads users can be given a token that cannot change them. `admin`
+*  grants every scope.
+*
+*  Scripts and metrics scrapers that only speak HTTP Basic can log in as one
+*  of the operator accounts in `BASIC_AUTH_USERS` instead, configured like API
+*  keys with the password in place of the key. rust-crud keeps no passwords
+*  for its users, so those accounts are the only credentials checked. Basic
+*  credentials are only taken by the route groups in `BASIC_AUTH_GROUPS`, and
+*  wrong passwords go through the login guard the admin token uses, so they
+*  are slowed down per address and eventually lock the account.
+*
+*  Until either is configured the API routes stay open, as they always were.
+*  The admin token and admin UI sessions keep working for the admin routes
+*  and count as `admin` there.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    UsersRead,
+    UsersWrite,
+    /// `POST /admin/passwords/check`, for the services owning registration
+    /// and password resets.
+    PasswordsCheck,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::PasswordsCheck => "passwords:check",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "users:read" => Ok(Scope::UsersRead),
+            "users:write" => Ok(Scope::UsersWrite),
+            "passwords:check" => Ok(Scope::PasswordsCheck),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An API key, configured as `<name>:<scope>+<scope>:<key>`. The key itself
+/// cannot contain `:`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    scopes: Vec<Scope>,
+    key: String,
+}
+
+impl FromStr for ApiKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s.split_once(':').ok_or(())?;
+        let (scopes, key) = rest.rsplit_once(':').ok_or(())?;
+        if name.is_empty() || key.is_empty() {
+            return Err(());
+        }
+        Ok(ApiKey {
+            name: name.to_string(),
+            scopes: scopes
+                .split('+')
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            key: key.to_string(),
+        })
+    }
+}
+
+impl ApiKey {
+    pub fn secret(&self) -> &str {
+        &self.key
+    }
+
+    pub fn grant(&self) -> Grant {
+        Grant {
+            subject: self.name.clone(),
+            scopes: self.scopes.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ApiKey({})", self.name)
+    }
+}
+
+/// Who a request acts for and what it may do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grant {
+    /// The key's name or the JWT's `sub`.
+    pub subject: String,
+    scopes: Vec<Scope>,
+}
+
+impl Grant {
+    /// For the admin token and admin UI sessions.
+    pub fn admin() -> Grant {
+        Grant {
+            subject: "admin".to_string(),
+            scopes: vec![Scope::Admin],
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// Why a JWT or a request signature was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidToken {
+    Malformed,
+    /// Not HS256, or signed with another secret.
+    Signature,
+    /// Past the JWT's `exp`, or a signature timestamp outside the window.
+    Expired,
+    /// Basic credentials of no operator account.
+    Credentials,
+    /// A signature names a key that is not configured.
+    UnknownKey,
+    /// A signature that was accepted before.
+    Replayed,
+    /// Basic credentials from an address that has to wait this long.
+    Throttled(Duration),
+    /// Basic credentials of an account locked for this long.
+    Locked(Duration),
+}
+
+impl fmt::Display for InvalidToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidToken::Malformed => write!(f, "malformed JWT or signature"),
+            InvalidToken::Signature => write!(f, "bad signature"),
+            InvalidToken::Expired => write!(f, "expired JWT or signature"),
+            InvalidToken::Credentials => write!(f, "wrong username or password"),
+            InvalidToken::UnknownKey => write!(f, "unknown signing key"),
+            InvalidToken::Replayed => write!(f, "replayed signature"),
+            InvalidToken::Throttled(_) => write!(f, "too many wrong passwords"),
+            InvalidToken::Locked(_) => write!(f, "account locked"),
+        }
+    }
+}
+
+pub struct AccessTokens {
+    keys: Vec<ApiKey>,
+    jwt_secret: Option<String>,
+    signatures: SignedRequests,
+    /// Operator accounts, with their password as the key.
+    accounts: Vec<ApiKey>,
+    basic_groups: Vec<RouteGroup>,
+    guard: Arc<LoginGuard>,
+}
+
+impl AccessTokens {
+    pub fn new(keys: Vec<ApiKey>, jwt_secret: Option<String>) -> AccessTokens {
+        AccessTokens {
+            keys,
+            jwt_secret,
+            signatures: SignedRequests::new(Vec::new(), Duration::ZERO),
+            accounts: Vec::new(),
+            basic_groups: Vec::new(),
+            guard: Arc::new(LoginGuard::new(0, Duration::ZERO)),
+        }
+    }
+
+    /// Lets the route groups in `groups` take Basic credentials of
+    /// `accounts`, with wrong passwords counted by `guard`.
+    pub fn with_basic(
+        mut self,
+        accounts: Vec<ApiKey>,
+        groups: Vec<RouteGroup>,
+        guard: Arc<LoginGuard>,
+    ) -> AccessTokens {
+        self.accounts = accounts;
+        self.basic_groups = groups;
+        self.guard = guard;
+        self
+    }
+
+    pub fn with_signatures(mut self, signatures: SignedRequests) -> AccessTokens {
+        self.signatures = signatures;
+        self
+    }
+
+    /// Whether API routes need a token at all.
+    pub fn required(&self) -> bool {
+        !self.keys.is_empty()
+            || self.jwt_secret.is_some()
+            || self.signatures.enabled()
+            || self.accepts_basic(RouteGroup::Api)
+    }
+
+    /// Whether routes of `group` take Basic credentials.
+    pub fn accepts_basic(&self, group: RouteGroup) -> bool {
+        !self.accounts.is_empty() && self.basic_groups.contains(&group)
+    }
+
+    /// Forgets request signatures that could no longer be replayed.
+    pub fn purge_expired(&self) -> usize {
+        self.signatures.purge_expired()
+    }
+
+    /// The grant of the request's signature or the bearer token sent, if
+    /// that is an API key or a JWT. Anything else, the admin token say, is
+    /// left to the admin checks, and Basic credentials to `log_in`. Has to
+    /// see the body as sent.
+    pub fn authenticate(&self, request: &Request) -> Result<Option<Grant>, InvalidToken> {
+        if let Some(grant) = self.signatures.verify(request)? {
+            return Ok(Some(grant));
+        }
+        let Some(sent) = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+        else {
+            return Ok(None);
+        };
+        // Every key is compared, so timing does not tell which one was close.
+        let key = self.keys.iter().fold(None, |found, key| {
+            if constant_time_eq(sent.as_bytes(), key.key.as_bytes()) {
+                Some(key)
+            } else {
+                found
+            }
+        });
+        if let Some(key) = key {
+            return Ok(Some(key.grant()));
+        }
+        match &self.jwt_secret {
+            Some(secret) if sent.split('.').count() == 3 => verify_jwt(sent, secret).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The grant of the request's Basic credentials, if it sends any. Only
+    /// for routes that take them, since wrong ones count towards a lock.
+    pub fn log_in(&self, request: &Request) -> Result<Option<Grant>, InvalidToken> {
+        let Some(credentials) = basic_credentials(request) else {
+            return Ok(None);
+        };
+        let (username, password) = credentials?;
+        let client = request.client_addr;
+        match self.guard.check(&username, client) {
+            Verdict::Allowed => {}
+            Verdict::Throttled(wait) => return Err(InvalidToken::Throttled(wait)),
+            Verdict::Locked(wait) => return Err(InvalidToken::Locked(wait)),
+        }
+        // As with keys, every password is compared.
+        let account = self.accounts.iter().fold(None, |found, account| {
+            let matches = constant_time_eq(password.as_bytes(), account.key.as_bytes());
+            if matches && account.name == username {
+                Some(account)
+            } else {
+                found
+            }
+        });
+        let Some(account) = account else {
+            if self.guard.failed(&username, client).is_some() {
+                warn!(
+                    "Operator account {:?} locked after too many wrong passwords",
+                    username
+                );
+            }
+            return Err(InvalidToken::Credentials);
+        };
+        self.guard.succeeded(&username, client);
+        Ok(Some(account.grant()))
+    }
+}
+
+/// Whether the request sends `Authorization: Basic`.
+pub fn is_basic(request: &Request) -> bool {
+    basic_credentials(request).is_some()
+}
+
+/// The username and password of `Authorization: Basic`, RFC 7617.
+fn basic_credentials(request: &Request) -> Option<Result<(String, String), InvalidToken>> {
+    let encoded = request
+        .header("Authorization")?
+        .strip_prefix("Basic ")?
+        .trim();
+    Some(
+        BASE64
+            .decode(encoded)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (username, password) = decoded.split_once(':')?;
+                Some((username.to_string(), password.to_string()))
+            })
+            .ok_or(InvalidToken::Malformed),
+    )
+}
+
+/// Checks an HS256 JWT and its `exp` and `nbf` claims. Scopes this server does
+/// not know are ignored, since a token may be meant for other services too.
+fn verify_jwt(token: &str, secret: &str) -> Result<Grant, InvalidToken> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(InvalidToken::Malformed)?;
+    let (header, claims) = signed.split_once('.').ok_or(InvalidToken::Malformed)?;
+    let header = decode_part(header)?;
+    if header.get("alg").and_then(Value::as_str) != Some("HS256") {
+        return Err(InvalidToken::Signature);
+    }
+    let signature = BASE64URL
+        .decode(signature)
+        .map_err(|_| InvalidToken::Malformed)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(signed.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| InvalidToken::Signature)?;
+
+    let claims = decode_part(claims)?;
+    let now = Utc::now().timestamp();
+    match claims.get("exp").map(Value::as_i64) {
+        Some(Some(exp)) if exp > now => {}
+        Some(Some(_)) => return Err(InvalidToken::Expired),
+        _ => return Err(InvalidToken::Malformed),
+    }
+    if let Some(nbf) = claims.get("nbf") {
+        if nbf.as_i64().ok_or(InvalidToken::Malformed)? > now {
+            return Err(InvalidToken::Expired);
+        }
+    }
+    Ok(Grant {
+        subject: claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .unwrap_or("jwt")
+            .to_string(),
+        scopes: claims
+            .get("scope")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|scope| scope.parse().ok())
+            .collect(),
+    })
+}
+
+fn decode_part(part: &str) -> Result<Value, InvalidToken> {
+    let json = BASE64URL
+        .decode(part)
+        .map_err(|_| InvalidToken::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| InvalidToken::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> Request {
+        Request::parse(
+            format!(
+                "GET /users HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+                token
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn basic(credentials: &str) -> Request {
+        Request::parse(
+            format!(
+                "GET /metrics HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n",
+                BASE64.encode(credentials)
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn jwt(claims: Value, secret: &str) -> String {
+        let signed = format!(
+            "{}.{}",
+            BASE64URL.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            BASE64URL.encode(claims.to_string())
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        format!(
+            "{}.{}",
+            signed,
+            BASE64URL.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn parses_api_keys() {
+        let key: ApiKey = "reporting:users:read+users:write:k3y".parse().unwrap();
+        assert_eq!(key.name, "reporting");
+        assert_eq!(key.scopes, [Scope::UsersRead, Scope::UsersWrite]);
+        assert_eq!(key.key, "k3y");
+        assert_eq!(format!("{:?}", key), "ApiKey(reporting)");
+        assert!("reporting:k3y".parse::<ApiKey>().is_err());
+        assert!("reporting:users:delete:k3y".parse::<ApiKey>().is_err());
+        assert!(":admin:k3y".parse::<ApiKey>().is_err());
+    }
+
+    #[test]
+    fn grants_the_scopes_of_api_keys() {
+        let tokens = AccessTokens::new(
+            vec![
+                "reporting:users:read:r3ad".parse().unwrap(),
+                "registration:passwords:check:r3g".parse().unwrap(),
+                "ops:admin:0ps".parse().unwrap(),
+            ],
+            None,
+        );
+        assert!(tokens.required());
+        let reporting = tokens.authenticate(&bearer("r3ad")).unwrap().unwrap();
+        assert_eq!(reporting.subject, "reporting");
+        assert!(reporting.allows(Scope::UsersRead));
+        assert!(!reporting.allows(Scope::UsersWrite));
+        assert!(!reporting.allows(Scope::Admin));
+        let registration = tokens.authenticate(&bearer("r3g")).unwrap().unwrap();
+        assert!(registration.allows(Scope::PasswordsCheck));
+        assert!(!registration.allows(Scope::Admin));
+        let ops = tokens.authenticate(&bearer("0ps")).unwrap().unwrap();
+        assert!(ops.allows(Scope::UsersWrite));
+        assert!(ops.allows(Scope::PasswordsCheck));
+        assert_eq!(tokens.authenticate(&bearer("guess")), Ok(None));
+        assert!(!AccessTokens::new(Vec::new(), None).required());
+    }
+
+    #[test]
+    fn logs_operator_accounts_in_with_basic_credentials() {
+        let tokens = AccessTokens::new(Vec::new(), None).with_basic(
+            vec!["scraper:admin:pa55".parse().unwrap()],
+            vec![RouteGroup::Admin],
+            Arc::new(LoginGuard::new(0, Duration::ZERO)),
+        );
+        assert!(tokens.accepts_basic(RouteGroup::Admin));
+        assert!(!tokens.accepts_basic(RouteGroup::Api));
+        assert!(!tokens.required());
+
+        assert_eq!(tokens.authenticate(&basic("scraper:pa55")), Ok(None));
+        let grant = tokens.log_in(&basic("scraper:pa55")).unwrap().unwrap();
+        assert_eq!(grant.subject, "scraper");
+        assert!(grant.allows(Scope::Admin));
+        assert!(is_basic(&basic("scraper:pa55")));
+        assert!(!is_basic(&bearer("pa55")));
+        for wrong in ["scraper:guess", "other:pa55", "scraper:"] {
+            assert_eq!(
+                tokens.log_in(&basic(wrong)),
+                Err(InvalidToken::Credentials),
+                "{:?}",
+                wrong
+            );
+        }
+        assert_eq!(
+            tokens.log_in(&basic("no colon")),
+            Err(InvalidToken::Malformed)
+        );
+        assert_eq!(tokens.log_in(&bearer("pa55")), Ok(None));
+    }
+
+    #[test]
+    fn locks_operator_accounts_after_wrong_passwords() {
+        let tokens = AccessTokens::new(Vec::new(), None).with_basic(
+            vec![
+                "scraper:admin:pa55".parse().unwrap(),
+                "backup:admin:b4ck".parse().unwrap(),
+            ],
+            vec![RouteGroup::Admin],
+            Arc::new(LoginGuard::new(3, Duration::from_secs(600))),
+        );
+        for _ in 0..3 {
+            assert_eq!(
+                tokens.log_in(&basic("scraper:guess")),
+                Err(InvalidToken::Credentials)
+            );
+        }
+        assert!(matches!(
+            tokens.log_in(&basic("scraper:pa55")),
+            Err(InvalidToken::Locked(_))
+        ));
+        assert!(tokens.log_in(&basic("backup:b4ck")).unwrap().is_some());
+    }
+
+    #[test]
+    fn verifies_jwts() {
+        let tokens = AccessTokens::new(Vec::new(), Some("s3cret".to_string()));
+        let exp = Utc::now().timestamp() + 60;
+
+        let token = jwt(
+            serde_json::json!({ "sub": "billing", "scope": "users:read openid", "exp": exp }),
+            "s3cret",
+        );
+        let grant = tokens.authenticate(&bearer(&token)).unwrap().unwrap();
+        assert_eq!(grant.subject, "billing");
+        assert!(grant.allows(Scope::UsersRead));
+        assert!(!grant.allows(Scope::UsersWrite));
+
+        let forged = jwt(serde_json::json!({ "scope": "admin", "exp": exp }), "guess");
+        assert_eq!(
+            tokens.authenticate(&bearer(&forged)),
+            Err(InvalidToken::Signature)
+        );
+        let expired = jwt(
+            serde_json::json!({ "scope": "admin", "exp": exp - 120 }),
+            "s3cret",
+        );
+        assert_eq!(
+            tokens.authenticate(&bearer(&expired)),
+            Err(InvalidToken::Expired)
+        );
+        let endless = jwt(serde_json::json!({ "scope": "admin" }), "s3cret");
+        assert_eq!(
+            tokens.authenticate(&bearer(&endless)),
+            Err(InvalidToken::Malformed)
+        );
+        let unsigned = format!(
+            "{}.{}.",
+            BASE64URL.encode(r#"{"alg":"none"}"#),
+            BASE64URL.encode(r#"{"scope":"admin"}"#)
+        );
+        assert_eq!(
+            tokens.authenticate(&bearer(&unsigned)),
+            Err(InvalidToken::Signature)
+        );
+    }
+}
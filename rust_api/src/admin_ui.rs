@@ -0,0 +1,65 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+/*
+*  Admin UI
+*
+*  A single page, script and styles inlined, that lists, searches, creates,
+*  edits and deletes users through the public API. It is served to anyone
+*  since it holds no data; it asks for the admin token and logs in with it
+*  before showing anything. Its Content-Security-Policy allows exactly the
+*  inlined script and styles, by hash, and nothing from elsewhere.
+*/
+
+const HTML: &str = include_str!("../assets/admin.html");
+const STYLE: &str = include_str!("../assets/admin.css");
+const SCRIPT: &str = include_str!("../assets/admin.js");
+
+pub struct Page {
+    pub html: String,
+    pub content_security_policy: String,
+}
+
+pub fn page() -> &'static Page {
+    static PAGE: OnceLock<Page> = OnceLock::new();
+    PAGE.get_or_init(|| Page {
+        html: HTML
+            .replace("{{style}}", STYLE)
+            .replace("{{script}}", SCRIPT),
+        content_security_policy: format!(
+            "default-src 'none'; connect-src 'self'; script-src '{}'; style-src '{}'; \
+             form-action 'none'; frame-ancestors 'none'; base-uri 'none'",
+            hash(SCRIPT),
+            hash(STYLE)
+        ),
+    })
+}
+
+fn hash(source: &str) -> String {
+    format!(
+        "sha256-{}",
+        BASE64.encode(Sha256::digest(source.as_bytes()))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inlines_what_the_policy_allows() {
+        let page = page();
+        assert!(!page.html.contains("{{"));
+        let script = page
+            .html
+            .split_once("<script>")
+            .and_then(|(_, rest)| rest.split_once("</script>"))
+            .unwrap()
+            .0;
+        assert!(page
+            .content_security_policy
+            .contains(&format!("script-src '{}'", hash(script))));
+    }
+}
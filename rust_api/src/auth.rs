@@ -0,0 +1,465 @@
+use crate::http::Request;
+use crate::lockout::{LoginGuard, Verdict};
+use crate::repository::{RepositoryError, TotpRepository};
+use crate::totp::{self, Check};
+use log::warn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+*  Authentication
+*
+*  The admin API is guarded by a single shared token, sent as
+*  `Authorization: Bearer <token>`. The admin UI trades that token for a
+*  session cookie instead, since a browser cannot attach the header to page
+*  loads; sessions live in memory, so a restart logs everyone out. Without
+*  `ADMIN_TOKEN` configured the admin routes do not exist at all.
+*
+*  A browser sends the cookie along with requests other sites make it send,
+*  so requests that change something must also echo the session's CSRF token
+*  in `X-CSRF-Token`, which other sites cannot read (synchronizer token
+*  pattern). Bearer tokens are never sent on their own and need no such proof.
+*
+*  Both ways of presenting the token go through the login guard, so guessing
+*  it is slowed down per address and eventually locks the admin account.
+*  Once two-factor login is enabled, the login also needs a one-time code and
+*  the bearer token alone no longer gets in: the token is then only good for
+*  logging in.
+*/
+
+pub const SESSION_COOKIE: &str = "rust_crud_admin";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// Sessions end this long after login.
+const SESSION_LIFETIME: Duration = Duration::from_secs(8 * 60 * 60);
+
+#[derive(Debug)]
+pub enum Denied {
+    /// No admin token is configured.
+    Disabled,
+    /// The token is missing or wrong.
+    Unauthorized,
+    /// A session changing something without its CSRF token.
+    Forged,
+    /// Too many wrong tokens came from the client's address lately; it may
+    /// try again after this long.
+    Throttled(Duration),
+    /// Too many wrong tokens came in overall; the admin account is locked for
+    /// this long, or until unlocked.
+    Locked(Duration),
+    /// The token is right, but two-factor login is on and no code came with it.
+    CodeRequired,
+    /// The two-factor enrollment could not be read.
+    Unavailable(RepositoryError),
+}
+
+type OnLock = Box<dyn Fn(&str) + Send + Sync>;
+
+struct Session {
+    expires: Instant,
+    csrf_token: String,
+}
+
+pub struct AdminAuth {
+    token: Option<String>,
+    /// Whether the session cookie is restricted to HTTPS.
+    secure_cookie: bool,
+    /// By session id.
+    sessions: Mutex<HashMap<String, Session>>,
+    guard: Arc<LoginGuard>,
+    /// Called with the unlock token when the account gets locked.
+    on_lock: Option<OnLock>,
+    /// Where the two-factor enrollment is kept; none means logins need none.
+    totp: Option<Arc<dyn TotpRepository>>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>, secure_cookie: bool, guard: Arc<LoginGuard>) -> AdminAuth {
+        AdminAuth {
+            token,
+            secure_cookie,
+            sessions: Mutex::default(),
+            guard,
+            on_lock: None,
+            totp: None,
+        }
+    }
+
+    pub fn with_totp(mut self, repository: Arc<dyn TotpRepository>) -> AdminAuth {
+        self.totp = Some(repository);
+        self
+    }
+
+    pub fn on_lock(mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> AdminAuth {
+        self.on_lock = Some(Box::new(callback));
+        self
+    }
+
+    /// Lets the request through with the admin token, unless two-factor login
+    /// is enabled, or a live session, the latter with its CSRF token unless the
+    /// method is safe.
+    pub fn authorize(&self, request: &Request) -> Result<(), Denied> {
+        let expected = self.token.as_deref().ok_or(Denied::Disabled)?;
+        if let Some(sent) = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            self.check(sent.trim(), expected, request.client_addr)?;
+            return match &self.totp {
+                Some(repository) => match totp::status(repository.as_ref(), totp::ADMIN_ACCOUNT) {
+                    Ok(Some(totp)) if totp.enabled => Err(Denied::CodeRequired),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(Denied::Unavailable(e)),
+                },
+                None => Ok(()),
+            };
+        }
+        let csrf_token = request
+            .cookie(SESSION_COOKIE)
+            .and_then(|session| self.csrf_token(session))
+            .ok_or(Denied::Unauthorized)?;
+        if matches!(request.method.as_str(), "GET" | "HEAD" | "OPTIONS") {
+            return Ok(());
+        }
+        match request.header(CSRF_HEADER) {
+            Some(sent) if constant_time_eq(sent.as_bytes(), csrf_token.as_bytes()) => Ok(()),
+            _ => Err(Denied::Forged),
+        }
+    }
+
+    /// Starts a session when `token` is the admin token and `code` passes
+    /// two-factor login, returning its id.
+    pub fn login(
+        &self,
+        token: &str,
+        code: Option<&str>,
+        client: Option<IpAddr>,
+    ) -> Result<String, Denied> {
+        let expected = self.token.as_deref().ok_or(Denied::Disabled)?;
+        self.admit(client)?;
+        if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+            return Err(self.failed(client));
+        }
+        if let Some(repository) = &self.totp {
+            match totp::verify(repository.as_ref(), totp::ADMIN_ACCOUNT, code) {
+                Ok(Check::NotEnabled | Check::Accepted) => {}
+                Ok(Check::Missing) => return Err(Denied::CodeRequired),
+                Ok(Check::Rejected) => return Err(self.failed(client)),
+                Err(e) => return Err(Denied::Unavailable(e)),
+            }
+        }
+        self.guard.succeeded(totp::ADMIN_ACCOUNT, client);
+        let session = format!("{:032x}", rand::random::<u128>());
+        self.sessions.lock().unwrap().insert(
+            session.clone(),
+            Session {
+                expires: Instant::now() + SESSION_LIFETIME,
+                csrf_token: format!("{:032x}", rand::random::<u128>()),
+            },
+        );
+        Ok(session)
+    }
+
+    /// Ends a lock early with the token handed to `on_lock`.
+    pub fn unlock(&self, token: &str) -> bool {
+        self.guard.unlock(token)
+    }
+
+    fn check(&self, sent: &str, expected: &str, client: Option<IpAddr>) -> Result<(), Denied> {
+        self.admit(client)?;
+        if !constant_time_eq(sent.as_bytes(), expected.as_bytes()) {
+            return Err(self.failed(client));
+        }
+        self.guard.succeeded(totp::ADMIN_ACCOUNT, client);
+        Ok(())
+    }
+
+    /// Whether the login guard lets `client` try at all.
+    fn admit(&self, client: Option<IpAddr>) -> Result<(), Denied> {
+        match self.guard.check(totp::ADMIN_ACCOUNT, client) {
+            Verdict::Allowed => Ok(()),
+            Verdict::Throttled(wait) => Err(Denied::Throttled(wait)),
+            Verdict::Locked(wait) => Err(Denied::Locked(wait)),
+        }
+    }
+
+    /// Counts wrong credentials from `client`, wrong codes included.
+    fn failed(&self, client: Option<IpAddr>) -> Denied {
+        if let Some(unlock_token) = self.guard.failed(totp::ADMIN_ACCOUNT, client) {
+            warn!("Admin account locked after too many wrong tokens");
+            if let Some(on_lock) = &self.on_lock {
+                on_lock(&unlock_token);
+            }
+        }
+        Denied::Unauthorized
+    }
+
+    pub fn logout(&self, session: &str) {
+        self.sessions.lock().unwrap().remove(session);
+    }
+
+    /// The CSRF token of a live session.
+    pub fn csrf_token(&self, session: &str) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session)
+            .filter(|session| session.expires > Instant::now())
+            .map(|session| session.csrf_token.clone())
+    }
+
+    /// Forgets expired sessions, returning how many there were.
+    pub fn purge_expired(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        before - sessions.len()
+    }
+
+    /// Forgets addresses whose failed logins no longer count.
+    pub fn purge_failures(&self) -> usize {
+        self.guard.purge_expired()
+    }
+
+    /// `Set-Cookie` value carrying `session` to the admin routes under
+    /// `path`; an empty session clears the cookie.
+    pub fn cookie(&self, session: &str, path: &str) -> String {
+        let max_age = match session {
+            "" => 0,
+            _ => SESSION_LIFETIME.as_secs(),
+        };
+        format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Strict{}",
+            SESSION_COOKIE,
+            session,
+            path,
+            max_age,
+            if self.secure_cookie { "; Secure" } else { "" }
+        )
+    }
+}
+
+/// Admin routes reachable without logging in: the UI's page, which holds no
+/// data, the login itself and the unlock link.
+pub fn is_public(request: &Request) -> bool {
+    matches!(
+        (request.method.as_str(), request.path()),
+        ("GET", "/admin") | ("POST", "/admin/session") | ("GET", "/admin/unlock")
+    )
+}
+
+/// Compares without stopping at the first difference, so response times do
+/// not reveal how much of a guess was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    fn admin(token: &str, secure_cookie: bool) -> AdminAuth {
+        AdminAuth::new(
+            Some(token.to_string()),
+            secure_cookie,
+            Arc::new(LoginGuard::new(0, Duration::ZERO)),
+        )
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        Request::parse(format!("{} /admin/drain HTTP/1.1\r\n{}\r\n", method, headers).as_bytes())
+            .unwrap()
+    }
+
+    fn with_authorization(value: Option<&str>) -> Request {
+        request(
+            "POST",
+            &value
+                .map(|value| [("Authorization", value)])
+                .unwrap_or_default(),
+        )
+    }
+
+    #[test]
+    fn needs_the_configured_bearer_token() {
+        let auth = admin("s3cret", false);
+        assert!(auth
+            .authorize(&with_authorization(Some("Bearer s3cret")))
+            .is_ok());
+        assert!(matches!(
+            auth.authorize(&with_authorization(Some("Bearer s3cre"))),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(matches!(
+            auth.authorize(&with_authorization(Some("Basic s3cret"))),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(matches!(
+            auth.authorize(&with_authorization(None)),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(matches!(
+            AdminAuth::new(None, false, Arc::new(LoginGuard::new(0, Duration::ZERO)))
+                .authorize(&with_authorization(Some("Bearer s3cret"))),
+            Err(Denied::Disabled)
+        ));
+    }
+
+    #[test]
+    fn trades_the_token_for_a_session_cookie() {
+        let auth = admin("s3cret", true);
+        assert!(matches!(
+            auth.login("guess", None, None),
+            Err(Denied::Unauthorized)
+        ));
+        let session = auth.login("s3cret", None, None).unwrap();
+        assert_eq!(
+            auth.cookie(&session, "/api/admin"),
+            format!(
+                "rust_crud_admin={}; Path=/api/admin; Max-Age=28800; HttpOnly; SameSite=Strict; Secure",
+                session
+            )
+        );
+
+        let cookie = format!("theme=dark; rust_crud_admin={}", session);
+        assert!(auth
+            .authorize(&request("GET", &[("Cookie", &cookie)]))
+            .is_ok());
+        let forged = request("GET", &[("Cookie", "rust_crud_admin=forged")]);
+        assert!(matches!(auth.authorize(&forged), Err(Denied::Unauthorized)));
+
+        auth.sessions.lock().unwrap().insert(
+            "stale".to_string(),
+            Session {
+                expires: Instant::now(),
+                csrf_token: "stale".to_string(),
+            },
+        );
+        let stale = request("GET", &[("Cookie", "rust_crud_admin=stale")]);
+        assert!(matches!(auth.authorize(&stale), Err(Denied::Unauthorized)));
+        assert_eq!(auth.purge_expired(), 1);
+
+        auth.logout(&session);
+        assert!(matches!(
+            auth.authorize(&request("GET", &[("Cookie", &cookie)])),
+            Err(Denied::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn sessions_need_their_csrf_token_to_change_anything() {
+        let auth = admin("s3cret", false);
+        let session = auth.login("s3cret", None, None).unwrap();
+        let csrf_token = auth.csrf_token(&session).unwrap();
+        let cookie = format!("rust_crud_admin={}", session);
+
+        for method in ["GET", "HEAD", "OPTIONS"] {
+            assert!(auth
+                .authorize(&request(method, &[("Cookie", &cookie)]))
+                .is_ok());
+        }
+        assert!(matches!(
+            auth.authorize(&request("POST", &[("Cookie", &cookie)])),
+            Err(Denied::Forged)
+        ));
+        let other = auth
+            .csrf_token(&auth.login("s3cret", None, None).unwrap())
+            .unwrap();
+        assert!(matches!(
+            auth.authorize(&request(
+                "DELETE",
+                &[("Cookie", &cookie), ("X-CSRF-Token", &other)]
+            )),
+            Err(Denied::Forged)
+        ));
+        assert!(auth
+            .authorize(&request(
+                "DELETE",
+                &[("Cookie", &cookie), ("X-CSRF-Token", &csrf_token)]
+            ))
+            .is_ok());
+        // Bearer tokens are not sent along by browsers.
+        assert!(auth
+            .authorize(&request("POST", &[("Authorization", "Bearer s3cret")]))
+            .is_ok());
+    }
+
+    #[test]
+    fn wrong_tokens_lock_the_account() {
+        let unlock_token = Arc::new(Mutex::new(None));
+        let sent = unlock_token.clone();
+        let auth = AdminAuth::new(
+            Some("s3cret".to_string()),
+            false,
+            Arc::new(LoginGuard::new(2, Duration::from_secs(60))),
+        )
+        .on_lock(move |token| *sent.lock().unwrap() = Some(token.to_string()));
+
+        assert!(matches!(
+            auth.authorize(&with_authorization(Some("Bearer guess"))),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(unlock_token.lock().unwrap().is_none());
+        assert!(matches!(
+            auth.login("guess", None, None),
+            Err(Denied::Unauthorized)
+        ));
+        // Locked even for the right token, whichever way it is sent.
+        assert!(matches!(
+            auth.login("s3cret", None, None),
+            Err(Denied::Locked(_))
+        ));
+        assert!(matches!(
+            auth.authorize(&with_authorization(Some("Bearer s3cret"))),
+            Err(Denied::Locked(_))
+        ));
+
+        let token = unlock_token.lock().unwrap().take().unwrap();
+        assert!(auth.unlock(&token));
+        assert!(auth.login("s3cret", None, None).is_ok());
+    }
+
+    #[test]
+    fn logins_need_a_code_once_two_factor_is_enabled() {
+        let repository = Arc::new(MemoryUserRepository::new());
+        let auth = admin("s3cret", false).with_totp(repository.clone());
+        assert!(auth.login("s3cret", None, None).is_ok());
+
+        let enrollment = totp::enroll(repository.as_ref(), totp::ADMIN_ACCOUNT)
+            .unwrap()
+            .unwrap();
+        repository
+            .change_totp(totp::ADMIN_ACCOUNT, &mut |totp| {
+                totp.as_mut().unwrap().enabled = true;
+                true
+            })
+            .unwrap();
+        assert!(matches!(
+            auth.login("s3cret", None, None),
+            Err(Denied::CodeRequired)
+        ));
+        assert!(matches!(
+            auth.login("s3cret", Some("not-a-code"), None),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(matches!(
+            auth.login("guess", Some(&enrollment.recovery_codes[0]), None),
+            Err(Denied::Unauthorized)
+        ));
+        assert!(auth
+            .login("s3cret", Some(&enrollment.recovery_codes[0]), None)
+            .is_ok());
+        // The bearer token alone no longer gets past two-factor login.
+        assert!(matches!(
+            auth.authorize(&with_authorization(Some("Bearer s3cret"))),
+            Err(Denied::CodeRequired)
+        ));
+    }
+}
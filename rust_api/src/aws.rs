@@ -0,0 +1,173 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+
+/*
+*  AWS request signing
+*
+*  Calls to AWS APIs are authenticated with Signature Version 4: a hash of the
+*  canonical form of the request, signed with a key derived from the secret
+*  key, the day, the region and the service.
+*  https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv-create-signed-request.html
+*/
+
+/// Keys from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and, for temporary
+/// credentials, `AWS_SESSION_TOKEN`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A request about to be sent, with every header that is signed.
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    /// Lowercase names; `host`, `x-amz-date` and, with a session token,
+    /// `x-amz-security-token` are added by `sign`.
+    pub headers: Vec<(&'a str, String)>,
+    pub body: &'a [u8],
+}
+
+/// Adds the date, token and `authorization` headers to `request`.
+pub fn sign(
+    request: &mut SignedRequest,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    now: DateTime<Utc>,
+) {
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let day = now.format("%Y%m%d").to_string();
+    request.headers.push(("host", request.host.to_string()));
+    request.headers.push(("x-amz-date", timestamp.clone()));
+    if let Some(token) = &credentials.session_token {
+        request
+            .headers
+            .push(("x-amz-security-token", token.clone()));
+    }
+    request.headers.sort();
+
+    let signed_headers = request
+        .headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical = canonical_request(request, &signed_headers);
+    let scope = format!("{}/{}/{}/aws4_request", day, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(&Sha256::digest(canonical.as_bytes()))
+    );
+    let key = signing_key(&credentials.secret_access_key, &day, region, service);
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    request.headers.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+}
+
+/// The request as it is hashed: headers sorted by name, values trimmed. The
+/// query string is always empty for the JSON APIs this is used for.
+fn canonical_request(request: &SignedRequest, signed_headers: &str) -> String {
+    let headers: String = request
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        request.method,
+        request.path,
+        headers,
+        signed_headers,
+        hex(&Sha256::digest(request.body))
+    )
+}
+
+fn signing_key(secret: &str, day: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), day.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // The example from the AWS Signature Version 4 documentation.
+    const SECRET: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+    #[test]
+    fn derives_the_documented_signing_key() {
+        assert_eq!(
+            hex(&signing_key(SECRET, "20150830", "us-east-1", "iam")),
+            "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+        );
+    }
+
+    #[test]
+    fn signs_like_the_aws_sdks() {
+        // Signature computed with botocore for the same request.
+        let credentials = Credentials {
+            access_key_id: "AKID".to_string(),
+            secret_access_key: "SECRET".to_string(),
+            session_token: None,
+        };
+        let mut request = SignedRequest {
+            method: "POST",
+            host: "dynamodb.us-east-1.amazonaws.com",
+            path: "/",
+            headers: vec![
+                ("content-type", "application/x-amz-json-1.0".to_string()),
+                ("x-amz-target", "DynamoDB_20120810.GetItem".to_string()),
+            ],
+            body: br#"{"TableName":"t"}"#,
+        };
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        sign(&mut request, &credentials, "us-east-1", "dynamodb", now);
+
+        assert_eq!(
+            request.headers.last().unwrap(),
+            &(
+                "authorization",
+                "AWS4-HMAC-SHA256 Credential=AKID/20240102/us-east-1/dynamodb/aws4_request, \
+                 SignedHeaders=content-type;host;x-amz-date;x-amz-target, \
+                 Signature=3242a1ec5c0944f95f92df27ecad0dc9558046b00eaec28881f4eeacab58044c"
+                    .to_string()
+            )
+        );
+        assert!(request
+            .headers
+            .contains(&("x-amz-date", "20240102T030405Z".to_string())));
+    }
+}
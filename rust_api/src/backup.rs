@@ -0,0 +1,251 @@
+use crate::pool::Pool;
+use crate::repository::RepositoryError;
+use chrono::{SecondsFormat, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use postgres::{IsolationLevel, Transaction};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+
+/*
+*  Backups
+*
+*  A logical backup of the users and everything attached to them, for when
+*  there is no shell to run pg_dump from. The archive is a gzipped SQL script
+*  of `COPY ... FROM stdin` blocks, as `pg_dump --data-only` writes them, so
+*  `gunzip -c backup.sql.gz | psql` restores it as well as the `restore`
+*  command does. Every table is read in one repeatable read transaction, so
+*  the backup is consistent while the API keeps writing. Values are copied as
+*  stored: encrypted emails stay encrypted and need the same keys afterwards.
+*  Restoring only accepts `COPY` blocks for the tables below, whatever else
+*  the file contains.
+*/
+
+/// Backed up in this order, which restores rows before rows referring to them.
+const TABLES: [&str; 4] = ["users", "addresses", "feature_flags", "totp"];
+/// Tables whose `id` sequence has to be moved past the restored rows.
+const SERIAL_TABLES: [&str; 2] = ["users", "addresses"];
+
+#[derive(Debug)]
+pub enum BackupError {
+    Database(RepositoryError),
+    Io(io::Error),
+    /// The archive has something other than the `COPY` blocks we write.
+    Invalid(String),
+    /// Restoring without replacing needs an empty table.
+    NotEmpty(&'static str),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::Database(e) => write!(f, "{}", e),
+            BackupError::Io(e) => write!(f, "{}", e),
+            BackupError::Invalid(line) => write!(f, "unexpected line in backup: {:?}", line),
+            BackupError::NotEmpty(table) => write!(f, "table {} is not empty", table),
+        }
+    }
+}
+
+impl From<postgres::Error> for BackupError {
+    fn from(e: postgres::Error) -> Self {
+        BackupError::Database(e.into())
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+/// Rows per table.
+pub type Tables = BTreeMap<&'static str, u64>;
+
+pub struct Backup {
+    pool: Arc<Pool>,
+}
+
+impl Backup {
+    pub fn new(pool: Arc<Pool>) -> Backup {
+        Backup { pool }
+    }
+
+    /// A name for the archive of a backup taken now.
+    pub fn file_name() -> String {
+        format!("rust-crud-{}.sql.gz", Utc::now().format("%Y%m%dT%H%M%SZ"))
+    }
+
+    /// Writes a gzipped backup to `out`. Nothing is written until the
+    /// snapshot is taken, so a database that cannot be reached fails before
+    /// any output.
+    pub fn write(&self, out: &mut dyn Write) -> Result<Tables, BackupError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()?;
+        transaction.batch_execute("SET LOCAL statement_timeout = 0")?;
+        let mut columns = Vec::new();
+        for table in TABLES {
+            columns.push(self::columns(&mut transaction, table)?);
+        }
+
+        let mut archive = GzEncoder::new(out, Compression::default());
+        writeln!(
+            archive,
+            "-- rust-crud backup taken {}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+        )?;
+        let mut rows = Tables::new();
+        for (table, columns) in TABLES.into_iter().zip(columns) {
+            writeln!(archive, "\nCOPY {} ({}) FROM stdin;", table, columns)?;
+            let mut data =
+                transaction.copy_out(&format!("COPY {} ({}) TO STDOUT", table, columns))?;
+            let mut counted = LineCounter {
+                out: &mut archive,
+                lines: 0,
+            };
+            io::copy(&mut data, &mut counted)?;
+            rows.insert(table, counted.lines);
+            writeln!(archive, "\\.")?;
+        }
+        archive.finish()?.flush()?;
+        transaction.commit()?;
+        Ok(rows)
+    }
+
+    /// Loads a backup made by `write`. Without `replace` every table must be
+    /// empty; with it their rows are deleted first. Either way it all happens
+    /// in one transaction.
+    pub fn restore(&self, input: &mut dyn Read, replace: bool) -> Result<Tables, BackupError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute("SET LOCAL statement_timeout = 0")?;
+        if replace {
+            transaction.batch_execute(&format!("TRUNCATE {}", TABLES.join(", ")))?;
+        } else {
+            for table in TABLES {
+                let sql = format!("SELECT EXISTS (SELECT 1 FROM {})", table);
+                if transaction.query_one(&sql, &[])?.get(0) {
+                    return Err(BackupError::NotEmpty(table));
+                }
+            }
+        }
+
+        let mut input = BufReader::new(GzDecoder::new(input));
+        let mut rows = Tables::new();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end();
+            if text.is_empty() || text.starts_with("--") {
+                continue;
+            }
+            let (table, columns) =
+                parse_copy(text).ok_or_else(|| BackupError::Invalid(text.to_string()))?;
+            let mut data =
+                transaction.copy_in(&format!("COPY {} ({}) FROM STDIN", table, columns))?;
+            loop {
+                line.clear();
+                if input.read_until(b'\n', &mut line)? == 0 {
+                    return Err(BackupError::Invalid(format!("end of {} data", table)));
+                }
+                if line.strip_suffix(b"\n").unwrap_or(&line) == b"\\." {
+                    break;
+                }
+                data.write_all(&line)?;
+            }
+            rows.insert(table, data.finish()?);
+        }
+
+        for table in SERIAL_TABLES {
+            transaction.batch_execute(&format!(
+                "SELECT setval(pg_get_serial_sequence('{0}', 'id'), COALESCE(MAX(id), 0) + 1, false)
+                 FROM {0}",
+                table
+            ))?;
+        }
+        transaction.commit()?;
+        Ok(rows)
+    }
+}
+
+/// The table's columns, comma separated, so a restore does not depend on the
+/// order migrations added them in.
+fn columns(transaction: &mut Transaction, table: &str) -> Result<String, BackupError> {
+    let rows = transaction.query(
+        "SELECT column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1
+         ORDER BY ordinal_position",
+        &[&table],
+    )?;
+    Ok(rows
+        .iter()
+        .map(|row| row.get::<_, String>(0))
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+/// The table and columns of a `COPY <table> (<columns>) FROM stdin;` line,
+/// for one of our tables and plain column names only.
+fn parse_copy(line: &str) -> Option<(&'static str, String)> {
+    let rest = line.strip_prefix("COPY ")?.strip_suffix(") FROM stdin;")?;
+    let (table, columns) = rest.split_once(" (")?;
+    let table = TABLES.into_iter().find(|known| *known == table)?;
+    let columns: Vec<&str> = columns.split(',').map(str::trim).collect();
+    let plain = columns.iter().all(|column| {
+        !column.is_empty()
+            && column
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    });
+    plain.then(|| (table, columns.join(", ")))
+}
+
+/// Counts the rows going by: in COPY's text format every row ends with a line
+/// feed and line feeds in values are escaped.
+struct LineCounter<'a, W: Write> {
+    out: &'a mut W,
+    lines: u64,
+}
+
+impl<W: Write> Write for LineCounter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.lines += buf[..written].iter().filter(|&&b| b == b'\n').count() as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_copy_blocks_for_our_tables_only() {
+        assert_eq!(
+            parse_copy("COPY users (id, name, email, email_index) FROM stdin;"),
+            Some(("users", "id, name, email, email_index".to_string()))
+        );
+        assert_eq!(parse_copy("COPY outbox (id, payload) FROM stdin;"), None);
+        assert_eq!(
+            parse_copy("COPY users (id, name); DROP TABLE users; --) FROM stdin;"),
+            None
+        );
+        assert_eq!(parse_copy("DROP TABLE users;"), None);
+        assert_eq!(parse_copy("COPY users () FROM stdin;"), None);
+    }
+}
@@ -0,0 +1,512 @@
+use crate::https;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+*  Load generation
+*
+*  `rust_api bench` runs a CRUD workload against a running instance and
+*  reports latency percentiles and error rates per operation:
+*
+*      rust_api bench --url http://127.0.0.1:8080 --concurrency 16 --duration 30 \
+*          --mix create=1,read=5,list=1,update=2,delete=1
+*
+*  Each worker picks operations at random by their weight in the mix and
+*  sends them one after another. Workers only read, update and delete users
+*  they created themselves, so they never step on each other; creates go
+*  through `PUT /users/by-email/:email`, which answers the new id. A worker
+*  without users creates one instead. What is left is deleted at the end,
+*  outside the measurements.
+*
+*  Any transport error or status other than 2xx counts as an error.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Create,
+    Read,
+    List,
+    Update,
+    Delete,
+}
+
+const OPERATIONS: [Operation; 5] = [
+    Operation::Create,
+    Operation::Read,
+    Operation::List,
+    Operation::Update,
+    Operation::Delete,
+];
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Read => "read",
+            Operation::List => "list",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+const USAGE: &str = "Usage: bench [--url <url>] [--concurrency <workers>] \
+    [--duration <seconds> | --requests <count>] [--mix <operation>=<weight>,...] \
+    [--token <bearer token>] [--timeout <seconds>]";
+
+#[derive(Debug)]
+pub struct Options {
+    /// Where the API is mounted, including any `BASE_PATH`.
+    pub url: String,
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// Stops after this many requests in all instead of after `duration`.
+    pub requests: Option<u64>,
+    /// Weight of each operation, in `OPERATIONS` order.
+    pub mix: [u32; 5],
+    /// Sent as `Authorization: Bearer <token>`, for instances that want an API
+    /// key.
+    pub token: Option<String>,
+    /// How long a request may take before it counts as an error.
+    pub timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            url: "http://127.0.0.1:8080".to_string(),
+            concurrency: 8,
+            duration: Duration::from_secs(10),
+            requests: None,
+            mix: [1, 5, 1, 2, 1],
+            token: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Options {
+    /// Reads the arguments following `bench`; whatever is not given keeps its
+    /// default.
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(USAGE)?;
+            match flag.as_str() {
+                "--url" => options.url = value.trim_end_matches('/').to_string(),
+                "--concurrency" => {
+                    options.concurrency = value
+                        .parse()
+                        .ok()
+                        .filter(|&workers| workers > 0)
+                        .ok_or("Invalid --concurrency: expected a positive number")?
+                }
+                "--duration" => options.duration = seconds("--duration", value)?,
+                "--requests" => {
+                    options.requests = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&count| count > 0)
+                            .ok_or("Invalid --requests: expected a positive number")?,
+                    )
+                }
+                "--mix" => options.mix = parse_mix(value)?,
+                "--token" => options.token = Some(value.clone()),
+                "--timeout" => options.timeout = seconds("--timeout", value)?,
+                _ => return Err(USAGE.to_string()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn seconds(flag: &str, value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("Invalid {}: expected a number of seconds", flag))
+}
+
+/// `create=1,read=5`: operations left out get no share.
+fn parse_mix(value: &str) -> Result<[u32; 5], String> {
+    let mut mix = [0; 5];
+    for pair in value.split(',') {
+        let invalid = || format!("Invalid --mix entry {:?}", pair);
+        let (name, weight) = pair.split_once('=').ok_or_else(invalid)?;
+        let index = OPERATIONS
+            .iter()
+            .position(|operation| operation.name() == name.trim())
+            .ok_or_else(invalid)?;
+        mix[index] = weight.trim().parse().map_err(|_| invalid())?;
+    }
+    if mix.iter().all(|&weight| weight == 0) {
+        return Err("Invalid --mix: every weight is 0".to_string());
+    }
+    Ok(mix)
+}
+
+/// A request sent and how it went.
+struct Sample {
+    operation: Operation,
+    latency: Duration,
+    /// Why it failed, e.g. `503 Service Unavailable`.
+    error: Option<String>,
+}
+
+/// Runs the workload and reports on it.
+pub fn run(options: &Options) -> Report {
+    // Tells this run's users apart from earlier ones.
+    let run = format!("{:08x}", rand::random::<u32>());
+    let sent = AtomicU64::new(0);
+    let started = Instant::now();
+    let samples = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.concurrency)
+            .map(|worker| {
+                let (run, sent) = (&run, &sent);
+                scope.spawn(move || {
+                    Worker {
+                        options,
+                        agent: https::agent(options.timeout),
+                        prefix: format!("bench-{}-{}", run, worker),
+                        created: 0,
+                        users: Vec::new(),
+                    }
+                    .work(started, sent)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    Report::new(samples, started.elapsed())
+}
+
+struct Worker<'a> {
+    options: &'a Options,
+    agent: ureq::Agent,
+    /// Starts the email of every user it creates.
+    prefix: String,
+    created: u64,
+    /// Id and email of the users it created and has not deleted yet.
+    users: Vec<(i64, String)>,
+}
+
+impl Worker<'_> {
+    fn work(mut self, started: Instant, sent: &AtomicU64) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        loop {
+            let more = match self.options.requests {
+                Some(requests) => sent.fetch_add(1, Ordering::Relaxed) < requests,
+                None => started.elapsed() < self.options.duration,
+            };
+            if !more {
+                break;
+            }
+            let operation = match self.pick() {
+                Operation::Create => Operation::Create,
+                _ if self.users.is_empty() => Operation::Create,
+                operation => operation,
+            };
+            let begun = Instant::now();
+            let error = self.send(operation).err();
+            samples.push(Sample {
+                operation,
+                latency: begun.elapsed(),
+                error,
+            });
+        }
+        for (id, _) in std::mem::take(&mut self.users) {
+            self.request("DELETE", &format!("/users/{}", id), None).ok();
+        }
+        samples
+    }
+
+    fn pick(&self) -> Operation {
+        let mix = &self.options.mix;
+        let mut roll = rand::random_range(0..mix.iter().sum::<u32>());
+        for (operation, &weight) in OPERATIONS.iter().zip(mix) {
+            if roll < weight {
+                return *operation;
+            }
+            roll -= weight;
+        }
+        unreachable!("the roll is below the sum of the weights")
+    }
+
+    fn send(&mut self, operation: Operation) -> Result<(), String> {
+        let user = rand::random_range(0..self.users.len().max(1));
+        match operation {
+            Operation::Create => {
+                self.created += 1;
+                let email = format!("{}-{}@example.com", self.prefix, self.created);
+                let body = json!({ "name": "Bench User" });
+                let created =
+                    self.request("PUT", &format!("/users/by-email/{}", email), Some(body))?;
+                let id = created["id"].as_i64().ok_or("no id in the response")?;
+                self.users.push((id, email));
+            }
+            Operation::Read => {
+                self.request("GET", &format!("/users/{}", self.users[user].0), None)?;
+            }
+            Operation::List => {
+                self.request("GET", "/users?$top=20", None)?;
+            }
+            Operation::Update => {
+                let (id, email) = &self.users[user];
+                let body =
+                    json!({ "name": format!("Bench User {}", self.created), "email": email });
+                self.request("PUT", &format!("/users/{}", id), Some(body))?;
+            }
+            Operation::Delete => {
+                let (id, _) = self.users.swap_remove(user);
+                self.request("DELETE", &format!("/users/{}", id), None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the request and returns the body when it is JSON, `Null` for any
+    /// other successful response.
+    fn request(&self, method: &str, path: &str, body: Option<Value>) -> Result<Value, String> {
+        let uri = format!("{}{}", self.options.url, path);
+        let mut request = ureq::http::Request::builder().method(method).uri(uri);
+        if let Some(token) = &self.options.token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        let response = match body {
+            Some(body) => request
+                .header("Content-Type", "application/json")
+                .body(body.to_string())
+                .map(|request| self.agent.run(request)),
+            None => request.body(()).map(|request| self.agent.run(request)),
+        };
+        let mut response = response
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        let text = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(status.to_string());
+        }
+        Ok(serde_json::from_str(&text).unwrap_or(Value::Null))
+    }
+}
+
+/// How each operation went.
+#[derive(Debug)]
+pub struct Report {
+    pub operations: Vec<OperationReport>,
+    pub elapsed: Duration,
+    /// How often each error happened, across operations.
+    pub errors: BTreeMap<String, u64>,
+}
+
+#[derive(Debug)]
+pub struct OperationReport {
+    pub operation: Operation,
+    /// Sorted, fastest first.
+    pub latencies: Vec<Duration>,
+    pub errors: u64,
+}
+
+impl OperationReport {
+    /// The latency `percent` of the requests stayed within (nearest rank).
+    pub fn percentile(&self, percent: f64) -> Duration {
+        percentile(&self.latencies, percent)
+    }
+}
+
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl Report {
+    fn new(samples: Vec<Sample>, elapsed: Duration) -> Report {
+        let mut operations: BTreeMap<Operation, OperationReport> = BTreeMap::new();
+        let mut errors = BTreeMap::new();
+        for sample in samples {
+            let report = operations
+                .entry(sample.operation)
+                .or_insert_with(|| OperationReport {
+                    operation: sample.operation,
+                    latencies: Vec::new(),
+                    errors: 0,
+                });
+            report.latencies.push(sample.latency);
+            if let Some(error) = sample.error {
+                report.errors += 1;
+                *errors.entry(error).or_insert(0) += 1;
+            }
+        }
+        let mut operations: Vec<_> = operations.into_values().collect();
+        for report in &mut operations {
+            report.latencies.sort();
+        }
+        Report {
+            operations,
+            elapsed,
+            errors,
+        }
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.operations
+            .iter()
+            .map(|report| report.latencies.len() as u64)
+            .sum()
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.operations.iter().map(|report| report.errors).sum()
+    }
+}
+
+/// A table with a line per operation and one for all of them.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            "operation", "requests", "errors", "p50", "p90", "p99", "max"
+        )?;
+        let mut all: Vec<Duration> = Vec::new();
+        for report in &self.operations {
+            all.extend(&report.latencies);
+            row(f, report.operation.name(), &report.latencies, report.errors)?;
+        }
+        all.sort();
+        row(f, "total", &all, self.error_count())?;
+
+        let requests = self.requests();
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "\n{} requests in {:.1}s, {:.1} requests/s, {:.2}% errors",
+            requests,
+            seconds,
+            requests as f64 / seconds.max(f64::EPSILON),
+            100.0 * self.error_count() as f64 / requests.max(1) as f64
+        )?;
+        for (error, count) in &self.errors {
+            writeln!(f, "{:>8}  {}", count, error)?;
+        }
+        Ok(())
+    }
+}
+
+fn row(f: &mut fmt::Formatter, name: &str, sorted: &[Duration], errors: u64) -> fmt::Result {
+    let millis = |percent| {
+        format!(
+            "{:.1}ms",
+            percentile(sorted, percent).as_secs_f64() * 1000.0
+        )
+    };
+    writeln!(
+        f,
+        "{:<10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        name,
+        sorted.len(),
+        errors,
+        millis(50.0),
+        millis(90.0),
+        millis(99.0),
+        millis(100.0)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = Options::parse(&args(
+            "--url http://api:8080/crud/ --concurrency 4 --requests 500 --mix read=3,list=1 --timeout 2.5",
+        ))
+        .unwrap();
+        assert_eq!(options.url, "http://api:8080/crud");
+        assert_eq!(options.concurrency, 4);
+        assert_eq!(options.requests, Some(500));
+        assert_eq!(options.mix, [0, 3, 1, 0, 0]);
+        assert_eq!(options.timeout, Duration::from_millis(2500));
+        assert_eq!(Options::parse(&[]).unwrap().mix, [1, 5, 1, 2, 1]);
+
+        for (line, error) in [
+            (
+                "--concurrency 0",
+                "Invalid --concurrency: expected a positive number",
+            ),
+            (
+                "--duration soon",
+                "Invalid --duration: expected a number of seconds",
+            ),
+            ("--mix read=1,fetch=2", "Invalid --mix entry \"fetch=2\""),
+            ("--mix read=0", "Invalid --mix: every weight is 0"),
+            ("--url", USAGE),
+            ("--verbose yes", USAGE),
+        ] {
+            assert_eq!(Options::parse(&args(line)).unwrap_err(), error, "{}", line);
+        }
+    }
+
+    #[test]
+    fn reports_percentiles_and_errors() {
+        let samples = (1..=100)
+            .map(|millis| Sample {
+                operation: if millis % 4 == 0 {
+                    Operation::Create
+                } else {
+                    Operation::Read
+                },
+                latency: Duration::from_millis(millis),
+                error: (millis > 98).then(|| "503 Service Unavailable".to_string()),
+            })
+            .collect();
+        let report = Report::new(samples, Duration::from_secs(2));
+        assert_eq!(report.requests(), 100);
+        assert_eq!(report.error_count(), 2);
+        assert_eq!(report.errors["503 Service Unavailable"], 2);
+
+        let create = &report.operations[0];
+        assert_eq!(create.operation, Operation::Create);
+        assert_eq!(create.latencies.len(), 25);
+        assert_eq!(create.percentile(50.0), Duration::from_millis(52));
+        assert_eq!(create.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(create.errors, 1);
+
+        let table = report.to_string();
+        assert!(
+            table.contains(
+                "\ntotal            100         2    50.0ms    90.0ms    99.0ms   100.0ms\n"
+            ),
+            "{}",
+            table
+        );
+        assert!(
+            table.contains("100 requests in 2.0s, 50.0 requests/s, 2.00% errors"),
+            "{}",
+            table
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use crate::context;
+use log::info;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/*
+*  Body log
+*
+*  Opt-in logging of request and response bodies for debugging client
+*  integrations. JSON bodies are logged with the values of redacted fields
+*  masked at any depth; anything else is logged by size only, since it cannot be
+*  redacted.
+*/
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Field names to mask, lowercased.
+static REDACTED_FIELDS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+const MASK: &str = "<redacted>";
+
+/// Masked unless `LOG_REDACT_FIELDS` says otherwise: personal data, and the
+/// credentials the admin login and two-factor routes send and return.
+pub const DEFAULT_REDACTED_FIELDS: [&str; 8] = [
+    "password",
+    "email",
+    "token",
+    "code",
+    "secret",
+    "uri",
+    "recovery_codes",
+    "csrf_token",
+];
+
+pub fn configure(enabled: bool, redacted_fields: &[String]) {
+    *REDACTED_FIELDS.write().unwrap() = redacted_fields
+        .iter()
+        .map(|field| field.to_ascii_lowercase())
+        .collect();
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn request(body: &[u8]) {
+    if ENABLED.load(Ordering::Relaxed) && !body.is_empty() {
+        info!(
+            "Request body (request {}): {}",
+            context::request_id().as_deref().unwrap_or("-"),
+            redact(body)
+        );
+    }
+}
+
+pub fn response(status: &str, body: &[u8]) {
+    if ENABLED.load(Ordering::Relaxed) {
+        info!(
+            "Response body (request {}, {}): {}",
+            context::request_id().as_deref().unwrap_or("-"),
+            status,
+            redact(body)
+        );
+    }
+}
+
+fn redact(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            mask(&mut value, &REDACTED_FIELDS.read().unwrap());
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", body.len()),
+    }
+}
+
+fn mask(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if fields.contains(&key.to_ascii_lowercase()) {
+                    *value = Value::String(MASK.to_string());
+                } else {
+                    mask(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| mask(item, fields)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn masks_redacted_fields_at_any_depth() {
+        let fields = vec!["password".to_string(), "email".to_string()];
+        let mut value = json!({
+            "name": "Ada",
+            "Email": "ada@example.com",
+            "credentials": { "password": { "nested": "secret" } },
+            "users": [{ "email": "bob@example.com", "id": 2 }]
+        });
+        mask(&mut value, &fields);
+
+        assert_eq!(
+            value,
+            json!({
+                "name": "Ada",
+                "Email": MASK,
+                "credentials": { "password": MASK },
+                "users": [{ "email": MASK, "id": 2 }]
+            })
+        );
+    }
+
+    #[test]
+    fn masks_admin_credentials_by_default() {
+        let fields: Vec<String> = DEFAULT_REDACTED_FIELDS.map(String::from).to_vec();
+        let mut login = json!({ "token": "s3cret", "code": "123456" });
+        mask(&mut login, &fields);
+        assert_eq!(login, json!({ "token": MASK, "code": MASK }));
+
+        let mut enrollment = json!({
+            "secret": "JBSWY3DPEHPK3PXP",
+            "uri": "otpauth://totp/rust-crud:admin?secret=JBSWY3DPEHPK3PXP",
+            "recovery_codes": ["ab12-cd34", "ef56-ab78"],
+        });
+        mask(&mut enrollment, &fields);
+        assert_eq!(
+            enrollment,
+            json!({ "secret": MASK, "uri": MASK, "recovery_codes": MASK })
+        );
+
+        let mut session = json!({ "authenticated": true, "csrf_token": "0f1e2d" });
+        mask(&mut session, &fields);
+        assert_eq!(
+            session,
+            json!({ "authenticated": true, "csrf_token": MASK })
+        );
+    }
+
+    #[test]
+    fn logs_non_json_by_size_only() {
+        assert_eq!(redact(b"email=ada@example.com"), "<21 bytes, not JSON>");
+    }
+}
@@ -0,0 +1,21 @@
+/*
+*  Build info
+*
+*  Which build is running, as embedded by `build.rs`, for `GET /version` and
+*  the startup log.
+*/
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The commit built, or `unknown` without git or `GIT_SHA`.
+pub const GIT_SHA: &str = env!("BUILD_GIT_SHA");
+/// RFC 3339, UTC.
+pub const BUILT_AT: &str = env!("BUILD_TIMESTAMP");
+const FEATURES: &str = env!("BUILD_FEATURES");
+
+/// The cargo features enabled, sorted.
+pub fn features() -> Vec<&'static str> {
+    FEATURES
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
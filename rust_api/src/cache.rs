@@ -0,0 +1,108 @@
+use crate::http::has_header;
+use std::io::{self, Write};
+
+/*
+*  Caching headers
+*
+*  Successful reads get the configured `Cache-Control` unless their handler
+*  picked one itself, which the export routes do to keep personal data out of
+*  shared caches. The header is added on the way to the client, so streamed
+*  responses get it too.
+*/
+
+/// Writes one response through, adding `Cache-Control` to its head if the
+/// status is 2xx and the handler did not set one. Buffers only until the end
+/// of the head.
+pub struct CacheControl<'a> {
+    out: &'a mut dyn Write,
+    /// Taken once the head has gone out.
+    value: Option<&'a str>,
+    head: Vec<u8>,
+}
+
+impl<'a> CacheControl<'a> {
+    /// With no value, everything is passed through untouched.
+    pub fn new(out: &'a mut dyn Write, value: Option<&'a str>) -> Self {
+        CacheControl {
+            out,
+            value,
+            head: Vec::new(),
+        }
+    }
+}
+
+impl Write for CacheControl<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(value) = self.value else {
+            return self.out.write(buf);
+        };
+
+        self.head.extend_from_slice(buf);
+        if let Some(end) = self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+            self.value = None;
+            let buffered = std::mem::take(&mut self.head);
+            let (head, rest) = buffered.split_at(end + 2);
+            self.out.write_all(head)?;
+            if is_success(head) && !has_header(head, "Cache-Control") {
+                write!(self.out, "Cache-Control: {}\r\n", value)?;
+            }
+            self.out.write_all(rest)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Sends whatever was held back, even if it never formed a full head.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.head.is_empty() {
+            self.value = None;
+            let buffered = std::mem::take(&mut self.head);
+            self.out.write_all(&buffered)?;
+        }
+        self.out.flush()
+    }
+}
+
+fn is_success(head: &[u8]) -> bool {
+    head.split(|&b| b == b' ')
+        .nth(1)
+        .is_some_and(|status| status.len() == 3 && status[0] == b'2')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(value: Option<&str>, chunks: &[&str]) -> String {
+        let mut sink = Vec::new();
+        let mut out = CacheControl::new(&mut sink, value);
+        for chunk in chunks {
+            out.write_all(chunk.as_bytes()).unwrap();
+        }
+        out.flush().unwrap();
+        String::from_utf8(sink).unwrap()
+    }
+
+    #[test]
+    fn adds_the_header_to_successful_responses_only() {
+        let policy = Some("private, max-age=30");
+        assert_eq!(
+            written(policy, &["HTTP/1.1 200 OK\r\nContent-", "Type: a/b\r\n\r\nbo", "dy"]),
+            "HTTP/1.1 200 OK\r\nContent-Type: a/b\r\nCache-Control: private, max-age=30\r\n\r\nbody"
+        );
+        assert_eq!(
+            written(policy, &["HTTP/1.1 404 NOT FOUND\r\n\r\nUser Not Found"]),
+            "HTTP/1.1 404 NOT FOUND\r\n\r\nUser Not Found"
+        );
+        assert_eq!(
+            written(
+                policy,
+                &["HTTP/1.1 200 OK\r\ncache-control: no-store\r\n\r\n"]
+            ),
+            "HTTP/1.1 200 OK\r\ncache-control: no-store\r\n\r\n"
+        );
+        assert_eq!(
+            written(None, &["HTTP/1.1 200 OK\r\n\r\n"]),
+            "HTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use crate::envelope;
+use crate::hal;
+use crate::http::Request;
+use crate::jsonapi::{self, InvalidDocument};
+use crate::multipart::{self, MultipartError};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/*
+*  Content negotiation
+*
+*  Handlers only ever see and produce JSON. Bodies sent as
+*  `Content-Type: application/msgpack` are converted to JSON before routing, and
+*  JSON responses are converted to MessagePack when the client's `Accept`
+*  header asks for it. Form bodies, `application/x-www-form-urlencoded` or
+*  `multipart/form-data` as HTML forms send them, become a JSON object of their
+*  fields, each a string, or an array of strings when the field was sent more
+*  than once. JSON:API documents are unwrapped on the way in and built on the
+*  way out, see `crate::jsonapi`, and HAL resources are built on the way out,
+*  see `crate::hal`. Routes that take a body refuse any other `Content-Type`
+*  with `415`.
+*/
+
+const JSON: &str = "application/json";
+const MSGPACK: &str = "application/msgpack";
+const FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+const FORM_DATA: &str = "multipart/form-data";
+/// Media types a request body may be sent as.
+pub const BODY_TYPES: [&str; 5] = [
+    JSON,
+    jsonapi::MEDIA_TYPE,
+    MSGPACK,
+    FORM_URLENCODED,
+    FORM_DATA,
+];
+
+#[derive(Debug)]
+pub enum DecodeError {
+    MessagePack(rmp_serde::decode::Error),
+    JsonApi(InvalidDocument),
+    Multipart(MultipartError),
+    /// Form fields must be text; names the field.
+    NotText(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::MessagePack(e) => write!(f, "{}", e),
+            DecodeError::JsonApi(e) => write!(f, "{}", e),
+            DecodeError::Multipart(e) => write!(f, "{}", e),
+            DecodeError::NotText(name) => write!(f, "field {:?} is not UTF-8 text", name),
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DecodeError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        DecodeError::MessagePack(e)
+    }
+}
+
+impl From<InvalidDocument> for DecodeError {
+    fn from(e: InvalidDocument) -> Self {
+        DecodeError::JsonApi(e)
+    }
+}
+
+impl From<MultipartError> for DecodeError {
+    fn from(e: MultipartError) -> Self {
+        DecodeError::Multipart(e)
+    }
+}
+
+const JSON_CONTENT_TYPE: &str = "Content-Type: application/json\r\n";
+
+fn is_msgpack(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(MSGPACK) || essence.eq_ignore_ascii_case("application/x-msgpack")
+}
+
+fn is_urlencoded(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(FORM_URLENCODED)
+}
+
+/// Whether the body's `Content-Type` is one of `BODY_TYPES`. JSON must be
+/// UTF-8 (RFC 8259), so a `charset` naming anything else is refused.
+pub fn has_supported_body(request: &Request) -> bool {
+    let Some(content_type) = request.header("Content-Type") else {
+        return false;
+    };
+    if is_msgpack(content_type)
+        || jsonapi::is_jsonapi(content_type)
+        || is_urlencoded(content_type)
+        || multipart::is_form_data(content_type)
+    {
+        return true;
+    }
+    let mut params = content_type.split(';');
+    params
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(JSON)
+        && params.all(|param| match param.split_once('=') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("charset") => {
+                let charset = value.trim().trim_matches('"');
+                charset.eq_ignore_ascii_case("utf-8") || charset.eq_ignore_ascii_case("utf8")
+            }
+            _ => true,
+        })
+}
+
+/// Rewrites a MessagePack, JSON:API or form body as JSON so the rest of the
+/// pipeline can stay JSON-only.
+pub fn decode_request(request: &mut Request) -> Result<(), DecodeError> {
+    let Some(content_type) = request.header("Content-Type") else {
+        return Ok(());
+    };
+    if is_msgpack(content_type) && !request.body.is_empty() {
+        let body: Value = rmp_serde::from_slice(&request.body)?;
+        request.body = body.to_string().into_bytes();
+    } else if jsonapi::is_jsonapi(content_type) && !request.body.is_empty() {
+        request.body = jsonapi::decode(&request.body)?.to_string().into_bytes();
+    } else if is_urlencoded(content_type) {
+        request.body = form_object(request.form()).to_string().into_bytes();
+    } else if multipart::is_form_data(content_type) {
+        request.body = multipart_fields(request)?.to_string().into_bytes();
+    }
+    Ok(())
+}
+
+/// The fields of a `multipart/form-data` body as a JSON object. Files are
+/// taken as fields too, as long as they hold text.
+fn multipart_fields(request: &Request) -> Result<Value, DecodeError> {
+    let fields = request
+        .multipart(&multipart::Limits::default())?
+        .into_iter()
+        .map(|part| match std::str::from_utf8(part.body) {
+            Ok(value) => Ok((part.name, value.to_string())),
+            Err(_) => Err(DecodeError::NotText(part.name)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(form_object(fields))
+}
+
+/// Form fields as a JSON object, with repeated fields as arrays.
+fn form_object(fields: Vec<(String, String)>) -> Value {
+    let mut object = Map::new();
+    for (name, value) in fields {
+        let value = Value::String(value);
+        match object.get_mut(&name) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(first) => *first = Value::Array(vec![first.take(), value]),
+            None => {
+                object.insert(name, value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// Whether the client listed MessagePack in `Accept` without ruling it out
+/// through `q=0`.
+pub fn wants_msgpack(request: &Request) -> bool {
+    accepts(request, is_msgpack)
+}
+
+/// Whether the client listed JSON:API in `Accept`, likewise.
+pub fn wants_jsonapi(request: &Request) -> bool {
+    accepts(request, jsonapi::is_jsonapi)
+}
+
+/// Whether the client listed HAL in `Accept`, likewise.
+pub fn wants_hal(request: &Request) -> bool {
+    accepts(request, hal::is_hal)
+}
+
+fn accepts(request: &Request, is_type: fn(&str) -> bool) -> bool {
+    request
+        .header_values("Accept")
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut params = range.split(';');
+            is_type(params.next().unwrap_or_default())
+                && !params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                })
+        })
+}
+
+/// Produces the bytes to send for a handler's response, encoding JSON bodies
+/// as MessagePack when negotiated. Bodies that are not valid JSON, such as the
+/// plain status messages, are sent as a MessagePack string.
+pub fn encode_response(
+    request: &Request,
+    status_line: String,
+    content: String,
+) -> (String, Vec<u8>) {
+    let (status_line, content) = if wants_jsonapi(request) {
+        jsonapi::render(request, status_line, content)
+    } else if wants_hal(request) {
+        hal::render(request, status_line, content)
+    } else {
+        envelope::wrap(request, status_line, content)
+    };
+    if !status_line.contains(JSON_CONTENT_TYPE) || !wants_msgpack(request) {
+        return (status_line, content.into_bytes());
+    }
+
+    let value = serde_json::from_str(&content).unwrap_or(Value::String(content));
+    match rmp_serde::to_vec_named(&value) {
+        Ok(body) => (
+            status_line.replace(JSON_CONTENT_TYPE, &format!("Content-Type: {}\r\n", MSGPACK)),
+            body,
+        ),
+        Err(_) => {
+            let content = value.to_string();
+            (status_line, content.into_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_content_type(content_type: Option<&str>) -> Request {
+        let header = content_type.map_or(String::new(), |value| {
+            format!("Content-Type: {}\r\n", value)
+        });
+        let raw = format!("POST /users HTTP/1.1\r\n{}\r\n{{}}", header);
+        Request::parse(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn accepts_json_and_messagepack_bodies_only() {
+        for supported in [
+            "application/json",
+            "Application/JSON",
+            "application/json; charset=utf-8",
+            "application/json;charset=\"UTF8\"",
+            "application/msgpack",
+            "application/x-msgpack",
+            "multipart/form-data; boundary=x",
+            "application/x-www-form-urlencoded",
+        ] {
+            assert!(
+                has_supported_body(&with_content_type(Some(supported))),
+                "{}",
+                supported
+            );
+        }
+        for unsupported in [
+            "text/plain",
+            "application/json; charset=iso-8859-1",
+            "application/jsonx",
+            "multipart/mixed; boundary=x",
+        ] {
+            assert!(
+                !has_supported_body(&with_content_type(Some(unsupported))),
+                "{}",
+                unsupported
+            );
+        }
+        assert!(!has_supported_body(&with_content_type(None)));
+    }
+
+    #[test]
+    fn turns_urlencoded_forms_into_json() {
+        let raw = "POST /users HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n\
+                   name=Ada+Lovelace&email=ada%40example.com&tag=a&tag=b";
+        let mut request = Request::parse(raw.as_bytes()).unwrap();
+        decode_request(&mut request).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&request.body).unwrap(),
+            serde_json::json!({
+                "name": "Ada Lovelace",
+                "email": "ada@example.com",
+                "tag": ["a", "b"],
+            })
+        );
+    }
+
+    #[test]
+    fn turns_form_data_into_json() {
+        let body = "--b\r\nContent-Disposition: form-data; name=\"name\"\r\n\r\nAda\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\na\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\nb\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"tag\"\r\n\r\nc\r\n--b--";
+        let raw = format!(
+            "POST /users HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\r\n{}",
+            body
+        );
+        let mut request = Request::parse(raw.as_bytes()).unwrap();
+        decode_request(&mut request).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&request.body).unwrap(),
+            serde_json::json!({ "name": "Ada", "tag": ["a", "b", "c"] })
+        );
+
+        let mut request = with_content_type(Some("multipart/form-data; boundary=b"));
+        assert!(matches!(
+            decode_request(&mut request),
+            Err(DecodeError::Multipart(MultipartError::Malformed(_)))
+        ));
+    }
+}
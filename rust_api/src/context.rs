@@ -0,0 +1,195 @@
+use crate::http::Request;
+use std::cell::RefCell;
+
+/*
+*  Request context
+*
+*  Every connection is served on its own thread, so the request currently being
+*  handled is tracked in a thread-local. Code far from the handler (queries,
+*  logging) can then tag its output without having the ids passed down.
+*/
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// What ties log entries of one request together, here and in the services
+/// around us.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    pub request_id: String,
+    /// From a W3C `traceparent` header.
+    pub trace_id: Option<String>,
+    /// From `X-Tenant-Id`, as told by the gateway.
+    pub tenant: Option<String>,
+    /// Subject of the client certificate, on `tls:` listeners that ask for one.
+    pub client_subject: Option<String>,
+    /// Pattern of the matched route, once routing is done.
+    pub route: Option<&'static str>,
+    /// The first few errors logged while serving the request, for error
+    /// reports.
+    pub errors: Vec<String>,
+    /// An error report was sent for the request already.
+    pub reported: bool,
+}
+
+/// Errors kept per request.
+const MAX_ERRORS: usize = 10;
+
+/// Clears the context when the request is done.
+pub struct RequestScope(());
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.borrow_mut().take());
+    }
+}
+
+pub fn enter(context: Context) -> RequestScope {
+    CONTEXT.with(|current| *current.borrow_mut() = Some(context));
+    RequestScope(())
+}
+
+pub fn current() -> Option<Context> {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+pub fn request_id() -> Option<String> {
+    CONTEXT.with(|context| {
+        context
+            .borrow()
+            .as_ref()
+            .map(|context| context.request_id.clone())
+    })
+}
+
+/// Records the route the request was dispatched to.
+pub fn set_route(route: &'static str) {
+    CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut() {
+            context.route = Some(route);
+        }
+    });
+}
+
+/// Remembers an error logged while serving the request.
+pub fn note_error(message: String) {
+    CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut() {
+            if context.errors.len() < MAX_ERRORS {
+                context.errors.push(message);
+            }
+        }
+    });
+}
+
+pub fn mark_reported() {
+    CONTEXT.with(|context| {
+        if let Some(context) = context.borrow_mut().as_mut() {
+            context.reported = true;
+        }
+    });
+}
+
+pub fn for_request(request: &Request) -> Context {
+    Context {
+        request_id: request_id_for(request),
+        trace_id: request.header("traceparent").and_then(trace_id),
+        tenant: request
+            .header("X-Tenant-Id")
+            .filter(|tenant| is_sane(tenant))
+            .map(str::to_string),
+        client_subject: request.client_subject.clone(),
+        route: None,
+        errors: Vec::new(),
+        reported: false,
+    }
+}
+
+/// Reuses the caller's `X-Request-Id` when it looks sane, otherwise makes one up.
+fn request_id_for(request: &Request) -> String {
+    request
+        .header("X-Request-Id")
+        .filter(|id| is_sane(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+/// Short and printable, so it cannot break a log line.
+fn is_sane(value: &str) -> bool {
+    !value.is_empty() && value.len() <= 128 && value.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// The trace id of a `traceparent` header (`00-<trace id>-<parent id>-<flags>`),
+/// unless it is malformed or the all-zero id.
+fn trace_id(traceparent: &str) -> Option<String> {
+    let mut fields = traceparent.trim().split('-');
+    let (version, trace_id) = (fields.next()?, fields.next()?);
+    let is_hex = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    let valid = version.len() == 2
+        && is_hex(version)
+        && version != "ff"
+        && trace_id.len() == 32
+        && is_hex(trace_id)
+        && trace_id.bytes().any(|b| b != b'0');
+    valid.then(|| trace_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> Request {
+        Request::parse(format!("GET /users HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn reads_correlation_headers() {
+        let context = for_request(&request(
+            "X-Request-Id: abc\r\n\
+             traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\
+             X-Tenant-Id: acme\r\n",
+        ));
+        assert_eq!(context.request_id, "abc");
+        assert_eq!(
+            context.trace_id.as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+
+        let context = for_request(&request("X-Tenant-Id: a b\r\n"));
+        assert_eq!(context.request_id.len(), 16);
+        assert_eq!(context.tenant, None);
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        assert_eq!(
+            trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            trace_id("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(
+            trace_id("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(trace_id("00-4bf92f35"), None);
+    }
+
+    #[test]
+    fn the_route_is_recorded_within_the_scope() {
+        let scope = enter(for_request(&request("")));
+        set_route("/users/:id");
+        assert_eq!(current().unwrap().route, Some("/users/:id"));
+        drop(scope);
+        assert_eq!(current(), None);
+        set_route("/users");
+        assert_eq!(current(), None);
+    }
+}
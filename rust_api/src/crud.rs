@@ -0,0 +1,132 @@
+use postgres::types::ToSql;
+use postgres::Row;
+use serde_json::Value;
+
+/*
+*  CRUD models
+*
+*  A model stored one field per column implements `CrudModel` with
+*  `#[derive(CrudModel)]` (the `crud_derive` crate) instead of spelling out
+*  its column list, statements, row mapping and request body schema by hand;
+*  the attributes are described there. The generated statements use
+*  positional parameters, filled from `insert_params` and `update_params`
+*  followed by the keys the statement names.
+*
+*  Backends still decide which statements to use: users go through their own
+*  SQL, since their email is encrypted and indexed separately.
+*/
+
+pub trait CrudModel: Sized {
+    const TABLE: &'static str;
+    /// Every column, in the order `from_row` reads them.
+    const COLUMNS: &'static str;
+    /// Columns with a unique constraint.
+    const UNIQUE: &'static [&'static str];
+    /// Writes the `insert_params` and returns the row as `COLUMNS`.
+    const INSERT: &'static str;
+    /// Writes the `update_params` to the row with the id, and parent if the
+    /// model has one, that follow them.
+    const UPDATE: &'static str;
+    /// Deletes the row with the id, and parent if the model has one.
+    const DELETE: &'static str;
+
+    fn from_row(row: &Row) -> Self;
+
+    fn insert_params(&self) -> Vec<&(dyn ToSql + Sync)>;
+
+    fn update_params(&self) -> Vec<&(dyn ToSql + Sync)>;
+
+    /// Request body schema for creating or replacing one.
+    fn schema() -> Value;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Address, User};
+    use crate::schema;
+    use serde_json::json;
+
+    #[test]
+    fn writes_the_statements_for_each_column() {
+        assert_eq!(Address::TABLE, "addresses");
+        assert_eq!(
+            Address::COLUMNS,
+            "id, user_id, line1, line2, city, postal_code, country"
+        );
+        assert_eq!(
+            Address::INSERT,
+            "INSERT INTO addresses (user_id, line1, line2, city, postal_code, country) \
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING \
+             id, user_id, line1, line2, city, postal_code, country"
+        );
+        assert_eq!(
+            Address::UPDATE,
+            "UPDATE addresses SET line1 = $1, line2 = $2, city = $3, postal_code = $4, \
+             country = $5 WHERE id = $6 AND user_id = $7"
+        );
+        assert_eq!(
+            Address::DELETE,
+            "DELETE FROM addresses WHERE id = $1 AND user_id = $2"
+        );
+
+        assert_eq!(User::COLUMNS, "id, name, email, created_at, updated_at");
+        assert_eq!(User::UNIQUE, ["email"]);
+        assert_eq!(
+            User::UPDATE,
+            "UPDATE users SET name = $1, email = $2 WHERE id = $3"
+        );
+    }
+
+    #[test]
+    fn passes_the_written_columns_in_order() {
+        let address = Address {
+            id: Some(1),
+            user_id: Some(2),
+            line1: "Unter den Linden 1".to_string(),
+            line2: None,
+            city: "Berlin".to_string(),
+            postal_code: "10117".to_string(),
+            country: "DE".to_string(),
+        };
+        assert_eq!(address.insert_params().len(), 6);
+        assert_eq!(address.update_params().len(), 5);
+    }
+
+    #[test]
+    fn describes_valid_request_bodies() {
+        assert_eq!(
+            User::schema(),
+            json!({
+                "type": "object",
+                "required": ["name", "email"],
+                "properties": {
+                    "id": { "type": ["integer", "null"] },
+                    "name": { "type": "string", "minLength": 1, "maxLength": 255 },
+                    "email": { "type": "string", "format": "email", "maxLength": 255 },
+                    "created_at": { "type": ["string", "null"] },
+                    "updated_at": { "type": ["string", "null"] }
+                },
+                "additionalProperties": false
+            })
+        );
+
+        let address = json!({
+            "line1": "Unter den Linden 1",
+            "city": "Berlin",
+            "postal_code": "10117",
+            "country": "DE"
+        });
+        assert!(schema::validate(&Address::schema(), &address).is_empty());
+        let mut invalid = address.clone();
+        invalid["country"] = json!("XX");
+        invalid["line2"] = json!("x".repeat(256));
+        let pointers: Vec<String> = schema::validate(&Address::schema(), &invalid)
+            .into_iter()
+            .map(|violation| violation.pointer)
+            .collect();
+        assert_eq!(pointers.len(), 2, "{:?}", pointers);
+        assert!(pointers.contains(&"/country".to_string()));
+        assert!(pointers.contains(&"/line2".to_string()));
+    }
+}
@@ -0,0 +1,266 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+/*
+*  Field encryption
+*
+*  Sensitive columns are stored as `enc:<key id>:<base64 nonce + ciphertext>`
+*  using AES-256-GCM. The key id lets old rows be read after a new key is
+*  introduced; values without the prefix are treated as not yet encrypted.
+*
+*  Encrypting with a fresh nonce every time means equal emails never store
+*  equal values, so they cannot be looked up or kept unique in SQL. A blind
+*  index stands in for that: the HMAC-SHA256 of the email under a key of its
+*  own, stored next to the ciphertext. It is deterministic, so equality works
+*  on it, and tells nothing about the email to anyone without the key. Unlike
+*  the encryption keys it cannot be rotated gradually: changing it means
+*  recomputing every row.
+*/
+
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// A named 256-bit key, configured as `<id>:<base64 key>`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    pub id: String,
+    bytes: [u8; 32],
+}
+
+impl FromStr for EncryptionKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, key) = s.split_once(':').ok_or(())?;
+        if id.is_empty()
+            || !id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(());
+        }
+        let bytes = BASE64.decode(key).map_err(|_| ())?;
+        Ok(EncryptionKey {
+            id: id.to_string(),
+            bytes: bytes.try_into().map_err(|_| ())?,
+        })
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey({})", self.id)
+    }
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    UnknownKey(String),
+    Malformed,
+    /// Wrong key or tampered ciphertext.
+    Decrypt,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::UnknownKey(id) => write!(f, "no encryption key with id {:?}", id),
+            CryptoError::Malformed => write!(f, "malformed encrypted value"),
+            CryptoError::Decrypt => write!(f, "decryption failed"),
+        }
+    }
+}
+
+/// Encrypts with the first key and decrypts with any of them.
+pub struct FieldCipher {
+    keys: Vec<(String, Aes256Gcm)>,
+}
+
+impl FieldCipher {
+    /// `None` when no keys are configured, meaning encryption is off.
+    pub fn new(keys: &[EncryptionKey]) -> Option<FieldCipher> {
+        if keys.is_empty() {
+            return None;
+        }
+        Some(FieldCipher {
+            keys: keys
+                .iter()
+                .map(|key| {
+                    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key.bytes));
+                    (key.id.clone(), cipher)
+                })
+                .collect(),
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.keys[0].0
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let (id, cipher) = &self.keys[0];
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("AES-GCM encryption cannot fail for in-memory input");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        format!("{}{}:{}", PREFIX, id, BASE64.encode(sealed))
+    }
+
+    /// Decrypts a stored value; values that were never encrypted pass through.
+    pub fn decrypt(&self, stored: &str) -> Result<String, CryptoError> {
+        let (id, sealed) = match stored.strip_prefix(PREFIX) {
+            Some(rest) => rest.split_once(':').ok_or(CryptoError::Malformed)?,
+            None => return Ok(stored.to_string()),
+        };
+        let cipher = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, cipher)| cipher)
+            .ok_or_else(|| CryptoError::UnknownKey(id.to_string()))?;
+
+        let sealed = BASE64.decode(sealed).map_err(|_| CryptoError::Malformed)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)
+    }
+
+    /// Whether `stored` is already encrypted with the current key.
+    pub fn is_current(&self, stored: &str) -> bool {
+        stored
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .is_some_and(|(id, _)| id == self.current_key_id())
+    }
+}
+
+/// HMAC key for the email blind index, configured as base64 of at least 32
+/// bytes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BlindIndex {
+    key: Vec<u8>,
+}
+
+impl FromStr for BlindIndex {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = BASE64.decode(s).map_err(|_| ())?;
+        if key.len() < 32 {
+            return Err(());
+        }
+        Ok(BlindIndex { key })
+    }
+}
+
+impl fmt::Debug for BlindIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlindIndex(..)")
+    }
+}
+
+impl BlindIndex {
+    /// The index of `value` as 64 hex digits. Values are taken as they are:
+    /// `Ada@example.com` and `ada@example.com` get different indexes, just as
+    /// they would be different values in a plaintext column.
+    pub fn of(&self, value: &str) -> String {
+        // `KeyInit` is also the name of the AES one, of another version.
+        let mut mac = <Hmac<Sha256> as hmac::KeyInit>::new_from_slice(&self.key)
+            .expect("HMAC takes keys of any length");
+        mac.update(value.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, byte: u8) -> EncryptionKey {
+        format!("{}:{}", id, BASE64.encode([byte; 32]))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trips_with_a_fresh_nonce_each_time() {
+        let cipher = FieldCipher::new(&[key("k1", 1)]).unwrap();
+        let first = cipher.encrypt("ada@example.com");
+        let second = cipher.encrypt("ada@example.com");
+
+        assert!(first.starts_with("enc:k1:"));
+        assert_ne!(first, second);
+        assert_eq!(cipher.decrypt(&first).unwrap(), "ada@example.com");
+        assert_eq!(cipher.decrypt(&second).unwrap(), "ada@example.com");
+    }
+
+    #[test]
+    fn reads_values_sealed_with_an_older_key() {
+        let old = FieldCipher::new(&[key("k1", 1)]).unwrap();
+        let rotated = FieldCipher::new(&[key("k2", 2), key("k1", 1)]).unwrap();
+        let stored = old.encrypt("ada@example.com");
+
+        assert!(!rotated.is_current(&stored));
+        assert_eq!(rotated.decrypt(&stored).unwrap(), "ada@example.com");
+        assert!(rotated.is_current(&rotated.encrypt("ada@example.com")));
+    }
+
+    #[test]
+    fn passes_plaintext_through_and_rejects_tampering() {
+        let cipher = FieldCipher::new(&[key("k1", 1)]).unwrap();
+        assert_eq!(
+            cipher.decrypt("ada@example.com").unwrap(),
+            "ada@example.com"
+        );
+
+        let stored = cipher.encrypt("ada@example.com");
+        let (head, sealed) = stored.rsplit_once(':').unwrap();
+        let mut sealed = BASE64.decode(sealed).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        let stored = format!("{}:{}", head, BASE64.encode(sealed));
+        assert!(matches!(cipher.decrypt(&stored), Err(CryptoError::Decrypt)));
+        assert!(matches!(
+            cipher.decrypt("enc:k9:AAAA"),
+            Err(CryptoError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert!("k1:short".parse::<EncryptionKey>().is_err());
+        assert!(format!(":{}", BASE64.encode([0; 32]))
+            .parse::<EncryptionKey>()
+            .is_err());
+        assert_eq!(key("2026-01", 7).id, "2026-01");
+    }
+
+    #[test]
+    fn indexes_equal_values_equally() {
+        let index: BlindIndex = BASE64.encode([3; 32]).parse().unwrap();
+        let other: BlindIndex = BASE64.encode([4; 32]).parse().unwrap();
+
+        assert_eq!(index.of("ada@example.com"), index.of("ada@example.com"));
+        assert_eq!(index.of("ada@example.com").len(), 64);
+        assert_ne!(index.of("ada@example.com"), index.of("bob@example.com"));
+        assert_ne!(index.of("ada@example.com"), other.of("ada@example.com"));
+        assert!(BASE64.encode([0; 16]).parse::<BlindIndex>().is_err());
+    }
+}
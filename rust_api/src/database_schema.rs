@@ -0,0 +1,135 @@
+use crate::pool::Pool;
+use crate::repository::{RepositoryError, SCHEMA_VERSION};
+use postgres::Transaction;
+use std::sync::Arc;
+
+/*
+*  Database schema
+*
+*  What the deployed Postgres schema looks like, as `GET /admin/schema`
+*  reports it: the migration version applied next to the one this build
+*  migrates to, and the columns and indexes of every table the app manages.
+*  Read from the catalog, so a column added or dropped by hand shows up too.
+*  A table that does not exist is listed without columns.
+*/
+
+/// Every table the migrations create.
+const TABLES: [&str; 6] = [
+    "users",
+    "addresses",
+    "feature_flags",
+    "outbox",
+    "totp",
+    "schema_migrations",
+];
+
+#[derive(Serialize, Debug)]
+pub struct Column {
+    pub name: String,
+    /// As information_schema names it, with arrays as `<element>[]`.
+    #[serde(rename = "type")]
+    pub data_type: String,
+    pub nullable: bool,
+    pub default: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Index {
+    pub name: String,
+    /// The `CREATE INDEX` statement.
+    pub definition: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Table {
+    pub name: &'static str,
+    pub exists: bool,
+    pub columns: Vec<Column>,
+    pub indexes: Vec<Index>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SchemaReport {
+    /// The last migration applied; `None` if migrations never ran.
+    pub version: Option<i32>,
+    /// The migration this build brings the schema to.
+    pub expected_version: i32,
+    pub tables: Vec<Table>,
+}
+
+pub struct DatabaseSchema {
+    pool: Arc<Pool>,
+}
+
+impl DatabaseSchema {
+    pub fn new(pool: Arc<Pool>) -> DatabaseSchema {
+        DatabaseSchema { pool }
+    }
+
+    /// Reads the schema in one read only transaction, so it is consistent
+    /// even while a migration runs.
+    pub fn describe(&self) -> Result<SchemaReport, RepositoryError> {
+        let mut client = self.pool.get()?;
+        let mut transaction = client.build_transaction().read_only(true).start()?;
+        let mut tables = Vec::new();
+        for name in TABLES {
+            tables.push(table(&mut transaction, name)?);
+        }
+        let migrated = tables
+            .iter()
+            .any(|table| table.name == "schema_migrations" && table.exists);
+        let version = if migrated {
+            transaction
+                .query_one("SELECT MAX(version) FROM schema_migrations", &[])?
+                .get(0)
+        } else {
+            None
+        };
+        transaction.commit()?;
+        Ok(SchemaReport {
+            version,
+            expected_version: SCHEMA_VERSION,
+            tables,
+        })
+    }
+}
+
+fn table(transaction: &mut Transaction, name: &'static str) -> Result<Table, RepositoryError> {
+    let columns = transaction.query(
+        "SELECT column_name::text,
+                CASE WHEN data_type = 'ARRAY' THEN substr(udt_name, 2) || '[]'
+                     ELSE data_type END::text,
+                is_nullable = 'YES',
+                column_default::text
+         FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1
+         ORDER BY ordinal_position",
+        &[&name],
+    )?;
+    let indexes = transaction.query(
+        "SELECT indexname::text, indexdef FROM pg_indexes
+         WHERE schemaname = current_schema() AND tablename = $1
+         ORDER BY indexname",
+        &[&name],
+    )?;
+    Ok(Table {
+        name,
+        exists: !columns.is_empty(),
+        columns: columns
+            .iter()
+            .map(|row| Column {
+                name: row.get(0),
+                data_type: row.get(1),
+                nullable: row.get(2),
+                default: row.get(3),
+            })
+            .collect(),
+        indexes: indexes
+            .iter()
+            .map(|row| Index {
+                name: row.get(0),
+                definition: row.get(1),
+            })
+            .collect(),
+    })
+}
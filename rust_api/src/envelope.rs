@@ -0,0 +1,178 @@
+use crate::http::Request;
+use crate::response;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+*  Response envelope
+*
+*  Some clients sit behind middleboxes that replace error statuses with their
+*  own pages, or simply want every answer in one shape. For them JSON and
+*  plain text responses can be wrapped as
+*  `{"data": ..., "meta": {"status": 404}, "errors": [...]}`: `data` holds the
+*  body of a success and is `null` otherwise, `errors` holds one object for a
+*  failure and is empty otherwise, and `meta` repeats the status and the
+*  `X-Total-Count` of lists as `total`. The status line is left as it is.
+*
+*  `RESPONSE_ENVELOPE` turns it on for every response and `?envelope=true` or
+*  `?envelope=false` decides for a single request. Responses in other formats,
+*  such as exports, backups and the admin page, are never wrapped.
+*/
+
+static DEFAULT: AtomicBool = AtomicBool::new(false);
+
+pub fn configure(enabled: bool) {
+    DEFAULT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the response to `request` is to be wrapped.
+pub fn wanted(request: &Request) -> bool {
+    match request.query("envelope") {
+        Some("true") => true,
+        Some("false") => false,
+        _ => DEFAULT.load(Ordering::Relaxed),
+    }
+}
+
+/// Wraps a buffered response if the request wants it and it is JSON or
+/// plain text.
+pub fn wrap(request: &Request, status_line: String, content: String) -> (String, String) {
+    let status = response::status_code(&status_line);
+    // 204 and 304 may not have a body, and 1xx are not final.
+    if !wanted(request)
+        || matches!(status, 100..=199 | 204 | 304)
+        || !matches!(
+            response::header_value(&status_line, "Content-Type"),
+            None | Some("application/json")
+        )
+    {
+        return (status_line, content);
+    }
+
+    let value = serde_json::from_str(&content).unwrap_or(Value::String(content));
+    let mut meta = Map::new();
+    meta.insert("status".to_string(), status.into());
+    let total = response::header_value(&status_line, "X-Total-Count");
+    if let Some(total) = total.and_then(|total| total.parse::<u64>().ok()) {
+        meta.insert("total".to_string(), total.into());
+    }
+    let body = if (200..300).contains(&status) {
+        serde_json::json!({ "data": value, "meta": meta, "errors": [] })
+    } else {
+        serde_json::json!({ "data": null, "meta": meta, "errors": [error(status, value)] })
+    };
+    (
+        response::set_content_type(&status_line, "application/json"),
+        body.to_string(),
+    )
+}
+
+/// What a streamed JSON list starts with, before its first item.
+pub const LIST_OPEN: &[u8] = b"{\"data\":";
+
+/// What a streamed JSON list ends with, after its last item.
+pub fn list_close(total: u64) -> Vec<u8> {
+    format!(
+        ",\"meta\":{},\"errors\":[]}}",
+        serde_json::json!({ "status": 200, "total": total })
+    )
+    .into_bytes()
+}
+
+/// Error bodies that are objects keep their fields; text becomes `message`.
+fn error(status: u16, value: Value) -> Value {
+    let mut error = match value {
+        Value::Object(fields) => fields,
+        Value::String(text) if text.is_empty() => Map::new(),
+        Value::String(text) => Map::from_iter([("message".to_string(), Value::String(text))]),
+        other => Map::from_iter([("message".to_string(), Value::String(other.to_string()))]),
+    };
+    error.insert("status".to_string(), status.into());
+    Value::Object(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(target: &str) -> Request {
+        Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap()
+    }
+
+    fn body(response: &(String, String)) -> Value {
+        serde_json::from_str(&response.1).unwrap()
+    }
+
+    #[test]
+    fn wraps_successes_and_failures() {
+        let request = get("/users/1?envelope=true");
+        let found = wrap(
+            &request,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Total-Count: 1\r\n\r\n"
+                .to_string(),
+            r#"[{"id":1}]"#.to_string(),
+        );
+        assert_eq!(
+            body(&found),
+            serde_json::json!({
+                "data": [{ "id": 1 }],
+                "meta": { "status": 200, "total": 1 },
+                "errors": []
+            })
+        );
+
+        let missing = wrap(
+            &request,
+            "HTTP/1.1 404 NOT FOUND\r\n\r\n".to_string(),
+            "Not Found URL".to_string(),
+        );
+        assert_eq!(
+            missing.0,
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\n\r\n"
+        );
+        assert_eq!(
+            body(&missing),
+            serde_json::json!({
+                "data": null,
+                "meta": { "status": 404 },
+                "errors": [{ "status": 404, "message": "Not Found URL" }]
+            })
+        );
+
+        let throttled = wrap(
+            &request,
+            "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/json\r\n\r\n"
+                .to_string(),
+            r#"{"error":"Service Unavailable","code":"draining"}"#.to_string(),
+        );
+        assert_eq!(
+            body(&throttled)["errors"][0],
+            serde_json::json!({ "status": 503, "error": "Service Unavailable", "code": "draining" })
+        );
+    }
+
+    #[test]
+    fn leaves_other_formats_and_requests_alone() {
+        let csv = (
+            "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\n\r\n".to_string(),
+            "id,name\r\n".to_string(),
+        );
+        assert_eq!(
+            wrap(
+                &get("/admin/export?envelope=true"),
+                csv.0.clone(),
+                csv.1.clone()
+            ),
+            csv
+        );
+        let plain = (
+            "HTTP/1.1 404 NOT FOUND\r\n\r\n".to_string(),
+            "Nope".to_string(),
+        );
+        assert_eq!(
+            wrap(&get("/users/1"), plain.0.clone(), plain.1.clone()),
+            plain
+        );
+        assert!(!wanted(&get("/users/1?envelope=false")));
+    }
+}
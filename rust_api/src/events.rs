@@ -0,0 +1,303 @@
+use crate::models::User;
+use crate::repository::PostgresUserRepository;
+use crate::statsd;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, warn};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/*
+*  User change events
+*
+*  Every committed create, update and delete of a user is announced as a JSON
+*  event to the configured publishers. Events are queued and published from a
+*  background thread, in the order they happened, with a few retries; when the
+*  broker stays down they are dropped rather than holding up requests.
+*/
+
+/// Events waiting to be published; further ones are dropped.
+const QUEUE_SIZE: usize = 1000;
+/// Delays before each retry of a failed publish.
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+/// Events relayed from the outbox per transaction.
+const OUTBOX_BATCH: i64 = 100;
+/// Longest wait between relay attempts while publishing fails.
+const OUTBOX_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Bumped when the shape of `data` changes incompatibly.
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Created => "user.created",
+            EventKind::Updated => "user.updated",
+            EventKind::Deleted => "user.deleted",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<EventKind> {
+        [EventKind::Created, EventKind::Updated, EventKind::Deleted]
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Clone)]
+pub struct Event {
+    /// Unique per event, for consumers to drop duplicates.
+    pub id: String,
+    pub kind: EventKind,
+    pub user_id: i32,
+    /// The fields as written; `None` for deletions.
+    pub user: Option<User>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, user_id: i32, user: Option<&User>) -> Event {
+        Event {
+            id: format!("{:032x}", rand::random::<u128>()),
+            kind,
+            user_id,
+            user: user.cloned(),
+            occurred_at: Utc::now(),
+        }
+    }
+
+    /// The key events are partitioned by, so that the events of one user
+    /// stay in order.
+    pub fn key(&self) -> String {
+        self.user_id.to_string()
+    }
+
+    /// Fills `{type}` and `{user_id}` into a routing key or subject template.
+    pub fn subject(&self, template: &str) -> String {
+        template
+            .replace("{type}", self.kind.name())
+            .replace("{user_id}", &self.user_id.to_string())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut data = json!({ "id": self.user_id });
+        if let Some(user) = &self.user {
+            data["name"] = json!(user.name);
+            data["email"] = json!(user.email);
+        }
+        json!({
+            "id": self.id,
+            "type": self.kind.name(),
+            "version": SCHEMA_VERSION,
+            "occurred_at": self.occurred_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "data": data,
+        })
+    }
+
+    /// Reads back what `to_json` wrote.
+    pub fn from_json(event: &Value) -> Option<Event> {
+        let data = &event["data"];
+        let user_id = i32::try_from(data["id"].as_i64()?).ok()?;
+        let user = match (data["name"].as_str(), data["email"].as_str()) {
+            (Some(name), Some(email)) => Some(User {
+                id: Some(user_id),
+                name: name.to_string(),
+                email: email.to_string(),
+                created_at: None,
+                updated_at: None,
+            }),
+            _ => None,
+        };
+        Some(Event {
+            id: event["id"].as_str()?.to_string(),
+            kind: EventKind::from_name(event["type"].as_str()?)?,
+            user_id,
+            user,
+            occurred_at: DateTime::parse_from_rfc3339(event["occurred_at"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+        })
+    }
+}
+
+/// Somewhere events are sent to.
+pub trait Publisher: Send {
+    /// Names the publisher in logs and metrics.
+    fn name(&self) -> &'static str;
+    fn publish(&mut self, event: &Event) -> Result<(), String>;
+}
+
+/// Hands events to the publishers on a background thread.
+pub struct EventBus {
+    queue: Option<SyncSender<Event>>,
+}
+
+impl EventBus {
+    /// With no publishers events are discarded right away.
+    pub fn start(mut publishers: Vec<Box<dyn Publisher>>) -> EventBus {
+        if publishers.is_empty() {
+            return EventBus { queue: None };
+        }
+        let (queue, events) = mpsc::sync_channel::<Event>(QUEUE_SIZE);
+        thread::spawn(move || {
+            for event in events {
+                for publisher in publishers.iter_mut() {
+                    deliver(publisher.as_mut(), &event);
+                }
+            }
+        });
+        EventBus { queue: Some(queue) }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        match queue.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                statsd::count("events.dropped", 1, &[("type", event.kind.name())]);
+                warn!(
+                    "Event {} {} dropped: queue full",
+                    event.kind.name(),
+                    event.id
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Publishes the events Postgres queued in its outbox table, checking for new
+/// ones every `poll`. An event is marked sent once every publisher took it;
+/// after a failure the relay backs off and starts over from that event, so
+/// publishers that had already taken it get it again.
+pub fn relay_outbox(
+    repository: Arc<PostgresUserRepository>,
+    mut publishers: Vec<Box<dyn Publisher>>,
+    poll: Duration,
+) {
+    thread::spawn(move || {
+        let mut wait = poll;
+        loop {
+            thread::sleep(wait);
+            let mut failed = false;
+            let relayed = repository.relay_outbox(OUTBOX_BATCH, &mut |event| {
+                for publisher in publishers.iter_mut() {
+                    let outcome = publisher.publish(event);
+                    let tags = [
+                        ("publisher", publisher.name()),
+                        ("type", event.kind.name()),
+                        ("outcome", if outcome.is_ok() { "ok" } else { "error" }),
+                    ];
+                    statsd::count("events.published", 1, &tags);
+                    if let Err(e) = outcome {
+                        warn!(
+                            "Event {} {} not published to {}, retrying: {}",
+                            event.kind.name(),
+                            event.id,
+                            publisher.name(),
+                            e
+                        );
+                        failed = true;
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            });
+            wait = match relayed {
+                // More may be waiting.
+                Ok(relayed) if relayed as i64 == OUTBOX_BATCH => Duration::ZERO,
+                Ok(_) if !failed => poll,
+                Ok(_) => (wait * 2).clamp(poll, OUTBOX_MAX_BACKOFF),
+                Err(e) => {
+                    warn!("Outbox not relayed: {}", e);
+                    (wait * 2).clamp(poll, OUTBOX_MAX_BACKOFF)
+                }
+            };
+        }
+    });
+}
+
+fn deliver(publisher: &mut dyn Publisher, event: &Event) {
+    let mut delays = RETRY_DELAYS.iter();
+    loop {
+        let outcome = publisher.publish(event);
+        let tags = [
+            ("publisher", publisher.name()),
+            ("type", event.kind.name()),
+            ("outcome", if outcome.is_ok() { "ok" } else { "error" }),
+        ];
+        statsd::count("events.published", 1, &tags);
+        let Err(e) = outcome else {
+            return;
+        };
+        match delays.next() {
+            Some(delay) => {
+                warn!(
+                    "Event {} {} not published to {}, retrying: {}",
+                    event.kind.name(),
+                    event.id,
+                    publisher.name(),
+                    e
+                );
+                thread::sleep(*delay);
+            }
+            None => {
+                error!(
+                    "Event {} {} not published to {}: {}",
+                    event.kind.name(),
+                    event.id,
+                    publisher.name(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_a_versioned_envelope() {
+        let user = User {
+            id: None,
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: None,
+            updated_at: None,
+        };
+        let created = Event::new(EventKind::Created, 7, Some(&user)).to_json();
+        assert_eq!(Event::from_json(&created).unwrap().to_json(), created);
+        assert_eq!(created["type"], "user.created");
+        assert_eq!(created["version"], 1);
+        assert_eq!(created["id"].as_str().unwrap().len(), 32);
+        assert_eq!(
+            created["data"],
+            json!({ "id": 7, "name": "Ada", "email": "ada@example.com" })
+        );
+
+        let deleted = Event::new(EventKind::Deleted, 7, None);
+        assert_eq!(deleted.key(), "7");
+        assert_eq!(
+            deleted.subject("crud.{type}.{user_id}"),
+            "crud.user.deleted.7"
+        );
+        assert_eq!(deleted.to_json()["data"], json!({ "id": 7 }));
+    }
+}
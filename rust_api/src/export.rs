@@ -0,0 +1,99 @@
+use crate::repository::{AddressRepository, RepositoryError, UserRepository};
+use chrono::{SecondsFormat, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+*  Data export
+*
+*  A user's export bundles everything we hold about them into one JSON archive.
+*  Archives built in the background are kept here until downloaded through
+*  their unguessable token, or until they expire.
+*/
+
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+pub enum ExportState {
+    Pending,
+    Ready(String),
+    Failed,
+}
+
+#[derive(Default)]
+pub struct ExportStore {
+    exports: Mutex<HashMap<String, (Instant, ExportState)>>,
+}
+
+/// Collects the archive for user `id`, or `None` when there is no such user.
+pub fn archive(
+    users: &dyn UserRepository,
+    addresses: &dyn AddressRepository,
+    id: i32,
+) -> Result<Option<Value>, RepositoryError> {
+    let user = match users.find(id)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    Ok(Some(serde_json::json!({
+        "exported_at": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        "user": user,
+        "metadata": users.metadata(id)?,
+        "addresses": addresses.list_addresses(id)?,
+    })))
+}
+
+/// Rows that would go into the archive, which decides whether it is built
+/// inline or in the background. `None` when there is no such user.
+pub fn record_count(
+    users: &dyn UserRepository,
+    addresses: &dyn AddressRepository,
+    id: i32,
+) -> Result<Option<u64>, RepositoryError> {
+    if users.find(id)?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(1 + addresses.count_addresses(id)?))
+}
+
+impl ExportStore {
+    /// Registers a pending export and returns its download token.
+    pub fn start(&self) -> String {
+        let token = format!("{:032x}", rand::random::<u128>());
+        self.purge_expired();
+        let mut exports = self.exports.lock().unwrap();
+        exports.insert(token.clone(), (Instant::now(), ExportState::Pending));
+        token
+    }
+
+    /// Forgets expired exports, returning how many there were.
+    pub fn purge_expired(&self) -> usize {
+        let mut exports = self.exports.lock().unwrap();
+        let before = exports.len();
+        exports.retain(|_, (created, _)| created.elapsed() < RETENTION);
+        before - exports.len()
+    }
+
+    pub fn finish(&self, token: &str, state: ExportState) {
+        if let Some(entry) = self.exports.lock().unwrap().get_mut(token) {
+            entry.1 = state;
+        }
+    }
+
+    /// `None` for unknown or expired tokens. A ready archive is handed out
+    /// once and then forgotten.
+    pub fn take(&self, token: &str) -> Option<ExportState> {
+        let mut exports = self.exports.lock().unwrap();
+        let (created, state) = exports.get(token)?;
+        if created.elapsed() >= RETENTION {
+            exports.remove(token);
+            return None;
+        }
+        Some(match state {
+            ExportState::Pending => ExportState::Pending,
+            ExportState::Failed => ExportState::Failed,
+            ExportState::Ready(_) => exports.remove(token)?.1,
+        })
+    }
+}
@@ -0,0 +1,489 @@
+use crate::access::Grant;
+use crate::http::Request;
+use crate::router::bad_request;
+use crate::services::Services;
+use crate::INTERNAL_SERVER_ERROR;
+use serde::de::value::{Error, MapDeserializer, SeqDeserializer};
+use serde::de::{DeserializeOwned, Deserializer, Error as _, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+/*
+*  Extractors
+*
+*  A handler can declare what it needs from the request as parameters after
+*  the services, and the router hands them over already parsed:
+*
+*      fn handle_put_request(services: &Services, Path(id): Path<i32>, Json(user): Json<User>)
+*
+*  `Path` takes the `:name` segments of the route, one value or a tuple in
+*  the order of the pattern; `Query` the query string, as a struct with a
+*  field per parameter; `Json` the body, as decoded by `crate::codec` and
+*  checked against the route's schema; `AuthUser` whoever the token or login
+*  belongs to. An extractor that fails answers for the handler, which never
+*  runs; a query or body that cannot be read is a `400` with an `error`.
+*  Handlers that need more of the request, such as its headers, take the
+*  `&Request` first, before the services and any extractors.
+*/
+
+/// What extractors are taken from.
+pub struct Parts<'a> {
+    pub request: &'a Request,
+    pub services: &'a Services,
+    /// What the request's token or admin login allows.
+    pub grant: Option<&'a Grant>,
+    /// The pattern of the route that matched, e.g. `/users/:id`.
+    pub route: &'static str,
+}
+
+pub trait FromRequest: Sized {
+    /// Whether the extractor reads the body, so the router refuses bodies of
+    /// other media types with a `415` first.
+    const READS_BODY: bool = false;
+
+    /// The value, or the response to send instead.
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)>;
+}
+
+/// Parameters taken from the path.
+pub struct Path<T>(pub T);
+
+/// Parameters taken from the query string. Missing ones need a default, and
+/// ones the struct does not name are ignored.
+pub struct Query<T>(pub T);
+
+/// The body.
+pub struct Json<T>(pub T);
+
+/// Who made the request. Refuses requests without a token or admin login;
+/// take `Option<AuthUser>` where those are let through.
+pub struct AuthUser {
+    /// The API key's name, the JWT's `sub` or `admin`.
+    pub subject: String,
+}
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    /// A segment that does not parse is a path the route should not have
+    /// matched, answered with a 500 as handlers always did.
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)> {
+        let mut params: Vec<Text> = parts
+            .route
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .zip(parts.request.segments())
+            .filter(|(pattern, _)| pattern.starts_with(':'))
+            .map(|(_, segment)| Text(segment))
+            .collect();
+        let parsed = match params.len() {
+            1 => T::deserialize(params.remove(0)),
+            _ => T::deserialize(SeqDeserializer::new(params.into_iter())),
+        };
+        parsed.map(Path).map_err(|_| {
+            (
+                INTERNAL_SERVER_ERROR.to_string(),
+                "Internal Server Error".to_string(),
+            )
+        })
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)> {
+        let mut params: Vec<(&str, Values)> = Vec::new();
+        for (name, value) in parts.request.query_pairs() {
+            match params.iter_mut().find(|(known, _)| *known == name) {
+                Some((_, values)) => values.0.push(value),
+                None => params.push((name, Values(vec![value]))),
+            }
+        }
+        T::deserialize(MapDeserializer::new(params.into_iter()))
+            .map(Query)
+            .map_err(|e: Error| bad_request(format!("Invalid query: {}", e)))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    const READS_BODY: bool = true;
+
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)> {
+        serde_json::from_slice(&parts.request.body)
+            .map(Json)
+            .map_err(|e| bad_request(format!("Invalid JSON: {}", e)))
+    }
+}
+
+impl FromRequest for AuthUser {
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)> {
+        match parts.grant {
+            Some(grant) => Ok(AuthUser {
+                subject: grant.subject.clone(),
+            }),
+            None => Err((
+                crate::router::UNAUTHORIZED.to_string(),
+                serde_json::json!({ "error": "Unauthorized" }).to_string(),
+            )),
+        }
+    }
+}
+
+/// `None` where the extractor would refuse the request.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(parts: &Parts) -> Result<Self, (String, String)> {
+        Ok(T::from_request(parts).ok())
+    }
+}
+
+/// A handler the router can run: `fn(&Request, &Services)`, or
+/// `fn(&Services, ...)` or `fn(&Request, &Services, ...)` taking up to four
+/// extractors.
+pub trait Respond<Args>: Send + Sync + 'static {
+    fn respond(&self, parts: &Parts) -> (String, String);
+
+    /// Whether one of the extractors reads the body.
+    fn reads_body(&self) -> bool {
+        false
+    }
+}
+
+/// The `Args` of handlers that take the whole request.
+pub enum Raw {}
+
+impl<F> Respond<Raw> for F
+where
+    F: Fn(&Request, &Services) -> (String, String) + Send + Sync + 'static,
+{
+    fn respond(&self, parts: &Parts) -> (String, String) {
+        self(parts.request, parts.services)
+    }
+}
+
+macro_rules! respond_with_extractors {
+    (@request $parts:ident, $request:ty) => {
+        $parts.request
+    };
+    ($($extractor:ident),+) => {
+        respond_with_extractors!(($($extractor,)+), [], $($extractor),+);
+        respond_with_extractors!((Raw, $($extractor,)+), [&Request], $($extractor),+);
+    };
+    ($args:ty, [$($request:ty)?], $($extractor:ident),+) => {
+        impl<F, $($extractor),+> Respond<$args> for F
+        where
+            F: Fn($($request,)? &Services, $($extractor),+) -> (String, String)
+                + Send
+                + Sync
+                + 'static,
+            $($extractor: FromRequest,)+
+        {
+            #[allow(non_snake_case)]
+            fn respond(&self, parts: &Parts) -> (String, String) {
+                $(
+                    let $extractor = match $extractor::from_request(parts) {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                )+
+                self($(respond_with_extractors!(@request parts, $request),)? parts.services, $($extractor),+)
+            }
+
+            fn reads_body(&self) -> bool {
+                false $(|| $extractor::READS_BODY)+
+            }
+        }
+    };
+}
+
+respond_with_extractors!(A);
+respond_with_extractors!(A, B);
+respond_with_extractors!(A, B, C);
+respond_with_extractors!(A, B, C, D);
+
+/// A path segment or query value, parsed as whatever the extractor's type
+/// asks for.
+struct Text<'a>(&'a str);
+
+macro_rules! parse_text {
+    ($($method:ident => $visit:ident),+) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(Error::custom(format!("invalid value {:?}", self.0))),
+                }
+            }
+        )+
+    };
+}
+
+impl<'de> Deserializer<'de> for Text<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    parse_text! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Unit variants only, by name.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, Error> for Text<'a> {
+    type Deserializer = Text<'a>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+/// Every value of a query parameter: a sequence for a `Vec` field, otherwise
+/// the last one.
+struct Values<'a>(Vec<&'a str>);
+
+macro_rules! last_value {
+    ($($method:ident),+) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                Text(self.0.last().copied().unwrap_or_default()).$method(visitor)
+            }
+        )+
+    };
+}
+
+impl<'de> Deserializer<'de> for Values<'_> {
+    type Error = Error;
+
+    last_value! {
+        deserialize_any, deserialize_bool, deserialize_i8, deserialize_i16, deserialize_i32,
+        deserialize_i64, deserialize_u8, deserialize_u16, deserialize_u32, deserialize_u64,
+        deserialize_f32, deserialize_f64, deserialize_option
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(SeqDeserializer::new(self.0.into_iter().map(Text)))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        Text(self.0.last().copied().unwrap_or_default()).deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, Error> for Values<'a> {
+    type Deserializer = Values<'a>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    fn parts<'a>(request: &'a Request, services: &'a Services, route: &'static str) -> Parts<'a> {
+        Parts {
+            request,
+            services,
+            grant: None,
+            route,
+        }
+    }
+
+    fn request(target: &str, body: &str) -> Request {
+        Request::parse(format!("POST {} HTTP/1.1\r\n\r\n{}", target, body).as_bytes()).unwrap()
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Order {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct ListParams {
+        #[serde(rename = "$top")]
+        top: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        order: Option<Order>,
+        #[serde(default)]
+        verbose: bool,
+    }
+
+    #[test]
+    fn takes_path_parameters_in_order() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let request = request("/users/7/addresses/3", "");
+        let parts = parts(&request, &services, "/users/:id/addresses/:address_id");
+        let Path((user_id, id)) = Path::<(i32, u64)>::from_request(&parts).unwrap();
+        assert_eq!((user_id, id), (7, 3));
+        assert!(Path::<i32>::from_request(&parts).is_err());
+        assert!(Path::<(i32, i32, i32)>::from_request(&parts).is_err());
+
+        let request = self::request("/users/by-email/ada%40example.com", "");
+        let parts = self::parts(&request, &services, "/users/by-email/:email");
+        let Path(email) = Path::<String>::from_request(&parts).unwrap();
+        assert_eq!(email, "ada@example.com");
+        let (status, _) = Path::<i32>::from_request(&parts).err().unwrap();
+        assert!(status.starts_with("HTTP/1.1 500"));
+    }
+
+    #[test]
+    fn takes_query_parameters_by_name() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let request = request("/users?$top=20&tag=a&tag=b&order=desc&verbose=true&x=1", "");
+        let Query(params) =
+            Query::<ListParams>::from_request(&parts(&request, &services, "/users")).unwrap();
+        assert_eq!(
+            params,
+            ListParams {
+                top: Some(20),
+                tag: vec!["a".to_string(), "b".to_string()],
+                order: Some(Order::Desc),
+                verbose: true,
+            }
+        );
+
+        let request = self::request("/users", "");
+        let Query(params) =
+            Query::<ListParams>::from_request(&parts(&request, &services, "/users")).unwrap();
+        assert_eq!(params.top, None);
+        assert!(params.tag.is_empty());
+
+        for target in ["/users?$top=many", "/users?order=sideways"] {
+            let request = self::request(target, "");
+            let (status, body) =
+                Query::<ListParams>::from_request(&parts(&request, &services, "/users"))
+                    .err()
+                    .unwrap();
+            assert!(status.starts_with("HTTP/1.1 400"));
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert!(body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid query: "));
+        }
+    }
+
+    #[test]
+    fn takes_the_body_as_json() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let request = request("/users", r#"{"name":"Ada","email":"ada@example.com"}"#);
+        let Json(user) =
+            Json::<crate::models::User>::from_request(&parts(&request, &services, "/users"))
+                .unwrap();
+        assert_eq!(user.name, "Ada");
+
+        let request = self::request("/users", r#"{"name":"#);
+        let (status, body) =
+            Json::<crate::models::User>::from_request(&parts(&request, &services, "/users"))
+                .err()
+                .unwrap();
+        assert!(status.starts_with("HTTP/1.1 400"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid JSON: "));
+    }
+
+    #[test]
+    fn knows_who_is_asking() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let request = request("/users", "");
+        let mut parts = parts(&request, &services, "/users");
+        assert!(AuthUser::from_request(&parts).is_err());
+        assert!(Option::<AuthUser>::from_request(&parts).unwrap().is_none());
+
+        let grant = Grant::admin();
+        parts.grant = Some(&grant);
+        assert_eq!(AuthUser::from_request(&parts).unwrap().subject, "admin");
+    }
+
+    fn greet(_: &Services, Path(id): Path<i32>, caller: Option<AuthUser>) -> (String, String) {
+        let caller = caller.map_or("anyone".to_string(), |caller| caller.subject);
+        (String::new(), format!("{} {}", id, caller))
+    }
+
+    fn echo(request: &Request, _: &Services, Json(name): Json<String>) -> (String, String) {
+        (String::new(), format!("{} {}", request.method, name))
+    }
+
+    #[test]
+    fn runs_handlers_with_what_they_asked_for() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let request = request("/users/5", "");
+        let parts = parts(&request, &services, "/users/:id");
+        assert_eq!(greet.respond(&parts).1, "5 anyone");
+
+        let request = self::request("/users/five", "");
+        let parts = self::parts(&request, &services, "/users/:id");
+        assert!(greet.respond(&parts).0.starts_with("HTTP/1.1 500"));
+
+        let request = self::request("/echo", r#""Ada""#);
+        assert_eq!(
+            echo.respond(&self::parts(&request, &services, "/echo")).1,
+            "POST Ada"
+        );
+    }
+
+    #[test]
+    fn knows_which_handlers_read_the_body() {
+        assert!(echo.reads_body());
+        assert!(!greet.reads_body());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::models::{Address, User};
+use crate::repository::{AddressRepository, RepositoryError, UserRepository};
+use serde_json::Value;
+
+/*
+*  Test data factories
+*
+*  Builders for users and addresses that look like real ones, for tests that
+*  need data but do not care about most of it:
+*
+*      let ada = UserFactory::new().with_email("ada@example.com").create(&repository)?;
+*      let address = AddressFactory::new().with_country("DE").create(&repository, id)?;
+*
+*  Whatever is not set is picked at random, and emails are unique, so tests
+*  can share a database. `build` and `body` make the data without storing
+*  it, for tests that go through the API. Only compiled into tests and
+*  builds with the `factories` feature.
+*/
+
+const FIRST_NAMES: [&str; 12] = [
+    "Ada", "Alan", "Grace", "Edsger", "Barbara", "Donald", "Frances", "John", "Margaret", "Ken",
+    "Radia", "Tim",
+];
+const LAST_NAMES: [&str; 12] = [
+    "Lovelace",
+    "Turing",
+    "Hopper",
+    "Dijkstra",
+    "Liskov",
+    "Knuth",
+    "Allen",
+    "Backus",
+    "Hamilton",
+    "Thompson",
+    "Perlman",
+    "Berners-Lee",
+];
+const STREETS: [&str; 6] = [
+    "Main St",
+    "High Street",
+    "Hauptstraße",
+    "Rue de la Paix",
+    "Calle Mayor",
+    "Oak Avenue",
+];
+/// A city, a postal code there and its country.
+const PLACES: [(&str, &str, &str); 6] = [
+    ("Springfield", "62701", "US"),
+    ("London", "SW1A 1AA", "GB"),
+    ("Berlin", "10115", "DE"),
+    ("Paris", "75002", "FR"),
+    ("Madrid", "28013", "ES"),
+    ("Toronto", "M5H 2N2", "CA"),
+];
+
+fn pick<T: Copy>(choices: &[T]) -> T {
+    choices[rand::random_range(0..choices.len())]
+}
+
+#[derive(Clone, Default)]
+pub struct UserFactory {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+impl UserFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_email(mut self, email: impl Into<String>) -> Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    /// A user with what was set and random values for the rest. Each call
+    /// picks anew.
+    pub fn build(&self) -> User {
+        let (first, last) = (pick(&FIRST_NAMES), pick(&LAST_NAMES));
+        User {
+            id: None,
+            name: self
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{} {}", first, last)),
+            email: self.email.clone().unwrap_or_else(|| {
+                format!(
+                    "{}.{}.{:012x}@example.com",
+                    first.to_lowercase(),
+                    last.to_lowercase(),
+                    rand::random::<u64>() >> 16
+                )
+            }),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// A request body for `POST /users`.
+    pub fn body(&self) -> Value {
+        serde_json::json!(self.build())
+    }
+
+    /// Stores a user built by `build` and returns it as stored.
+    pub fn create(&self, repository: &dyn UserRepository) -> Result<User, RepositoryError> {
+        let user = self.build();
+        let id = repository.create(&user)?;
+        Ok(repository.find(id)?.unwrap_or(User {
+            id: Some(id),
+            ..user
+        }))
+    }
+
+    /// Stores `count` users, each built anew, so only what was set repeats.
+    pub fn create_many(
+        &self,
+        repository: &dyn UserRepository,
+        count: usize,
+    ) -> Result<Vec<User>, RepositoryError> {
+        (0..count).map(|_| self.create(repository)).collect()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AddressFactory {
+    city: Option<String>,
+    country: Option<String>,
+}
+
+impl AddressFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_city(mut self, city: impl Into<String>) -> Self {
+        self.city = Some(city.into());
+        self
+    }
+
+    /// An ISO 3166-1 alpha-2 code.
+    pub fn with_country(mut self, country: impl Into<String>) -> Self {
+        self.country = Some(country.into());
+        self
+    }
+
+    /// An address with what was set and random values for the rest.
+    pub fn build(&self) -> Address {
+        let (city, postal_code, country) = pick(&PLACES);
+        Address {
+            id: None,
+            user_id: None,
+            line1: format!("{} {}", rand::random_range(1..300), pick(&STREETS)),
+            line2: rand::random_bool(0.3).then(|| format!("Apt {}", rand::random_range(1..50))),
+            city: self.city.clone().unwrap_or_else(|| city.to_string()),
+            postal_code: postal_code.to_string(),
+            country: self.country.clone().unwrap_or_else(|| country.to_string()),
+        }
+    }
+
+    /// A request body for `POST /users/:id/addresses`.
+    pub fn body(&self) -> Value {
+        let mut body = serde_json::json!(self.build());
+        if let Value::Object(fields) = &mut body {
+            fields.remove("id");
+            fields.remove("user_id");
+        }
+        body
+    }
+
+    /// Stores an address built by `build` for the user.
+    pub fn create(
+        &self,
+        repository: &dyn AddressRepository,
+        user_id: i32,
+    ) -> Result<Address, RepositoryError> {
+        repository.create_address(user_id, &self.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{address_schema, user_schema};
+    use crate::repository::MemoryUserRepository;
+    use crate::schema;
+
+    #[test]
+    fn builds_valid_and_unique_data() {
+        let users: Vec<User> = (0..20).map(|_| UserFactory::new().build()).collect();
+        for user in &users {
+            assert!(schema::validate(&user_schema(), &serde_json::json!(user)).is_empty());
+        }
+        let mut emails: Vec<&str> = users.iter().map(|user| user.email.as_str()).collect();
+        emails.sort();
+        emails.dedup();
+        assert_eq!(emails.len(), users.len());
+
+        for _ in 0..20 {
+            let body = AddressFactory::new().body();
+            assert!(
+                schema::validate(&address_schema(), &body).is_empty(),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn creates_what_was_asked_for() {
+        let repository = MemoryUserRepository::new();
+        let ada = UserFactory::new()
+            .with_email("ada@example.com")
+            .create(&repository)
+            .unwrap();
+        assert_eq!(ada.email, "ada@example.com");
+        assert!(ada.created_at.is_some());
+
+        let others = UserFactory::new()
+            .with_name("Grace Hopper")
+            .create_many(&repository, 3)
+            .unwrap();
+        assert!(others.iter().all(|user| user.name == "Grace Hopper"));
+        assert_eq!(repository.count(&Default::default()).unwrap(), 4);
+
+        let address = AddressFactory::new()
+            .with_country("DE")
+            .create(&repository, ada.id.unwrap())
+            .unwrap();
+        assert_eq!(address.country, "DE");
+        assert_eq!(address.user_id, ada.id);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::repository::{FlagRepository, RepositoryError};
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/*
+*  Feature flags
+*
+*  Named switches stored with the rest of the data and toggled through the
+*  admin API, so behavior can be rolled out or back without a deploy. Reads
+*  come from a copy refreshed at most every `FLAG_CACHE_TTL_MS`; a toggle is
+*  seen at once by the instance that made it and within the TTL by the others.
+*  A flag that was never set is off.
+*/
+
+/// Rejects user names that are only whitespace, which the schema lets
+/// through.
+pub const STRICT_NAMES: &str = "strict-names";
+
+/// Longest flag name accepted.
+pub const MAX_NAME_LEN: usize = 64;
+
+pub struct FeatureFlags {
+    store: Arc<dyn FlagRepository>,
+    ttl: Duration,
+    cache: RwLock<Cache>,
+}
+
+struct Cache {
+    flags: BTreeMap<String, bool>,
+    /// `None` until the first load.
+    loaded: Option<Instant>,
+}
+
+impl FeatureFlags {
+    pub fn new(store: Arc<dyn FlagRepository>, ttl: Duration) -> Self {
+        FeatureFlags {
+            store,
+            ttl,
+            cache: RwLock::new(Cache {
+                flags: BTreeMap::new(),
+                loaded: None,
+            }),
+        }
+    }
+
+    /// Whether the flag is on. When the store cannot be read the last known
+    /// state is kept until the next refresh.
+    pub fn enabled(&self, name: &str) -> bool {
+        if self.is_stale() {
+            self.refresh();
+        }
+        let cache = self.cache.read().unwrap();
+        cache.flags.get(name).copied().unwrap_or(false)
+    }
+
+    /// Every flag that was set, read from the store.
+    pub fn all(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        let flags = self.store.list_flags()?;
+        self.remember(flags.clone());
+        Ok(flags)
+    }
+
+    pub fn set(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        self.store.set_flag(name, enabled)?;
+        self.cache
+            .write()
+            .unwrap()
+            .flags
+            .insert(name.to_string(), enabled);
+        Ok(())
+    }
+
+    /// Forgets the flag, which turns it off. Returns whether it was set.
+    pub fn remove(&self, name: &str) -> Result<bool, RepositoryError> {
+        let removed = self.store.delete_flag(name)? > 0;
+        self.cache.write().unwrap().flags.remove(name);
+        Ok(removed)
+    }
+
+    fn is_stale(&self) -> bool {
+        let cache = self.cache.read().unwrap();
+        cache
+            .loaded
+            .is_none_or(|loaded| loaded.elapsed() >= self.ttl)
+    }
+
+    fn refresh(&self) {
+        match self.store.list_flags() {
+            Ok(flags) => self.remember(flags),
+            Err(e) => {
+                warn!("Feature flags not refreshed: {}", e);
+                self.cache.write().unwrap().loaded = Some(Instant::now());
+            }
+        }
+    }
+
+    fn remember(&self, flags: BTreeMap<String, bool>) {
+        *self.cache.write().unwrap() = Cache {
+            flags,
+            loaded: Some(Instant::now()),
+        };
+    }
+}
+
+/// Names are lowercase letters, digits, `_`, `-` and `.`, so they are safe in
+/// a path and in logs.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_-.".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    #[test]
+    fn toggles_are_cached_until_the_ttl_runs_out() {
+        let store = Arc::new(MemoryUserRepository::new());
+        let flags = FeatureFlags::new(store.clone(), Duration::from_secs(3600));
+        assert!(!flags.enabled("strict_names"));
+
+        flags.set("strict_names", true).unwrap();
+        assert!(flags.enabled("strict_names"));
+
+        // Another instance turning it off is not seen before the refresh.
+        store.set_flag("strict_names", false).unwrap();
+        assert!(flags.enabled("strict_names"));
+        let fresh = FeatureFlags::new(store, Duration::ZERO);
+        assert!(!fresh.enabled("strict_names"));
+
+        assert!(flags.remove("strict_names").unwrap());
+        assert!(!flags.enabled("strict_names"));
+        assert!(!flags.remove("strict_names").unwrap());
+    }
+
+    #[test]
+    fn names_are_path_safe() {
+        assert!(valid_name("soft-delete.v2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("Upper"));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name(&"x".repeat(MAX_NAME_LEN + 1)));
+    }
+}
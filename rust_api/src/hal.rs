@@ -0,0 +1,179 @@
+use crate::http::Request;
+use crate::links;
+use crate::response;
+use serde_json::{Map, Value};
+
+/*
+*  HAL
+*
+*  Clients that send `Accept: application/hal+json` get users and addresses
+*  as HAL resources (https://datatracker.ietf.org/doc/html/draft-kelly-json-hal):
+*  their fields as they are, plus `_links` to themselves and, for users, to
+*  their addresses. Addresses a user was fetched with (`?include=addresses`)
+*  are moved to `_embedded`. A list is a resource of its own, with the items
+*  in `_embedded` and `X-Total-Count` as `total`. HAL says nothing about
+*  errors, so they and other responses that are not resources are sent as
+*  they are.
+*/
+
+pub const MEDIA_TYPE: &str = "application/hal+json";
+
+pub fn is_hal(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(MEDIA_TYPE)
+}
+
+/// Turns a successful JSON response holding users or addresses into HAL.
+pub fn render(request: &Request, status_line: String, content: String) -> (String, String) {
+    if !(200..300).contains(&response::status_code(&status_line))
+        || response::header_value(&status_line, "Content-Type") != Some("application/json")
+    {
+        return (status_line, content);
+    }
+    let Ok(value) = serde_json::from_str::<Value>(&content) else {
+        return (status_line, content);
+    };
+    let addresses = links::lists_addresses(request.path());
+    let rendered = match value {
+        Value::Object(object) if object.contains_key("id") => resource(addresses, object),
+        Value::Array(items) if items.iter().all(Value::is_object) => {
+            let total = response::header_value(&status_line, "X-Total-Count")
+                .and_then(|total| total.parse::<u64>().ok());
+            let relation = if addresses { "addresses" } else { "users" };
+            let items: Vec<Value> = items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Value::Object(object) => Some(resource(addresses, object)),
+                    _ => None,
+                })
+                .collect();
+            let mut list = serde_json::json!({
+                "_links": { "self": { "href": links::to(request.path()) } },
+                "_embedded": { relation: items },
+            });
+            if let Some(total) = total {
+                list["total"] = total.into();
+            }
+            list
+        }
+        _ => return (status_line, content),
+    };
+    (
+        response::set_content_type(&status_line, MEDIA_TYPE),
+        rendered.to_string(),
+    )
+}
+
+fn resource(address: bool, mut object: Map<String, Value>) -> Value {
+    let id = object.get("id").and_then(links::id).unwrap_or_default();
+    let mut related = Map::new();
+    if address {
+        if let Some(user_id) = object.get("user_id").and_then(links::id) {
+            related.insert("self".to_string(), href(links::address(&user_id, &id)));
+            related.insert("user".to_string(), href(links::user(&user_id)));
+        }
+    } else {
+        related.insert("self".to_string(), href(links::user(&id)));
+        related.insert("addresses".to_string(), href(links::addresses(&id)));
+        if let Some(Value::Array(addresses)) = object.remove("addresses") {
+            let addresses: Vec<Value> = addresses
+                .into_iter()
+                .filter_map(|address| match address {
+                    Value::Object(address) => Some(resource(true, address)),
+                    _ => None,
+                })
+                .collect();
+            object.insert(
+                "_embedded".to_string(),
+                serde_json::json!({ "addresses": addresses }),
+            );
+        }
+    }
+    object.insert("_links".to_string(), Value::Object(related));
+    Value::Object(object)
+}
+
+fn href(link: String) -> Value {
+    serde_json::json!({ "href": link })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(target: &str) -> Request {
+        Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn links_users_to_their_addresses() {
+        let user = serde_json::json!({
+            "id": 7,
+            "name": "Ada",
+            "addresses": [{ "id": 3, "user_id": 7, "city": "London" }]
+        });
+        let (head, body) = render(
+            &get("/users/7?include=addresses"),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n".to_string(),
+            user.to_string(),
+        );
+        assert_eq!(
+            head,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/hal+json\r\n\r\n"
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            serde_json::json!({
+                "id": 7,
+                "name": "Ada",
+                "_links": {
+                    "self": { "href": "/users/7" },
+                    "addresses": { "href": "/users/7/addresses" }
+                },
+                "_embedded": {
+                    "addresses": [{
+                        "id": 3,
+                        "user_id": 7,
+                        "city": "London",
+                        "_links": {
+                            "self": { "href": "/users/7/addresses/3" },
+                            "user": { "href": "/users/7" }
+                        }
+                    }]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn embeds_lists_and_leaves_the_rest_alone() {
+        let (_, body) = render(
+            &get("/users?created_after=2024-01-01"),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Total-Count: 1\r\n\r\n"
+                .to_string(),
+            r#"[{"id":1,"name":"Ada"}]"#.to_string(),
+        );
+        let list: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(list["total"], 1);
+        assert_eq!(list["_links"]["self"]["href"], "/users");
+        assert_eq!(
+            list["_embedded"]["users"][0]["_links"]["self"]["href"],
+            "/users/1"
+        );
+
+        for response in [
+            (
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n",
+                "User Created",
+            ),
+            ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "Not Found URL"),
+        ] {
+            let (head, body) = render(
+                &get("/users"),
+                response.0.to_string(),
+                response.1.to_string(),
+            );
+            assert_eq!((head.as_str(), body.as_str()), response);
+        }
+    }
+}
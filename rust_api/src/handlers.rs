@@ -0,0 +1,3067 @@
+use crate::admin_ui;
+use crate::auth;
+use crate::backup::{Backup, BackupError};
+use crate::build_info;
+use crate::codec;
+use crate::email::{Message, Template};
+use crate::envelope;
+use crate::export::{self, ExportState};
+use crate::extract::{AuthUser, Json, Path, Query};
+use crate::flags;
+use crate::http::{self, ChunkedResponse, Request};
+use crate::import::{self, ImportError};
+use crate::json_patch::{self, PatchError};
+use crate::logger;
+use crate::metadata;
+use crate::models::{user_schema, Address, User, MAX_LOOKUP_IDS};
+use crate::odata;
+use crate::repository::{RepositoryError, Upserted, UserField, UserFilter, UserRepository};
+use crate::response::{self, status_code, ResponseHead};
+use crate::retention;
+use crate::router::validation_failed;
+use crate::rsql;
+use crate::schema::{self, Violation};
+use crate::services::Services;
+use crate::totp;
+use crate::{
+    BAD_REQUEST, CONFLICT, GATEWAY_TIMEOUT, INTERNAL_SERVER_ERROR, NOT_FOUND, NOT_IMPLEMENTED,
+    OK_RESPONSE, UNAUTHORIZED,
+};
+use chrono::{DateTime, NaiveDate, SecondsFormat, SubsecRound, Utc};
+use log::{debug, error, info, warn, LevelFilter};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+*  Controllers
+*
+*  Handlers get their services passed in and never reach for a connection
+*  themselves, so they can run against any `UserRepository`.
+*/
+
+const ACCEPTED: &str = "HTTP/1.1 202 ACCEPTED\r\nContent-Type: application/json\r\n\r\n";
+/// What clients are asked to wait when DynamoDB throttles us. Its own
+/// backoff starts far lower, but `Retry-After` has whole seconds only.
+const STORAGE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+fn internal_server_error() -> (String, String) {
+    (
+        INTERNAL_SERVER_ERROR.to_string(),
+        "Internal Server Error".to_string(),
+    )
+}
+
+/// The response with `head`, or a 500 if one of its headers was refused.
+fn with_head(head: ResponseHead, content: String) -> (String, String) {
+    match head.build() {
+        Ok(head) => (head, content),
+        Err(e) => {
+            error!("{}", e);
+            internal_server_error()
+        }
+    }
+}
+
+fn repository_error(action: &str, e: RepositoryError) -> (String, String) {
+    match e {
+        RepositoryError::Timeout => {
+            warn!("{} User timed out", action);
+            (GATEWAY_TIMEOUT.to_string(), "Gateway Timeout".to_string())
+        }
+        RepositoryError::Conflict => (CONFLICT.to_string(), "Email Already Exists".to_string()),
+        RepositoryError::Unsupported(reason) => (BAD_REQUEST.to_string(), reason.to_string()),
+        RepositoryError::DynamoDb(e) if e.throttled() => {
+            warn!("{} User throttled: {}", action, e);
+            retry_later(
+                "503 SERVICE UNAVAILABLE",
+                STORAGE_RETRY_AFTER,
+                "Service Unavailable",
+                "storage_throttled",
+            )
+        }
+        e => {
+            error!("{} User Error: {}", action, e);
+            internal_server_error()
+        }
+    }
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IfExists {
+    #[default]
+    Error,
+    Return,
+}
+
+#[derive(Deserialize)]
+pub struct CreateParams {
+    #[serde(default)]
+    if_exists: IfExists,
+}
+
+/// With `?if_exists=return`, posting an email that is already taken answers
+/// with the user who has it instead of a conflict.
+pub fn handle_post_request(
+    services: &Services,
+    Query(params): Query<CreateParams>,
+    Json(user): Json<User>,
+) -> (String, String) {
+    let return_existing = params.if_exists == IfExists::Return;
+    let violations = name_violations(&user, services);
+    if !violations.is_empty() {
+        return validation_failed(&violations);
+    }
+    match services.repository.create(&user) {
+        Ok(_) => {
+            send_email(services, Template::Welcome, &user, None);
+            (OK_RESPONSE.to_string(), "User Created".to_string())
+        }
+        Err(RepositoryError::Conflict) if return_existing => {
+            match services.repository.find_by_email(&user.email) {
+                Ok(Some(existing)) => (
+                    OK_RESPONSE.to_string(),
+                    serde_json::to_string(&existing).unwrap(),
+                ),
+                // Deleted in the meantime.
+                Ok(None) => repository_error("Create", RepositoryError::Conflict),
+                Err(e) => repository_error("Create", e),
+            }
+        }
+        Err(e) => repository_error("Create", e),
+    }
+}
+
+/// Media types `POST /users/bulk` reads.
+const IMPORT_TYPES: [&str; 2] = ["text/csv", "application/x-ndjson"];
+
+/// Creates users from CSV or newline-delimited JSON, reading the body as it
+/// arrives, see `crate::import`. Unlike `POST /users`, no welcome emails go
+/// out, since imports usually bring in people who already have an account
+/// elsewhere.
+pub fn handle_import_request(
+    request: &Request,
+    services: &Services,
+    body: &mut dyn Read,
+) -> (String, String) {
+    let media_type = request
+        .header("Content-Type")
+        .and_then(|value| value.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase());
+    let repository = services.repository.as_ref();
+    let check = |user: &User| name_violations(user, services);
+    let body = BufReader::new(body);
+    let imported = match media_type.as_deref() {
+        Some("text/csv") => {
+            import::CsvUsers::new(body).and_then(|users| import::import(repository, users, check))
+        }
+        Some("application/x-ndjson") => {
+            import::import(repository, import::NdjsonUsers::new(body), check)
+        }
+        _ => {
+            let supported = IMPORT_TYPES.join(", ");
+            return (
+                format!(
+                    "HTTP/1.1 415 UNSUPPORTED MEDIA TYPE\r\nContent-Type: application/json\r\nAccept: {}\r\n\r\n",
+                    supported
+                ),
+                serde_json::json!({
+                    "error": format!("Unsupported Content-Type, expected one of: {}", supported),
+                })
+                .to_string(),
+            );
+        }
+    };
+    match imported {
+        Ok(report) => {
+            info!(
+                "Imported users: {} created, {} existing, {} invalid",
+                report.created, report.existing, report.invalid
+            );
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::to_string(&report).unwrap(),
+            )
+        }
+        Err(ImportError::Body(e)) => {
+            debug!("Import body ended early: {}", e);
+            (BAD_REQUEST.to_string(), "Incomplete Body".to_string())
+        }
+        Err(ImportError::Malformed(message)) => (
+            BAD_REQUEST.to_string(),
+            format!("Invalid Upload: {}", message),
+        ),
+        Err(ImportError::Repository(e)) => repository_error("Import", e),
+    }
+}
+
+/// Related resources `?include=` can nest into a user.
+const INCLUDES: [&str; 1] = ["addresses"];
+
+#[derive(Deserialize)]
+pub struct GetParams {
+    #[serde(default)]
+    include: Vec<String>,
+}
+
+/// With `?include=addresses` the related resources are loaded alongside and
+/// nested into the user, saving the client a request per relation.
+///
+/// A plain user carries `Last-Modified`, and a client whose `If-Modified-Since`
+/// is still current gets a bodiless `304`. Addresses have no timestamp of their
+/// own, so responses that include them are always sent in full.
+pub fn handle_get_request(
+    request: &Request,
+    services: &Services,
+    Path(id): Path<i32>,
+    Query(params): Query<GetParams>,
+) -> (String, String) {
+    let includes: Vec<&str> = params
+        .include
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if let Some(unknown) = includes.iter().find(|name| !INCLUDES.contains(name)) {
+        return (
+            BAD_REQUEST.to_string(),
+            format!(
+                "Unknown include: {} (supported: {})",
+                unknown,
+                INCLUDES.join(", ")
+            ),
+        );
+    }
+
+    debug!("ID: {}", id);
+    let user = match services.repository.find(id) {
+        Ok(Some(user)) => user,
+        Ok(None) => return (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Err(e) => return repository_error("Get", e),
+    };
+    // HTTP dates have whole seconds only.
+    let last_modified = user
+        .updated_at
+        .filter(|_| includes.is_empty())
+        .map(|updated_at| updated_at.trunc_subsecs(0));
+    if let Some(last_modified) = last_modified {
+        let current = request
+            .header("If-Modified-Since")
+            .and_then(http::parse_date)
+            .is_some_and(|since| last_modified <= since);
+        if current {
+            return with_head(
+                ResponseHead::new("304 NOT MODIFIED")
+                    .header("Last-Modified", http::format_date(last_modified)),
+                String::new(),
+            );
+        }
+    }
+    let mut content = serde_json::json!(user);
+    for name in includes {
+        let related = match name {
+            "addresses" => services
+                .addresses
+                .list_addresses(id)
+                .map(|addresses| serde_json::json!(addresses)),
+            _ => unreachable!("checked against INCLUDES"),
+        };
+        match related {
+            Ok(related) => content[name] = related,
+            Err(e) => return repository_error("Get", e),
+        }
+    }
+    let head = ResponseHead::extend(OK_RESPONSE);
+    let head = match last_modified {
+        Some(last_modified) => head.header("Last-Modified", http::format_date(last_modified)),
+        None => head,
+    };
+    with_head(head, content.to_string())
+}
+
+/// `HEAD /users/:id`: whether the user exists, without sending it.
+pub fn handle_exists_request(services: &Services, Path(id): Path<i32>) -> (String, String) {
+    match services.repository.find(id) {
+        Ok(Some(_)) => (OK_RESPONSE.to_string(), String::new()),
+        Ok(None) => (NOT_FOUND.to_string(), String::new()),
+        Err(e) => repository_error("Get", e),
+    }
+}
+
+/// `HEAD /users`: the number of users in `X-Total-Count`.
+pub fn handle_count_request(request: &Request, services: &Services) -> (String, String) {
+    let filter = match user_filter(request) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    match services.repository.count(&filter) {
+        Ok(count) => with_head(
+            ResponseHead::extend(OK_RESPONSE).header("X-Total-Count", count),
+            String::new(),
+        ),
+        Err(e) => repository_error("Count", e),
+    }
+}
+
+/// Lists every user, or with `?ids=1,5,9` just those.
+pub fn handle_get_all_request(
+    request: &Request,
+    services: &Services,
+    out: &mut dyn Write,
+) -> io::Result<u16> {
+    let (filter, fields) = match user_filter(request).and_then(|filter| {
+        let fields =
+            odata::selection(request).map_err(|e| (BAD_REQUEST.to_string(), e.to_string()))?;
+        Ok((filter, fields))
+    }) {
+        Ok(listing) => listing,
+        Err(response) => return write_response(request, out, response),
+    };
+    if let Some(ids) = request.query("ids") {
+        let response = match parse_ids(ids) {
+            Some(ids) => lookup_users(&ids, services),
+            None => (BAD_REQUEST.to_string(), "Invalid ids".to_string()),
+        };
+        return write_response(request, out, response);
+    }
+
+    // MessagePack, JSON:API and HAL are encoded from a complete JSON document,
+    // so those paths still buffer.
+    if codec::wants_msgpack(request) || codec::wants_jsonapi(request) || codec::wants_hal(request) {
+        let listed = services.repository.list(&filter).and_then(|users| {
+            let total = if filter.is_paged() {
+                services.repository.count(&filter)?
+            } else {
+                users.len() as u64
+            };
+            Ok((users, total))
+        });
+        let response = match listed {
+            Ok((users, total)) => {
+                let users: Vec<serde_json::Value> = users
+                    .iter()
+                    .map(|user| selected(user, fields.as_deref()))
+                    .collect();
+                with_head(
+                    ResponseHead::extend(OK_RESPONSE).header("X-Total-Count", total),
+                    serde_json::to_string(&users).unwrap(),
+                )
+            }
+            Err(e) => repository_error("List", e),
+        };
+        return write_response(request, out, response);
+    }
+
+    stream_users(
+        services.repository.as_ref(),
+        &filter,
+        fields.as_deref(),
+        out,
+        &JSON_ARRAY,
+        envelope::wanted(request),
+    )
+}
+
+/// A user as sent, with only the fields of `$select`.
+fn selected(user: &User, fields: Option<&[UserField]>) -> serde_json::Value {
+    let user = serde_json::to_value(user).unwrap();
+    match fields {
+        Some(fields) => odata::project(user, fields),
+        None => user,
+    }
+}
+
+/// `POST /users/lookup` with a JSON array of ids, for lists too long for a
+/// query string.
+pub fn handle_lookup_request(services: &Services, Json(ids): Json<Vec<i32>>) -> (String, String) {
+    lookup_users(&ids, services)
+}
+
+fn parse_ids(ids: &str) -> Option<Vec<i32>> {
+    let ids = ids
+        .split(',')
+        .map(|id| id.trim().parse().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    (ids.len() <= MAX_LOOKUP_IDS).then_some(ids)
+}
+
+/// Fetches the users in one query, answering them in the order asked for and
+/// naming the ids that do not exist.
+fn lookup_users(ids: &[i32], services: &Services) -> (String, String) {
+    let mut wanted = Vec::with_capacity(ids.len());
+    for id in ids {
+        if !wanted.contains(id) {
+            wanted.push(*id);
+        }
+    }
+
+    let mut found: HashMap<i32, User> = match services.repository.find_many(&wanted) {
+        Ok(users) => users
+            .into_iter()
+            .filter_map(|user| Some((user.id?, user)))
+            .collect(),
+        Err(e) => return repository_error("Lookup", e),
+    };
+    let mut users = Vec::new();
+    let mut missing = Vec::new();
+    for id in wanted {
+        match found.remove(&id) {
+            Some(user) => users.push(user),
+            None => missing.push(id),
+        }
+    }
+    (
+        OK_RESPONSE.to_string(),
+        serde_json::json!({ "users": users, "missing": missing }).to_string(),
+    )
+}
+
+/// Sends a buffered response from a streaming handler.
+fn write_response(
+    request: &Request,
+    out: &mut dyn Write,
+    (status_line, content): (String, String),
+) -> io::Result<u16> {
+    let status = status_code(&status_line);
+    let (status_line, content) = codec::encode_response(request, status_line, content);
+    out.write_all(status_line.as_bytes())?;
+    out.write_all(&content)?;
+    Ok(status)
+}
+
+pub fn handle_stream_request(
+    request: &Request,
+    services: &Services,
+    out: &mut dyn Write,
+) -> io::Result<u16> {
+    match user_filter(request) {
+        Ok(filter) => stream_users(
+            services.repository.as_ref(),
+            &filter,
+            None,
+            out,
+            &NDJSON,
+            false,
+        ),
+        Err(response) => write_response(request, out, response),
+    }
+}
+
+/// Reads the `created_after` / `created_before` listing filters, the OData
+/// query options, see `crate::odata`, and an RSQL `filter`, see `crate::rsql`.
+fn user_filter(request: &Request) -> Result<UserFilter, (String, String)> {
+    let bound = |name: &str| match request.query(name) {
+        None => Ok(None),
+        Some(value) => parse_timestamp(value).map(Some).ok_or_else(|| {
+            (
+                BAD_REQUEST.to_string(),
+                format!("Invalid {}: expected an ISO-8601 date or timestamp", name),
+            )
+        }),
+    };
+    let mut filter = UserFilter {
+        created_after: bound("created_after")?,
+        created_before: bound("created_before")?,
+        ..UserFilter::default()
+    };
+    odata::apply(request, &mut filter).map_err(|e| (BAD_REQUEST.to_string(), e.to_string()))?;
+    if let Some(expression) = request.query("filter") {
+        let expression =
+            rsql::parse(expression).map_err(|e| (BAD_REQUEST.to_string(), e.to_string()))?;
+        filter.conditions.push(expression);
+    }
+    Ok(filter)
+}
+
+/// Accepts an RFC 3339 timestamp or a plain date, taken as midnight UTC.
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// How a streamed list of users is framed on the wire.
+struct ListFormat {
+    head: &'static str,
+    open: &'static [u8],
+    separator: &'static [u8],
+    terminator: &'static [u8],
+    close: &'static [u8],
+}
+
+const JSON_ARRAY: ListFormat = ListFormat {
+    head: "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n",
+    open: b"[",
+    separator: b",",
+    terminator: b"",
+    close: b"]",
+};
+
+const NDJSON: ListFormat = ListFormat {
+    head: "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n",
+    open: b"",
+    separator: b"",
+    terminator: b"\n",
+    close: b"",
+};
+
+/// Writes users to the client as they come off the database cursor. Output is
+/// buffered, so an error before the first buffer is flushed can still be sent
+/// as a proper error response; after that the stream is cut short.
+fn stream_users(
+    repository: &dyn UserRepository,
+    filter: &UserFilter,
+    fields: Option<&[UserField]>,
+    out: &mut dyn Write,
+    format: &ListFormat,
+    envelope: bool,
+) -> io::Result<u16> {
+    // Admin frameworks size their grids by this header. It is counted before
+    // the rows are read, so writes in between can leave it off by a few.
+    let (head, total) = match repository.count(filter) {
+        Ok(total) => (
+            ResponseHead::extend(format.head)
+                .header("X-Total-Count", total)
+                .build()
+                .expect("a count is a valid header value"),
+            total,
+        ),
+        Err(e) => {
+            let (status_line, content) = repository_error("Count", e);
+            out.write_all(format!("{}{}", status_line, content).as_bytes())?;
+            return Ok(status_code(&status_line));
+        }
+    };
+    let mut body = BufWriter::new(ChunkedResponse::new(out, &head));
+    if envelope {
+        body.write_all(envelope::LIST_OPEN)?;
+    }
+    body.write_all(format.open)?;
+
+    let mut first = true;
+    let mut write_error = None;
+    let result = repository.stream_all(filter, &mut |user| {
+        let separator = if first { &b""[..] } else { format.separator };
+        first = false;
+        let written = body
+            .write_all(separator)
+            .and_then(|()| {
+                match fields {
+                    Some(fields) => {
+                        serde_json::to_writer(&mut body, &selected(&user, Some(fields)))
+                    }
+                    None => serde_json::to_writer(&mut body, &user),
+                }
+                .map_err(io::Error::from)
+            })
+            .and_then(|()| body.write_all(format.terminator));
+        match written {
+            Ok(()) => true,
+            Err(e) => {
+                write_error = Some(e);
+                false
+            }
+        }
+    });
+
+    if let Some(e) = write_error {
+        return Err(e);
+    }
+    match result {
+        Ok(()) => {
+            body.write_all(format.close)?;
+            if envelope {
+                body.write_all(&envelope::list_close(total))?;
+            }
+            body.into_inner().map_err(|e| e.into_error())?.finish()?;
+            Ok(200)
+        }
+        Err(e) => {
+            let (response, _) = body.into_parts();
+            if response.started() {
+                return Err(io::Error::other(e.to_string()));
+            }
+            let (status_line, content) = repository_error("List", e);
+            response
+                .into_inner()
+                .write_all(format!("{}{}", status_line, content).as_bytes())?;
+            Ok(status_code(&status_line))
+        }
+    }
+}
+
+pub fn handle_put_request(
+    services: &Services,
+    Path(id): Path<i32>,
+    Json(user): Json<User>,
+) -> (String, String) {
+    let violations = name_violations(&user, services);
+    if !violations.is_empty() {
+        return validation_failed(&violations);
+    }
+    match services.repository.update(id, &user) {
+        Ok(0) => (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Ok(_) => (OK_RESPONSE.to_string(), "User Updated".to_string()),
+        Err(e) => repository_error("Update", e),
+    }
+}
+
+/// Applies a JSON Patch or JSON Merge Patch to the user, as its
+/// `Content-Type` says. The result must still be a valid user with the same
+/// id and timestamps, or nothing is stored.
+pub fn handle_patch_request(
+    request: &Request,
+    services: &Services,
+    Path(id): Path<i32>,
+    Json(patch): Json<serde_json::Value>,
+) -> (String, String) {
+    // Read before the user is locked: the memory repository keeps flags in
+    // the same store.
+    let strict_names = services.flags.enabled(flags::STRICT_NAMES);
+    let merge = request
+        .header("Content-Type")
+        .is_some_and(json_patch::is_merge_patch);
+    let mut refusal = None;
+    let changed = services.repository.change_user(id, &mut |user| {
+        refusal = None;
+        match patched(user, &patch, merge, strict_names) {
+            Ok(patched) => {
+                *user = patched;
+                true
+            }
+            Err(response) => {
+                refusal = Some(response);
+                false
+            }
+        }
+    });
+    match changed {
+        Ok(Some(user)) => refusal.unwrap_or_else(|| {
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::to_string(&user).unwrap(),
+            )
+        }),
+        Ok(None) => user_not_found(),
+        Err(e) => repository_error("Patch", e),
+    }
+}
+
+/// `user` with `patch` applied, or the response refusing it. `merge` takes
+/// `patch` as a merge patch, which cannot fail to apply.
+fn patched(
+    user: &User,
+    patch: &serde_json::Value,
+    merge: bool,
+    strict_names: bool,
+) -> Result<User, (String, String)> {
+    let original = serde_json::json!(user);
+    let mut document = original.clone();
+    if merge {
+        json_patch::merge_patch(&mut document, patch);
+    } else if let Err(e) = json_patch::apply(&mut document, patch) {
+        return Err(match e {
+            PatchError::TestFailed { .. } => (CONFLICT.to_string(), e.to_string()),
+            PatchError::Invalid { index, reason } => validation_failed(&[Violation {
+                pointer: format!("/{}", index),
+                code: "patch_failed",
+                message: reason,
+            }]),
+        });
+    }
+
+    let mut violations = schema::validate(&user_schema(), &document);
+    for field in ["id", "created_at", "updated_at"] {
+        if document.get(field) != original.get(field) {
+            violations.push(Violation {
+                pointer: format!("/{}", field),
+                code: "read_only",
+                message: "cannot be changed".to_string(),
+            });
+        }
+    }
+    if !violations.is_empty() {
+        return Err(validation_failed(&violations));
+    }
+    let Ok(changed) = serde_json::from_value::<User>(document) else {
+        return Err(internal_server_error());
+    };
+    let changed = User {
+        name: changed.name,
+        email: changed.email,
+        ..user.clone()
+    };
+    let violations = blank_name_violations(&changed, strict_names);
+    if !violations.is_empty() {
+        return Err(validation_failed(&violations));
+    }
+    Ok(changed)
+}
+
+#[derive(Deserialize)]
+pub struct UpsertBody {
+    name: String,
+}
+
+/// Creates or renames the user with the email in the path, answering which of
+/// the two it did.
+pub fn handle_upsert_request(
+    services: &Services,
+    Path(email): Path<String>,
+    Json(body): Json<UpsertBody>,
+) -> (String, String) {
+    let user = User {
+        id: None,
+        name: body.name,
+        email,
+        created_at: None,
+        updated_at: None,
+    };
+    // The body was checked by the route; the email still needs checking.
+    let mut violations = schema::validate(&user_schema(), &serde_json::json!(user));
+    violations.extend(name_violations(&user, services));
+    if !violations.is_empty() {
+        return validation_failed(&violations);
+    }
+
+    let (id, created) = match services.repository.upsert(&user) {
+        Ok(Upserted::Created(id)) => {
+            send_email(services, Template::Welcome, &user, None);
+            (id, true)
+        }
+        Ok(Upserted::Updated(id)) => (id, false),
+        Err(e) => return repository_error("Upsert", e),
+    };
+    (
+        OK_RESPONSE.to_string(),
+        serde_json::json!({ "id": id, "created": created }).to_string(),
+    )
+}
+
+/// Checks that only apply while their feature flag is on.
+fn name_violations(user: &User, services: &Services) -> Vec<Violation> {
+    blank_name_violations(user, services.flags.enabled(flags::STRICT_NAMES))
+}
+
+fn blank_name_violations(user: &User, strict_names: bool) -> Vec<Violation> {
+    if strict_names && user.name.trim().is_empty() {
+        vec![Violation {
+            pointer: "/name".to_string(),
+            code: "blank",
+            message: "must not be blank".to_string(),
+        }]
+    } else {
+        Vec::new()
+    }
+}
+
+pub fn handle_delete_request(services: &Services, Path(id): Path<i32>) -> (String, String) {
+    match services.repository.delete(id) {
+        Ok(0) => (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Ok(_) => (OK_RESPONSE.to_string(), "User Deleted".to_string()),
+        Err(e) => repository_error("Delete", e),
+    }
+}
+
+/// Right-to-erasure: overwrites the user's name and email with placeholders
+/// that cannot be traced back, keeping the row (and its id) in place. Logs
+/// who asked for it when the request carried a token.
+pub fn handle_erase_request(
+    services: &Services,
+    Path(id): Path<i32>,
+    caller: Option<AuthUser>,
+) -> (String, String) {
+    let erased = retention::erase(
+        services.repository.as_ref(),
+        services.addresses.as_ref(),
+        id,
+    );
+    match erased {
+        Ok(0) => (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Ok(_) => {
+            info!(
+                "Personal data of user {} erased by {}",
+                id,
+                caller.map_or("-".to_string(), |caller| caller.subject)
+            );
+            (OK_RESPONSE.to_string(), "Personal Data Erased".to_string())
+        }
+        Err(e) => repository_error("Erase", e),
+    }
+}
+
+/// Sends the user's data export right away when it is small, otherwise queues
+/// it and answers `202` with a link to poll.
+pub fn handle_export_request(services: &Services, Path(id): Path<i32>) -> (String, String) {
+    let users = services.repository.as_ref();
+    let addresses = services.addresses.as_ref();
+    match export::record_count(users, addresses, id) {
+        Ok(Some(records)) if records < services.export_async_threshold => {
+            return match export::archive(users, addresses, id) {
+                Ok(Some(archive)) => export_download(id, archive.to_string()),
+                Ok(None) => (NOT_FOUND.to_string(), "User Not Found".to_string()),
+                Err(e) => repository_error("Export", e),
+            };
+        }
+        Ok(Some(_)) => {}
+        Ok(None) => return (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Err(e) => return repository_error("Export", e),
+    }
+
+    let token = services.exports.start();
+    let link = format!("{}/exports/{}", services.base_path, token);
+    let users = Arc::clone(&services.repository);
+    let addresses = Arc::clone(&services.addresses);
+    let exports = Arc::clone(&services.exports);
+    services.jobs.submit(move || {
+        let state = match export::archive(users.as_ref(), addresses.as_ref(), id) {
+            Ok(Some(archive)) => ExportState::Ready(archive.to_string()),
+            Ok(None) => ExportState::Failed,
+            Err(e) => {
+                error!("Export User Error: {}", e);
+                ExportState::Failed
+            }
+        };
+        exports.finish(&token, state);
+    });
+    with_head(
+        ResponseHead::extend(ACCEPTED)
+            .header("Cache-Control", "no-store")
+            .header("Location", &link),
+        serde_json::json!({ "status": "pending", "download": link }).to_string(),
+    )
+}
+
+/// Hands out an export built in the background. The archive can be downloaded
+/// once; afterwards the link is gone.
+pub fn handle_export_download_request(
+    services: &Services,
+    Path(token): Path<String>,
+) -> (String, String) {
+    match services.exports.take(&token) {
+        Some(ExportState::Ready(archive)) => export_download(token, archive),
+        Some(ExportState::Pending) => (
+            "HTTP/1.1 202 ACCEPTED\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nRetry-After: 1\r\n\r\n"
+                .to_string(),
+            serde_json::json!({ "status": "pending" }).to_string(),
+        ),
+        Some(ExportState::Failed) => internal_server_error(),
+        None => (NOT_FOUND.to_string(), "Export Not Found".to_string()),
+    }
+}
+
+/// Exports hold personal data and must not end up in any cache.
+fn export_download(name: impl fmt::Display, archive: String) -> (String, String) {
+    with_head(
+        ResponseHead::extend(OK_RESPONSE)
+            .header("Cache-Control", "no-store")
+            .header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename={}",
+                    response::quoted(&format!("export-{}.json", name))
+                ),
+            ),
+        archive,
+    )
+}
+
+/*
+*  Addresses
+*
+*  Nested under `/users/:id/addresses`. Only the collection takes a POST, only
+*  single addresses take PUT and DELETE.
+*/
+
+fn user_not_found() -> (String, String) {
+    (NOT_FOUND.to_string(), "User Not Found".to_string())
+}
+
+fn address_not_found() -> (String, String) {
+    (NOT_FOUND.to_string(), "Address Not Found".to_string())
+}
+
+pub fn handle_list_addresses_request(
+    services: &Services,
+    Path(user_id): Path<i32>,
+) -> (String, String) {
+    let found = services
+        .repository
+        .find(user_id)
+        .and_then(|user| match user {
+            Some(_) => services
+                .addresses
+                .list_addresses(user_id)
+                .map(|addresses| Some(serde_json::to_string(&addresses).unwrap())),
+            None => Ok(None),
+        });
+    match found {
+        Ok(Some(content)) => (OK_RESPONSE.to_string(), content),
+        Ok(None) => user_not_found(),
+        Err(e) => repository_error("Get Address", e),
+    }
+}
+
+pub fn handle_get_address_request(
+    services: &Services,
+    Path((user_id, id)): Path<(i32, i32)>,
+) -> (String, String) {
+    match services.addresses.find_address(user_id, id) {
+        Ok(Some(address)) => (
+            OK_RESPONSE.to_string(),
+            serde_json::to_string(&address).unwrap(),
+        ),
+        Ok(None) => address_not_found(),
+        Err(e) => repository_error("Get Address", e),
+    }
+}
+
+/// Adds an address to the user and answers with it, including its new id.
+pub fn handle_post_address_request(
+    services: &Services,
+    Path(user_id): Path<i32>,
+    Json(address): Json<Address>,
+) -> (String, String) {
+    match services.repository.find(user_id) {
+        Ok(Some(_)) => {}
+        Ok(None) => return user_not_found(),
+        Err(e) => return repository_error("Create Address", e),
+    }
+    match services.addresses.create_address(user_id, &address) {
+        Ok(address) => (
+            OK_RESPONSE.to_string(),
+            serde_json::to_string(&address).unwrap(),
+        ),
+        Err(e) => repository_error("Create Address", e),
+    }
+}
+
+pub fn handle_put_address_request(
+    services: &Services,
+    Path((user_id, id)): Path<(i32, i32)>,
+    Json(address): Json<Address>,
+) -> (String, String) {
+    match services.addresses.update_address(user_id, id, &address) {
+        Ok(0) => address_not_found(),
+        Ok(_) => (OK_RESPONSE.to_string(), "Address Updated".to_string()),
+        Err(e) => repository_error("Update Address", e),
+    }
+}
+
+pub fn handle_delete_address_request(
+    services: &Services,
+    Path((user_id, id)): Path<(i32, i32)>,
+) -> (String, String) {
+    match services.addresses.delete_addresses(user_id, Some(id)) {
+        Ok(0) => address_not_found(),
+        Ok(_) => (OK_RESPONSE.to_string(), "Address Deleted".to_string()),
+        Err(e) => repository_error("Delete Address", e),
+    }
+}
+
+/*
+*  Metadata
+*/
+
+pub fn handle_get_metadata_request(services: &Services, Path(id): Path<i32>) -> (String, String) {
+    match services.repository.metadata(id) {
+        Ok(Some(metadata)) => (OK_RESPONSE.to_string(), metadata.to_string()),
+        Ok(None) => user_not_found(),
+        Err(e) => repository_error("Get Metadata", e),
+    }
+}
+
+/// `PUT` replaces the metadata with the body.
+pub fn handle_put_metadata_request(
+    services: &Services,
+    Path(id): Path<i32>,
+    Json(body): Json<serde_json::Value>,
+) -> (String, String) {
+    change_metadata(services, id, &body, |metadata, body| {
+        *metadata = body.clone()
+    })
+}
+
+/// `PATCH` applies the body as a JSON Merge Patch.
+pub fn handle_patch_metadata_request(
+    services: &Services,
+    Path(id): Path<i32>,
+    Json(body): Json<serde_json::Value>,
+) -> (String, String) {
+    change_metadata(services, id, &body, json_patch::merge_patch)
+}
+
+/// Stores the changed metadata if it stays within the limits, answering with
+/// the result.
+fn change_metadata(
+    services: &Services,
+    id: i32,
+    body: &serde_json::Value,
+    apply: fn(&mut serde_json::Value, &serde_json::Value),
+) -> (String, String) {
+    let mut violations = Vec::new();
+    let changed = services.repository.change_metadata(id, &mut |metadata| {
+        apply(metadata, body);
+        violations = metadata::validate(metadata);
+        violations.is_empty()
+    });
+    match changed {
+        Ok(Some(_)) if !violations.is_empty() => validation_failed(&violations),
+        Ok(Some(metadata)) => (OK_RESPONSE.to_string(), metadata.to_string()),
+        Ok(None) => user_not_found(),
+        Err(e) => repository_error("Update Metadata", e),
+    }
+}
+
+/*
+*  Admin
+*
+*  Drain and shutdown answer right away; the connections in flight, this one
+*  included, are left to finish.
+*/
+
+/// Stops serving API requests while the process keeps running, so an
+/// orchestrator can move traffic away before stopping it.
+pub fn handle_drain_request(_: &Request, services: &Services) -> (String, String) {
+    warn!("Draining on admin request");
+    services.lifecycle.drain();
+    (
+        ACCEPTED.to_string(),
+        serde_json::json!({ "status": "draining" }).to_string(),
+    )
+}
+
+/// The configured log level and the overrides in force.
+pub fn handle_get_log_level_request(_: &Request, _: &Services) -> (String, String) {
+    log_levels()
+}
+
+fn log_levels() -> (String, String) {
+    let (level, overrides) = logger::levels();
+    let overrides: Vec<_> = overrides
+        .iter()
+        .map(|o| {
+            serde_json::json!({
+                "target": o.target,
+                "level": o.level.as_str().to_ascii_lowercase(),
+                "until": o.until.map(|until| until.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            })
+        })
+        .collect();
+    (
+        OK_RESPONSE.to_string(),
+        serde_json::json!({
+            "level": level.as_str().to_ascii_lowercase(),
+            "overrides": overrides,
+        })
+        .to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelBody {
+    level: String,
+    #[serde(default)]
+    target: String,
+    duration_secs: Option<u64>,
+}
+
+/// Overrides the log level for a module, or all of them, answering with the
+/// levels now in force.
+pub fn handle_put_log_level_request(
+    _: &Services,
+    Json(body): Json<LogLevelBody>,
+) -> (String, String) {
+    // The route's schema only lets known levels through.
+    let Ok(level) = body.level.parse::<LevelFilter>() else {
+        return internal_server_error();
+    };
+    let duration = body.duration_secs.map(Duration::from_secs);
+    warn!(
+        "Log level for {:?} set to {} for {}",
+        body.target,
+        level,
+        duration.map_or("good".to_string(), |d| format!("{}s", d.as_secs()))
+    );
+    logger::set_override(&body.target, level, duration);
+    log_levels()
+}
+
+/// Drops every override, leaving `LOG_LEVEL` alone in force.
+pub fn handle_delete_log_level_request(_: &Request, _: &Services) -> (String, String) {
+    warn!("Log level overrides cleared");
+    logger::clear_overrides();
+    log_levels()
+}
+
+/// Whether maintenance is on, and what clients are told meanwhile.
+pub fn handle_get_maintenance_request(_: &Request, services: &Services) -> (String, String) {
+    maintenance_status(services)
+}
+
+fn maintenance_status(services: &Services) -> (String, String) {
+    let status = match services.maintenance.window() {
+        Some(window) => serde_json::json!({
+            "maintenance": true,
+            "message": window.message,
+            "retry_after_secs": window.retry_after.as_secs(),
+        }),
+        None => serde_json::json!({ "maintenance": false }),
+    };
+    (OK_RESPONSE.to_string(), status.to_string())
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceBody {
+    message: Option<String>,
+    retry_after_secs: Option<u64>,
+}
+
+pub fn handle_put_maintenance_request(
+    services: &Services,
+    Json(body): Json<MaintenanceBody>,
+) -> (String, String) {
+    let window = services
+        .maintenance
+        .start(body.message, body.retry_after_secs.map(Duration::from_secs));
+    warn!("Maintenance started: {}", window.message);
+    maintenance_status(services)
+}
+
+pub fn handle_delete_maintenance_request(
+    request: &Request,
+    services: &Services,
+) -> (String, String) {
+    if services.maintenance.end() {
+        warn!("Maintenance ended");
+    }
+    handle_get_maintenance_request(request, services)
+}
+
+/// Streams a gzipped backup of the database, see `crate::backup`. An error
+/// before the first buffer went out is answered with a 500; after that the
+/// archive is cut short and fails to decompress.
+pub fn handle_backup_request(
+    request: &Request,
+    services: &Services,
+    out: &mut dyn Write,
+) -> io::Result<u16> {
+    let Some(backup) = &services.backup else {
+        return write_response(
+            request,
+            out,
+            (
+                NOT_IMPLEMENTED.to_string(),
+                "Backups Need STORAGE=postgres".to_string(),
+            ),
+        );
+    };
+    let head = ResponseHead::new("200 OK")
+        .header("Content-Type", "application/gzip")
+        .header("Cache-Control", "no-store")
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename={}",
+                response::quoted(&Backup::file_name())
+            ),
+        )
+        .header("Transfer-Encoding", "chunked")
+        .build();
+    let head = match head {
+        Ok(head) => head,
+        Err(e) => {
+            error!("{}", e);
+            return write_response(request, out, internal_server_error());
+        }
+    };
+
+    let mut body = BufWriter::new(ChunkedResponse::new(out, &head));
+    match backup.write(&mut body) {
+        Ok(rows) => {
+            body.into_inner().map_err(|e| e.into_error())?.finish()?;
+            info!("Backup sent: {:?}", rows);
+            Ok(200)
+        }
+        Err(BackupError::Io(e)) => Err(e),
+        Err(e) => {
+            error!("Backup Error: {}", e);
+            let (response, _) = body.into_parts();
+            if response.started() {
+                return Err(io::Error::other(e.to_string()));
+            }
+            let (status_line, content) = internal_server_error();
+            response
+                .into_inner()
+                .write_all(format!("{}{}", status_line, content).as_bytes())?;
+            Ok(500)
+        }
+    }
+}
+
+/// What the retention rules would do if they ran now, without doing it.
+pub fn handle_get_retention_request(_: &Request, services: &Services) -> (String, String) {
+    let now = Utc::now();
+    match retention::affected(&services.retention, services.repository.as_ref(), now) {
+        Ok(affected) => {
+            let rules: Vec<_> = affected
+                .iter()
+                .map(|affected| {
+                    serde_json::json!({
+                        "rule": affected.rule.to_string(),
+                        "action": affected.rule.action.as_str(),
+                        "inactive_since": affected.cutoff.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        "users": affected.ids.len(),
+                        "ids": &affected.ids[..affected.ids.len().min(retention::REPORT_IDS)],
+                    })
+                })
+                .collect();
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::json!({ "dry_run": true, "rules": rules }).to_string(),
+            )
+        }
+        Err(e) => repository_error("Retention", e),
+    }
+}
+
+/// The tables, columns and indexes the app manages and the migration version,
+/// see `crate::database_schema`.
+pub fn handle_get_schema_request(_: &Request, services: &Services) -> (String, String) {
+    let Some(schema) = &services.schema else {
+        return (
+            NOT_IMPLEMENTED.to_string(),
+            "Schema Needs STORAGE=postgres".to_string(),
+        );
+    };
+    match schema.describe() {
+        Ok(report) => (
+            OK_RESPONSE.to_string(),
+            serde_json::to_string(&report).unwrap(),
+        ),
+        Err(e) => repository_error("Schema", e),
+    }
+}
+
+/// Every flag that was set, by name.
+pub fn handle_get_flags_request(_: &Request, services: &Services) -> (String, String) {
+    match services.flags.all() {
+        Ok(flags) => (
+            OK_RESPONSE.to_string(),
+            serde_json::to_string(&flags).unwrap(),
+        ),
+        Err(e) => flag_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FlagBody {
+    enabled: bool,
+}
+
+pub fn handle_put_flag_request(
+    services: &Services,
+    Path(name): Path<String>,
+    Json(FlagBody { enabled }): Json<FlagBody>,
+) -> (String, String) {
+    if !flags::valid_name(&name) {
+        return (BAD_REQUEST.to_string(), "Invalid Flag Name".to_string());
+    }
+    match services.flags.set(&name, enabled) {
+        Ok(()) => {
+            warn!(
+                "Feature flag {} turned {}",
+                name,
+                if enabled { "on" } else { "off" }
+            );
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::json!({ "name": name, "enabled": enabled }).to_string(),
+            )
+        }
+        Err(e) => flag_error(e),
+    }
+}
+
+/// Forgets the flag, which leaves it off.
+pub fn handle_delete_flag_request(
+    services: &Services,
+    Path(name): Path<String>,
+) -> (String, String) {
+    match services.flags.remove(&name) {
+        Ok(true) => {
+            warn!("Feature flag {} removed", name);
+            (OK_RESPONSE.to_string(), "Flag Deleted".to_string())
+        }
+        Ok(false) => (NOT_FOUND.to_string(), "Flag Not Found".to_string()),
+        Err(e) => flag_error(e),
+    }
+}
+
+fn flag_error(e: RepositoryError) -> (String, String) {
+    error!("Feature Flag Error: {}", e);
+    internal_server_error()
+}
+
+/// The admin UI. Served without login, see `auth::is_public`.
+pub fn handle_admin_page_request(_: &Request, _: &Services) -> (String, String) {
+    let page = admin_ui::page();
+    with_head(
+        ResponseHead::new("200 OK")
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .header("Content-Security-Policy", &page.content_security_policy)
+            .header("X-Frame-Options", "DENY")
+            .header("X-Content-Type-Options", "nosniff")
+            .header("Referrer-Policy", "no-referrer"),
+        page.html.clone(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SessionBody {
+    token: String,
+    code: Option<String>,
+}
+
+/// Logs the admin UI in: trades the admin token for a session cookie.
+pub fn handle_post_session_request(
+    request: &Request,
+    services: &Services,
+    Json(body): Json<SessionBody>,
+) -> (String, String) {
+    match services
+        .admin
+        .login(&body.token, body.code.as_deref(), request.client_addr)
+    {
+        Ok(session) => {
+            warn!("Admin UI login from {}", client(request));
+            session_response(services, &session)
+        }
+        Err(denied) => {
+            warn!("Admin UI login refused for {}", client(request));
+            login_refused(denied)
+        }
+    }
+}
+
+/// `429` while the client's address has to wait after wrong admin tokens or
+/// Basic passwords, `423` while the account is locked; `401` otherwise.
+pub fn login_refused(denied: auth::Denied) -> (String, String) {
+    match denied {
+        auth::Denied::Throttled(wait) => retry_later(
+            "429 TOO MANY REQUESTS",
+            wait,
+            "Too Many Failed Logins",
+            "login_throttled",
+        ),
+        auth::Denied::Locked(wait) => {
+            retry_later("423 LOCKED", wait, "Account Locked", "account_locked")
+        }
+        auth::Denied::CodeRequired => (
+            UNAUTHORIZED.to_string(),
+            "Two-Factor Code Required".to_string(),
+        ),
+        auth::Denied::Unavailable(e) => totp_error(e),
+        _ => (UNAUTHORIZED.to_string(), "Unauthorized".to_string()),
+    }
+}
+
+/// Answers that a limit was hit, with `Retry-After` rounded up to whole
+/// seconds and the limit's `code` in the body, so clients can back off
+/// without parsing messages.
+pub fn retry_later(status: &str, wait: Duration, error: &str, code: &str) -> (String, String) {
+    retry_later_with(
+        status,
+        wait,
+        serde_json::json!({ "error": error, "code": code }),
+    )
+}
+
+/// `retry_later` with a body of its own, which should still carry an `error`
+/// and a `code`.
+pub fn retry_later_with(status: &str, wait: Duration, body: serde_json::Value) -> (String, String) {
+    with_head(
+        ResponseHead::new(status)
+            .header("Content-Type", "application/json")
+            .header(
+                "Retry-After",
+                wait.as_secs() + u64::from(wait.subsec_nanos() > 0),
+            ),
+        body.to_string(),
+    )
+}
+
+/// Whether two-factor login is on for the admin UI.
+pub fn handle_get_totp_request(_: &Request, services: &Services) -> (String, String) {
+    match totp::status(services.totp.as_ref(), totp::ADMIN_ACCOUNT) {
+        Ok(enrollment) => (
+            OK_RESPONSE.to_string(),
+            serde_json::json!({
+                "enabled": enrollment.as_ref().is_some_and(|totp| totp.enabled),
+                "pending": enrollment.as_ref().is_some_and(|totp| !totp.enabled),
+                "recovery_codes_left": enrollment.map_or(0, |totp| totp.recovery_codes.len()),
+            })
+            .to_string(),
+        ),
+        Err(e) => totp_error(e),
+    }
+}
+
+/// Starts enrolling an authenticator app. The secret and recovery codes are
+/// shown this once; logins need codes after `POST /admin/totp/confirm`.
+pub fn handle_post_totp_request(_: &Request, services: &Services) -> (String, String) {
+    match totp::enroll(services.totp.as_ref(), totp::ADMIN_ACCOUNT) {
+        Ok(Some(enrollment)) => no_store(serde_json::json!({
+            "secret": enrollment.secret,
+            "uri": enrollment.uri,
+            "recovery_codes": enrollment.recovery_codes,
+        })),
+        Ok(None) => (
+            CONFLICT.to_string(),
+            "Two-Factor Already Enabled".to_string(),
+        ),
+        Err(e) => totp_error(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeBody {
+    code: String,
+}
+
+/// Turns two-factor login on with a first code from the enrolled app.
+pub fn handle_confirm_totp_request(
+    services: &Services,
+    Json(body): Json<TotpCodeBody>,
+) -> (String, String) {
+    match totp::confirm(services.totp.as_ref(), totp::ADMIN_ACCOUNT, &body.code) {
+        Ok(Some(true)) => {
+            warn!("Two-factor login enabled for the admin UI");
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::json!({ "enabled": true }).to_string(),
+            )
+        }
+        Ok(Some(false)) => validation_failed(&[Violation {
+            pointer: "/code".to_string(),
+            code: "invalid_code",
+            message: "Not a current code of the enrolled secret".to_string(),
+        }]),
+        Ok(None) => (NOT_FOUND.to_string(), "Two-Factor Not Enrolled".to_string()),
+        Err(e) => totp_error(e),
+    }
+}
+
+/// Replaces the recovery codes, e.g. once most are used up.
+pub fn handle_post_recovery_codes_request(_: &Request, services: &Services) -> (String, String) {
+    match totp::renew_recovery_codes(services.totp.as_ref(), totp::ADMIN_ACCOUNT) {
+        Ok(Some(codes)) => no_store(serde_json::json!({ "recovery_codes": codes })),
+        Ok(None) => (NOT_FOUND.to_string(), "Two-Factor Not Enabled".to_string()),
+        Err(e) => totp_error(e),
+    }
+}
+
+pub fn handle_delete_totp_request(_: &Request, services: &Services) -> (String, String) {
+    match totp::disable(services.totp.as_ref(), totp::ADMIN_ACCOUNT) {
+        Ok(enrolled) => {
+            if enrolled {
+                warn!("Two-factor login disabled for the admin UI");
+            }
+            (
+                OK_RESPONSE.to_string(),
+                serde_json::json!({ "enabled": false }).to_string(),
+            )
+        }
+        Err(e) => totp_error(e),
+    }
+}
+
+/// For bodies holding secrets, which must not linger in caches.
+fn no_store(body: serde_json::Value) -> (String, String) {
+    (
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-store\r\n\r\n"
+            .to_string(),
+        body.to_string(),
+    )
+}
+
+fn totp_error(e: RepositoryError) -> (String, String) {
+    error!("Two-Factor Error: {}", e);
+    internal_server_error()
+}
+
+/// Opened from the email sent when the admin account got locked.
+pub fn handle_unlock_request(request: &Request, services: &Services) -> (String, String) {
+    let unlocked = request
+        .query("token")
+        .is_some_and(|token| services.admin.unlock(token));
+    if !unlocked {
+        return (NOT_FOUND.to_string(), "Unlock Link Invalid".to_string());
+    }
+    warn!("Admin account unlocked from {}", client(request));
+    (
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nCache-Control: no-store\r\n\r\n"
+            .to_string(),
+        "Admin account unlocked".to_string(),
+    )
+}
+
+/// Only reached when logged in; tells the admin UI to skip the login form
+/// and hands it the CSRF token again after a page reload.
+pub fn handle_get_session_request(request: &Request, services: &Services) -> (String, String) {
+    let csrf_token = request
+        .cookie(auth::SESSION_COOKIE)
+        .and_then(|session| services.admin.csrf_token(session));
+    (
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-store\r\n\r\n"
+            .to_string(),
+        serde_json::json!({ "authenticated": true, "csrf_token": csrf_token }).to_string(),
+    )
+}
+
+pub fn handle_delete_session_request(request: &Request, services: &Services) -> (String, String) {
+    if let Some(session) = request.cookie(auth::SESSION_COOKIE) {
+        services.admin.logout(session);
+    }
+    session_response(services, "")
+}
+
+/// Sets the session cookie, or clears it for an empty `session`.
+fn session_response(services: &Services, session: &str) -> (String, String) {
+    let path = format!("{}/admin", services.base_path);
+    let csrf_token = services.admin.csrf_token(session);
+    with_head(
+        ResponseHead::extend(OK_RESPONSE)
+            .header("Cache-Control", "no-store")
+            .header("Set-Cookie", services.admin.cookie(session, &path)),
+        serde_json::json!({ "authenticated": csrf_token.is_some(), "csrf_token": csrf_token })
+            .to_string(),
+    )
+}
+
+fn client(request: &Request) -> String {
+    request
+        .client_addr
+        .map_or("a local socket".to_string(), |addr| addr.to_string())
+}
+
+/// Which build is serving, see `crate::build_info`.
+pub fn handle_version_request(_: &Request, _: &Services) -> (String, String) {
+    let version = serde_json::json!({
+        "version": build_info::VERSION,
+        "git_sha": build_info::GIT_SHA,
+        "built_at": build_info::BUILT_AT,
+        "features": build_info::features(),
+    });
+    (OK_RESPONSE.to_string(), version.to_string())
+}
+
+/// Uptime, requests, the last database failure and queued jobs, see
+/// `crate::status`.
+pub fn handle_status_request(_: &Request, services: &Services) -> (String, String) {
+    let status = &services.status;
+    let body = serde_json::json!({
+        "started_at": status.started_at().to_rfc3339_opts(SecondsFormat::Secs, true),
+        "uptime_seconds": status.uptime().as_secs(),
+        "requests": {
+            "served": status.served(),
+            "in_flight": status.in_flight(),
+        },
+        "last_database_error": services.queries.last_error(),
+        "jobs": { "queued": services.jobs.depth() },
+    });
+    (OK_RESPONSE.to_string(), body.to_string())
+}
+
+/// Per-query repository totals, for Prometheus to scrape.
+pub fn handle_metrics_request(_: &Request, services: &Services) -> (String, String) {
+    (
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\r\n".to_string(),
+        services.queries.render(),
+    )
+}
+
+/// Drains, then stops the server once the connections in flight are done.
+pub fn handle_shutdown_request(_: &Request, services: &Services) -> (String, String) {
+    warn!("Shutting down on admin request");
+    services.lifecycle.shutdown();
+    (
+        ACCEPTED.to_string(),
+        serde_json::json!({ "status": "shutting_down" }).to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct EmailBody {
+    template: String,
+    link: Option<String>,
+}
+
+/// Sends one of the email templates to a user, e.g. a password reset link
+/// another service issued. Answers `202` once the email is queued.
+pub fn handle_post_email_request(
+    services: &Services,
+    Path(id): Path<i32>,
+    Json(body): Json<EmailBody>,
+) -> (String, String) {
+    let Some(mailer) = &services.mailer else {
+        return (NOT_FOUND.to_string(), "Email Not Configured".to_string());
+    };
+    // The route's schema only lets known templates through.
+    let Some(template) = Template::from_name(&body.template) else {
+        return internal_server_error();
+    };
+    if template.needs_link() && body.link.is_none() {
+        return validation_failed(&[Violation {
+            pointer: "/link".to_string(),
+            code: "required",
+            message: format!("A {} email needs a link", template.name()),
+        }]);
+    }
+    let user = match services.repository.find(id) {
+        Ok(Some(user)) => user,
+        Ok(None) => return (NOT_FOUND.to_string(), "User Not Found".to_string()),
+        Err(e) => return repository_error("Email", e),
+    };
+    let message = Message::render(template, &user, body.link.as_deref());
+    mailer.send_later(&services.jobs, message);
+    (
+        ACCEPTED.to_string(),
+        serde_json::json!({ "status": "queued" }).to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct PasswordCheckBody {
+    password: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// Tells the service that owns registration or a password reset whether a
+/// new password is acceptable: `200` if so, `422` naming each broken rule.
+pub fn handle_password_check_request(
+    services: &Services,
+    Json(body): Json<PasswordCheckBody>,
+) -> (String, String) {
+    let user_data: Vec<&str> = [&body.name, &body.email]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+    let violations = services.passwords.check(&body.password, &user_data);
+    if !violations.is_empty() {
+        return validation_failed(&violations);
+    }
+    (
+        OK_RESPONSE.to_string(),
+        serde_json::json!({ "acceptable": true }).to_string(),
+    )
+}
+
+/// Queues an email when email is configured.
+fn send_email(services: &Services, template: Template, user: &User, link: Option<&str>) {
+    if let Some(mailer) = &services.mailer {
+        mailer.send_later(&services.jobs, Message::render(template, user, link));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessTokens;
+    use crate::extract::{Parts, Respond};
+    use crate::lifecycle::Phase;
+    use crate::maintenance;
+    use crate::repository::{
+        AddressRepository, FlagRepository, MemoryUserRepository, Repository, TotpRepository,
+    };
+    use crate::router::Outcome;
+    use std::collections::BTreeMap;
+
+    /// A request whose body, if any, is declared as JSON.
+    fn request(method: &str, target: &str, body: &str) -> Request {
+        let content_type = if body.is_empty() {
+            ""
+        } else {
+            "Content-Type: application/json\r\n"
+        };
+        let raw = format!(
+            "{} {} HTTP/1.1\r\n{}\r\n{}",
+            method, target, content_type, body
+        );
+        Request::parse(raw.as_bytes()).unwrap()
+    }
+
+    /// Runs `handler` the way the router does for a request that matched
+    /// `route`.
+    fn call<Args>(
+        handler: impl Respond<Args>,
+        route: &'static str,
+        request: &Request,
+        services: &Services,
+    ) -> (String, String) {
+        handler.respond(&Parts {
+            request,
+            services,
+            grant: None,
+            route,
+        })
+    }
+
+    fn status(response: &(String, String)) -> u16 {
+        status_code(&response.0)
+    }
+
+    fn services(repository: impl Repository + 'static) -> Services {
+        Services::for_tests(repository)
+    }
+
+    fn services_with(users: &[(&str, &str)]) -> Services {
+        let repository = MemoryUserRepository::new();
+        for (name, email) in users {
+            repository
+                .create(&User {
+                    id: None,
+                    name: name.to_string(),
+                    email: email.to_string(),
+                    created_at: None,
+                    updated_at: None,
+                })
+                .unwrap();
+        }
+        services(repository)
+    }
+
+    /// Drops the `created_at` and `updated_at` fields, which differ on every
+    /// run.
+    fn without_timestamps(json: &str) -> serde_json::Value {
+        fn strip(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(fields) => {
+                    fields.remove("created_at");
+                    fields.remove("updated_at");
+                }
+                serde_json::Value::Array(items) => items.iter_mut().for_each(strip),
+                _ => {}
+            }
+        }
+        let mut value = serde_json::from_str(json).unwrap();
+        strip(&mut value);
+        value
+    }
+
+    /// Fails every call the way a cancelled statement would.
+    struct TimingOutRepository;
+
+    impl UserRepository for TimingOutRepository {
+        fn migrate(&self) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn purge(&self, _: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn create(&self, _: &User) -> Result<i32, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn create_many(&self, _: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn find(&self, _: i32) -> Result<Option<User>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn find_many(&self, _: &[i32]) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn find_by_email(&self, _: &str) -> Result<Option<User>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn list(&self, _: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn count(&self, _: &UserFilter) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn stream_all(
+            &self,
+            _: &UserFilter,
+            _: &mut dyn FnMut(User) -> bool,
+        ) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn update(&self, _: i32, _: &User) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn change_user(
+            &self,
+            _: i32,
+            _: &mut dyn FnMut(&mut User) -> bool,
+        ) -> Result<Option<User>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn upsert(&self, _: &User) -> Result<Upserted, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn delete(&self, _: i32) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn metadata(&self, _: i32) -> Result<Option<serde_json::Value>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn change_metadata(
+            &self,
+            _: i32,
+            _: &mut dyn FnMut(&mut serde_json::Value) -> bool,
+        ) -> Result<Option<serde_json::Value>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+    }
+
+    impl AddressRepository for TimingOutRepository {
+        fn list_addresses(&self, _: i32) -> Result<Vec<Address>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn count_addresses(&self, _: i32) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn create_address(&self, _: i32, _: &Address) -> Result<Address, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn find_address(&self, _: i32, _: i32) -> Result<Option<Address>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn update_address(&self, _: i32, _: i32, _: &Address) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn delete_addresses(&self, _: i32, _: Option<i32>) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+    }
+
+    impl FlagRepository for TimingOutRepository {
+        fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn set_flag(&self, _: &str, _: bool) -> Result<(), RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+        fn delete_flag(&self, _: &str) -> Result<u64, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+    }
+
+    impl TotpRepository for TimingOutRepository {
+        fn change_totp(
+            &self,
+            _: &str,
+            _: &mut dyn FnMut(&mut Option<totp::Totp>) -> bool,
+        ) -> Result<Option<totp::Totp>, RepositoryError> {
+            Err(RepositoryError::Timeout)
+        }
+    }
+
+    #[test]
+    fn post_creates_a_user() {
+        let services = services_with(&[]);
+        let response = call(
+            handle_post_request,
+            "/users",
+            &request(
+                "POST",
+                "/users",
+                r#"{"name":"Ada","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        assert_eq!(response.1, "User Created");
+        assert_eq!(services.repository.find(1).unwrap().unwrap().name, "Ada");
+    }
+
+    #[test]
+    fn post_with_a_taken_email_conflicts() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_post_request,
+            "/users",
+            &request(
+                "POST",
+                "/users",
+                r#"{"name":"Imposter","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+
+        assert_eq!(status(&response), 409);
+        assert_eq!(
+            services
+                .repository
+                .list(&UserFilter::default())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn post_can_return_the_existing_user() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_post_request,
+            "/users",
+            &request(
+                "POST",
+                "/users?if_exists=return",
+                r#"{"name":"Ada again","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            without_timestamps(&response.1),
+            serde_json::json!({ "id": 1, "name": "Ada", "email": "ada@example.com" })
+        );
+
+        let response = call(
+            handle_post_request,
+            "/users",
+            &request(
+                "POST",
+                "/users?if_exists=maybe",
+                r#"{"name":"Ada","email":"a@b.io"}"#,
+            ),
+            &services,
+        );
+        assert_eq!(status(&response), 400);
+    }
+
+    #[test]
+    fn bulk_creates_users_from_the_body_as_it_arrives() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let upload = |content_type: &str, body: &str| {
+            let raw = format!(
+                "POST /users/bulk HTTP/1.1\r\nContent-Type: {}\r\n\r\n",
+                content_type
+            );
+            let request = Request::parse(raw.as_bytes()).unwrap();
+            handle_import_request(&request, &services, &mut body.as_bytes())
+        };
+
+        let response = upload(
+            "text/csv; charset=utf-8",
+            "name,email\nAda,ada@example.com\nGrace,grace@example.com\n",
+        );
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response.1).unwrap(),
+            serde_json::json!({ "created": 1, "existing": 1, "invalid": 0, "errors": [] })
+        );
+        let response = upload(
+            "application/x-ndjson",
+            "{\"name\":\"Alan\",\"email\":\"alan@example.com\"}\n",
+        );
+        assert_eq!(status(&response), 200);
+        assert!(services
+            .repository
+            .find_by_email("alan@example.com")
+            .unwrap()
+            .is_some());
+
+        assert_eq!(status(&upload("text/csv", "email\nada@example.com\n")), 400);
+        assert_eq!(status(&upload("application/json", "[]")), 415);
+    }
+
+    #[test]
+    fn get_returns_the_user() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/1", ""),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            without_timestamps(&response.1),
+            serde_json::json!({ "id": 1, "name": "Ada", "email": "ada@example.com" })
+        );
+    }
+
+    #[test]
+    fn get_can_include_addresses() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let home =
+            r#"{"line1":"12 Analytical Row","city":"London","postal_code":"W1","country":"GB"}"#;
+        call(
+            handle_post_address_request,
+            "/users/:id/addresses",
+            &request("POST", "/users/1/addresses", home),
+            &services,
+        );
+
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/1?include=addresses", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 200);
+        let user: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+        assert_eq!(user["name"], "Ada");
+        assert_eq!(user["addresses"][0]["city"], "London");
+
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/1?include=posts", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 400);
+    }
+
+    #[test]
+    fn get_honours_if_modified_since() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/users/1", ""),
+            &services,
+        );
+        let last_modified = response
+            .0
+            .lines()
+            .find_map(|line| line.strip_prefix("Last-Modified: "))
+            .unwrap()
+            .to_string();
+
+        let conditional = |since: &str| {
+            let raw = format!(
+                "GET /users/1 HTTP/1.1\r\nIf-Modified-Since: {}\r\n\r\n",
+                since
+            );
+            call(
+                handle_get_request,
+                "/users/:id",
+                &Request::parse(raw.as_bytes()).unwrap(),
+                &services,
+            )
+        };
+        let response = conditional(&last_modified);
+        assert_eq!(status(&response), 304);
+        assert!(response.1.is_empty());
+        assert_eq!(status(&conditional("Sat, 01 Jan 2000 00:00:00 GMT")), 200);
+        assert_eq!(status(&conditional("not a date")), 200);
+
+        let included = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/users/1?include=addresses", ""),
+            &services,
+        );
+        assert!(!included.0.contains("Last-Modified"));
+    }
+
+    #[test]
+    fn get_missing_user_is_not_found() {
+        let services = services_with(&[]);
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/7", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 404);
+    }
+
+    #[test]
+    fn get_with_a_bad_id_fails() {
+        let services = services_with(&[]);
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/seven", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 500);
+    }
+
+    #[test]
+    fn head_checks_existence_and_counts() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+
+        let exists = call(
+            handle_exists_request,
+            "/users/:id",
+            &request("HEAD", "/users/2", ""),
+            &services,
+        );
+        assert_eq!(status(&exists), 200);
+        let missing = call(
+            handle_exists_request,
+            "/users/:id",
+            &request("HEAD", "/users/3", ""),
+            &services,
+        );
+        assert_eq!(status(&missing), 404);
+        let count = handle_count_request(&request("HEAD", "/users", ""), &services);
+        assert!(count.0.contains("X-Total-Count: 2\r\n"));
+    }
+
+    #[test]
+    fn put_updates_the_user() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_put_request,
+            "/users/:id",
+            &request(
+                "PUT",
+                "/users/1",
+                r#"{"name":"Ada L.","email":"ada@example.org"}"#,
+            ),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        let user = services.repository.find(1).unwrap().unwrap();
+        assert_eq!(user.name, "Ada L.");
+        assert_eq!(user.email, "ada@example.org");
+    }
+
+    #[test]
+    fn put_may_keep_its_own_email() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_put_request,
+            "/users/:id",
+            &request(
+                "PUT",
+                "/users/1",
+                r#"{"name":"Ada L.","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+        assert_eq!(status(&response), 200);
+    }
+
+    #[test]
+    fn put_with_another_users_email_conflicts() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+        let response = call(
+            handle_put_request,
+            "/users/:id",
+            &request(
+                "PUT",
+                "/users/2",
+                r#"{"name":"Bob","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+
+        assert_eq!(status(&response), 409);
+        assert_eq!(
+            services.repository.find(2).unwrap().unwrap().email,
+            "bob@example.com"
+        );
+    }
+
+    #[test]
+    fn put_missing_user_is_not_found() {
+        let services = services_with(&[]);
+        let response = call(
+            handle_put_request,
+            "/users/:id",
+            &request(
+                "PUT",
+                "/users/3",
+                r#"{"name":"Ada","email":"ada@example.com"}"#,
+            ),
+            &services,
+        );
+        assert_eq!(status(&response), 404);
+    }
+
+    #[test]
+    fn patch_applies_json_patch_documents() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+        let patch = |id: i32, body: &str| {
+            call(
+                handle_patch_request,
+                "/users/:id",
+                &request("PATCH", &format!("/users/{}", id), body),
+                &services,
+            )
+        };
+
+        let response = patch(
+            1,
+            r#"[{"op":"test","path":"/name","value":"Ada"},{"op":"replace","path":"/name","value":"Ada L."}]"#,
+        );
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            without_timestamps(&response.1),
+            serde_json::json!({ "id": 1, "name": "Ada L.", "email": "ada@example.com" })
+        );
+
+        for (id, body, expected) in [
+            (1, r#"[{"op":"test","path":"/name","value":"Ada"}]"#, 409),
+            (1, r#"[{"op":"remove","path":"/nickname"}]"#, 422),
+            (1, r#"[{"op":"replace","path":"/id","value":7}]"#, 422),
+            (1, r#"[{"op":"remove","path":"/email"}]"#, 422),
+            (1, r#"[{"op":"add","path":"/role","value":"admin"}]"#, 422),
+            (
+                1,
+                r#"[{"op":"replace","path":"/email","value":"bob@example.com"}]"#,
+                409,
+            ),
+            (3, r#"[]"#, 404),
+        ] {
+            assert_eq!(status(&patch(id, body)), expected, "{}", body);
+        }
+        let user = services.repository.find(1).unwrap().unwrap();
+        assert_eq!(
+            (user.name.as_str(), user.email.as_str()),
+            ("Ada L.", "ada@example.com")
+        );
+    }
+
+    #[test]
+    fn patch_applies_merge_patches() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let patch = |body: &str| {
+            let raw = format!(
+                "PATCH /users/1 HTTP/1.1\r\nContent-Type: application/merge-patch+json\r\n\r\n{}",
+                body
+            );
+            call(
+                handle_patch_request,
+                "/users/:id",
+                &Request::parse(raw.as_bytes()).unwrap(),
+                &services,
+            )
+        };
+
+        let response = patch(r#"{"email":"ada@example.org"}"#);
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            without_timestamps(&response.1),
+            serde_json::json!({ "id": 1, "name": "Ada", "email": "ada@example.org" })
+        );
+
+        // Leaving the name out keeps it; sending null clears it, which a
+        // user cannot do without.
+        let response = patch(r#"{"name":null}"#);
+        assert_eq!(status(&response), 422);
+        assert!(
+            response.1.contains(r#""pointer":"/name""#),
+            "{}",
+            response.1
+        );
+        for body in [r#"{"created_at":null}"#, r#"{"nickname":"Ada"}"#, "[]"] {
+            assert_eq!(status(&patch(body)), 422, "{}", body);
+        }
+        assert_eq!(services.repository.find(1).unwrap().unwrap().name, "Ada");
+    }
+
+    #[test]
+    fn upsert_creates_then_updates() {
+        let services = services_with(&[]);
+        let upsert = request(
+            "PUT",
+            "/users/by-email/ada@example.com",
+            r#"{"name":"Ada"}"#,
+        );
+
+        let response = call(
+            handle_upsert_request,
+            "/users/by-email/:email",
+            &upsert,
+            &services,
+        );
+        assert_eq!(status(&response), 200);
+        assert_eq!(response.1, r#"{"created":true,"id":1}"#);
+
+        let rename = request(
+            "PUT",
+            "/users/by-email/ada@example.com",
+            r#"{"name":"Ada L."}"#,
+        );
+        let response = call(
+            handle_upsert_request,
+            "/users/by-email/:email",
+            &rename,
+            &services,
+        );
+        assert_eq!(response.1, r#"{"created":false,"id":1}"#);
+        assert_eq!(services.repository.find(1).unwrap().unwrap().name, "Ada L.");
+    }
+
+    #[test]
+    fn upsert_checks_the_email_in_the_path() {
+        let services = services_with(&[]);
+        let response = call(
+            handle_upsert_request,
+            "/users/by-email/:email",
+            &request("PUT", "/users/by-email/nope", r#"{"name":"Ada"}"#),
+            &services,
+        );
+        assert_eq!(status(&response), 422);
+        assert!(services
+            .repository
+            .list(&UserFilter::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn delete_removes_the_user_once() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let delete = request("DELETE", "/users/1", "");
+
+        assert_eq!(
+            status(&call(
+                handle_delete_request,
+                "/users/:id",
+                &delete,
+                &services
+            )),
+            200
+        );
+        assert!(services.repository.find(1).unwrap().is_none());
+        assert_eq!(
+            status(&call(
+                handle_delete_request,
+                "/users/:id",
+                &delete,
+                &services
+            )),
+            404
+        );
+    }
+
+    #[test]
+    fn erasure_anonymizes_but_keeps_the_row() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let home =
+            r#"{"line1":"12 Analytical Row","city":"London","postal_code":"W1","country":"GB"}"#;
+        call(
+            handle_post_address_request,
+            "/users/:id/addresses",
+            &request("POST", "/users/1/addresses", home),
+            &services,
+        );
+        let response = call(
+            handle_erase_request,
+            "/users/:id/personal-data",
+            &request("DELETE", "/users/1/personal-data", ""),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        let user = services.repository.find(1).unwrap().unwrap();
+        assert_eq!(user.name, "Erased User");
+        assert!(user.email.ends_with("@invalid"), "{}", user.email);
+        assert!(services.addresses.list_addresses(1).unwrap().is_empty());
+        assert!(!user.email.contains("ada"));
+
+        let response = call(
+            handle_erase_request,
+            "/users/:id/personal-data",
+            &request("DELETE", "/users/2/personal-data", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 404);
+    }
+
+    #[test]
+    fn timeouts_become_gateway_timeouts() {
+        let response = call(
+            handle_get_request,
+            "/users/:id",
+            &request("GET", "/user/1", ""),
+            &services(TimingOutRepository),
+        );
+        assert_eq!(status(&response), 504);
+    }
+
+    #[test]
+    fn throttled_logins_say_how_long_to_wait() {
+        let (head, body) = login_refused(auth::Denied::Throttled(Duration::from_millis(1500)));
+        assert!(head.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+        assert!(head.contains("\r\nRetry-After: 2\r\n"));
+        assert!(head.contains("\r\nContent-Type: application/json\r\n"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "error": "Too Many Failed Logins", "code": "login_throttled" })
+        );
+        let (head, _) = login_refused(auth::Denied::Locked(Duration::from_secs(60)));
+        assert!(head.starts_with("HTTP/1.1 423 LOCKED\r\n"));
+        assert!(head.contains("\r\nRetry-After: 60\r\n"));
+    }
+
+    #[test]
+    fn maintenance_answers_round_the_wait_up() {
+        let body = serde_json::json!({
+            "error": "Service Unavailable",
+            "code": "maintenance",
+            "message": "Back soon",
+        });
+        let (head, content) = retry_later_with(
+            "503 SERVICE UNAVAILABLE",
+            Duration::from_millis(90_500),
+            body.clone(),
+        );
+        assert!(head.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+        assert!(head.contains("\r\nRetry-After: 91\r\n"));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&content).unwrap(),
+            body
+        );
+    }
+
+    #[test]
+    fn invalid_bodies_are_rejected_before_the_handler() {
+        let services = services_with(&[]);
+        let router = crate::routes();
+
+        let outcome = router.dispatch(
+            &request("POST", "/users", r#"{"name":"","email":"nope"}"#),
+            &services,
+            None,
+        );
+        match outcome {
+            Some(Outcome::Response(status_line, content)) => {
+                assert_eq!(status_code(&status_line), 422);
+                assert!(content.contains("/email"), "{}", content);
+            }
+            _ => panic!("expected a validation response"),
+        }
+
+        let outcome = router.dispatch(&request("POST", "/users", "{"), &services, None);
+        match outcome {
+            Some(Outcome::Response(status_line, _)) => assert_eq!(status_code(&status_line), 400),
+            _ => panic!("expected a bad request"),
+        }
+        assert!(services
+            .repository
+            .list(&UserFilter::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn routes_need_their_scope_once_tokens_are_configured() {
+        let mut services = services_with(&[("Ada", "ada@example.com")]);
+        let router = crate::routes();
+        let get = request("GET", "/users/1", "");
+        let dispatch = |services: &Services, request: &Request, key: Option<&str>| {
+            let bearer = key.map(|key| {
+                Request::parse(
+                    format!("GET / HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", key).as_bytes(),
+                )
+                .unwrap()
+            });
+            let grant = bearer.and_then(|bearer| services.tokens.authenticate(&bearer).unwrap());
+            match router.dispatch(request, services, grant.as_ref()) {
+                Some(Outcome::Response(status_line, _)) => status_code(&status_line),
+                _ => panic!("expected a response"),
+            }
+        };
+        assert_eq!(dispatch(&services, &get, None), 200);
+
+        services.tokens = Arc::new(AccessTokens::new(
+            vec![
+                "reporting:users:read:r3ad".parse().unwrap(),
+                "ops:admin:0ps".parse().unwrap(),
+            ],
+            None,
+        ));
+        let post = request(
+            "POST",
+            "/users",
+            r#"{"name":"Grace","email":"grace@example.com"}"#,
+        );
+        assert_eq!(dispatch(&services, &get, None), 401);
+        assert_eq!(dispatch(&services, &get, Some("r3ad")), 200);
+        assert_eq!(dispatch(&services, &post, Some("r3ad")), 403);
+        assert_eq!(dispatch(&services, &post, Some("0ps")), 200);
+        let maintenance = request("GET", "/admin/maintenance", "");
+        assert_eq!(dispatch(&services, &maintenance, Some("r3ad")), 403);
+        assert_eq!(dispatch(&services, &maintenance, Some("0ps")), 200);
+    }
+
+    #[test]
+    fn bodies_of_other_media_types_are_unsupported() {
+        let services = services_with(&[]);
+        let raw = "POST /users HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n{\"name\":\"Ada\",\"email\":\"ada@example.com\"}";
+        let outcome =
+            crate::routes().dispatch(&Request::parse(raw.as_bytes()).unwrap(), &services, None);
+        match outcome {
+            Some(Outcome::Response(status_line, _)) => {
+                assert_eq!(status_code(&status_line), 415);
+                assert!(status_line.contains(
+                    "Accept: application/json, application/vnd.api+json, application/msgpack, application/x-www-form-urlencoded, multipart/form-data\r\n"
+                ));
+            }
+            _ => panic!("expected an unsupported media type response"),
+        }
+        assert!(services.repository.find(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn list_is_sent_as_a_chunked_array() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+        let mut out = Vec::new();
+        let status =
+            handle_get_all_request(&request("GET", "/users", ""), &services, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(status, 200);
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(head.contains("\r\nX-Total-Count: 2"));
+        let (size, rest) = body.split_once("\r\n").unwrap();
+        let (chunk, rest) = rest.split_at(usize::from_str_radix(size, 16).unwrap());
+        assert_eq!(rest, "\r\n0\r\n\r\n");
+        assert_eq!(
+            without_timestamps(chunk),
+            serde_json::json!([
+                { "id": 1, "name": "Ada", "email": "ada@example.com" },
+                { "id": 2, "name": "Bob", "email": "bob@example.com" }
+            ])
+        );
+    }
+
+    #[test]
+    fn list_can_be_wrapped_in_an_envelope() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let mut out = Vec::new();
+        handle_get_all_request(
+            &request("GET", "/users?envelope=true", ""),
+            &services,
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (_, body) = out.split_once("\r\n\r\n").unwrap();
+        let (size, rest) = body.split_once("\r\n").unwrap();
+        let (chunk, _) = rest.split_at(usize::from_str_radix(size, 16).unwrap());
+        let mut body: serde_json::Value = serde_json::from_str(chunk).unwrap();
+        assert_eq!(body["data"][0]["email"], "ada@example.com");
+        body["data"] = serde_json::Value::Null;
+        assert_eq!(
+            body,
+            serde_json::json!({ "data": null, "meta": { "status": 200, "total": 1 }, "errors": [] })
+        );
+    }
+
+    #[test]
+    fn list_filters_by_creation_date() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let list = |target: &str| {
+            let mut out = Vec::new();
+            let status =
+                handle_stream_request(&request("GET", target, ""), &services, &mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+
+        let (status, out) = list("/users/stream?created_after=2000-01-01");
+        assert_eq!(status, 200);
+        assert!(out.contains("ada@example.com"));
+
+        let (_, out) =
+            list("/users/stream?created_after=2000-01-01&created_before=2000-01-02T00:00:00Z");
+        assert!(!out.contains("ada@example.com"));
+        assert!(out.contains("X-Total-Count: 0\r\n"));
+
+        let (status, _) = list("/users/stream?created_before=yesterday");
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn list_takes_odata_query_options() {
+        let services = services_with(&[
+            ("Ada", "ada@example.com"),
+            ("Bob", "bob@example.com"),
+            ("Alan", "alan@example.org"),
+        ]);
+        let list = |target: &str| {
+            let mut out = Vec::new();
+            let status =
+                handle_get_all_request(&request("GET", target, ""), &services, &mut out).unwrap();
+            (status, String::from_utf8(out).unwrap())
+        };
+
+        let (status, out) = list(
+            "/users?$filter=endswith(email,'.com')%20and%20id%20ge%201&$orderby=name%20desc\
+             &$top=1&$skip=1&$select=name",
+        );
+        assert_eq!(status, 200);
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nX-Total-Count: 2"));
+        let (size, rest) = body.split_once("\r\n").unwrap();
+        let (chunk, _) = rest.split_at(usize::from_str_radix(size, 16).unwrap());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(chunk).unwrap(),
+            serde_json::json!([{ "name": "Ada" }])
+        );
+
+        let (status, out) = list("/users?$filter=name%20eq%20'Ada'%20or%20id%20eq%202");
+        assert_eq!(status, 400);
+        assert!(out.ends_with("Invalid $filter: or is not supported"));
+        assert_eq!(list("/users?$select=password").0, 400);
+    }
+
+    #[test]
+    fn timestamps_may_be_dates_or_rfc3339() {
+        let midnight = parse_timestamp("2024-03-01").unwrap();
+        assert_eq!(midnight, parse_timestamp("2024-03-01T00:00:00Z").unwrap());
+        assert_eq!(
+            midnight,
+            parse_timestamp("2024-03-01T02:00:00+02:00").unwrap()
+        );
+        assert!(parse_timestamp("2024-13-01").is_none());
+    }
+
+    #[test]
+    fn lookup_keeps_the_requested_order() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+        let mut out = Vec::new();
+        let status = handle_get_all_request(
+            &request("GET", "/users?ids=2,9,1,2", ""),
+            &services,
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(status, 200);
+        let out = String::from_utf8(out).unwrap();
+        let body: serde_json::Value =
+            serde_json::from_str(out.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["users"][0]["name"], "Bob");
+        assert_eq!(body["users"][1]["name"], "Ada");
+        assert_eq!(body["missing"], serde_json::json!([9]));
+
+        let lookup = request("POST", "/users/lookup", "[1]");
+        let response = call(handle_lookup_request, "/users/lookup", &lookup, &services);
+        assert!(response.1.contains("ada@example.com"));
+    }
+
+    #[test]
+    fn lookup_rejects_bad_ids() {
+        let services = services_with(&[]);
+        let mut out = Vec::new();
+        let status =
+            handle_get_all_request(&request("GET", "/users?ids=1,two", ""), &services, &mut out)
+                .unwrap();
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn list_failure_before_output_is_a_proper_error() {
+        let mut out = Vec::new();
+        let status = handle_stream_request(
+            &request("GET", "/users/stream", ""),
+            &services(TimingOutRepository),
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(status, 504);
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("HTTP/1.1 504 GATEWAY TIMEOUT\r\n"));
+    }
+
+    #[test]
+    fn small_exports_are_downloaded_right_away() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let response = call(
+            handle_export_request,
+            "/users/:id/export",
+            &request("GET", "/users/1/export", ""),
+            &services,
+        );
+
+        assert_eq!(status(&response), 200);
+        assert!(response.0.contains("Content-Disposition: attachment"));
+        let archive: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+        assert_eq!(archive["user"]["email"], "ada@example.com");
+        assert!(archive["exported_at"].is_string());
+
+        let response = call(
+            handle_export_request,
+            "/users/:id/export",
+            &request("GET", "/users/2/export", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 404);
+    }
+
+    #[test]
+    fn large_exports_are_built_in_the_background() {
+        let mut services = services_with(&[("Ada", "ada@example.com")]);
+        services.export_async_threshold = 0;
+        services.base_path = "/api".to_string();
+
+        let response = call(
+            handle_export_request,
+            "/users/:id/export",
+            &request("GET", "/users/1/export", ""),
+            &services,
+        );
+        assert_eq!(status(&response), 202);
+        let link = response
+            .0
+            .lines()
+            .find_map(|line| line.strip_prefix("Location: "))
+            .unwrap()
+            .to_string();
+
+        assert!(link.starts_with("/api/exports/"), "{}", link);
+        let download = request("GET", link.strip_prefix("/api").unwrap(), "");
+        let mut response = call(
+            handle_export_download_request,
+            "/exports/:token",
+            &download,
+            &services,
+        );
+        for _ in 0..100 {
+            if status(&response) != 202 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            response = call(
+                handle_export_download_request,
+                "/exports/:token",
+                &download,
+                &services,
+            );
+        }
+        assert_eq!(status(&response), 200);
+        assert!(response.1.contains("ada@example.com"));
+
+        // The archive is only handed out once.
+        assert_eq!(
+            status(&call(
+                handle_export_download_request,
+                "/exports/:token",
+                &download,
+                &services
+            )),
+            404
+        );
+    }
+
+    #[test]
+    fn admin_drains_and_shuts_down() {
+        let services = services_with(&[]);
+        let drain = handle_drain_request(&request("POST", "/admin/drain", ""), &services);
+        assert_eq!(status(&drain), 202);
+        assert_eq!(services.lifecycle.phase(), Phase::Draining);
+
+        let shutdown = handle_shutdown_request(&request("POST", "/admin/shutdown", ""), &services);
+        assert_eq!(status(&shutdown), 202);
+        assert_eq!(services.lifecycle.phase(), Phase::ShuttingDown);
+    }
+
+    #[test]
+    fn maintenance_is_started_and_ended() {
+        let services = services_with(&[]);
+        let put = request("PUT", "/admin/maintenance", r#"{"retry_after_secs":60}"#);
+        let response = call(
+            handle_put_maintenance_request,
+            "/admin/maintenance",
+            &put,
+            &services,
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response.1).unwrap(),
+            serde_json::json!({
+                "maintenance": true,
+                "message": maintenance::DEFAULT_MESSAGE,
+                "retry_after_secs": 60,
+            })
+        );
+        assert!(services.maintenance.window().is_some());
+
+        let delete = request("DELETE", "/admin/maintenance", "");
+        let response = handle_delete_maintenance_request(&delete, &services);
+        assert_eq!(response.1, r#"{"maintenance":false}"#);
+        assert!(services.maintenance.window().is_none());
+    }
+
+    #[test]
+    fn flags_are_set_listed_and_removed() {
+        let services = services_with(&[]);
+        let put = request("PUT", "/admin/flags/strict-names", r#"{"enabled":true}"#);
+        let put_flag = |request: &Request| {
+            call(
+                handle_put_flag_request,
+                "/admin/flags/:name",
+                request,
+                &services,
+            )
+        };
+        assert_eq!(status(&put_flag(&put)), 200);
+        assert!(services.flags.enabled("strict-names"));
+
+        let listed = handle_get_flags_request(&request("GET", "/admin/flags", ""), &services);
+        assert_eq!(listed.1, r#"{"strict-names":true}"#);
+
+        let invalid = request("PUT", "/admin/flags/Strict", r#"{"enabled":true}"#);
+        assert_eq!(status(&put_flag(&invalid)), 400);
+        let malformed = request("PUT", "/admin/flags/strict-names", r#"{"enabled":"#);
+        assert_eq!(status(&put_flag(&malformed)), 400);
+
+        let blank = request(
+            "POST",
+            "/users",
+            r#"{"name":" ","email":"ada@example.com"}"#,
+        );
+        let response = call(handle_post_request, "/users", &blank, &services);
+        assert_eq!(status(&response), 422);
+        assert!(response.1.contains(r#""code":"blank""#));
+
+        let delete = request("DELETE", "/admin/flags/strict-names", "");
+        let delete_flag = || {
+            call(
+                handle_delete_flag_request,
+                "/admin/flags/:name",
+                &delete,
+                &services,
+            )
+        };
+        assert_eq!(status(&delete_flag()), 200);
+        assert!(!services.flags.enabled("strict-names"));
+        assert_eq!(status(&delete_flag()), 404);
+        assert_eq!(
+            status(&call(handle_post_request, "/users", &blank, &services)),
+            200
+        );
+    }
+
+    #[test]
+    fn log_levels_are_overridden_and_cleared() {
+        let services = services_with(&[]);
+        let put = request(
+            "PUT",
+            "/admin/log-level",
+            r#"{"level":"debug","target":"rust_api::repository","duration_secs":600}"#,
+        );
+        let response = call(
+            handle_put_log_level_request,
+            "/admin/log-level",
+            &put,
+            &services,
+        );
+        assert_eq!(status(&response), 200);
+        let levels: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+        let set = &levels["overrides"][0];
+        assert_eq!(set["target"], "rust_api::repository");
+        assert_eq!(set["level"], "debug");
+        assert!(set["until"].is_string());
+
+        let cleared =
+            handle_delete_log_level_request(&request("DELETE", "/admin/log-level", ""), &services);
+        let levels: serde_json::Value = serde_json::from_str(&cleared.1).unwrap();
+        assert_eq!(levels["overrides"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn addresses_belong_to_their_user() {
+        let services = services_with(&[("Ada", "ada@example.com"), ("Bob", "bob@example.com")]);
+        let body =
+            r#"{"line1":"12 Analytical Row","city":"London","postal_code":"W1","country":"GB"}"#;
+
+        let created = call(
+            handle_post_address_request,
+            "/users/:id/addresses",
+            &request("POST", "/users/1/addresses", body),
+            &services,
+        );
+        assert_eq!(status(&created), 200);
+        let address: serde_json::Value = serde_json::from_str(&created.1).unwrap();
+        assert_eq!(address["id"], 1);
+        assert_eq!(address["user_id"], 1);
+
+        let list = call(
+            handle_list_addresses_request,
+            "/users/:id/addresses",
+            &request("GET", "/users/1/addresses", ""),
+            &services,
+        );
+        assert!(list.1.contains("Analytical Row"));
+        let other = request("GET", "/users/2/addresses/1", "");
+        assert_eq!(
+            status(&call(
+                handle_get_address_request,
+                "/users/:id/addresses/:address_id",
+                &other,
+                &services
+            )),
+            404
+        );
+        let missing_user = request("POST", "/users/9/addresses", body);
+        assert_eq!(
+            status(&call(
+                handle_post_address_request,
+                "/users/:id/addresses",
+                &missing_user,
+                &services
+            )),
+            404
+        );
+
+        let moved = body.replace("London", "Cambridge");
+        let update = request("PUT", "/users/1/addresses/1", &moved);
+        assert_eq!(
+            status(&call(
+                handle_put_address_request,
+                "/users/:id/addresses/:address_id",
+                &update,
+                &services
+            )),
+            200
+        );
+        assert_eq!(
+            services.addresses.find_address(1, 1).unwrap().unwrap().city,
+            "Cambridge"
+        );
+
+        call(
+            handle_delete_request,
+            "/users/:id",
+            &request("DELETE", "/users/1", ""),
+            &services,
+        );
+        assert!(services.addresses.list_addresses(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn addresses_need_a_known_country_code() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let outcome = crate::routes().dispatch(
+            &request(
+                "POST",
+                "/users/1/addresses",
+                r#"{"line1":"1 Main St","city":"Springfield","postal_code":"1","country":"XX"}"#,
+            ),
+            &services,
+            None,
+        );
+        match outcome {
+            Some(Outcome::Response(status_line, content)) => {
+                assert_eq!(status_code(&status_line), 422);
+                assert!(content.contains("/country"), "{}", content);
+            }
+            _ => panic!("expected a validation response"),
+        }
+    }
+
+    #[test]
+    fn metadata_is_replaced_or_merged() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let put = request(
+            "PUT",
+            "/users/1/metadata",
+            r#"{"plan":"pro","flags":{"beta":true}}"#,
+        );
+        assert_eq!(
+            status(&call(
+                handle_put_metadata_request,
+                "/users/:id/metadata",
+                &put,
+                &services
+            )),
+            200
+        );
+
+        let patch = request(
+            "PATCH",
+            "/users/1/metadata",
+            r#"{"plan":null,"flags":{"dark":true}}"#,
+        );
+        let response = call(
+            handle_patch_metadata_request,
+            "/users/:id/metadata",
+            &patch,
+            &services,
+        );
+        assert_eq!(status(&response), 200);
+        assert_eq!(
+            services.repository.metadata(1).unwrap().unwrap(),
+            serde_json::json!({ "flags": { "beta": true, "dark": true } })
+        );
+
+        let missing = request("GET", "/users/2/metadata", "");
+        assert_eq!(
+            status(&call(
+                handle_get_metadata_request,
+                "/users/:id/metadata",
+                &missing,
+                &services
+            )),
+            404
+        );
+    }
+
+    #[test]
+    fn metadata_over_the_limits_is_not_stored() {
+        let services = services_with(&[("Ada", "ada@example.com")]);
+        let deep = request(
+            "PATCH",
+            "/users/1/metadata",
+            r#"{"a":{"b":{"c":{"d":{"e":{"f":{"g":1}}}}}}}"#,
+        );
+
+        let response = call(
+            handle_patch_metadata_request,
+            "/users/:id/metadata",
+            &deep,
+            &services,
+        );
+        assert_eq!(status(&response), 422);
+        let body: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+        assert_eq!(body["violations"][0]["pointer"], "/a/b/c/d/e/f");
+        assert_eq!(body["details"][0]["field"], "a.b.c.d.e.f");
+        assert_eq!(body["details"][0]["code"], "too_deep");
+        assert_eq!(
+            services.repository.metadata(1).unwrap().unwrap(),
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn password_checks_name_each_broken_rule() {
+        let services = services_with(&[]);
+        let check = |body: &str| {
+            call(
+                handle_password_check_request,
+                "/admin/passwords/check",
+                &request("POST", "/admin/passwords/check", body),
+                &services,
+            )
+        };
+
+        let response = check(r#"{"password":"correct horse battery"}"#);
+        assert_eq!(status(&response), 200);
+        assert_eq!(response.1, r#"{"acceptable":true}"#);
+
+        let response = check(r#"{"password":"adalovelace1","email":"ada.lovelace@example.com"}"#);
+        assert_eq!(status(&response), 422);
+        let body: serde_json::Value = serde_json::from_str(&response.1).unwrap();
+        assert_eq!(body["violations"][0]["pointer"], "/password");
+        assert_eq!(body["details"][0]["code"], "contains_user_data");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::lifecycle::Lifecycle;
+use log::{error, info, warn};
+use signal_hook::consts::SIGUSR2;
+use signal_hook::iterator::Signals;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+/*
+*  Binary handoff
+*
+*  On SIGUSR2 the server starts its binary again, usually a new version just
+*  installed over it, with the same arguments and environment, and passes it
+*  the listening sockets the way systemd socket activation does (see
+*  `crate::systemd`). The new process serves on them instead of binding its
+*  own, so the sockets stay open throughout and connections arriving in the
+*  meantime wait in the backlog.
+*
+*  Once the new process has its listeners, it says so over a pipe, and the
+*  old one stops accepting, finishes what it accepted and exits. If the new
+*  process fails to start, the old one keeps serving as if nothing happened.
+*/
+
+/// Names the descriptor the new process writes to once it serves.
+const READY_FD: &str = "HANDOFF_READY_FD";
+
+/// A listening socket to pass on, and the name to pass it under.
+pub struct Socket {
+    pub name: String,
+    pub fd: OwnedFd,
+}
+
+/// Hands `sockets` over to a new process on SIGUSR2, then tells
+/// `lifecycle`, which stops accepting and drains.
+pub fn watch(sockets: Vec<Socket>, lifecycle: Arc<Lifecycle>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGUSR2])?;
+    thread::Builder::new()
+        .name("handoff".to_string())
+        .spawn(move || {
+            for _ in signals.forever() {
+                info!("Handing the listeners over to a new process");
+                match hand_off(&sockets) {
+                    Ok(pid) => {
+                        info!("Process {} took over; draining", pid);
+                        lifecycle.hand_off();
+                        return;
+                    }
+                    Err(e) => error!("Handoff Error: {}; still serving", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Starts the new process and waits until it serves. Returns its pid.
+fn hand_off(sockets: &[Socket]) -> io::Result<u32> {
+    let (mut ready, ready_writer) = pipe()?;
+    // The sockets go to 3 and up, then the pipe. Copies above that range
+    // cannot be clobbered while moving them there.
+    let ready_fd = 3 + sockets.len() as RawFd;
+    let mut passed = Vec::new();
+    for fd in sockets
+        .iter()
+        .map(|socket| socket.fd.as_raw_fd())
+        .chain([ready_writer.as_raw_fd()])
+    {
+        passed.push(duplicate_above(fd, ready_fd + 1)?);
+    }
+    let sources: Vec<RawFd> = passed.iter().map(AsRawFd::as_raw_fd).collect();
+    let names: Vec<&str> = sockets.iter().map(|socket| socket.name.as_str()).collect();
+
+    let mut command = Command::new(executable()?);
+    command
+        .args(env::args_os().skip(1))
+        .env_remove("LISTEN_PID")
+        .env("LISTEN_FDS", sockets.len().to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env(READY_FD, ready_fd.to_string());
+    // Only async-signal-safe calls between fork and exec. `dup2` leaves the
+    // copies open across exec, unlike the originals.
+    unsafe {
+        command.pre_exec(move || {
+            for (target, source) in (3..).zip(&sources) {
+                if libc::dup2(*source, target) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // Only the new process holds the writing end now, so the read below ends
+    // when it writes or exits.
+    drop(ready_writer);
+    drop(passed);
+
+    let mut byte = [0];
+    match ready.read(&mut byte) {
+        Ok(1) => Ok(child.id()),
+        _ => {
+            let status = child.try_wait().ok().flatten();
+            if status.is_none() {
+                warn!(
+                    "Process {} closed the handoff pipe without serving",
+                    child.id()
+                );
+            }
+            // Reaped in the background, so it does not linger as a zombie.
+            thread::spawn(move || child.wait());
+            Err(io::Error::other(match status {
+                Some(status) => format!("the new process exited with {}", status),
+                None => "the new process did not start serving".to_string(),
+            }))
+        }
+    }
+}
+
+/// Tells the process that handed over its listeners that this one serves
+/// now. Does nothing unless started by a handoff.
+pub fn ready() {
+    let Some(fd) = env::var(READY_FD)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    // Set by the previous process for this one alone; nothing else uses it.
+    let mut pipe = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if let Err(e) = pipe.write_all(b"1") {
+        warn!("Could not tell the previous process we serve: {}", e);
+    }
+}
+
+/// The binary on disk, also when the running one was replaced.
+fn executable() -> io::Result<PathBuf> {
+    let path = env::current_exe()?;
+    Ok(
+        match path.to_str().and_then(|p| p.strip_suffix(" (deleted)")) {
+            Some(replaced) => PathBuf::from(replaced),
+            None => path,
+        },
+    )
+}
+
+/// A pipe whose ends are closed on exec: `(reader, writer)`.
+fn pipe() -> io::Result<(File, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Both were just opened for us alone.
+    let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    Ok((File::from(reader), writer))
+}
+
+/// A copy of `fd` numbered `min` or above, closed on exec.
+fn duplicate_above(fd: RawFd, min: RawFd) -> io::Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min) } {
+        copy if copy < 0 => Err(io::Error::last_os_error()),
+        // A new descriptor nothing else owns.
+        copy => Ok(unsafe { OwnedFd::from_raw_fd(copy) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_bytes_through_copies_above_the_range() {
+        let (mut reader, writer) = pipe().unwrap();
+        let copy = duplicate_above(writer.as_raw_fd(), 100).unwrap();
+        assert!(copy.as_raw_fd() >= 100);
+        let flags = unsafe { libc::fcntl(copy.as_raw_fd(), libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        drop(writer);
+        File::from(copy).write_all(b"1").unwrap();
+        let mut byte = Vec::new();
+        reader.read_to_end(&mut byte).unwrap();
+        assert_eq!(byte, b"1");
+    }
+}
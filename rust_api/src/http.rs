@@ -0,0 +1,671 @@
+use crate::multipart::{self, MultipartError, Part};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+/*
+*  HTTP request parsing
+*/
+
+pub struct Request {
+    pub method: String,
+    /// Request target as sent, including any query string.
+    pub target: String,
+    /// Decoded, normalized path segments of `target`, see `decode_path`.
+    /// `None` when the path is not valid percent-encoded UTF-8.
+    segments: Option<Vec<String>>,
+    /// `segments` joined with `/`, or the raw path when it failed to decode.
+    path: String,
+    /// Decoded query string parameters in the order sent.
+    query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Address of the client, after PROXY protocol and trusted proxy headers
+    /// have been taken into account. `None` for Unix socket peers.
+    pub client_addr: Option<IpAddr>,
+    /// Subject of the client certificate verified on a `tls:` listener, e.g.
+    /// `CN=billing,O=Example`.
+    pub client_subject: Option<String>,
+}
+
+impl Request {
+    pub fn parse(raw: &[u8]) -> Option<Request> {
+        let (head, body) = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => (&raw[..end], &raw[end + 4..]),
+            None => (raw, &[][..]),
+        };
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split_whitespace();
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?.to_string();
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_form(query)),
+            None => (target.as_str(), Vec::new()),
+        };
+        let segments = decode_path(path);
+        let path = match &segments {
+            Some(segments) => format!("/{}", segments.join("/")),
+            None => path.to_string(),
+        };
+        Some(Request {
+            method,
+            target,
+            segments,
+            path,
+            query,
+            headers,
+            body: body.to_vec(),
+            client_addr: None,
+            client_subject: None,
+        })
+    }
+
+    /// The decoded path used for routing; the raw form stays in `target`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// `target` as it may be logged or reported: without the secrets that
+    /// links carry, the `token` query parameter (such as unlock links) and the
+    /// token of an export download.
+    pub fn redacted_target(&self) -> String {
+        let (path, query) = match self.target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (self.target.as_str(), None),
+        };
+        let mut segments: Vec<&str> = path.split('/').collect();
+        for i in 1..segments.len() {
+            if segments[i - 1] == "exports" && !segments[i].is_empty() {
+                segments[i] = REDACTED;
+            }
+        }
+        let mut target = segments.join("/");
+        if let Some(query) = query {
+            let pairs: Vec<String> = query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((key, _)) if key.eq_ignore_ascii_case("token") => {
+                        format!("{}={}", key, REDACTED)
+                    }
+                    _ => pair.to_string(),
+                })
+                .collect();
+            target.push('?');
+            target.push_str(&pairs.join("&"));
+        }
+        target
+    }
+
+    /// Removes the leading segments of `prefix` (like `/api/crud`) from the
+    /// path. Returns `false`, leaving the request untouched, when the path does
+    /// not start with them. A path that failed to decode is left for the
+    /// router to reject.
+    pub fn strip_prefix(&mut self, prefix: &str) -> bool {
+        if self.segments.is_none() {
+            return true;
+        }
+        let Some(rest) = self.segments_under(prefix).map(<[String]>::to_vec) else {
+            return false;
+        };
+        self.path = format!("/{}", rest.join("/"));
+        self.segments = Some(rest);
+        true
+    }
+
+    /// The decoded path segments following those of `prefix`, as
+    /// `strip_prefix` would leave them. `None` when the path does not start
+    /// with them or did not decode.
+    pub fn segments_under(&self, prefix: &str) -> Option<&[String]> {
+        let segments = self.segments.as_deref()?;
+        let prefix: Vec<&str> = prefix.split('/').filter(|s| !s.is_empty()).collect();
+        if segments.len() < prefix.len() || segments.iter().zip(&prefix).any(|(a, b)| a != b) {
+            return None;
+        }
+        Some(&segments[prefix.len()..])
+    }
+
+    /// Whether every path segment decoded cleanly; the router answers 400
+    /// otherwise.
+    pub fn has_valid_path(&self) -> bool {
+        self.segments.is_some()
+    }
+
+    /// The decoded path segments; empty when the path did not decode.
+    pub fn segments(&self) -> &[String] {
+        self.segments.as_deref().unwrap_or_default()
+    }
+
+    /// The decoded path segment at `index`, e.g. `Some("5")` for index 1 of
+    /// `/users/5`. An escaped slash stays inside its segment.
+    pub fn segment(&self, index: usize) -> Option<&str> {
+        self.segments.as_ref()?.get(index).map(String::as_str)
+    }
+
+    /// Declared body length; `None` when absent or not a number.
+    pub fn content_length(&self) -> Option<usize> {
+        self.header("Content-Length")?.parse().ok()
+    }
+
+    /// The pairs of an `application/x-www-form-urlencoded` body, in the order
+    /// sent. Bytes that are not UTF-8 are replaced, as in the query string.
+    pub fn form(&self) -> Vec<(String, String)> {
+        parse_form(&String::from_utf8_lossy(&self.body))
+    }
+
+    /// The parts of a `multipart/form-data` body, borrowed from it.
+    pub fn multipart(&self, limits: &multipart::Limits) -> Result<Vec<Part<'_>>, MultipartError> {
+        let boundary = self
+            .header("Content-Type")
+            .and_then(multipart::boundary)
+            .ok_or(MultipartError::NotMultipart)?;
+        multipart::parse(&self.body, &boundary, limits)
+    }
+
+    /// First value of the query parameter, e.g. `Some("2")` for `?page=2`. A key
+    /// without `=` has an empty value.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of a repeated query parameter, e.g. `?tag=a&tag=b`.
+    pub fn query_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.query
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every query parameter in the order sent.
+    pub fn query_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// First value of the header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of the header in the order received.
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the named cookie from the `Cookie` headers.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.header_values("Cookie")
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+}
+
+/// Stands in for the secrets `Request::redacted_target` strips.
+const REDACTED: &str = "<redacted>";
+
+const DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Formats a timestamp as an HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_date(time: DateTime<Utc>) -> String {
+    time.format(DATE_FORMAT).to_string()
+}
+
+/// Parses an HTTP-date in the IMF-fixdate form every current client sends.
+/// The obsolete RFC 850 and asctime forms are not understood.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value.trim(), DATE_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Reads from `stream`, up to `chunk_size` bytes at a time, until `buffer`
+/// holds a complete request head and returns where the head ends, including
+/// the blank line. Bytes already in `buffer` count. `None` if the peer closed
+/// the connection or the head grew past `max_size` first.
+pub fn read_head(
+    stream: &mut dyn Read,
+    buffer: &mut Vec<u8>,
+    chunk_size: usize,
+    max_size: usize,
+) -> io::Result<Option<usize>> {
+    let mut searched = 0;
+    let mut chunk = vec![0; chunk_size];
+    loop {
+        if let Some(end) = buffer[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let end = searched + end + 4;
+            return Ok((end <= max_size).then_some(end));
+        }
+        if buffer.len() > max_size {
+            return Ok(None);
+        }
+        // The terminator may straddle two reads.
+        searched = buffer.len().saturating_sub(3);
+
+        match stream.read(&mut chunk)? {
+            0 => return Ok(None),
+            size => buffer.extend_from_slice(&chunk[..size]),
+        }
+    }
+}
+
+/// A request body handed to its route as it arrives: first the bytes that came
+/// in with the head, then the rest straight off the connection, up to the
+/// declared length. A peer that closes the connection early shows up as an
+/// `UnexpectedEof` error rather than as the end of the body.
+pub struct Body<'a> {
+    buffered: &'a [u8],
+    stream: &'a mut dyn Read,
+    /// Bytes still expected from `stream`.
+    remaining: usize,
+}
+
+impl<'a> Body<'a> {
+    /// `buffered` beyond `length` is ignored.
+    pub fn new(buffered: &'a [u8], stream: &'a mut dyn Read, length: usize) -> Self {
+        let buffered = &buffered[..buffered.len().min(length)];
+        Body {
+            buffered,
+            stream,
+            remaining: length - buffered.len(),
+        }
+    }
+}
+
+impl Read for Body<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffered.is_empty() {
+            return self.buffered.read(buf);
+        }
+        let size = buf.len().min(self.remaining);
+        if size == 0 {
+            return Ok(0);
+        }
+        match self.stream.read(&mut buf[..size])? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the end of the body",
+            )),
+            read => {
+                self.remaining -= read;
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Whether a response head, status line included, sets header `name`.
+pub fn has_header(head: &[u8], name: &str) -> bool {
+    String::from_utf8_lossy(head).lines().skip(1).any(|line| {
+        line.split_once(':')
+            .is_some_and(|(field, _)| field.trim().eq_ignore_ascii_case(name))
+    })
+}
+
+/// Splits a path into percent-decoded segments, dropping empty and `.`
+/// segments and resolving `..`, so `//users/`, `/users/./` and `/users` are the
+/// same path. Dots are resolved after decoding, so `%2e%2e` cannot be used to
+/// sneak past it, and `..` never climbs above the root.
+fn decode_path(path: &str) -> Option<Vec<String>> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        let segment = String::from_utf8(unescape(segment, false)?).ok()?;
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    Some(segments)
+}
+
+/// Parses `application/x-www-form-urlencoded` pairs, as query strings and
+/// form bodies hold them: `+` is a space, percent-escapes are decoded and
+/// empty pairs (`a=1&&b=2`) are skipped.
+pub fn parse_form(encoded: &str) -> Vec<(String, String)> {
+    encoded
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+/// Decodes a query string component. Malformed escapes are kept as written and
+/// invalid UTF-8 is replaced, as browsers do, rather than rejecting the request.
+fn decode_component(component: &str) -> String {
+    let decoded = unescape(component, true).unwrap_or_default();
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes a percent-encoded URL component, such as the user or path of a
+/// connection URL. `None` when an escape is malformed or the result is not
+/// UTF-8.
+pub fn percent_decode(component: &str) -> Option<String> {
+    String::from_utf8(unescape(component, false)?).ok()
+}
+
+/// Resolves `%XX` escapes. Form encoding also turns `+` into a space and keeps
+/// malformed escapes literally; otherwise a malformed escape is an error.
+fn unescape(component: &str, form: bool) -> Option<Vec<u8>> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if form => decoded.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3).and_then(hex_byte) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None if form => decoded.push(b'%'),
+                None => return None,
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    Some(decoded)
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    // from_str_radix alone would also accept a sign, as in `%+1`.
+    if !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+/// Writes a response body with `Transfer-Encoding: chunked`. The status line
+/// and headers in `head` are held back until the first body byte, so a handler
+/// that fails before producing output can still send an error instead.
+pub struct ChunkedResponse<'a> {
+    out: &'a mut dyn Write,
+    head: &'a str,
+    started: bool,
+}
+
+impl<'a> ChunkedResponse<'a> {
+    pub fn new(out: &'a mut dyn Write, head: &'a str) -> Self {
+        ChunkedResponse {
+            out,
+            head,
+            started: false,
+        }
+    }
+
+    /// Whether the head has been sent.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Gives the connection back, e.g. to send an error response instead.
+    pub fn into_inner(self) -> &'a mut dyn Write {
+        self.out
+    }
+
+    /// Sends the head if nothing was written, then the terminating chunk.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.start()?;
+        self.out.write_all(b"0\r\n\r\n")?;
+        self.out.flush()
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.started = true;
+            self.out.write_all(self.head.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for ChunkedResponse<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            // An empty chunk would end the body.
+            return Ok(0);
+        }
+        self.start()?;
+        write!(self.out, "{:x}\r\n", buf.len())?;
+        self.out.write_all(buf)?;
+        self.out.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn path_of(target: &str) -> String {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        Request::parse(raw.as_bytes()).unwrap().path().to_string()
+    }
+
+    #[test]
+    fn reads_heads_up_to_the_limit() {
+        let raw = b"GET /users HTTP/1.1\r\nHost: localhost\r\n\r\n{\"body\": 1}";
+        let mut buffer = b"GET /us".to_vec();
+        // Chunks of 5 bytes split the terminator between reads.
+        let end = read_head(&mut &raw[7..], &mut buffer, 5, 64).unwrap();
+        assert_eq!(end, Some(40));
+        assert!(buffer.starts_with(&raw[..40]));
+
+        let mut buffer = Vec::new();
+        assert_eq!(
+            read_head(&mut &raw[..], &mut buffer, 4096, 39).unwrap(),
+            None
+        );
+        assert!(buffer.len() > 39);
+        let mut buffer = Vec::new();
+        assert_eq!(
+            read_head(&mut &raw[..20], &mut buffer, 4096, 64).unwrap(),
+            None
+        );
+        assert!(buffer.len() <= 64);
+    }
+
+    #[test]
+    fn reads_bodies_up_to_their_length() {
+        let mut rest = &b"lo world, and the next request"[..];
+        let mut body = Body::new(b"hel", &mut rest, 11);
+        let mut read = String::new();
+        body.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello world");
+
+        let mut rest = &b""[..];
+        let mut body = Body::new(b"hello world", &mut rest, 5);
+        read.clear();
+        body.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello");
+
+        let mut rest = &b"lo"[..];
+        let mut body = Body::new(b"hel", &mut rest, 11);
+        let error = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        for (raw, normalized) in [
+            ("/users", "/users"),
+            ("/users/", "/users"),
+            ("//users", "/users"),
+            ("/users//5/", "/users/5"),
+            ("/users/./5", "/users/5"),
+            ("/admin/../users", "/users"),
+            ("/../../users", "/users"),
+            ("/admin/%2e%2e/users", "/users"),
+            ("/us%65rs", "/users"),
+            ("/", "/"),
+        ] {
+            assert_eq!(path_of(raw), normalized, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn decodes_path_segments() {
+        let request =
+            Request::parse(b"GET /users/by-email/ada%40example.com/a%2Fb+c HTTP/1.1\r\n\r\n")
+                .unwrap();
+        assert!(request.has_valid_path());
+        assert_eq!(request.segment(2), Some("ada@example.com"));
+        assert_eq!(request.segment(3), Some("a/b+c"));
+        assert_eq!(request.segment(4), None);
+    }
+
+    #[test]
+    fn rejects_badly_encoded_paths() {
+        for target in ["/users/%zz", "/users/%4", "/users/%FF", "/users/%C3%28"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let request = Request::parse(raw.as_bytes()).unwrap();
+            assert!(!request.has_valid_path(), "{:?}", target);
+            assert_eq!(request.segment(0), None);
+        }
+    }
+
+    #[test]
+    fn parses_the_query_string() {
+        let request = Request::parse(
+            b"GET /users?q=ada+lovelace&tag=a&tag=b%2Cc&flag&&name=Jos%C3%A9&bad=%zz%4 HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.query("q"), Some("ada lovelace"));
+        assert_eq!(
+            request.query_values("tag").collect::<Vec<_>>(),
+            ["a", "b,c"]
+        );
+        assert_eq!(request.query("flag"), Some(""));
+        assert_eq!(request.query("name"), Some("José"));
+        assert_eq!(request.query("bad"), Some("%zz%4"));
+        assert_eq!(request.query("missing"), None);
+    }
+
+    #[test]
+    fn redacts_tokens_from_targets() {
+        let target = |target: &str| {
+            Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes())
+                .unwrap()
+                .redacted_target()
+        };
+        assert_eq!(
+            target("/admin/unlock?token=5ecr3t&next=%2F"),
+            "/admin/unlock?token=<redacted>&next=%2F"
+        );
+        assert_eq!(
+            target("/api/exports/0f1e2d3c?download=1"),
+            "/api/exports/<redacted>?download=1"
+        );
+        assert_eq!(target("/users/7/export"), "/users/7/export");
+        assert_eq!(target("/users?tokens=a&q=b"), "/users?tokens=a&q=b");
+    }
+
+    #[test]
+    fn reads_cookies() {
+        let request = Request::parse(
+            b"GET /admin HTTP/1.1\r\nCookie: theme=dark; id=\"a=b\"\r\nCookie: lang=pt\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.cookie("theme"), Some("dark"));
+        assert_eq!(request.cookie("id"), Some("a=b"));
+        assert_eq!(request.cookie("lang"), Some("pt"));
+        assert_eq!(request.cookie("dark"), None);
+    }
+
+    #[test]
+    fn decodes_plus_and_escaped_plus_differently() {
+        assert_eq!(decode_component("a+b%2Bc"), "a b+c");
+        assert_eq!(decode_component("%E2%9C%93"), "\u{2713}");
+        assert_eq!(decode_component("%FF"), "\u{FFFD}");
+        assert_eq!(decode_component("%+1"), "% 1");
+    }
+
+    #[test]
+    fn strips_the_base_path() {
+        let mut request = Request::parse(b"GET /api/crud/users/5 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.strip_prefix("/api/crud"));
+        assert_eq!(request.path(), "/users/5");
+        assert_eq!(request.segment(1), Some("5"));
+        assert!(request.strip_prefix(""));
+        assert_eq!(request.path(), "/users/5");
+
+        let mut outside = Request::parse(b"GET /api/crudx/users HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!outside.strip_prefix("/api/crud"));
+        assert_eq!(outside.path(), "/api/crudx/users");
+        let mut root = Request::parse(b"GET /api HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!root.strip_prefix("/api/crud"));
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let date = parse_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date.to_rfc3339(), "1994-11-06T08:49:37+00:00");
+        assert_eq!(format_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(parse_date("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+        assert!(parse_date("yesterday").is_none());
+    }
+
+    #[test]
+    fn path_ignores_the_query_string() {
+        let request = Request::parse(b"GET //users/?page=2 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path(), "/users");
+        assert_eq!(request.target, "//users/?page=2");
+    }
+
+    proptest! {
+        #[test]
+        fn parses_anything_without_panicking(raw in prop::collection::vec(any::<u8>(), 0..1024)) {
+            if let Some(mut request) = Request::parse(&raw) {
+                request.content_length();
+                request.form();
+                request.multipart(&multipart::Limits::default()).ok();
+                request.cookie("id");
+                request.strip_prefix("/api/crud");
+                prop_assert!(request.path().starts_with('/') || !request.has_valid_path());
+            }
+        }
+
+        #[test]
+        fn finds_the_head_however_it_arrives(
+            head in "[ -~]{0,64}(\r\n[ -~]{0,64}){0,4}\r\n\r\n",
+            body in "[ -~\r\n]{0,32}",
+            chunk_size in 1..16usize,
+        ) {
+            let raw = format!("{}{}", head, body);
+            let expected = raw.find("\r\n\r\n").map(|end| end + 4);
+            let mut buffer = Vec::new();
+            let end = read_head(&mut raw.as_bytes(), &mut buffer, chunk_size, 1024).unwrap();
+            prop_assert_eq!(end, expected);
+        }
+    }
+}
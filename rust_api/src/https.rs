@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+/*
+*  Outbound HTTPS
+*
+*  Calls to other services (Sentry, AWS) share one client setup: rustls with
+*  the system's root certificates. Error statuses are returned as responses
+*  so callers can read the error body.
+*/
+
+/// A client giving up on calls after `timeout`.
+pub fn agent(timeout: Duration) -> ureq::Agent {
+    let certs = rustls_native_certs::load_native_certs().certs;
+    let roots: Vec<_> = certs
+        .iter()
+        .map(|cert| ureq::tls::Certificate::from_der(cert).to_owned())
+        .collect();
+    ureq::Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .http_status_as_error(false)
+        .tls_config(
+            ureq::tls::TlsConfig::builder()
+                .provider(ureq::tls::TlsProvider::Rustls)
+                .root_certs(ureq::tls::RootCerts::new_with_certs(&roots))
+                .unversioned_rustls_crypto_provider(Arc::new(
+                    rustls::crypto::ring::default_provider(),
+                ))
+                .build(),
+        )
+        .build()
+        .new_agent()
+}
@@ -0,0 +1,437 @@
+use crate::models::{user_schema, User};
+use crate::repository::{RepositoryError, UserRepository};
+use crate::schema::{self, Violation};
+use serde_json::Value;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+/*
+*  Bulk imports
+*
+*  Users uploaded as CSV or newline-delimited JSON are parsed record by record
+*  as the body arrives and created `BATCH_SIZE` at a time, so memory stays
+*  bounded by one batch and one record however large the upload. Each record
+*  is checked like a `POST /users` body. Invalid records and emails that are
+*  already taken are counted and skipped rather than failing the import, so
+*  running an interrupted import again picks up where it stopped.
+*/
+
+/// Users created per repository call.
+pub const BATCH_SIZE: usize = 500;
+/// Longest record, in bytes; a CSV record may span several lines.
+const MAX_RECORD: usize = 64 * 1024;
+/// Invalid records described in the report; further ones are only counted.
+const MAX_REPORTED: usize = 100;
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The body could not be read to its end.
+    Body(io::Error),
+    /// The upload as a whole cannot be parsed, e.g. a CSV without a header.
+    Malformed(String),
+    Repository(RepositoryError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Body(e) => write!(f, "{}", e),
+            ImportError::Malformed(message) => write!(f, "{}", message),
+            ImportError::Repository(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RepositoryError> for ImportError {
+    fn from(e: RepositoryError) -> Self {
+        ImportError::Repository(e)
+    }
+}
+
+/// One record of an upload: the user it describes, or why it does not.
+pub struct Entry {
+    /// Line the record starts on, counting from 1.
+    pub line: u64,
+    pub user: Result<User, String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct Report {
+    pub created: u64,
+    /// Records whose email was taken, also by an earlier record.
+    pub existing: u64,
+    pub invalid: u64,
+    /// The first `MAX_REPORTED` invalid records.
+    pub errors: Vec<RecordError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecordError {
+    pub line: u64,
+    pub message: String,
+}
+
+/// Creates the users of `entries` in batches. `check` adds rules beyond the
+/// user schema, like the ones `POST /users` applies. Batches created before an
+/// error stay created.
+pub fn import(
+    repository: &dyn UserRepository,
+    entries: impl Iterator<Item = Result<Entry, ImportError>>,
+    check: impl Fn(&User) -> Vec<Violation>,
+) -> Result<Report, ImportError> {
+    let mut report = Report::default();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    for entry in entries {
+        let entry = entry?;
+        let violations = match &entry.user {
+            Ok(user) => check(user),
+            Err(_) => Vec::new(),
+        };
+        match entry.user {
+            Ok(user) if violations.is_empty() => batch.push(user),
+            Ok(_) => report.reject(entry.line, describe(&violations)),
+            Err(message) => report.reject(entry.line, message),
+        }
+        if batch.len() == BATCH_SIZE {
+            report.store(repository, &batch)?;
+            batch.clear();
+        }
+    }
+    report.store(repository, &batch)?;
+    Ok(report)
+}
+
+impl Report {
+    fn reject(&mut self, line: u64, message: String) {
+        self.invalid += 1;
+        if self.errors.len() < MAX_REPORTED {
+            self.errors.push(RecordError { line, message });
+        }
+    }
+
+    fn store(
+        &mut self,
+        repository: &dyn UserRepository,
+        batch: &[User],
+    ) -> Result<(), RepositoryError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ids = repository.create_many(batch)?;
+        let created = ids.iter().flatten().count() as u64;
+        self.created += created;
+        self.existing += ids.len() as u64 - created;
+        Ok(())
+    }
+}
+
+/// The user `value` describes, checked against the user schema.
+fn user_from(value: Value) -> Result<User, String> {
+    let violations = schema::validate(&user_schema(), &value);
+    if !violations.is_empty() {
+        return Err(describe(&violations));
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+fn describe(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(|violation| match violation.field().as_str() {
+            "" => violation.message.clone(),
+            field => format!("{} {}", field, violation.message),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reads up to and including the next `\n`, failing on lines longer than
+/// `MAX_RECORD` less what `line` already holds. Returns the bytes read.
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> Result<usize, ImportError> {
+    let limit = MAX_RECORD.saturating_sub(line.len()) as u64 + 1;
+    let read = reader
+        .take(limit)
+        .read_until(b'\n', line)
+        .map_err(ImportError::Body)?;
+    if line.len() > MAX_RECORD {
+        return Err(ImportError::Malformed(format!(
+            "record longer than {} bytes",
+            MAX_RECORD
+        )));
+    }
+    Ok(read)
+}
+
+/// Users from newline-delimited JSON, one user object per line. Blank lines
+/// are skipped.
+pub struct NdjsonUsers<R> {
+    reader: R,
+    line: u64,
+}
+
+impl<R: BufRead> NdjsonUsers<R> {
+    pub fn new(reader: R) -> Self {
+        NdjsonUsers { reader, line: 0 }
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonUsers<R> {
+    type Item = Result<Entry, ImportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match read_line(&mut self.reader, &mut line) {
+                Ok(0) => return None,
+                Ok(_) => self.line += 1,
+                Err(e) => return Some(Err(e)),
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let user = serde_json::from_slice(&line)
+                .map_err(|e| format!("invalid JSON: {}", e))
+                .and_then(user_from);
+            return Some(Ok(Entry {
+                line: self.line,
+                user,
+            }));
+        }
+    }
+}
+
+/// Users from CSV as RFC 4180 describes it: comma separated, fields with
+/// commas, quotes or line breaks quoted, and quotes in them doubled. The first
+/// record names the columns; `name` and `email` are required and any others
+/// are ignored. Blank lines are skipped.
+pub struct CsvUsers<R> {
+    reader: R,
+    line: u64,
+    name: usize,
+    email: usize,
+    columns: usize,
+}
+
+impl<R: BufRead> CsvUsers<R> {
+    /// Reads the header.
+    pub fn new(reader: R) -> Result<Self, ImportError> {
+        let mut users = CsvUsers {
+            reader,
+            line: 0,
+            name: 0,
+            email: 0,
+            columns: 0,
+        };
+        let (_, header) = users
+            .record()?
+            .ok_or_else(|| ImportError::Malformed("missing CSV header".to_string()))?;
+        let column = |name: &str| {
+            header
+                .iter()
+                // Spreadsheets like to start their exports with a BOM.
+                .position(|column| {
+                    let column = column.trim_start_matches('\u{feff}').trim();
+                    column.eq_ignore_ascii_case(name)
+                })
+                .ok_or_else(|| ImportError::Malformed(format!("missing CSV column: {}", name)))
+        };
+        users.name = column("name")?;
+        users.email = column("email")?;
+        users.columns = header.len();
+        Ok(users)
+    }
+
+    /// The next record and the line it starts on.
+    fn record(&mut self) -> Result<Option<(u64, Vec<String>)>, ImportError> {
+        let mut raw = Vec::new();
+        let mut start = self.line + 1;
+        loop {
+            let read = read_line(&mut self.reader, &mut raw)?;
+            if read == 0 {
+                if raw.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ImportError::Malformed(format!(
+                    "unterminated quote in the record on line {}",
+                    start
+                )));
+            }
+            self.line += 1;
+            if raw.iter().all(|byte| matches!(byte, b'\r' | b'\n')) {
+                raw.clear();
+                start = self.line + 1;
+                continue;
+            }
+            if let Some(fields) = split_record(&raw) {
+                let fields = fields
+                    .into_iter()
+                    .map(|field| {
+                        String::from_utf8(field).map_err(|_| {
+                            ImportError::Malformed(format!("invalid UTF-8 on line {}", start))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                return Ok(Some((start, fields)));
+            }
+            // A quoted field goes on past the line break.
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvUsers<R> {
+    type Item = Result<Entry, ImportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (line, mut fields) = match self.record() {
+            Ok(record) => record?,
+            Err(e) => return Some(Err(e)),
+        };
+        if fields.len() != self.columns {
+            return Some(Ok(Entry {
+                line,
+                user: Err(format!(
+                    "expected {} fields, found {}",
+                    self.columns,
+                    fields.len()
+                )),
+            }));
+        }
+        let user = serde_json::json!({
+            "name": std::mem::take(&mut fields[self.name]),
+            "email": std::mem::take(&mut fields[self.email]).trim(),
+        });
+        Some(Ok(Entry {
+            line,
+            user: user_from(user),
+        }))
+    }
+}
+
+/// The fields of a complete record, or `None` while a quoted field is still
+/// open at the end of `raw`. The final line break is not part of the record.
+fn split_record(raw: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let raw = raw.strip_suffix(b"\n").unwrap_or(raw);
+    let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut quoted = false;
+    let mut bytes = raw.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'"' if quoted && bytes.peek() == Some(&b'"') => {
+                field.push(b'"');
+                bytes.next();
+            }
+            b'"' if quoted => quoted = false,
+            b'"' if field.is_empty() => quoted = true,
+            b',' if !quoted => fields.push(std::mem::take(&mut field)),
+            byte => field.push(byte),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    fn csv(body: &str) -> Result<Report, ImportError> {
+        let users = CsvUsers::new(body.as_bytes())?;
+        import(&MemoryUserRepository::new(), users, |_| Vec::new())
+    }
+
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(
+            split_record(b"a,\"b,c\",\"say \"\"hi\"\"\",\r\n").unwrap(),
+            [&b"a"[..], b"b,c", b"say \"hi\"", b""]
+        );
+        assert!(split_record(b"a,\"b\n").is_none());
+        assert_eq!(split_record(b"a,\"b\nc\"\n").unwrap(), [&b"a"[..], b"b\nc"]);
+    }
+
+    #[test]
+    fn imports_csv_in_batches() {
+        let mut body = "\u{feff}id,email,name\n".to_string();
+        for i in 0..BATCH_SIZE + 10 {
+            body.push_str(&format!("{},user{}@example.com,User {}\r\n", i, i, i));
+        }
+        body.push_str("\n9,user3@example.com,\"Again, with a comma\"\n");
+        body.push_str("10,not an email,Nobody\n11,short@example.com\n");
+
+        let report = csv(&body).unwrap();
+        assert_eq!(report.created, BATCH_SIZE as u64 + 10);
+        assert_eq!(report.existing, 1);
+        assert_eq!(report.invalid, 2);
+        let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+        let last = BATCH_SIZE as u64 + 12;
+        assert_eq!(lines, [last + 2, last + 3]);
+        assert_eq!(report.errors[1].message, "expected 3 fields, found 2");
+    }
+
+    #[test]
+    fn refuses_csv_it_cannot_read() {
+        for body in [
+            "",
+            "name,mail\nAda,ada@example.com\n",
+            "name,email\n\"Ada,ada@example.com\n",
+        ] {
+            assert!(
+                matches!(csv(body), Err(ImportError::Malformed(_))),
+                "{:?}",
+                body
+            );
+        }
+        let long = format!("name,email\n{},a@example.com\n", "a".repeat(MAX_RECORD));
+        assert!(matches!(csv(&long), Err(ImportError::Malformed(_))));
+    }
+
+    #[test]
+    fn imports_ndjson() {
+        let body = concat!(
+            "{\"name\": \"Ada\", \"email\": \"ada@example.com\"}\n",
+            "\n",
+            "{\"name\": \"Grace\"}\n",
+            "{\"name\": \"Ada\", \"email\": \"ada@example.com\"}\n",
+            "not json\n",
+            "{\"name\": \"Alan\", \"email\": \"alan@example.com\"}",
+        );
+        let users = NdjsonUsers::new(body.as_bytes());
+        let repository = MemoryUserRepository::new();
+        let report = import(&repository, users, |_| Vec::new()).unwrap();
+
+        assert_eq!((report.created, report.existing, report.invalid), (2, 1, 2));
+        assert_eq!(report.errors[0].line, 3);
+        assert_eq!(report.errors[1].line, 5);
+        assert!(repository
+            .find_by_email("alan@example.com")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn applies_extra_checks() {
+        let body = "name,email\n ,blank@example.com\n";
+        let users = CsvUsers::new(body.as_bytes()).unwrap();
+        let report = import(&MemoryUserRepository::new(), users, |user| {
+            if user.name.trim().is_empty() {
+                vec![Violation {
+                    pointer: "/name".to_string(),
+                    code: "blank",
+                    message: "must not be blank".to_string(),
+                }]
+            } else {
+                Vec::new()
+            }
+        })
+        .unwrap();
+        assert_eq!(report.invalid, 1);
+        assert_eq!(report.errors[0].message, "name must not be blank");
+    }
+}
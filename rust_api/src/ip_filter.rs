@@ -0,0 +1,93 @@
+use crate::cidr::Cidr;
+use crate::listener::RouteGroup;
+use std::net::IpAddr;
+
+/*
+*  IP filter
+*
+*  Allow and deny lists of networks, checked against the client address (as
+*  resolved through trusted proxies) before a request is routed. One pair of
+*  lists applies to every request, and each route group has a pair of its
+*  own, so the admin routes can be limited to the office range while the API
+*  stays public. A request has to pass both the global and its group's lists:
+*  it must not be in a deny list, and must be in every allow list that is not
+*  empty. Requests without an address come over a Unix socket, so from this
+*  host, and are let through.
+*/
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpRules {
+    /// Empty allows every address.
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpRules {
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    pub global: IpRules,
+    pub api: IpRules,
+    pub admin: IpRules,
+}
+
+impl IpFilter {
+    pub fn permits(&self, group: RouteGroup, client: Option<IpAddr>) -> bool {
+        let Some(ip) = client else {
+            return true;
+        };
+        let group = match group {
+            RouteGroup::Api => &self.api,
+            RouteGroup::Admin => &self.admin,
+        };
+        self.global.permits(ip) && group.permits(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn limits_each_group_to_its_networks() {
+        let filter = IpFilter {
+            global: IpRules {
+                allow: Vec::new(),
+                deny: nets(&["203.0.113.0/24"]),
+            },
+            api: IpRules::default(),
+            admin: IpRules {
+                allow: nets(&["10.20.0.0/16", "2001:db8::/32"]),
+                deny: nets(&["10.20.99.0/24"]),
+            },
+        };
+
+        assert!(filter.permits(RouteGroup::Api, ip("198.51.100.7")));
+        assert!(!filter.permits(RouteGroup::Api, ip("203.0.113.9")));
+        assert!(!filter.permits(RouteGroup::Admin, ip("198.51.100.7")));
+        assert!(filter.permits(RouteGroup::Admin, ip("10.20.1.2")));
+        assert!(filter.permits(RouteGroup::Admin, ip("::ffff:10.20.1.2")));
+        assert!(filter.permits(RouteGroup::Admin, ip("2001:db8::1")));
+        // Deny wins over allow.
+        assert!(!filter.permits(RouteGroup::Admin, ip("10.20.99.1")));
+        assert!(filter.permits(RouteGroup::Admin, None));
+    }
+
+    #[test]
+    fn allows_everyone_without_lists() {
+        assert!(IpFilter::default().permits(RouteGroup::Admin, ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,106 @@
+use log::{debug, error};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/*
+*  Job queue
+*
+*  Work that should not hold up a response runs on a small pool of background
+*  threads. Jobs live in memory only, so anything queued is lost on restart.
+*/
+
+pub type Job = Box<dyn FnOnce() + Send>;
+
+pub struct JobQueue {
+    sender: Mutex<Sender<Job>>,
+    /// Jobs submitted that no worker has picked up yet.
+    queued: Arc<AtomicUsize>,
+}
+
+impl JobQueue {
+    pub fn new(workers: usize) -> JobQueue {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        for index in 0..workers {
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            thread::Builder::new()
+                .name(format!("job-worker-{}", index))
+                .spawn(move || work(&receiver, &queued))
+                .expect("spawn job worker");
+        }
+
+        JobQueue {
+            sender: Mutex::new(sender),
+            queued,
+        }
+    }
+
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        if self.sender.lock().unwrap().send(Box::new(job)).is_err() {
+            self.queued.fetch_sub(1, Ordering::AcqRel);
+            error!("Job queue is closed, dropping job");
+        }
+    }
+
+    /// Jobs waiting for a worker, not counting the ones running.
+    pub fn depth(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>, queued: &AtomicUsize) {
+    loop {
+        // The guard is dropped before the job runs so other workers can pick
+        // up the next one.
+        let job = receiver.lock().unwrap().recv();
+        let job = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        queued.fetch_sub(1, Ordering::AcqRel);
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            debug!("Job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_jobs_in_the_background_and_survives_panics() {
+        let queue = JobQueue::new(1);
+        let (done, finished) = mpsc::channel();
+
+        queue.submit(|| panic!("job failed"));
+        queue.submit(move || done.send(42).unwrap());
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(42));
+    }
+
+    #[test]
+    fn counts_jobs_waiting_for_a_worker() {
+        let queue = JobQueue::new(1);
+        let (release, released) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+        queue.submit(move || {
+            started.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        queue.submit(|| {});
+        queue.submit(|| {});
+        assert_eq!(queue.depth(), 2);
+        release.send(()).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use serde_json::Value;
+use std::fmt;
+
+/*
+*  JSON Patch and JSON Merge Patch
+*
+*  `PATCH /users/:id` takes `application/json-patch+json` bodies, a list of
+*  operations (https://datatracker.ietf.org/doc/html/rfc6902) run one after
+*  another against the user's JSON representation:
+*
+*      [{ "op": "replace", "path": "/name", "value": "Ada" },
+*       { "op": "test", "path": "/email", "value": "ada@example.com" }]
+*
+*  All six operations are known. They run on a copy, so a document either
+*  applies as a whole or leaves the target as it was.
+*
+*  It also takes `application/merge-patch+json` bodies
+*  (https://datatracker.ietf.org/doc/html/rfc7396), which user metadata is
+*  patched with too: a partial document whose members replace those of the
+*  target, where `null` removes the member. Both are applied as JSON values
+*  rather than deserialized into a model first, so a member left out and one
+*  set to `null` stay different things. Whether the result is valid is up to
+*  the caller.
+*/
+
+pub const MEDIA_TYPE: &str = "application/json-patch+json";
+pub const MERGE_MEDIA_TYPE: &str = "application/merge-patch+json";
+
+pub fn is_merge_patch(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(MERGE_MEDIA_TYPE)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// The operation at the index cannot be applied to the target.
+    Invalid { index: usize, reason: String },
+    /// The `test` operation at the index found another value.
+    TestFailed { index: usize },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Invalid { index, reason } => write!(f, "Operation {}: {}", index, reason),
+            PatchError::TestFailed { index } => write!(f, "Operation {}: test failed", index),
+        }
+    }
+}
+
+/// Applies the operations in `patch` to `target`, all of them or none.
+pub fn apply(target: &mut Value, patch: &Value) -> Result<(), PatchError> {
+    let Value::Array(operations) = patch else {
+        return Err(PatchError::Invalid {
+            index: 0,
+            reason: "a patch is a list of operations".to_string(),
+        });
+    };
+    let mut patched = target.clone();
+    for (index, operation) in operations.iter().enumerate() {
+        run(&mut patched, operation).map_err(|reason| match reason {
+            Failure::Test => PatchError::TestFailed { index },
+            Failure::Invalid(reason) => PatchError::Invalid { index, reason },
+        })?;
+    }
+    *target = patched;
+    Ok(())
+}
+
+/// Applies `patch` to `target` as RFC 7396 describes: objects are merged key
+/// by key, `null` removes a key and anything else replaces the value.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let fields = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            fields.remove(key);
+        } else {
+            merge_patch(fields.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+enum Failure {
+    Test,
+    Invalid(String),
+}
+
+impl From<String> for Failure {
+    fn from(reason: String) -> Self {
+        Failure::Invalid(reason)
+    }
+}
+
+fn run(target: &mut Value, operation: &Value) -> Result<(), Failure> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .ok_or_else(|| format!("{} is missing", name))
+    };
+    let pointer = |name: &str| -> Result<Vec<String>, String> {
+        match member(name)? {
+            Value::String(pointer) => tokens(pointer),
+            _ => Err(format!("{} is not a string", name)),
+        }
+    };
+    let path = pointer("path")?;
+    match member("op")?.as_str().unwrap_or_default() {
+        "add" => add(target, &path, member("value")?.clone())?,
+        "remove" => {
+            remove(target, &path)?;
+        }
+        "replace" => {
+            *get_mut(target, &path)? = member("value")?.clone();
+        }
+        "move" => {
+            let from = pointer("from")?;
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err(Failure::Invalid(
+                    "cannot move a value into itself".to_string(),
+                ));
+            }
+            let value = remove(target, &from)?;
+            add(target, &path, value)?;
+        }
+        "copy" => {
+            let value = get_mut(target, &pointer("from")?)?.clone();
+            add(target, &path, value)?;
+        }
+        "test" => {
+            if get_mut(target, &path)? != member("value")? {
+                return Err(Failure::Test);
+            }
+        }
+        other => return Err(Failure::Invalid(format!("unknown op {:?}", other))),
+    }
+    Ok(())
+}
+
+/// The reference tokens of a JSON Pointer (RFC 6901), unescaped.
+fn tokens(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(format!("{:?} is not a JSON Pointer", pointer));
+    };
+    Ok(rest
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+/// An array index as RFC 6901 spells it: no sign and no leading zeros.
+fn index(token: &str, len: usize) -> Result<usize, String> {
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(index) if valid && index < len => Ok(index),
+        _ => Err(format!("no element {:?}", token)),
+    }
+}
+
+fn get_mut<'v>(target: &'v mut Value, path: &[String]) -> Result<&'v mut Value, String> {
+    path.iter().try_fold(target, |value, token| match value {
+        Value::Object(object) => object
+            .get_mut(token)
+            .ok_or_else(|| format!("no member {:?}", token)),
+        Value::Array(items) => {
+            let index = index(token, items.len())?;
+            Ok(&mut items[index])
+        }
+        _ => Err(format!("{:?} is inside a scalar", token)),
+    })
+}
+
+fn add(target: &mut Value, path: &[String], value: Value) -> Result<(), String> {
+    let Some((last, parent)) = path.split_last() else {
+        *target = value;
+        return Ok(());
+    };
+    match get_mut(target, parent)? {
+        Value::Object(object) => {
+            object.insert(last.clone(), value);
+        }
+        Value::Array(items) if last == "-" => items.push(value),
+        Value::Array(items) => {
+            // One past the end appends.
+            let index = index(last, items.len() + 1)?;
+            items.insert(index, value);
+        }
+        _ => return Err(format!("{:?} is inside a scalar", last)),
+    }
+    Ok(())
+}
+
+fn remove(target: &mut Value, path: &[String]) -> Result<Value, String> {
+    let Some((last, parent)) = path.split_last() else {
+        return Err("cannot remove the whole document".to_string());
+    };
+    match get_mut(target, parent)? {
+        Value::Object(object) => object
+            .remove(last)
+            .ok_or_else(|| format!("no member {:?}", last)),
+        Value::Array(items) => {
+            let index = index(last, items.len())?;
+            Ok(items.remove(index))
+        }
+        _ => Err(format!("{:?} is inside a scalar", last)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn applies_each_operation() {
+        let mut target = json!({ "name": "Ada", "tags": ["a", "b"], "a/b": { "~": 1 } });
+        let patch = json!([
+            { "op": "replace", "path": "/name", "value": "Grace" },
+            { "op": "add", "path": "/tags/1", "value": "x" },
+            { "op": "add", "path": "/tags/-", "value": "z" },
+            { "op": "remove", "path": "/tags/0" },
+            { "op": "move", "from": "/a~1b/~0", "path": "/count" },
+            { "op": "copy", "from": "/name", "path": "/nickname" },
+            { "op": "test", "path": "/tags", "value": ["x", "b", "z"] },
+        ]);
+        apply(&mut target, &patch).unwrap();
+        assert_eq!(
+            target,
+            json!({
+                "name": "Grace",
+                "nickname": "Grace",
+                "tags": ["x", "b", "z"],
+                "a/b": {},
+                "count": 1
+            })
+        );
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut target = json!({ "a": "b", "c": { "d": "e", "f": "g" } });
+        merge_patch(&mut target, &json!({ "a": "z", "c": { "f": null } }));
+        assert_eq!(target, json!({ "a": "z", "c": { "d": "e" } }));
+
+        merge_patch(&mut target, &json!({ "c": [1, 2], "new": { "x": 1 } }));
+        assert_eq!(target, json!({ "a": "z", "c": [1, 2], "new": { "x": 1 } }));
+    }
+
+    #[test]
+    fn leaves_the_target_alone_when_an_operation_fails() {
+        let original = json!({ "name": "Ada", "tags": [] });
+        for (patch, error) in [
+            (
+                json!([
+                    { "op": "replace", "path": "/name", "value": "Grace" },
+                    { "op": "test", "path": "/name", "value": "Ada" },
+                ]),
+                PatchError::TestFailed { index: 1 },
+            ),
+            (
+                json!([{ "op": "remove", "path": "/email" }]),
+                PatchError::Invalid {
+                    index: 0,
+                    reason: "no member \"email\"".to_string(),
+                },
+            ),
+            (
+                json!([{ "op": "add", "path": "/tags/01", "value": 1 }]),
+                PatchError::Invalid {
+                    index: 0,
+                    reason: "no element \"01\"".to_string(),
+                },
+            ),
+            (
+                json!([{ "op": "move", "from": "/tags", "path": "/tags/0" }]),
+                PatchError::Invalid {
+                    index: 0,
+                    reason: "cannot move a value into itself".to_string(),
+                },
+            ),
+            (
+                json!([{ "op": "replace", "path": "name", "value": 1 }]),
+                PatchError::Invalid {
+                    index: 0,
+                    reason: "\"name\" is not a JSON Pointer".to_string(),
+                },
+            ),
+        ] {
+            let mut target = original.clone();
+            assert_eq!(apply(&mut target, &patch), Err(error));
+            assert_eq!(target, original);
+        }
+    }
+}
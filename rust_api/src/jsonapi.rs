@@ -0,0 +1,338 @@
+use crate::http::Request;
+use crate::links;
+use crate::response;
+use serde_json::{Map, Value};
+use std::fmt;
+
+/*
+*  JSON:API
+*
+*  Clients that send `Accept: application/vnd.api+json` get responses shaped
+*  as JSON:API documents (https://jsonapi.org/format/1.1/). Handlers still
+*  produce plain JSON; anything with an `id` becomes a resource object with
+*  its other fields as `attributes` and links to itself, and users link to
+*  their addresses as a relationship. Addresses a user was fetched with
+*  (`?include=addresses`) are moved to the top-level `included`. Failures
+*  become `errors`, one per schema violation, pointing into the attributes.
+*  Successes that are not resources, such as status messages, are sent as
+*  `meta`.
+*
+*  Bodies sent as `application/vnd.api+json` are resource objects too; their
+*  `attributes` are what the handlers see.
+*/
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidDocument(&'static str);
+
+impl fmt::Display for InvalidDocument {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Whether `media_type` is JSON:API. The spec forbids parameters other than
+/// `ext` and `profile`, but they are not worth refusing a request over.
+pub fn is_jsonapi(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(MEDIA_TYPE)
+}
+
+/// The attributes of the resource object in a request body.
+pub fn decode(body: &[u8]) -> Result<Value, InvalidDocument> {
+    let document: Value =
+        serde_json::from_slice(body).map_err(|_| InvalidDocument("not a JSON document"))?;
+    let data = document
+        .get("data")
+        .and_then(Value::as_object)
+        .ok_or(InvalidDocument("no resource object in data"))?;
+    if !data.get("type").is_some_and(Value::is_string) {
+        return Err(InvalidDocument("resource object without a type"));
+    }
+    match data.get("attributes") {
+        Some(attributes @ Value::Object(_)) => Ok(attributes.clone()),
+        None => Ok(Value::Object(Map::new())),
+        Some(_) => Err(InvalidDocument("attributes are not an object")),
+    }
+}
+
+/// Turns a JSON or plain text response into a JSON:API document. Other
+/// responses are left alone.
+pub fn render(request: &Request, status_line: String, content: String) -> (String, String) {
+    let status = response::status_code(&status_line);
+    if matches!(status, 100..=199 | 204 | 304)
+        || !matches!(
+            response::header_value(&status_line, "Content-Type"),
+            None | Some("application/json")
+        )
+    {
+        return (status_line, content);
+    }
+
+    let value = serde_json::from_str(&content).unwrap_or(Value::String(content));
+    let document = if (200..300).contains(&status) {
+        let total = response::header_value(&status_line, "X-Total-Count");
+        success(request, value, total.and_then(|total| total.parse().ok()))
+    } else {
+        serde_json::json!({ "errors": errors(status, value) })
+    };
+    (
+        response::set_content_type(&status_line, MEDIA_TYPE),
+        document.to_string(),
+    )
+}
+
+fn success(request: &Request, value: Value, total: Option<u64>) -> Value {
+    let kind = if links::lists_addresses(request.path()) {
+        "addresses"
+    } else {
+        "users"
+    };
+    let mut included = Vec::new();
+    let data = match value {
+        Value::Object(object) if object.contains_key("id") => resource(kind, object, &mut included),
+        Value::Array(items) if items.iter().all(|item| item.get("id").is_some()) => Value::Array(
+            items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Value::Object(object) => Some(resource(kind, object, &mut included)),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        Value::String(message) => return serde_json::json!({ "meta": { "message": message } }),
+        other => return serde_json::json!({ "meta": other }),
+    };
+
+    let mut document = Map::new();
+    document.insert("data".to_string(), data);
+    if !included.is_empty() {
+        document.insert("included".to_string(), Value::Array(included));
+    }
+    if let Some(total) = total {
+        document.insert("meta".to_string(), serde_json::json!({ "total": total }));
+    }
+    document.insert(
+        "links".to_string(),
+        serde_json::json!({ "self": links::to(request.path()) }),
+    );
+    Value::Object(document)
+}
+
+fn resource(kind: &str, mut object: Map<String, Value>, included: &mut Vec<Value>) -> Value {
+    let id = object
+        .remove("id")
+        .and_then(|id| links::id(&id))
+        .unwrap_or_default();
+    let mut rendered = Map::new();
+    rendered.insert("type".to_string(), kind.into());
+    rendered.insert("id".to_string(), id.clone().into());
+
+    if kind == "users" {
+        let mut relationship = serde_json::json!({ "links": { "related": links::addresses(&id) } });
+        if let Some(Value::Array(addresses)) = object.remove("addresses") {
+            let mut linkage = Vec::new();
+            for address in addresses {
+                if let Value::Object(address) = address {
+                    let address = resource("addresses", address, &mut Vec::new());
+                    linkage.push(serde_json::json!({ "type": "addresses", "id": address["id"] }));
+                    included.push(address);
+                }
+            }
+            relationship["data"] = Value::Array(linkage);
+        }
+        rendered.insert("attributes".to_string(), Value::Object(object));
+        rendered.insert(
+            "relationships".to_string(),
+            serde_json::json!({ "addresses": relationship }),
+        );
+        rendered.insert(
+            "links".to_string(),
+            serde_json::json!({ "self": links::user(&id) }),
+        );
+    } else {
+        let user_id = object.get("user_id").and_then(links::id);
+        rendered.insert("attributes".to_string(), Value::Object(object));
+        if let Some(user_id) = user_id {
+            rendered.insert(
+                "links".to_string(),
+                serde_json::json!({ "self": links::address(&user_id, &id) }),
+            );
+        }
+    }
+    Value::Object(rendered)
+}
+
+/// Error objects for a failure. Schema violations get one each, pointing at
+/// the attribute; other JSON errors keep their `code` and text becomes the
+/// `title`.
+fn errors(status: u16, value: Value) -> Vec<Value> {
+    let status = status.to_string();
+    let mut body = match value {
+        Value::Object(body) => body,
+        Value::String(text) => {
+            return vec![serde_json::json!({ "status": status, "title": text })];
+        }
+        other => return vec![serde_json::json!({ "status": status, "detail": other })],
+    };
+    let title = body.remove("error").unwrap_or(Value::Null);
+    let details = body.remove("details");
+    if let Some(Value::Array(violations)) = body.remove("violations") {
+        let codes = details.as_ref().and_then(Value::as_array);
+        return violations
+            .iter()
+            .enumerate()
+            .map(|(i, violation)| {
+                let pointer = violation["pointer"].as_str().unwrap_or_default();
+                let mut error = serde_json::json!({
+                    "status": status,
+                    "title": title,
+                    "detail": violation["message"],
+                    "source": { "pointer": format!("/data/attributes{}", pointer) },
+                });
+                if let Some(code) = codes.and_then(|codes| codes.get(i)).map(|d| &d["code"]) {
+                    error["code"] = code.clone();
+                }
+                error
+            })
+            .collect();
+    }
+
+    let mut error = Map::new();
+    error.insert("status".to_string(), status.into());
+    if let Some(code) = body.remove("code") {
+        error.insert("code".to_string(), code);
+    }
+    if !title.is_null() {
+        error.insert("title".to_string(), title);
+    }
+    if let Some(message) = body.remove("message") {
+        error.insert("detail".to_string(), message);
+    }
+    if !body.is_empty() {
+        error.insert("meta".to_string(), Value::Object(body));
+    }
+    vec![Value::Object(error)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+
+    fn get(target: &str) -> Request {
+        Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap()
+    }
+
+    fn document(response: &(String, String)) -> Value {
+        serde_json::from_str(&response.1).unwrap()
+    }
+
+    #[test]
+    fn renders_users_with_their_addresses() {
+        let user = serde_json::json!({
+            "id": 7,
+            "name": "Ada",
+            "email": "ada@example.com",
+            "addresses": [{ "id": 3, "user_id": 7, "city": "London" }]
+        });
+        let response = render(
+            &get("/users/7?include=addresses"),
+            OK.to_string(),
+            user.to_string(),
+        );
+        assert_eq!(
+            response.0,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.api+json\r\n\r\n"
+        );
+        assert_eq!(
+            document(&response),
+            serde_json::json!({
+                "data": {
+                    "type": "users",
+                    "id": "7",
+                    "attributes": { "name": "Ada", "email": "ada@example.com" },
+                    "relationships": {
+                        "addresses": {
+                            "links": { "related": "/users/7/addresses" },
+                            "data": [{ "type": "addresses", "id": "3" }]
+                        }
+                    },
+                    "links": { "self": "/users/7" }
+                },
+                "included": [{
+                    "type": "addresses",
+                    "id": "3",
+                    "attributes": { "user_id": 7, "city": "London" },
+                    "links": { "self": "/users/7/addresses/3" }
+                }],
+                "links": { "self": "/users/7" }
+            })
+        );
+    }
+
+    #[test]
+    fn renders_lists_messages_and_errors() {
+        let list = render(
+            &get("/users"),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Total-Count: 1\r\n\r\n"
+                .to_string(),
+            r#"[{"id":1,"name":"Ada"}]"#.to_string(),
+        );
+        let list = document(&list);
+        assert_eq!(list["data"][0]["id"], "1");
+        assert_eq!(list["meta"], serde_json::json!({ "total": 1 }));
+
+        let created = render(&get("/users"), OK.to_string(), "User Created".to_string());
+        assert_eq!(
+            document(&created),
+            serde_json::json!({ "meta": { "message": "User Created" } })
+        );
+
+        let missing = render(
+            &get("/users/9"),
+            "HTTP/1.1 404 NOT FOUND\r\n\r\n".to_string(),
+            "Not Found URL".to_string(),
+        );
+        assert!(missing
+            .0
+            .contains("Content-Type: application/vnd.api+json\r\n"));
+        assert_eq!(
+            document(&missing),
+            serde_json::json!({ "errors": [{ "status": "404", "title": "Not Found URL" }] })
+        );
+
+        let invalid = render(
+            &get("/users"),
+            "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\nContent-Type: application/json\r\n\r\n"
+                .to_string(),
+            serde_json::json!({
+                "error": "Validation Failed",
+                "violations": [{ "pointer": "/email", "message": "Is not an email address" }],
+                "details": [{ "field": "email", "code": "invalid_format", "message": "..." }]
+            })
+            .to_string(),
+        );
+        assert_eq!(
+            document(&invalid)["errors"][0],
+            serde_json::json!({
+                "status": "422",
+                "code": "invalid_format",
+                "title": "Validation Failed",
+                "detail": "Is not an email address",
+                "source": { "pointer": "/data/attributes/email" }
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_resource_objects() {
+        let body = br#"{"data":{"type":"users","attributes":{"name":"Ada"}}}"#;
+        assert_eq!(decode(body), Ok(serde_json::json!({ "name": "Ada" })));
+        assert!(decode(br#"{"data":{"attributes":{}}}"#).is_err());
+        assert!(decode(br#"{"name":"Ada"}"#).is_err());
+        assert!(decode(br#"{"data":{"type":"users","attributes":[]}}"#).is_err());
+    }
+}
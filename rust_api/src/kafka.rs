@@ -0,0 +1,148 @@
+use crate::events::{Event, Publisher};
+use crate::https;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/*
+*  Kafka
+*
+*  Events are produced through the Kafka REST Proxy API (Confluent REST Proxy,
+*  Redpanda's HTTP proxy), keyed by user id so that the events of one user land
+*  on the same partition and stay in order. A record counts as published once
+*  the proxy reports the offset it was written at.
+*  https://docs.confluent.io/platform/current/kafka-rest/api.html#topics
+*/
+
+const PRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONTENT_TYPE: &str = "application/vnd.kafka.json.v2+json";
+
+pub struct KafkaPublisher {
+    agent: ureq::Agent,
+    /// `<KAFKA_REST_URL>/topics/<KAFKA_TOPIC>`
+    url: String,
+}
+
+impl KafkaPublisher {
+    pub fn new(rest_url: &str, topic: &str) -> KafkaPublisher {
+        KafkaPublisher {
+            agent: https::agent(PRODUCE_TIMEOUT),
+            url: format!("{}/topics/{}", rest_url.trim_end_matches('/'), topic),
+        }
+    }
+}
+
+impl Publisher for KafkaPublisher {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn publish(&mut self, event: &Event) -> Result<(), String> {
+        let body = json!({
+            "records": [{ "key": event.key(), "value": event.to_json() }],
+        });
+        let mut response = self
+            .agent
+            .post(&self.url)
+            .header("Content-Type", CONTENT_TYPE)
+            .header("Accept", "application/vnd.kafka.v2+json")
+            .send(body.to_string())
+            .map_err(|e| e.to_string())?;
+        let text = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{}: {}", response.status(), text));
+        }
+        produced(&text)
+    }
+}
+
+/// The proxy answers 200 even when a record was rejected by the broker; the
+/// offsets then carry the error.
+fn produced(response: &str) -> Result<(), String> {
+    let response: Value = serde_json::from_str(response).map_err(|e| e.to_string())?;
+    let offsets = response["offsets"]
+        .as_array()
+        .ok_or("response without offsets")?;
+    match offsets
+        .iter()
+        .find(|offset| !offset["error_code"].is_null())
+    {
+        Some(offset) => Err(format!(
+            "error {}: {}",
+            offset["error_code"],
+            offset["error"].as_str().unwrap_or_default()
+        )),
+        None if offsets.is_empty() => Err("no offset for the record".to_string()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn produces_a_keyed_record() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let proxy = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let answer =
+                r#"{"offsets":[{"partition":0,"offset":3,"error_code":null,"error":null}]}"#;
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.kafka.v2+json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                answer.len(),
+                answer
+            )
+            .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+
+        let event = Event::new(EventKind::Deleted, 42, None);
+        KafkaPublisher::new(&url, "user-events")
+            .publish(&event)
+            .unwrap();
+
+        let (request_line, body) = proxy.join().unwrap();
+        assert_eq!(request_line, "POST /topics/user-events HTTP/1.1\r\n");
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["records"][0]["key"], "42");
+        assert_eq!(body["records"][0]["value"]["type"], "user.deleted");
+    }
+
+    #[test]
+    fn reports_records_the_broker_rejected() {
+        assert!(produced(r#"{"offsets":[{"partition":0,"offset":1,"error_code":null}]}"#).is_ok());
+        assert_eq!(
+            produced(r#"{"offsets":[{"error_code":40403,"error":"Topic not found"}]}"#),
+            Err("error 40403: Topic not found".to_string())
+        );
+        assert!(produced(r#"{"offsets":[]}"#).is_err());
+    }
+}
@@ -0,0 +1,1335 @@
+use access::{AccessTokens, Grant, InvalidToken, Scope};
+use amqp::AmqpPublisher;
+use auth::AdminAuth;
+use backup::{Backup, BackupError, Tables};
+use cache::CacheControl;
+use chrono::{NaiveDate, Utc};
+use config::Config;
+use database_schema::DatabaseSchema;
+use email::{Mailer, Message, Template};
+use events::Publisher;
+use export::ExportStore;
+use flags::FeatureFlags;
+use handlers::{
+    handle_admin_page_request, handle_backup_request, handle_confirm_totp_request,
+    handle_count_request, handle_delete_address_request, handle_delete_flag_request,
+    handle_delete_log_level_request, handle_delete_maintenance_request, handle_delete_request,
+    handle_delete_session_request, handle_delete_totp_request, handle_drain_request,
+    handle_erase_request, handle_exists_request, handle_export_download_request,
+    handle_export_request, handle_get_address_request, handle_get_all_request,
+    handle_get_flags_request, handle_get_log_level_request, handle_get_maintenance_request,
+    handle_get_metadata_request, handle_get_request, handle_get_retention_request,
+    handle_get_schema_request, handle_get_session_request, handle_get_totp_request,
+    handle_import_request, handle_list_addresses_request, handle_lookup_request,
+    handle_metrics_request, handle_password_check_request, handle_patch_metadata_request,
+    handle_patch_request, handle_post_address_request, handle_post_email_request,
+    handle_post_recovery_codes_request, handle_post_request, handle_post_session_request,
+    handle_post_totp_request, handle_put_address_request, handle_put_flag_request,
+    handle_put_log_level_request, handle_put_maintenance_request, handle_put_metadata_request,
+    handle_put_request, handle_shutdown_request, handle_status_request, handle_stream_request,
+    handle_unlock_request, handle_upsert_request, handle_version_request, login_refused,
+    retry_later, retry_later_with,
+};
+use http::Request;
+use jobs::JobQueue;
+use kafka::KafkaPublisher;
+use limit::ConnectionLimit;
+use listener::{ListenAddr, ListenSpec, Listener, RouteGroup, Stream};
+use lockout::LoginGuard;
+use log::{debug, error, info, warn};
+use maintenance::Maintenance;
+use models::{
+    address_schema, email_schema, flag_schema, log_level_schema, lookup_schema, maintenance_schema,
+    metadata_schema, password_check_schema, session_schema, totp_confirm_schema, upsert_schema,
+    user_patch_schema, user_schema,
+};
+use nats::NatsPublisher;
+use password::PasswordPolicy;
+use pool::Pool;
+use repository::migrations;
+use repository::{
+    slow_query, InstrumentedRepository, PostgresUserRepository, QueryMetrics, Repository,
+    RepositoryError, UserRepository,
+};
+use router::{Deprecation, Outcome, Route, Router};
+use scheduler::Scheduler;
+use security_headers::Secured;
+use services::Services;
+use signature::SignedRequests;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::AsFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate crud_derive;
+
+mod access;
+mod admin_ui;
+mod amqp;
+mod auth;
+mod aws;
+mod backup;
+pub mod bench;
+mod body_log;
+pub mod build_info;
+mod cache;
+pub mod cidr;
+mod codec;
+pub mod config;
+mod context;
+mod crud;
+pub mod crypto;
+mod database_schema;
+mod email;
+mod envelope;
+mod events;
+mod export;
+mod extract;
+#[cfg(any(test, feature = "factories"))]
+pub mod factories;
+mod flags;
+mod hal;
+mod handlers;
+mod handoff;
+pub mod http;
+mod https;
+mod import;
+mod ip_filter;
+mod jobs;
+mod json_patch;
+mod jsonapi;
+mod kafka;
+mod lifecycle;
+mod limit;
+mod links;
+pub mod listener;
+mod lockout;
+pub mod log_file;
+pub mod logger;
+mod maintenance;
+mod metadata;
+mod models;
+pub mod multipart;
+mod nats;
+mod odata;
+mod password;
+mod pool;
+mod proxy;
+mod reload;
+mod repository;
+mod response;
+mod retention;
+mod router;
+mod rsql;
+mod scheduler;
+mod schema;
+mod security_headers;
+mod sentry;
+mod services;
+mod signature;
+mod statsd;
+mod status;
+mod systemd;
+mod tls;
+mod totp;
+mod vhost;
+
+const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
+const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
+const UNAUTHORIZED: &str =
+    "HTTP/1.1 401 UNAUTHORIZED\r\nWWW-Authenticate: Bearer realm=\"admin\"\r\n\r\n";
+const UNAUTHORIZED_OR_BASIC: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nWWW-Authenticate: Bearer realm=\"admin\"\r\nWWW-Authenticate: Basic realm=\"rust-crud\", charset=\"UTF-8\"\r\n\r\n";
+const BASIC_CHALLENGE: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nWWW-Authenticate: Basic realm=\"rust-crud\", charset=\"UTF-8\"\r\n\r\n";
+const INVALID_TOKEN: &str =
+    "HTTP/1.1 401 UNAUTHORIZED\r\nWWW-Authenticate: Bearer error=\"invalid_token\"\r\n\r\n";
+const FORBIDDEN: &str = "HTTP/1.1 403 FORBIDDEN\r\n\r\n";
+const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n\r\n";
+const PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 PAYLOAD TOO LARGE\r\n\r\n";
+const EXPECTATION_FAILED: &str = "HTTP/1.1 417 EXPECTATION FAILED\r\n\r\n";
+const MISDIRECTED_REQUEST: &str = "HTTP/1.1 421 MISDIRECTED REQUEST\r\n\r\n";
+const HEADERS_TOO_LARGE: &str = "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\n\r\n";
+const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n";
+/// What shed connections and requests during a drain are asked to wait;
+/// either is over in moments, or another instance takes over.
+const RETRY_SOON: Duration = Duration::from_secs(1);
+const NOT_IMPLEMENTED: &str = "HTTP/1.1 501 NOT IMPLEMENTED\r\n\r\n";
+const GATEWAY_TIMEOUT: &str = "HTTP/1.1 504 GATEWAY TIMEOUT\r\n\r\n";
+
+pub use repository::migrations::{MigrationStatus, Step};
+
+#[derive(Debug)]
+pub enum StartError {
+    Database(RepositoryError),
+    Bind(ListenAddr, io::Error),
+    Tls(String),
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartError::Database(e) => write!(f, "Setup Database Error: {}", e),
+            StartError::Bind(addr, e) => write!(f, "Bind Error on {}: {}", addr, e),
+            StartError::Tls(e) => write!(f, "TLS Setup Error: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command cannot run with this configuration.
+    Unsupported(&'static str),
+    Database(RepositoryError),
+    Backup(BackupError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unsupported(reason) => write!(f, "{}", reason),
+            CommandError::Database(e) => write!(f, "Database Error: {}", e),
+            CommandError::Backup(e) => write!(f, "Backup Error: {}", e),
+        }
+    }
+}
+
+/// Re-encrypts stored emails with the first of `EMAIL_ENCRYPTION_KEYS`, so
+/// older keys can be dropped afterwards, and fills in their blind index for
+/// `EMAIL_INDEX_KEY`. Returns the number of rows rewritten.
+pub fn rotate_email_key(config: &Config) -> Result<u64, CommandError> {
+    let (true, Some(url)) = (config.storage.is_postgres(), &config.database_url) else {
+        return Err(CommandError::Unsupported(
+            "Key rotation needs STORAGE=postgres",
+        ));
+    };
+    let cipher = crypto::FieldCipher::new(&config.email_keys).ok_or(CommandError::Unsupported(
+        "EMAIL_ENCRYPTION_KEYS is not set",
+    ))?;
+    info!("Re-encrypting emails with key {}", cipher.current_key_id());
+
+    let pool = Arc::new(Pool::new(url.clone(), 1, config.statement_timeout));
+    let fetch_size = i32::try_from(config.fetch_size.get()).unwrap_or(i32::MAX);
+    let mut repository = PostgresUserRepository::new(pool, fetch_size, Some(cipher));
+    if let Some(index) = &config.email_index_key {
+        repository = repository.with_email_index(index.clone());
+    }
+    repository
+        .rotate_email_key()
+        .map_err(CommandError::Database)
+}
+
+/// Writes a backup of the database to `path`, see `backup`.
+pub fn backup(config: &Config, path: &Path) -> Result<Tables, CommandError> {
+    let pool = command_pool(config, "Backups need STORAGE=postgres")?;
+    let mut file = File::create(path).map_err(|e| CommandError::Backup(BackupError::Io(e)))?;
+    Backup::new(pool)
+        .write(&mut file)
+        .map_err(CommandError::Backup)
+}
+
+/// Loads the backup at `path`, after running migrations so the tables
+/// exist. Tables must be empty unless `replace` is set.
+pub fn restore(config: &Config, path: &Path, replace: bool) -> Result<Tables, CommandError> {
+    let pool = command_pool(config, "Restoring needs STORAGE=postgres")?;
+    let mut file = File::open(path).map_err(|e| CommandError::Backup(BackupError::Io(e)))?;
+    PostgresUserRepository::new(pool.clone(), 1, None)
+        .migrate()
+        .map_err(CommandError::Database)?;
+    Backup::new(pool)
+        .restore(&mut file, replace)
+        .map_err(CommandError::Backup)
+}
+
+/// Applies the pending migrations, see `repository::migrations`. A dry run
+/// only returns what it would run.
+pub fn migrate(config: &Config, dry_run: bool) -> Result<Vec<Step>, CommandError> {
+    let pool = command_pool(config, "Migrations need STORAGE=postgres")?;
+    let mut client = pool.get().map_err(|e| CommandError::Database(e.into()))?;
+    migrations::up(&mut client, dry_run).map_err(CommandError::Database)
+}
+
+/// Rolls back the last `count` migrations applied.
+pub fn migrate_down(config: &Config, count: u32, dry_run: bool) -> Result<Vec<Step>, CommandError> {
+    let pool = command_pool(config, "Migrations need STORAGE=postgres")?;
+    let mut client = pool.get().map_err(|e| CommandError::Database(e.into()))?;
+    migrations::down(&mut client, count, dry_run).map_err(CommandError::Database)
+}
+
+/// Which migrations are applied and which are pending.
+pub fn migration_status(config: &Config) -> Result<Vec<MigrationStatus>, CommandError> {
+    let pool = command_pool(config, "Migrations need STORAGE=postgres")?;
+    let mut client = pool.get().map_err(|e| CommandError::Database(e.into()))?;
+    migrations::status(&mut client).map_err(CommandError::Database)
+}
+
+/// A single connection for a command that needs Postgres.
+fn command_pool(config: &Config, unsupported: &'static str) -> Result<Arc<Pool>, CommandError> {
+    match (config.storage.is_postgres(), &config.database_url) {
+        (true, Some(url)) => Ok(Arc::new(Pool::new(
+            url.clone(),
+            1,
+            config.statement_timeout,
+        ))),
+        _ => Err(CommandError::Unsupported(unsupported)),
+    }
+}
+
+/// Binds the listeners for `config` and serves until shut down.
+pub fn run(config: Config) -> Result<(), StartError> {
+    Server::bind(config)?.run();
+    Ok(())
+}
+
+/// A server whose listeners are bound but not yet accepting connections.
+pub struct Server {
+    app: Arc<App>,
+    listeners: Vec<(Listener, ListenSpec)>,
+}
+
+impl Server {
+    /// Sets up storage, runs migrations when enabled and binds every listener.
+    pub fn bind(config: Config) -> Result<Server, StartError> {
+        install_panic_hook();
+
+        let pool = match (config.storage.is_postgres(), &config.database_url) {
+            (true, Some(url)) => Some(Arc::new(Pool::new(
+                url.clone(),
+                config.pool_size.get(),
+                config.statement_timeout,
+            ))),
+            _ => None,
+        };
+        let queries = Arc::new(QueryMetrics::default());
+        let storage: Arc<dyn Repository> = Arc::new(InstrumentedRepository::new(
+            repository::from_config(&config, pool.clone(), publishers(&config))
+                .map_err(StartError::Database)?,
+            queries.clone(),
+        ));
+        if let Some(settings) = &config.nats {
+            if let Some(subject) = &settings.lookup_subject {
+                nats::serve_lookups(settings.url.clone(), subject.clone(), storage.clone());
+            }
+        }
+        let exports = Arc::new(ExportStore::default());
+        let jobs = Arc::new(JobQueue::new(config.job_workers.get()));
+        let mailer = config
+            .email
+            .clone()
+            .map(|settings| Arc::new(Mailer::new(settings)));
+        // Wrong admin tokens and Basic passwords are counted together.
+        let guard = Arc::new(LoginGuard::new(
+            config.admin_lockout_failures,
+            config.admin_lockout,
+        ));
+        let tokens = Arc::new(
+            AccessTokens::new(config.api_keys.clone(), config.jwt_secret.clone())
+                .with_signatures(SignedRequests::new(
+                    config.signing_keys.clone(),
+                    config.signature_window,
+                ))
+                .with_basic(
+                    config.basic_auth_users.clone(),
+                    config.basic_auth_groups.clone(),
+                    guard.clone(),
+                ),
+        );
+        let admin = Arc::new(
+            admin_auth(&config, guard, mailer.clone(), jobs.clone()).with_totp(storage.clone()),
+        );
+
+        slow_query::configure(config.slow_query_threshold, config.slow_query_redact);
+        body_log::configure(config.log_bodies, &config.log_redact_fields);
+        envelope::configure(config.response_envelope);
+        links::configure(&config.base_path);
+        if let Some(dsn) = &config.sentry_dsn {
+            sentry::init(
+                dsn.clone(),
+                config.sentry_release.clone(),
+                config.sentry_environment.clone(),
+            );
+        }
+        if let Some(addr) = &config.statsd_addr {
+            statsd::init(addr, &config.statsd_prefix, &config.statsd_tags);
+        }
+
+        let connections = ConnectionLimit::new(config.max_connections.get());
+        if statsd::enabled() {
+            let pool = pool.clone();
+            let connections = connections.clone();
+            thread::spawn(move || loop {
+                thread::sleep(GAUGE_INTERVAL);
+                record_gauges(pool.as_deref(), &connections);
+            });
+        }
+
+        let reload_pool = pool.clone();
+        let reload_connections = connections.clone();
+        let reloaded = reload::watch(config.clone(), move |config| {
+            if let Err(e) = logger::reconfigure(config) {
+                warn!("LOG_FILE not reopened: {}", e);
+            }
+            reload_connections.resize(config.max_connections.get());
+            slow_query::configure(config.slow_query_threshold, config.slow_query_redact);
+            body_log::configure(config.log_bodies, &config.log_redact_fields);
+            envelope::configure(config.response_envelope);
+            if let Some(pool) = &reload_pool {
+                pool.resize(config.pool_size.get());
+            }
+        });
+        if let Err(e) = reloaded {
+            warn!("Config reload disabled: {}", e);
+        }
+
+        if config.auto_migrate {
+            setup_database(storage.as_ref()).map_err(StartError::Database)?;
+        }
+
+        let mut scheduler = Scheduler::default();
+        if let Some(schedule) = &config.purge_schedule {
+            let storage = storage.clone();
+            let exports = exports.clone();
+            let admin = admin.clone();
+            let tokens = tokens.clone();
+            let keep = config.purge_after;
+            scheduler.add("purge", schedule.clone(), move || {
+                purge(storage.as_ref(), &exports, &admin, &tokens, keep)
+            });
+        }
+        if let (Some(schedule), false) = (
+            &config.retention_schedule,
+            config.retention_rules.is_empty(),
+        ) {
+            let storage = storage.clone();
+            let rules = config.retention_rules.clone();
+            scheduler.add("retention", schedule.clone(), move || {
+                retention::enforce(&rules, storage.as_ref(), storage.as_ref())
+            });
+        }
+        scheduler.start();
+
+        let tls = match &config.tls {
+            Some(settings) => Some(tls::server_config(settings).map_err(StartError::Tls)?),
+            None => None,
+        };
+        let mut listeners = Vec::new();
+        for spec in &config.listen {
+            let listener = Listener::bind(&spec.addr, tls.clone())
+                .map_err(|e| StartError::Bind(spec.addr.clone(), e))?;
+            listeners.push((listener, spec.clone()));
+        }
+        systemd::close_unused();
+
+        Ok(Server {
+            app: Arc::new(App {
+                services: Services {
+                    repository: storage.clone(),
+                    addresses: storage.clone(),
+                    totp: storage.clone(),
+                    jobs,
+                    exports,
+                    export_async_threshold: config.export_async_threshold,
+                    base_path: config.base_path.clone(),
+                    lifecycle: Arc::default(),
+                    maintenance: Arc::new(Maintenance::new(
+                        maintenance::Window {
+                            message: config.maintenance_message.clone(),
+                            retry_after: config.maintenance_retry_after,
+                        },
+                        config.maintenance,
+                    )),
+                    flags: Arc::new(FeatureFlags::new(storage, config.flag_cache_ttl)),
+                    tokens,
+                    admin,
+                    mailer,
+                    passwords: Arc::new(PasswordPolicy::new(
+                        config.password_min_length,
+                        config.password_breach_check,
+                        &config.password_breach_api_url,
+                    )),
+                    retention: config.retention_rules.clone(),
+                    backup: pool.clone().map(|pool| Arc::new(Backup::new(pool))),
+                    schema: pool.clone().map(|pool| Arc::new(DatabaseSchema::new(pool))),
+                    queries,
+                    status: Arc::default(),
+                },
+                config,
+                router: routes(),
+                connections,
+            }),
+            listeners,
+        })
+    }
+
+    /// The address each listener is bound to, in `LISTEN` order. Differs from
+    /// the configured one when port 0 was asked for.
+    pub fn local_addrs(&self) -> Vec<ListenAddr> {
+        self.listeners
+            .iter()
+            .map(|(listener, spec)| listener.local_addr().unwrap_or_else(|_| spec.addr.clone()))
+            .collect()
+    }
+
+    /// Accepts connections on every listener until `POST /admin/shutdown`,
+    /// then returns once the connections in flight are done or the drain
+    /// timeout runs out.
+    pub fn run(self) {
+        let app = self.app;
+        let sockets = self
+            .listeners
+            .iter()
+            .filter_map(|(listener, spec)| {
+                Some(handoff::Socket {
+                    name: spec.addr.socket_name(),
+                    fd: listener.as_fd().try_clone_to_owned().ok()?,
+                })
+            })
+            .collect();
+        if let Err(e) = handoff::watch(sockets, app.services.lifecycle.clone()) {
+            warn!("SIGUSR2 handoff is unavailable: {}", e);
+        }
+        for (listener, spec) in self.listeners {
+            info!("Server started on {}", spec.addr);
+            let app = app.clone();
+            thread::spawn(move || serve(listener, spec, app));
+        }
+        handoff::ready();
+
+        let lifecycle = &app.services.lifecycle;
+        lifecycle.wait_for_shutdown();
+        let deadline = Instant::now() + app.config.drain_timeout;
+        while app.connections.active() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        match app.connections.active() {
+            0 => info!("Server stopped"),
+            left => warn!("Server stopped with {} connections still open", left),
+        }
+    }
+}
+
+/// State shared by every connection.
+struct App {
+    config: Config,
+    services: Services,
+    router: Router,
+    connections: Arc<ConnectionLimit>,
+}
+
+fn serve(listener: Listener, spec: ListenSpec, app: Arc<App>) {
+    //handle the client
+    loop {
+        // The process that took over accepts from here on.
+        if app.services.lifecycle.handed_off() {
+            return;
+        }
+        match listener.accept() {
+            Ok(mut stream) => {
+                let slot = match app.connections.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        debug!("Connection limit reached, shedding connection");
+                        let (head, body) = retry_later(
+                            "503 SERVICE UNAVAILABLE",
+                            RETRY_SOON,
+                            "Service Unavailable",
+                            "connection_limit",
+                        );
+                        stream
+                            .write_all(format!("{}{}", head, body).as_bytes())
+                            .ok();
+                        continue;
+                    }
+                };
+                debug!("Connection established on {}", spec.addr);
+                let spec = spec.clone();
+                let app = app.clone();
+                thread::spawn(move || {
+                    handle_client(stream, &spec, &app);
+                    drop(slot);
+                });
+            }
+            Err(e) => {
+                error!("Connection Error: {}", e);
+            }
+        }
+    }
+}
+
+fn handle_client(mut stream: Stream, spec: &ListenSpec, app: &App) {
+    let mut buffer = vec![0; app.config.read_buffer_size.get()];
+    match stream.read(&mut buffer) {
+        Ok(0) => return,
+        Ok(size) => buffer.truncate(size),
+        // rustls reports failed handshakes, such as a missing client
+        // certificate, as invalid data; that is the client's problem.
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            warn!("TLS handshake failed: {}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to read from connection: {}", e);
+            return;
+        }
+    }
+    let mut peer = stream.peer_ip();
+    // Known once the first read finished the TLS handshake.
+    let client_subject = stream.client_subject();
+
+    if app.config.proxy_protocol && peer.is_some() {
+        match proxy::read_proxy_header(&mut stream, &mut buffer) {
+            Ok(Some(header)) => {
+                buffer.drain(..header.length);
+                peer = header.source.or(peer);
+            }
+            Ok(None) => {
+                warn!("Dropping connection from {:?} without a PROXY header", peer);
+                return;
+            }
+            Err(e) => {
+                error!("Failed to read from connection: {}", e);
+                return;
+            }
+        }
+    }
+
+    let max_head_size = app.config.max_head_size.get();
+    match http::read_head(
+        &mut stream,
+        &mut buffer,
+        app.config.read_buffer_size.get(),
+        max_head_size,
+    ) {
+        Ok(Some(_)) => {}
+        Ok(None) if buffer.len() > max_head_size => {
+            let response = format!("{}{}", HEADERS_TOO_LARGE, "Request Header Fields Too Large");
+            stream.write_all(response.as_bytes()).ok();
+            stream.linger();
+            return;
+        }
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to read from connection: {}", e);
+            return;
+        }
+    }
+
+    match Request::parse(&buffer) {
+        Some(mut request) => {
+            request.client_addr = proxy::client_addr(peer, &request, &app.config.trusted_proxies);
+            request.client_subject = client_subject;
+            let _scope = context::enter(context::for_request(&request));
+            let started = Instant::now();
+            let in_flight = app.services.status.begin();
+            let status = respond(&mut stream, &mut request, spec, app);
+            drop(in_flight);
+            record_request(&request, &status, started.elapsed());
+            // 503s are on purpose: draining, maintenance or too many connections.
+            if status.starts_with('5') && status != "503" {
+                sentry::capture_response(&request, &status);
+            }
+            info!(
+                "{} \"{} {}\" {}",
+                request
+                    .client_addr
+                    .map_or("-".to_string(), |ip| ip.to_string()),
+                request.method,
+                request.redacted_target(),
+                status
+            );
+        }
+        None => {
+            let response = format!("{}{}", NOT_FOUND, "Not Found URL");
+            stream.write_all(response.as_bytes()).ok();
+        }
+    }
+}
+
+/// How often gauges are sampled for StatsD.
+const GAUGE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn record_gauges(pool: Option<&Pool>, connections: &ConnectionLimit) {
+    statsd::gauge("http.connections_active", connections.active() as u64, &[]);
+    if let Some(pool) = pool {
+        let stats = pool.stats();
+        statsd::gauge("db.pool.max_size", stats.max_size as u64, &[]);
+        statsd::gauge("db.pool.open", stats.open as u64, &[]);
+        statsd::gauge("db.pool.idle", stats.idle as u64, &[]);
+        statsd::gauge("db.pool.in_use", stats.in_use as u64, &[]);
+        statsd::gauge("db.pool.waiting", stats.waiting as u64, &[]);
+    }
+}
+
+fn record_request(request: &Request, status: &str, elapsed: Duration) {
+    let route = context::current()
+        .and_then(|context| context.route)
+        .unwrap_or("unmatched");
+    let tags = [
+        ("method", request.method.as_str()),
+        ("route", route),
+        ("status", status),
+    ];
+    statsd::count("http.requests", 1, &tags);
+    statsd::timing("http.request_duration", elapsed, &tags);
+}
+
+/// Reads the rest of the body announced by `Content-Length`, sending the
+/// interim `100 Continue` first when the client waits for it. For an `upload`
+/// only the checks are made, leaving the rest for its handler to read. Returns
+/// the response to send instead when the body is refused.
+fn read_body(
+    stream: &mut Stream,
+    request: &mut Request,
+    max_body_size: usize,
+    upload: bool,
+) -> Result<(), (String, String)> {
+    let expects_continue = match request.header("Expect") {
+        None => false,
+        Some(expect) if expect.eq_ignore_ascii_case("100-continue") => true,
+        Some(_) => {
+            return Err((
+                EXPECTATION_FAILED.to_string(),
+                "Unsupported Expectation".to_string(),
+            ))
+        }
+    };
+
+    let length = match request.header("Content-Length") {
+        None => return Ok(()),
+        Some(_) => request.content_length().ok_or_else(|| {
+            (
+                BAD_REQUEST.to_string(),
+                "Invalid Content-Length".to_string(),
+            )
+        })?,
+    };
+    if length > max_body_size {
+        // With 100-continue the client has not sent the body yet, and 417 tells
+        // it not to.
+        let status_line = if expects_continue {
+            EXPECTATION_FAILED
+        } else {
+            PAYLOAD_TOO_LARGE
+        };
+        return Err((status_line.to_string(), "Body Too Large".to_string()));
+    }
+
+    if request.body.len() < length {
+        if expects_continue {
+            stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .map_err(|_| incomplete_body())?;
+        }
+        if upload {
+            request.body.truncate(length);
+            return Ok(());
+        }
+        let mut rest = vec![0; length - request.body.len()];
+        stream
+            .read_exact(&mut rest)
+            .map_err(|_| incomplete_body())?;
+        request.body.extend_from_slice(&rest);
+    }
+    request.body.truncate(length);
+    Ok(())
+}
+
+fn incomplete_body() -> (String, String) {
+    (BAD_REQUEST.to_string(), "Incomplete Body".to_string())
+}
+
+/// Routes the request and writes the response. Returns the status code sent,
+/// for the access log. A panicking handler is answered with a 500 as long as
+/// none of its response has gone out yet.
+fn respond(stream: &mut Stream, request: &mut Request, spec: &ListenSpec, app: &App) -> String {
+    let headers = app
+        .config
+        .security_headers
+        .for_connection(spec.addr.is_tls());
+    let upload = app.router.takes_upload(request, &app.config.base_path);
+    let max_body_size = if upload {
+        app.config.max_upload_size
+    } else {
+        app.config.max_body_size
+    };
+    if let Err((status_line, content)) = read_body(stream, request, max_body_size, upload) {
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        let response = format!("{}{}", status_line, content);
+        let mut out = Secured::new(stream, headers);
+        out.write_all(response.as_bytes())
+            .and_then(|()| out.flush())
+            .ok();
+        stream.linger();
+        return status;
+    }
+    // Kept out of the request, so an upload is never mistaken for a small
+    // body: its handler reads this and then the rest of it.
+    let buffered = if upload {
+        std::mem::take(&mut request.body)
+    } else {
+        Vec::new()
+    };
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        // Signatures cover the body as sent, before decoding.
+        let grant = app.services.tokens.authenticate(request);
+        match codec::decode_request(request) {
+            Ok(()) => {
+                body_log::request(&request.body);
+                match route(request, spec, app, grant) {
+                    Outcome::Upload(handler) => {
+                        let length = request.content_length().unwrap_or(0);
+                        let mut body = http::Body::new(&buffered, &mut *stream, length);
+                        let (status_line, content) = handler(request, &app.services, &mut body);
+                        Outcome::Response(status_line, content)
+                    }
+                    outcome => outcome,
+                }
+            }
+            Err(e) => Outcome::Response(BAD_REQUEST.to_string(), format!("Invalid Body: {}", e)),
+        }
+    }))
+    .unwrap_or_else(|_| {
+        Outcome::Response(INTERNAL_SERVER_ERROR.to_string(), PANIC_MESSAGE.to_string())
+    });
+
+    let cache_control = matches!(request.method.as_str(), "GET" | "HEAD")
+        .then_some(app.config.cache_control.as_str());
+    match outcome {
+        Outcome::Response(status_line, content) => {
+            let status = status_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            body_log::response(&status, content.as_bytes());
+            let (status_line, mut content) = codec::encode_response(request, status_line, content);
+            // HEAD answers with the headers GET would send, but never a body.
+            if request.method == "HEAD" {
+                content.clear();
+            }
+            let mut secured = Secured::new(stream, headers);
+            let mut out = CacheControl::new(&mut secured, cache_control);
+            let written = out
+                .write_all(status_line.as_bytes())
+                .and_then(|()| out.write_all(&content))
+                .and_then(|()| out.flush());
+            if let Err(e) = written {
+                debug!("Failed to write response: {}", e);
+            }
+            status
+        }
+        Outcome::Upload(_) => unreachable!("uploads are answered while routing"),
+        Outcome::Stream(handler) => {
+            let mut out = Tracked {
+                inner: stream,
+                written: false,
+            };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut out = Secured::new(&mut out, headers);
+                let mut out = CacheControl::new(&mut out, cache_control);
+                let status = handler(request, &app.services, &mut out)?;
+                out.flush().map(|()| status)
+            }));
+            match result {
+                Ok(Ok(status)) => status.to_string(),
+                Ok(Err(e)) => {
+                    warn!("Streaming response aborted: {}", e);
+                    "-".to_string()
+                }
+                Err(_) if !out.written => {
+                    let response = format!("{}{}", INTERNAL_SERVER_ERROR, PANIC_MESSAGE);
+                    out.inner.write_all(response.as_bytes()).ok();
+                    "500".to_string()
+                }
+                Err(_) => "-".to_string(),
+            }
+        }
+    }
+}
+
+const PANIC_MESSAGE: &str = "Internal Server Error";
+
+/// Remembers whether anything reached the client.
+struct Tracked<'a> {
+    inner: &'a mut Stream,
+    written: bool,
+}
+
+impl Write for Tracked<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written |= !buf.is_empty();
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sends panic messages through the logger, tagged with the request being
+/// handled, instead of printing them to stderr, and on to Sentry.
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        panic::set_hook(Box::new(|info| {
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("Box<dyn Any>");
+            let location = info
+                .location()
+                .map_or("unknown location".to_string(), |l| l.to_string());
+            error!(
+                "Panic in request {} at {}: {}",
+                context::request_id().unwrap_or_else(|| "-".to_string()),
+                location,
+                message
+            );
+            sentry::capture_panic(message, &location);
+        }));
+    });
+}
+
+/// Strips `BASE_PATH` off the request, so everything downstream sees paths as
+/// if the API were mounted at the root. `grant` is what the request's API
+/// key, JWT or signature allows. With `VIRTUAL_HOSTS` set, the `Host` header
+/// decides which route groups the request can reach.
+fn route(
+    request: &mut Request,
+    spec: &ListenSpec,
+    app: &App,
+    grant: Result<Option<Grant>, InvalidToken>,
+) -> Outcome {
+    let hosted = match request.header("Host") {
+        _ if app.config.virtual_hosts.is_empty() => &RouteGroup::ALL[..],
+        None => return Outcome::Response(BAD_REQUEST.to_string(), "Missing Host".to_string()),
+        Some(host) => match vhost::groups(&app.config.virtual_hosts, host) {
+            Some(groups) => groups,
+            None => {
+                debug!("Refused a request for host {:?}", host);
+                return Outcome::Response(
+                    MISDIRECTED_REQUEST.to_string(),
+                    "Unknown Host".to_string(),
+                );
+            }
+        },
+    };
+    if !request.strip_prefix(&app.config.base_path) {
+        return Outcome::Response(NOT_FOUND.to_string(), "Not Found URL".to_string());
+    }
+    let group = RouteGroup::of_path(request.path());
+    if !spec.serves(group) || !hosted.contains(&group) {
+        return Outcome::Response(NOT_FOUND.to_string(), "Not Found URL".to_string());
+    }
+    if !app.config.ip_filter.permits(group, request.client_addr) {
+        warn!(
+            "Refused {} {} from {}, which the IP filter does not allow",
+            request.method,
+            request.path(),
+            request
+                .client_addr
+                .map_or("-".to_string(), |ip| ip.to_string())
+        );
+        return Outcome::Response(FORBIDDEN.to_string(), "Forbidden".to_string());
+    }
+    // Admin routes stay reachable, so a drain can still be followed by a
+    // shutdown and maintenance can be ended.
+    if group == RouteGroup::Api && !app.services.lifecycle.accepting() {
+        let (head, body) = retry_later(
+            "503 SERVICE UNAVAILABLE",
+            RETRY_SOON,
+            "Service Unavailable",
+            "draining",
+        );
+        return Outcome::Response(head, body);
+    }
+    if let Some(window) = app
+        .services
+        .maintenance
+        .window()
+        .filter(|_| group == RouteGroup::Api)
+    {
+        let (head, body) = retry_later_with(
+            "503 SERVICE UNAVAILABLE",
+            window.retry_after,
+            serde_json::json!({
+                "error": "Service Unavailable",
+                "code": "maintenance",
+                "message": window.message,
+            }),
+        );
+        return Outcome::Response(head, body);
+    }
+    // Where Basic credentials are not taken they are ignored, as they were
+    // before, since a proxy in front may be using them.
+    let grant = match grant {
+        Ok(None) if app.services.tokens.accepts_basic(group) => app.services.tokens.log_in(request),
+        grant => grant,
+    };
+    let mut grant = match grant {
+        Ok(grant) => grant,
+        Err(InvalidToken::Throttled(wait)) => {
+            let (headers, body) = login_refused(auth::Denied::Throttled(wait));
+            return Outcome::Response(headers, body);
+        }
+        Err(InvalidToken::Locked(wait)) => {
+            let (headers, body) = login_refused(auth::Denied::Locked(wait));
+            return Outcome::Response(headers, body);
+        }
+        Err(e @ (InvalidToken::Credentials | InvalidToken::Malformed))
+            if access::is_basic(request) =>
+        {
+            warn!("Basic credentials refused: {}", e);
+            return Outcome::Response(BASIC_CHALLENGE.to_string(), "Unauthorized".to_string());
+        }
+        Err(e) => {
+            debug!("Bearer token refused: {}", e);
+            return Outcome::Response(INVALID_TOKEN.to_string(), "Invalid Token".to_string());
+        }
+    };
+    // An API key or JWT without the admin scope is refused by the route, not
+    // counted as a wrong admin token.
+    if group == RouteGroup::Admin && grant.is_none() {
+        match app.services.admin.authorize(request) {
+            Ok(()) => grant = Some(Grant::admin()),
+            Err(auth::Denied::Disabled) => {
+                return Outcome::Response(NOT_FOUND.to_string(), "Not Found URL".to_string())
+            }
+            Err(denied @ (auth::Denied::Throttled(_) | auth::Denied::Locked(_))) => {
+                let (headers, body) = login_refused(denied);
+                return Outcome::Response(headers, body);
+            }
+            Err(_) if auth::is_public(request) => {}
+            // A right bearer token once two-factor login is on.
+            Err(denied @ (auth::Denied::CodeRequired | auth::Denied::Unavailable(_))) => {
+                let (headers, body) = login_refused(denied);
+                return Outcome::Response(headers, body);
+            }
+            Err(auth::Denied::Forged) => {
+                warn!("Admin request without a valid CSRF token refused");
+                return Outcome::Response(FORBIDDEN.to_string(), "Invalid CSRF Token".to_string());
+            }
+            Err(_) if app.services.tokens.accepts_basic(group) => {
+                return Outcome::Response(
+                    UNAUTHORIZED_OR_BASIC.to_string(),
+                    "Unauthorized".to_string(),
+                )
+            }
+            Err(_) => {
+                return Outcome::Response(UNAUTHORIZED.to_string(), "Unauthorized".to_string())
+            }
+        }
+    }
+
+    app.router
+        .dispatch(request, &app.services, grant.as_ref())
+        .unwrap_or_else(|| Outcome::Response(NOT_FOUND.to_string(), "Not Found URL".to_string()))
+}
+
+fn routes() -> Router {
+    Router::new()
+        .route(
+            Route::new("GET", "/users/:id/addresses", handle_list_addresses_request)
+                .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::new(
+                "GET",
+                "/users/:id/addresses/:address_id",
+                handle_get_address_request,
+            )
+            .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::new("POST", "/users/:id/addresses", handle_post_address_request)
+                .with_schema(address_schema())
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new(
+                "PUT",
+                "/users/:id/addresses/:address_id",
+                handle_put_address_request,
+            )
+            .with_schema(address_schema())
+            .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new(
+                "DELETE",
+                "/users/:id/addresses/:address_id",
+                handle_delete_address_request,
+            )
+            .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("GET", "/users/:id/metadata", handle_get_metadata_request)
+                .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::new("PUT", "/users/:id/metadata", handle_put_metadata_request)
+                .with_schema(metadata_schema())
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new(
+                "PATCH",
+                "/users/:id/metadata",
+                handle_patch_metadata_request,
+            )
+            .with_schema(metadata_schema())
+            .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("POST", "/users/lookup", handle_lookup_request)
+                .with_schema(lookup_schema())
+                .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::upload("POST", "/users/bulk", handle_import_request).requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("POST", "/users", handle_post_request)
+                .with_schema(user_schema())
+                .requires(Scope::UsersWrite),
+        )
+        // The singular path predates the plural one every other route uses.
+        .route(
+            Route::new("GET", "/user/:id", handle_get_request)
+                .deprecated(Deprecation {
+                    since: NaiveDate::from_ymd_opt(2026, 10, 14).unwrap(),
+                    sunset: NaiveDate::from_ymd_opt(2027, 4, 30).unwrap(),
+                    successor: "/users/:id",
+                })
+                .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::new("GET", "/exports/:token", handle_export_download_request)
+                .requires(Scope::UsersRead),
+        )
+        .route(
+            Route::new("GET", "/users/:id/export", handle_export_request)
+                .requires(Scope::UsersRead),
+        )
+        // Ahead of `/users/:id`, which would take `stream` for an id.
+        .route(
+            Route::stream("GET", "/users/stream", handle_stream_request).requires(Scope::UsersRead),
+        )
+        .route(Route::new("HEAD", "/users/:id", handle_exists_request).requires(Scope::UsersRead))
+        .route(Route::new("HEAD", "/users", handle_count_request).requires(Scope::UsersRead))
+        .route(Route::new("GET", "/users/:id", handle_get_request).requires(Scope::UsersRead))
+        .route(Route::stream("GET", "/users", handle_get_all_request).requires(Scope::UsersRead))
+        .route(
+            Route::new("PUT", "/users/by-email/:email", handle_upsert_request)
+                .with_schema(upsert_schema())
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("PUT", "/users/:id", handle_put_request)
+                .with_schema(user_schema())
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("PATCH", "/users/:id", handle_patch_request)
+                .with_schema(user_patch_schema())
+                .accepting(&[json_patch::MEDIA_TYPE, json_patch::MERGE_MEDIA_TYPE])
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("DELETE", "/users/:id/personal-data", handle_erase_request)
+                .requires(Scope::UsersWrite),
+        )
+        .route(
+            Route::new("DELETE", "/users/:id", handle_delete_request).requires(Scope::UsersWrite),
+        )
+        .route(Route::new("GET", "/version", handle_version_request))
+        .route(Route::new("GET", "/metrics", handle_metrics_request).requires(Scope::Admin))
+        .route(Route::new("GET", "/status", handle_status_request).requires(Scope::Admin))
+        .route(Route::new("POST", "/admin/drain", handle_drain_request).requires(Scope::Admin))
+        .route(
+            Route::new("POST", "/admin/shutdown", handle_shutdown_request).requires(Scope::Admin),
+        )
+        .route(
+            Route::new("GET", "/admin/log-level", handle_get_log_level_request)
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("PUT", "/admin/log-level", handle_put_log_level_request)
+                .with_schema(log_level_schema())
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new(
+                "DELETE",
+                "/admin/log-level",
+                handle_delete_log_level_request,
+            )
+            .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("GET", "/admin/maintenance", handle_get_maintenance_request)
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("PUT", "/admin/maintenance", handle_put_maintenance_request)
+                .with_schema(maintenance_schema())
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new(
+                "DELETE",
+                "/admin/maintenance",
+                handle_delete_maintenance_request,
+            )
+            .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("POST", "/admin/users/:id/emails", handle_post_email_request)
+                .with_schema(email_schema())
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new(
+                "POST",
+                "/admin/passwords/check",
+                handle_password_check_request,
+            )
+            .with_schema(password_check_schema())
+            .requires(Scope::PasswordsCheck),
+        )
+        .route(Route::new("GET", "/admin", handle_admin_page_request))
+        .route(
+            Route::new("GET", "/admin/session", handle_get_session_request).requires(Scope::Admin),
+        )
+        .route(
+            Route::new("POST", "/admin/session", handle_post_session_request)
+                .with_schema(session_schema()),
+        )
+        .route(
+            Route::new("DELETE", "/admin/session", handle_delete_session_request)
+                .requires(Scope::Admin),
+        )
+        .route(Route::new("GET", "/admin/unlock", handle_unlock_request))
+        .route(Route::new("GET", "/admin/totp", handle_get_totp_request).requires(Scope::Admin))
+        .route(Route::new("POST", "/admin/totp", handle_post_totp_request).requires(Scope::Admin))
+        .route(
+            Route::new("POST", "/admin/totp/confirm", handle_confirm_totp_request)
+                .with_schema(totp_confirm_schema())
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new(
+                "POST",
+                "/admin/totp/recovery-codes",
+                handle_post_recovery_codes_request,
+            )
+            .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("DELETE", "/admin/totp", handle_delete_totp_request).requires(Scope::Admin),
+        )
+        .route(Route::stream("GET", "/admin/backup", handle_backup_request).requires(Scope::Admin))
+        .route(Route::new("GET", "/admin/schema", handle_get_schema_request).requires(Scope::Admin))
+        .route(
+            Route::new("GET", "/admin/retention", handle_get_retention_request)
+                .requires(Scope::Admin),
+        )
+        .route(Route::new("GET", "/admin/flags", handle_get_flags_request).requires(Scope::Admin))
+        .route(
+            Route::new("PUT", "/admin/flags/:name", handle_put_flag_request)
+                .with_schema(flag_schema())
+                .requires(Scope::Admin),
+        )
+        .route(
+            Route::new("DELETE", "/admin/flags/:name", handle_delete_flag_request)
+                .requires(Scope::Admin),
+        )
+}
+
+/// Where user change events go, in the order they are published to.
+fn publishers(config: &Config) -> Vec<Box<dyn Publisher>> {
+    let mut publishers: Vec<Box<dyn Publisher>> = Vec::new();
+    if let Some(url) = &config.kafka_rest_url {
+        publishers.push(Box::new(KafkaPublisher::new(url, &config.kafka_topic)));
+    }
+    if let Some(settings) = &config.amqp {
+        publishers.push(Box::new(AmqpPublisher::new(settings.clone())));
+    }
+    if let Some(settings) = &config.nats {
+        publishers.push(Box::new(NatsPublisher::new(settings.clone())));
+    }
+    publishers
+}
+
+/// Admin token checks, mailing the unlock link to `ADMIN_EMAIL` whenever
+/// wrong tokens lock the admin account.
+fn admin_auth(
+    config: &Config,
+    guard: Arc<LoginGuard>,
+    mailer: Option<Arc<Mailer>>,
+    jobs: Arc<JobQueue>,
+) -> AdminAuth {
+    let admin = AdminAuth::new(
+        config.admin_token.clone(),
+        config.admin_cookie_secure,
+        guard,
+    );
+    let (Some(mailer), Some(to)) = (mailer, config.admin_email.clone()) else {
+        return admin;
+    };
+    let unlock_url = format!(
+        "{}{}/admin/unlock?token=",
+        config.public_url.as_deref().unwrap_or_default(),
+        config.base_path
+    );
+    admin.on_lock(move |token| {
+        let link = format!("{}{}", unlock_url, token);
+        let message = Message::render_to(Template::AdminLocked, "admin", &to, Some(&link));
+        mailer.send_later(&jobs, message);
+    })
+}
+
+/// Permanently deletes what is no longer needed: rows done with longer than
+/// `keep` ago, expired data exports, expired admin sessions, failed admin
+/// logins that no longer count and nonces of expired request signatures.
+fn purge(
+    repository: &dyn UserRepository,
+    exports: &ExportStore,
+    admin: &AdminAuth,
+    tokens: &AccessTokens,
+    keep: Duration,
+) -> Result<String, String> {
+    let keep = chrono::Duration::from_std(keep).map_err(|e| e.to_string())?;
+    let mut purged = repository
+        .purge(Utc::now() - keep)
+        .map_err(|e| e.to_string())?;
+    purged.insert("exports", exports.purge_expired() as u64);
+    purged.insert("admin_sessions", admin.purge_expired() as u64);
+    purged.insert("login_failures", admin.purge_failures() as u64);
+    purged.insert("signature_nonces", tokens.purge_expired() as u64);
+    let mut report = Vec::new();
+    for (table, rows) in purged {
+        statsd::count("purge.rows", rows, &[("table", table)]);
+        report.push(format!("{} {}", rows, table));
+    }
+    Ok(format!("purged {}", report.join(", ")))
+}
+
+fn setup_database(repository: &dyn UserRepository) -> Result<(), RepositoryError> {
+    debug!("Running migrations");
+    repository.migrate()
+}
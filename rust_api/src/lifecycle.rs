@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/*
+*  Lifecycle
+*
+*  Rollouts without access to signals go through the admin API instead. Once
+*  draining, the server answers new API requests with 503 while the ones in
+*  flight finish; a shutdown also makes `Server::run` return once they have.
+*
+*  After a handoff (see `crate::handoff`) another process serves the
+*  listeners. This one stops accepting connections, serves those it has
+*  in whatever phase it is in, and `Server::run` returns once they are done.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Serving,
+    Draining,
+    ShuttingDown,
+}
+
+pub struct Lifecycle {
+    phase: Mutex<Phase>,
+    /// Set under the `phase` lock, so waiters see it.
+    handed_off: AtomicBool,
+    changed: Condvar,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle {
+            phase: Mutex::new(Phase::Serving),
+            handed_off: AtomicBool::new(false),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl Lifecycle {
+    pub fn phase(&self) -> Phase {
+        *self.phase.lock().unwrap()
+    }
+
+    /// Whether new API requests are still served.
+    pub fn accepting(&self) -> bool {
+        self.phase() == Phase::Serving
+    }
+
+    /// Stops serving new API requests. Does nothing once shutting down.
+    pub fn drain(&self) {
+        let mut phase = self.phase.lock().unwrap();
+        if *phase == Phase::Serving {
+            *phase = Phase::Draining;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Stops serving new API requests and lets `wait_for_shutdown` return.
+    pub fn shutdown(&self) {
+        *self.phase.lock().unwrap() = Phase::ShuttingDown;
+        self.changed.notify_all();
+    }
+
+    /// Stops accepting connections and lets `wait_for_shutdown` return.
+    pub fn hand_off(&self) {
+        let _phase = self.phase.lock().unwrap();
+        self.handed_off.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    /// Whether another process took over the listeners.
+    pub fn handed_off(&self) -> bool {
+        self.handed_off.load(Ordering::SeqCst)
+    }
+
+    pub fn wait_for_shutdown(&self) {
+        let mut phase = self.phase.lock().unwrap();
+        while *phase != Phase::ShuttingDown && !self.handed_off() {
+            phase = self.changed.wait(phase).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn draining_stops_accepting_and_shutdown_wakes_the_waiter() {
+        let lifecycle = Arc::new(Lifecycle::default());
+        assert!(lifecycle.accepting());
+
+        lifecycle.drain();
+        assert!(!lifecycle.accepting());
+        assert_eq!(lifecycle.phase(), Phase::Draining);
+
+        let waiter = {
+            let lifecycle = lifecycle.clone();
+            thread::spawn(move || lifecycle.wait_for_shutdown())
+        };
+        lifecycle.shutdown();
+        waiter.join().unwrap();
+
+        lifecycle.drain();
+        assert_eq!(lifecycle.phase(), Phase::ShuttingDown);
+    }
+
+    #[test]
+    fn a_handoff_wakes_the_waiter_and_keeps_the_phase() {
+        let lifecycle = Arc::new(Lifecycle::default());
+        let waiter = {
+            let lifecycle = lifecycle.clone();
+            thread::spawn(move || lifecycle.wait_for_shutdown())
+        };
+        lifecycle.hand_off();
+        waiter.join().unwrap();
+        assert!(lifecycle.handed_off());
+        assert!(lifecycle.accepting());
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/*
+*  Connection limit
+*
+*  Every connection gets its own thread, so an unbounded number of them can
+*  exhaust the process. Connections beyond the cap are answered with 503 right
+*  away instead of being queued.
+*/
+
+pub struct ConnectionLimit {
+    max: AtomicUsize,
+    active: AtomicUsize,
+}
+
+/// Held for the lifetime of a connection; frees its slot when dropped.
+pub struct ConnectionSlot(Arc<ConnectionLimit>);
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(ConnectionLimit {
+            max: AtomicUsize::new(max),
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    /// Takes a slot, or returns `None` when the limit is reached.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let max = self.max.load(Ordering::Relaxed);
+        let taken = self
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max).then_some(active + 1)
+            });
+        taken.ok().map(|_| ConnectionSlot(self.clone()))
+    }
+
+    /// Connections currently holding a slot.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Changes the cap. Lowering it never drops open connections; new ones are
+    /// refused until enough of them finish.
+    pub fn resize(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_beyond_the_cap_until_a_slot_frees() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn resizing_applies_to_new_connections() {
+        let limit = ConnectionLimit::new(2);
+        let _first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+
+        limit.resize(1);
+        assert!(limit.try_acquire().is_none());
+        limit.resize(3);
+        assert!(limit.try_acquire().is_some());
+    }
+}
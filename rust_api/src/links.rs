@@ -0,0 +1,47 @@
+use serde_json::Value;
+use std::sync::RwLock;
+
+/*
+*  Resource links
+*
+*  The hypermedia formats, JSON:API and HAL, link every user and address to
+*  itself and users to their addresses. Links are paths under `BASE_PATH`,
+*  so they work through whatever host the client used.
+*/
+
+static BASE_PATH: RwLock<String> = RwLock::new(String::new());
+
+pub fn configure(base_path: &str) {
+    *BASE_PATH.write().unwrap() = base_path.to_string();
+}
+
+/// `path` under `BASE_PATH`.
+pub fn to(path: &str) -> String {
+    format!("{}{}", BASE_PATH.read().unwrap(), path)
+}
+
+pub fn user(id: &str) -> String {
+    to(&format!("/users/{}", id))
+}
+
+pub fn addresses(user_id: &str) -> String {
+    to(&format!("/users/{}/addresses", user_id))
+}
+
+pub fn address(user_id: &str, id: &str) -> String {
+    to(&format!("/users/{}/addresses/{}", user_id, id))
+}
+
+/// An `id` or `user_id` field as it goes into a link.
+pub fn id(value: &Value) -> Option<String> {
+    match value {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Whether the resources at `path` are addresses rather than users.
+pub fn lists_addresses(path: &str) -> bool {
+    path.split('/').any(|segment| segment == "addresses")
+}
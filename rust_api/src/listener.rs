@@ -0,0 +1,452 @@
+use crate::systemd;
+use crate::tls;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/*
+*  Listeners
+*
+*  `LISTEN` takes a comma separated list of socket addresses (`0.0.0.0:8080`,
+*  `[::]:8080`), `tls:<address>` for HTTPS, `unix:<path>` or
+*  `systemd:<name>` for a socket passed by systemd (see `crate::systemd`),
+*  each optionally followed by `@group+group` to restrict the route groups
+*  served there, e.g. `tls:[::]:8443@api,127.0.0.1:8081@admin`.
+*  Connections from every transport are handed out as a `Stream`, so request
+*  handling never needs to know which one it is talking to.
+*/
+
+/// How long `Stream::linger` waits for the client, and how much of what it
+/// sends it reads, at most.
+const LINGER_TIME: Duration = Duration::from_secs(1);
+const LINGER_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteGroup {
+    Api,
+    Admin,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenSpec {
+    pub addr: ListenAddr,
+    /// Route groups reachable through this listener.
+    pub groups: Vec<RouteGroup>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// TCP with TLS, see `crate::tls`.
+    Tls(SocketAddr),
+    Unix(PathBuf),
+    /// A socket passed by systemd, by name or position, serving HTTPS when
+    /// `tls` is set.
+    Systemd {
+        name: String,
+        tls: bool,
+    },
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    Unix(UnixListener),
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl RouteGroup {
+    pub const ALL: [RouteGroup; 2] = [RouteGroup::Api, RouteGroup::Admin];
+
+    pub fn of_path(path: &str) -> RouteGroup {
+        if path == "/admin"
+            || path.starts_with("/admin/")
+            || path == "/metrics"
+            || path == "/status"
+        {
+            RouteGroup::Admin
+        } else {
+            RouteGroup::Api
+        }
+    }
+}
+
+impl FromStr for RouteGroup {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "api" => Ok(RouteGroup::Api),
+            "admin" => Ok(RouteGroup::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ListenSpec {
+    pub fn serves(&self, group: RouteGroup) -> bool {
+        self.groups.contains(&group)
+    }
+}
+
+impl FromStr for ListenSpec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, groups) = match s.rsplit_once('@') {
+            Some((addr, groups)) => (
+                addr,
+                groups
+                    .split('+')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => (s, RouteGroup::ALL.to_vec()),
+        };
+
+        Ok(ListenSpec {
+            addr: addr.parse()?,
+            groups,
+        })
+    }
+}
+
+impl From<ListenAddr> for ListenSpec {
+    fn from(addr: ListenAddr) -> Self {
+        ListenSpec {
+            addr,
+            groups: RouteGroup::ALL.to_vec(),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, addr) = match s.strip_prefix("tls:") {
+            Some(addr) => (true, addr),
+            None => (false, s),
+        };
+        match addr.strip_prefix("systemd") {
+            Some("") => {
+                return Ok(ListenAddr::Systemd {
+                    name: "0".to_string(),
+                    tls,
+                })
+            }
+            Some(name) => {
+                return match name.strip_prefix(':') {
+                    Some(name) if !name.is_empty() => Ok(ListenAddr::Systemd {
+                        name: name.to_string(),
+                        tls,
+                    }),
+                    _ => Err(()),
+                }
+            }
+            None => {}
+        }
+        if tls {
+            return addr.parse().map(ListenAddr::Tls).map_err(|_| ());
+        }
+        match s.strip_prefix("unix:") {
+            Some("") => Err(()),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(ListenAddr::Tcp).map_err(|_| ()),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Tls(addr) => write!(f, "tls:{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::Systemd { name, tls: false } => write!(f, "systemd:{}", name),
+            ListenAddr::Systemd { name, tls: true } => write!(f, "tls:systemd:{}", name),
+        }
+    }
+}
+
+impl ListenAddr {
+    /// What the socket is passed as to another process: its systemd name,
+    /// or `unknown` like any socket systemd did not name, since the new
+    /// process finds the others by address.
+    pub fn socket_name(&self) -> String {
+        match self {
+            ListenAddr::Systemd { name, .. } => name.clone(),
+            _ => "unknown".to_string(),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(
+            self,
+            ListenAddr::Tls(_) | ListenAddr::Systemd { tls: true, .. }
+        )
+    }
+}
+
+impl Listener {
+    /// Binds `addr`; `tls` is needed for, and only used by, TLS addresses.
+    /// A socket of the same address handed over by the previous process is
+    /// used instead of binding a new one.
+    pub fn bind(addr: &ListenAddr, tls: Option<Arc<ServerConfig>>) -> io::Result<Listener> {
+        let bound_to = match addr {
+            ListenAddr::Tcp(addr) | ListenAddr::Tls(addr) => Some(ListenAddr::Tcp(*addr)),
+            ListenAddr::Unix(path) => Some(ListenAddr::Unix(path.clone())),
+            ListenAddr::Systemd { .. } => None,
+        };
+        let inherited = bound_to.and_then(|bound_to| {
+            systemd::take_matching(|fd| {
+                let address = fd
+                    .try_clone()
+                    .and_then(|fd| Listener::from_fd(fd, None))
+                    .and_then(|listener| listener.local_addr());
+                address.is_ok_and(|address| address == bound_to)
+            })
+        });
+        if let Some(fd) = inherited {
+            let tls = match addr.is_tls() {
+                true => Some(tls.ok_or_else(|| io::Error::other("TLS is not configured"))?),
+                false => None,
+            };
+            return Listener::from_fd(fd, tls);
+        }
+
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Tls(addr) => {
+                let tls = tls.ok_or_else(|| io::Error::other("TLS is not configured"))?;
+                Ok(Listener::Tls(TcpListener::bind(addr)?, tls))
+            }
+            ListenAddr::Unix(path) => {
+                // A socket file left behind by a previous run would make bind fail.
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            ListenAddr::Systemd { name, tls: secure } => {
+                let tls = match secure {
+                    true => Some(tls.ok_or_else(|| io::Error::other("TLS is not configured"))?),
+                    false => None,
+                };
+                Listener::from_fd(systemd::take(name)?, tls)
+            }
+        }
+    }
+
+    /// Serves on a socket bound elsewhere, TCP or Unix, with TLS when `tls` is
+    /// given.
+    pub fn from_fd(fd: OwnedFd, tls: Option<Arc<ServerConfig>>) -> io::Result<Listener> {
+        let unix = UnixListener::from(fd);
+        if unix.local_addr().is_ok() {
+            return match tls {
+                Some(_) => Err(io::Error::other("TLS is not supported on Unix sockets")),
+                None => Ok(Listener::Unix(unix)),
+            };
+        }
+        let tcp = TcpListener::from(OwnedFd::from(unix));
+        tcp.local_addr()?;
+        Ok(match tls {
+            Some(tls) => Listener::Tls(tcp, tls),
+            None => Listener::Tcp(tcp),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Tls(listener, _) => listener.local_addr().map(ListenAddr::Tls),
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| ListenAddr::Unix(path.to_path_buf()))
+                .ok_or_else(|| io::Error::other("unnamed Unix socket")),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            // The handshake happens on the first read, on the connection's
+            // own thread.
+            Listener::Tls(listener, config) => {
+                let (stream, _) = listener.accept()?;
+                let connection = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+                Ok(Stream::Tls(Box::new(StreamOwned::new(connection, stream))))
+            }
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => listener.as_fd(),
+            Listener::Unix(listener) => listener.as_fd(),
+        }
+    }
+}
+
+impl Stream {
+    /// The remote IP address; `None` for Unix socket peers.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok().map(|addr| addr.ip()),
+            Stream::Tls(stream) => stream.sock.peer_addr().ok().map(|addr| addr.ip()),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    /// The subject of the client certificate verified during the TLS
+    /// handshake, once it is done.
+    pub fn client_subject(&self) -> Option<String> {
+        match self {
+            Stream::Tls(stream) => stream
+                .conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| tls::subject(cert)),
+            _ => None,
+        }
+    }
+
+    /// Ends a response sent before the request was read to its end, such as
+    /// a 431 or 413: stops sending and throws away what the client still
+    /// sends, for a while. Closing with unread input resets the connection,
+    /// and a reset can lose the response before the client reads it.
+    pub fn linger(&mut self) {
+        let socket: &mut dyn Read = match self {
+            Stream::Tcp(stream) => {
+                stream.shutdown(Shutdown::Write).ok();
+                stream.set_read_timeout(Some(LINGER_TIME)).ok();
+                stream
+            }
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush().ok();
+                stream.sock.shutdown(Shutdown::Write).ok();
+                stream.sock.set_read_timeout(Some(LINGER_TIME)).ok();
+                // Undecrypted, since the records only need to go.
+                &mut stream.sock
+            }
+            Stream::Unix(stream) => {
+                stream.shutdown(Shutdown::Write).ok();
+                stream.set_read_timeout(Some(LINGER_TIME)).ok();
+                stream
+            }
+        };
+        let deadline = Instant::now() + LINGER_TIME;
+        let mut discarded = 0;
+        let mut sink = [0; 8192];
+        while discarded < LINGER_BYTES && Instant::now() < deadline {
+            match socket.read(&mut sink) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => discarded += size,
+            }
+        }
+    }
+}
+
+impl Drop for Stream {
+    /// Tells TLS clients the response is complete, since responses without a
+    /// length end with the connection.
+    fn drop(&mut self) {
+        match self {
+            // A failed handshake already sent its alert.
+            Stream::Tls(stream) if !stream.conn.is_handshaking() => {
+                stream.conn.send_close_notify();
+                stream.flush().ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_systemd_addresses() {
+        for (spec, addr) in [
+            ("systemd", "systemd:0"),
+            ("systemd:http", "systemd:http"),
+            ("tls:systemd:1", "tls:systemd:1"),
+        ] {
+            let parsed: ListenAddr = spec.parse().unwrap();
+            assert_eq!(parsed.to_string(), addr);
+        }
+        assert!("tls:systemd:https".parse::<ListenAddr>().unwrap().is_tls());
+        assert!("systemd:".parse::<ListenAddr>().is_err());
+        assert!("systemdx".parse::<ListenAddr>().is_err());
+
+        let spec: ListenSpec = "systemd:admin@admin".parse().unwrap();
+        assert_eq!(spec.groups, [RouteGroup::Admin]);
+    }
+
+    #[test]
+    fn serves_on_sockets_bound_elsewhere() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(tcp), None).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), ListenAddr::Tcp(addr));
+
+        let path = std::env::temp_dir().join(format!("rust-crud-fd-{}.sock", std::process::id()));
+        let unix = UnixListener::bind(&path).unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(unix), None).unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            ListenAddr::Unix(path.clone())
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::auth::constant_time_eq;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+*  Login throttling
+*
+*  Wrong credentials for an account slow down the address they came from:
+*  after a few free attempts each further failure blocks that address from
+*  the account for twice as long as the one before. Failures from all
+*  addresses together lock the account for a while, which stops guessing
+*  spread over many addresses; an unlock token ends the lock early. Sessions
+*  that were already logged in keep working. The admin token and the Basic
+*  operator accounts share one guard, each under its own account name.
+*/
+
+/// Failures from one address before delays start.
+const FREE_FAILURES: u32 = 3;
+/// Longest block of one address.
+const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+/// Failures older than this are forgotten.
+const FAILURE_MEMORY: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The address has to wait this long.
+    Throttled(Duration),
+    /// The account is locked for this long.
+    Locked(Duration),
+}
+
+/// Failures since the last success.
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Instant,
+}
+
+/// Failures of one account from every address.
+struct Account {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+    unlock_token: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    /// By account and address, `None` for Unix socket peers.
+    addresses: HashMap<(String, Option<IpAddr>), Failures>,
+    accounts: HashMap<String, Account>,
+}
+
+pub struct LoginGuard {
+    /// Failures of an account that lock it; 0 never does.
+    max_failures: u32,
+    lock_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl LoginGuard {
+    pub fn new(max_failures: u32, lock_duration: Duration) -> LoginGuard {
+        LoginGuard {
+            max_failures,
+            lock_duration,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Whether credentials for `account` from `client` may be checked at
+    /// all right now.
+    pub fn check(&self, account: &str, client: Option<IpAddr>) -> Verdict {
+        self.check_at(account, client, Instant::now())
+    }
+
+    /// Records wrong credentials. Returns the unlock token when this failure
+    /// locked the account.
+    pub fn failed(&self, account: &str, client: Option<IpAddr>) -> Option<String> {
+        self.failed_at(account, client, Instant::now())
+    }
+
+    /// Records right credentials, which wipe the failures of `client`. The
+    /// account's failures stay until they are forgotten, or else any script
+    /// using the credentials would keep resetting them during an attack.
+    pub fn succeeded(&self, account: &str, client: Option<IpAddr>) {
+        self.state
+            .lock()
+            .unwrap()
+            .addresses
+            .remove(&(account.to_string(), client));
+    }
+
+    /// Ends a lock early with the token handed out when it began.
+    pub fn unlock(&self, token: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        // Every account is compared, so timing does not tell which one was close.
+        let locked = state.accounts.values_mut().fold(None, |found, account| {
+            let matches = account
+                .unlock_token
+                .as_deref()
+                .is_some_and(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes()));
+            if matches {
+                Some(account)
+            } else {
+                found
+            }
+        });
+        let Some(account) = locked else {
+            return false;
+        };
+        account.locked_until = None;
+        account.unlock_token = None;
+        account.failures = 0;
+        true
+    }
+
+    /// Forgets addresses and accounts whose failures no longer count,
+    /// returning how many addresses there were.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let before = state.addresses.len();
+        state
+            .addresses
+            .retain(|_, failures| !forgotten(failures, now));
+        state.accounts.retain(|_, account| {
+            account.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(account.last_failure) < FAILURE_MEMORY
+        });
+        before - state.addresses.len()
+    }
+
+    fn check_at(&self, account: &str, client: Option<IpAddr>, now: Instant) -> Verdict {
+        let state = self.state.lock().unwrap();
+        if let Some(until) = state
+            .accounts
+            .get(account)
+            .and_then(|account| account.locked_until)
+            .filter(|until| *until > now)
+        {
+            return Verdict::Locked(until - now);
+        }
+        match state.addresses.get(&(account.to_string(), client)) {
+            Some(failures) if failures.blocked_until > now => {
+                Verdict::Throttled(failures.blocked_until - now)
+            }
+            _ => Verdict::Allowed,
+        }
+    }
+
+    fn failed_at(&self, account: &str, client: Option<IpAddr>, now: Instant) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let failures = state
+            .addresses
+            .entry((account.to_string(), client))
+            .or_insert(Failures {
+                count: 0,
+                last: now,
+                blocked_until: now,
+            });
+        if forgotten(failures, now) {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if let Some(beyond) = failures.count.checked_sub(FREE_FAILURES + 1) {
+            let delay = Duration::from_secs(1u64 << beyond.min(20));
+            failures.blocked_until = now + delay.min(MAX_DELAY);
+        }
+
+        let account = state
+            .accounts
+            .entry(account.to_string())
+            .or_insert(Account {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+                unlock_token: None,
+            });
+        if now.duration_since(account.last_failure) >= FAILURE_MEMORY {
+            account.failures = 0;
+        }
+        account.failures += 1;
+        account.last_failure = now;
+        if self.max_failures == 0 || account.failures < self.max_failures {
+            return None;
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        account.failures = 0;
+        account.locked_until = Some(now + self.lock_duration);
+        account.unlock_token = Some(token.clone());
+        Some(token)
+    }
+}
+
+fn forgotten(failures: &Failures, now: Instant) -> bool {
+    now.duration_since(failures.last) >= FAILURE_MEMORY && failures.blocked_until <= now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTACKER: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 9)));
+    const ADMIN: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)));
+
+    #[test]
+    fn delays_an_address_exponentially() {
+        let guard = LoginGuard::new(0, Duration::ZERO);
+        let start = Instant::now();
+        for _ in 0..FREE_FAILURES {
+            guard.failed_at("admin", ATTACKER, start);
+        }
+        assert_eq!(guard.check_at("admin", ATTACKER, start), Verdict::Allowed);
+
+        let mut now = start;
+        for delay in [1, 2, 4, 8] {
+            guard.failed_at("admin", ATTACKER, now);
+            assert_eq!(
+                guard.check_at("admin", ATTACKER, now),
+                Verdict::Throttled(Duration::from_secs(delay))
+            );
+            assert_eq!(guard.check_at("admin", ADMIN, now), Verdict::Allowed);
+            now += Duration::from_secs(delay);
+            assert_eq!(guard.check_at("admin", ATTACKER, now), Verdict::Allowed);
+        }
+        for _ in 0..30 {
+            guard.failed_at("admin", ATTACKER, now);
+        }
+        assert_eq!(
+            guard.check_at("admin", ATTACKER, now),
+            Verdict::Throttled(MAX_DELAY)
+        );
+
+        guard.succeeded("admin", ATTACKER);
+        assert_eq!(guard.check_at("admin", ATTACKER, now), Verdict::Allowed);
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let guard = LoginGuard::new(0, Duration::ZERO);
+        let start = Instant::now();
+        for _ in 0..FREE_FAILURES {
+            guard.failed_at("admin", ATTACKER, start);
+        }
+        guard.failed_at("admin", ATTACKER, start + FAILURE_MEMORY);
+        assert_eq!(
+            guard.check_at("admin", ATTACKER, start + FAILURE_MEMORY),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn locks_the_account_until_unlocked() {
+        let guard = LoginGuard::new(5, Duration::from_secs(600));
+        let now = Instant::now();
+        // Spread over addresses, so none of them is delayed yet.
+        for octet in 0..4 {
+            let client = Some(IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, octet)));
+            assert_eq!(guard.failed_at("admin", client, now), None);
+        }
+        let token = guard.failed_at("admin", ATTACKER, now).unwrap();
+        assert_eq!(
+            guard.check_at("admin", ADMIN, now),
+            Verdict::Locked(Duration::from_secs(600))
+        );
+        assert_eq!(
+            guard.check_at("admin", ADMIN, now + Duration::from_secs(600)),
+            Verdict::Allowed
+        );
+
+        assert!(!guard.unlock("guess"));
+        assert!(guard.unlock(&token));
+        assert_eq!(guard.check_at("admin", ADMIN, now), Verdict::Allowed);
+        assert!(!guard.unlock(&token));
+    }
+
+    #[test]
+    fn keeps_accounts_apart() {
+        let guard = LoginGuard::new(2, Duration::from_secs(600));
+        let now = Instant::now();
+        for _ in 0..FREE_FAILURES + 1 {
+            guard.failed_at("prometheus", ATTACKER, now);
+        }
+        assert!(matches!(
+            guard.check_at("prometheus", ATTACKER, now),
+            Verdict::Locked(_)
+        ));
+        assert_eq!(guard.check_at("admin", ATTACKER, now), Verdict::Allowed);
+
+        guard.failed_at("admin", ADMIN, now);
+        let token = guard.failed_at("admin", ADMIN, now).unwrap();
+        assert!(guard.unlock(&token));
+        assert!(matches!(
+            guard.check_at("prometheus", ADMIN, now),
+            Verdict::Locked(_)
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/*
+*  Log file
+*
+*  For hosts without a log collector, entries can also go to a file. It is
+*  rotated once it would grow past a size, or when the day changes, by renaming
+*  `app.log` to `app.log.1`, `app.log.1` to `app.log.2` and so on; files past
+*  the number kept are deleted.
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate before the file would grow past this many bytes; zero never does.
+    pub max_bytes: u64,
+    /// Rotate when the first entry of a new day (UTC) is written.
+    pub daily: bool,
+    /// Rotated files kept next to the current one.
+    pub keep: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFileSettings {
+    pub path: PathBuf,
+    pub rotation: Rotation,
+}
+
+pub struct LogFile {
+    settings: LogFileSettings,
+    file: File,
+    /// Size of the current file.
+    written: u64,
+    /// Day the current file was started or last written to.
+    day: NaiveDate,
+}
+
+impl LogFile {
+    /// Opens the file for appending, creating it if needed.
+    pub fn open(settings: LogFileSettings) -> io::Result<LogFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)?;
+        let metadata = file.metadata()?;
+        let day = metadata
+            .modified()
+            .map(|modified| DateTime::<Utc>::from(modified).date_naive())
+            .unwrap_or_else(|_| Utc::now().date_naive());
+        Ok(LogFile {
+            settings,
+            file,
+            written: metadata.len(),
+            day,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.settings.path
+    }
+
+    /// Takes new limits; they apply from the next entry on.
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        self.settings.rotation = rotation;
+    }
+
+    /// Appends `line` and a newline, rotating first when it is time to.
+    pub fn write_line(&mut self, line: &str, now: DateTime<Utc>) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        let rotation = &self.settings.rotation;
+        let too_big = rotation.max_bytes > 0 && self.written + length > rotation.max_bytes;
+        let new_day = rotation.daily && now.date_naive() != self.day;
+        if self.written > 0 && (too_big || new_day) {
+            self.rotate()?;
+        }
+
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.written += length;
+        self.day = now.date_naive();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.settings.rotation.keep;
+        remove_if_exists(&self.numbered(keep.max(1)))?;
+        for n in (1..keep).rev() {
+            rename_if_exists(&self.numbered(n), &self.numbered(n + 1))?;
+        }
+        if keep > 0 {
+            fs::rename(&self.settings.path, self.numbered(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.settings.path)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn numbered(&self, n: usize) -> PathBuf {
+        let mut path = self.settings.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rust_api-{}-{}-{}",
+            name,
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_the_newest_files() {
+        let dir = scratch_dir("size");
+        let path = dir.join("app.log");
+        let mut log = LogFile::open(LogFileSettings {
+            path: path.clone(),
+            rotation: Rotation {
+                max_bytes: 6,
+                daily: false,
+                keep: 2,
+            },
+        })
+        .unwrap();
+        let now = Utc::now();
+        for line in ["one", "two", "three", "four", "five"] {
+            log.write_line(line, now).unwrap();
+        }
+
+        assert_eq!(read(path.clone()), "five\n");
+        assert_eq!(read(dir.join("app.log.1")), "four\n");
+        assert_eq!(read(dir.join("app.log.2")), "three\n");
+        assert!(!dir.join("app.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_when_the_day_changes() {
+        let dir = scratch_dir("daily");
+        let path = dir.join("app.log");
+        let mut log = LogFile::open(LogFileSettings {
+            path: path.clone(),
+            rotation: Rotation {
+                max_bytes: 0,
+                daily: true,
+                keep: 1,
+            },
+        })
+        .unwrap();
+        let monday = Utc.with_ymd_and_hms(2024, 3, 4, 23, 59, 0).unwrap();
+        log.write_line("late", monday).unwrap();
+        log.write_line("later", monday).unwrap();
+        log.write_line("early", monday + chrono::Duration::minutes(2))
+            .unwrap();
+
+        assert_eq!(read(path), "early\n");
+        assert_eq!(read(dir.join("app.log.1")), "late\nlater\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::{Config, LogFormat};
+use crate::context;
+use crate::log_file::LogFile;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+/*
+*  Logging
+*
+*  `LOG_LEVEL` sets the level for everything. Overrides raise or lower it for
+*  one module and those below it (`rust_api::repository`), or for all of them
+*  with an empty target, optionally until a deadline. They are set through the
+*  admin API while chasing a problem and survive config reloads.
+*
+*  Entries go to stdout, to `LOG_FILE`, or both.
+*/
+
+struct Logger {
+    format: RwLock<LogFormat>,
+    filter: RwLock<Filter>,
+    stdout: AtomicBool,
+    file: Mutex<Option<LogFile>>,
+}
+
+#[derive(Debug)]
+struct Filter {
+    level: LevelFilter,
+    overrides: Vec<Override>,
+}
+
+/// A level for `target` and the modules below it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    pub target: String,
+    pub level: LevelFilter,
+    /// Ignored from this time on.
+    pub until: Option<DateTime<Utc>>,
+}
+
+static LOGGER: Logger = Logger {
+    format: RwLock::new(LogFormat::Text),
+    filter: RwLock::new(Filter {
+        level: LevelFilter::Info,
+        overrides: Vec::new(),
+    }),
+    stdout: AtomicBool::new(true),
+    file: Mutex::new(None),
+};
+
+#[derive(Debug)]
+pub enum LoggerError {
+    Install(SetLoggerError),
+    File(io::Error),
+}
+
+impl fmt::Display for LoggerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoggerError::Install(e) => write!(f, "{}", e),
+            LoggerError::File(e) => write!(f, "cannot open LOG_FILE: {}", e),
+        }
+    }
+}
+
+impl Override {
+    fn covers(&self, target: &str) -> bool {
+        self.target.is_empty()
+            || target == self.target
+            || target
+                .strip_prefix(&self.target)
+                .is_some_and(|rest| rest.starts_with("::"))
+    }
+
+    fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.until.is_some_and(|until| until <= now)
+    }
+}
+
+impl Filter {
+    /// The level of the most specific live override covering `target`, or the
+    /// configured one.
+    fn level_for(&self, target: &str, now: DateTime<Utc>) -> LevelFilter {
+        self.overrides
+            .iter()
+            .filter(|o| o.covers(target) && !o.expired(now))
+            .max_by_key(|o| o.target.len())
+            .map_or(self.level, |o| o.level)
+    }
+
+    /// The most verbose level anything may log at, for `log::set_max_level`.
+    fn max_level(&self) -> LevelFilter {
+        self.overrides
+            .iter()
+            .map(|o| o.level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && metadata.level()
+                <= self
+                    .filter
+                    .read()
+                    .unwrap()
+                    .level_for(metadata.target(), Utc::now())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if record.level() == Level::Error {
+            context::note_error(record.args().to_string());
+        }
+        let now = Utc::now();
+        let timestamp = now.to_rfc3339_opts(SecondsFormat::Millis, true);
+        let line = match *self.format.read().unwrap() {
+            LogFormat::Text => format!(
+                "{} {:<5} {}: {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => {
+                let context = context::current().unwrap_or_default();
+                serde_json::json!({
+                        "timestamp": timestamp,
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                        "request_id": Some(context.request_id).filter(|id| !id.is_empty()),
+                        "trace_id": context.trace_id,
+                        "route": context.route,
+                        "tenant": context.tenant,
+                        "client_subject": context.client_subject,
+                })
+                .to_string()
+            }
+        };
+
+        if self.stdout.load(Ordering::Relaxed) {
+            println!("{}", line);
+        }
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            if let Err(e) = file.write_line(&line, now) {
+                eprintln!("Cannot write to {}: {}", file.path().display(), e);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(config: &Config) -> Result<(), LoggerError> {
+    log::set_logger(&LOGGER).map_err(LoggerError::Install)?;
+    reconfigure(config).map_err(LoggerError::File)
+}
+
+/// Applies the level and outputs from `config`; safe to call at any time.
+/// Overrides are kept, and so is the open log file when its path did not
+/// change. When a new log file cannot be opened, the old one stays in use.
+pub fn reconfigure(config: &Config) -> io::Result<()> {
+    *LOGGER.format.write().unwrap() = config.log_format;
+    {
+        let mut filter = LOGGER.filter.write().unwrap();
+        filter.level = config.log_level;
+        log::set_max_level(filter.max_level());
+    }
+    LOGGER.stdout.store(config.log_stdout, Ordering::Relaxed);
+
+    let mut file = LOGGER.file.lock().unwrap();
+    match (&config.log_file, file.as_mut()) {
+        (None, _) => *file = None,
+        (Some(settings), Some(current)) if current.path() == settings.path => {
+            current.set_rotation(settings.rotation.clone())
+        }
+        (Some(settings), _) => *file = Some(LogFile::open(settings.clone())?),
+    }
+    Ok(())
+}
+
+/// Logs `target` at `level` from now on, or for `duration`, replacing any
+/// earlier override for the same target.
+pub fn set_override(target: &str, level: LevelFilter, duration: Option<Duration>) {
+    let until = duration.and_then(|duration| chrono::Duration::from_std(duration).ok());
+    let now = Utc::now();
+    let mut filter = LOGGER.filter.write().unwrap();
+    filter
+        .overrides
+        .retain(|o| o.target != target && !o.expired(now));
+    filter.overrides.push(Override {
+        target: target.to_string(),
+        level,
+        until: until.map(|until| now + until),
+    });
+    log::set_max_level(filter.max_level());
+}
+
+/// Drops every override, going back to `LOG_LEVEL` alone.
+pub fn clear_overrides() {
+    let mut filter = LOGGER.filter.write().unwrap();
+    filter.overrides.clear();
+    log::set_max_level(filter.max_level());
+}
+
+/// The configured level and the overrides still in force.
+pub fn levels() -> (LevelFilter, Vec<Override>) {
+    let now = Utc::now();
+    let filter = LOGGER.filter.read().unwrap();
+    let live = filter
+        .overrides
+        .iter()
+        .filter(|o| !o.expired(now))
+        .cloned()
+        .collect();
+    (filter.level, live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_override(target: &str, level: LevelFilter, until: Option<DateTime<Utc>>) -> Override {
+        Override {
+            target: target.to_string(),
+            level,
+            until,
+        }
+    }
+
+    #[test]
+    fn the_most_specific_live_override_wins() {
+        let now = Utc::now();
+        let filter = Filter {
+            level: LevelFilter::Info,
+            overrides: vec![
+                level_override("", LevelFilter::Warn, None),
+                level_override("rust_api::repository", LevelFilter::Debug, None),
+                level_override(
+                    "rust_api::repository::slow_query",
+                    LevelFilter::Trace,
+                    Some(now - chrono::Duration::seconds(1)),
+                ),
+            ],
+        };
+
+        assert_eq!(filter.level_for("rust_api", now), LevelFilter::Warn);
+        assert_eq!(
+            filter.level_for("rust_api::repository", now),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filter.level_for("rust_api::repository::slow_query", now),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filter.level_for("rust_api::repositoryx", now),
+            LevelFilter::Warn
+        );
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+}
@@ -1,226 +1,141 @@
-use dotenv::dotenv;
-use postgres::Error as PostgresError;
-use postgres::{Client, NoTls};
+use log::{error, info, warn};
+use rust_api::bench;
+use rust_api::build_info;
+use rust_api::config::Config;
+use rust_api::logger;
+use rust_api::{CommandError, Step};
 use std::env;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-
-#[macro_use]
-extern crate serde_derive;
-
-// Model
-#[derive(Serialize, Deserialize)]
-struct User {
-    id: Option<i32>,
-    name: String,
-    email: String,
-}
-fn DB_URL() -> String {
-    dotenv().ok();
-    env::var("DATABASE_URL").unwrap()
-}
-
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
-const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n";
-
-fn main() {
-    if let Err(e) = setup_database() {
-        println!("Setup Database Error: {}", e);
-        return;
-    }
-
-    let listener = TcpListener::bind(format!("0.0.0.0:8080")).unwrap();
-    println!("Server started at port 8080");
-
-    //handle the client
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("Connection established");
-                handle_client(stream);
-            }
-            Err(e) => {
-                println!("Connection Error: {}", e);
-            }
+use std::fmt::Display;
+use std::path::Path;
+use std::process::ExitCode;
+
+/// Exits with a failure status whenever a command fails, so scripts and
+/// service managers can tell.
+fn main() -> ExitCode {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Config Error: {}", e);
+            return ExitCode::FAILURE;
         }
-    }
-}
-
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
-
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            request.push_str(String::from_utf8_lossy(&buffer[..size]).as_ref());
+    };
 
-            let (status_line, content) = match &*request {
-                r if r.starts_with("POST") && r.contains("/users") => handle_post_request(r),
-                r if r.starts_with("GET") && r.contains("/user/") => handle_get_request(r),
-                r if r.starts_with("GET") && r.contains("/users") => handle_get_all_request(r),
-                r if r.starts_with("PUT") && r.contains("/users/") => handle_put_request(r),
-                r if r.starts_with("DELETE") && r.contains("/users/") => handle_delete_request(r),
-                _ => (NOT_FOUND.to_string(), "Not Found URL".to_string()),
-            };
-
-            stream
-                .write_all(format!("{}{}", status_line, content).as_bytes())
-                .unwrap();
-        }
-        Err(e) => println!("Failed to read from connection: {}", e),
+    if let Err(e) = logger::init(&config) {
+        println!("Logger Error: {}", e);
+        return ExitCode::FAILURE;
     }
-}
-
-/*
-*  Controllers
-*/
-
-fn handle_post_request(request: &str) -> (String, String) {
-    match (
-        get_user_request_body(&request),
-        Client::connect(DB_URL().as_str(), NoTls),
-    ) {
-        (Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "INSERT INTO users (name, email) VALUES ($1, $2)",
-                    &[&user.name, &user.email],
-                )
-                .unwrap();
-
-            (OK_RESPONSE.to_string(), "User Created".to_string())
-        }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Internal Server Error".to_string(),
-        ),
+    for warning in &config.warnings {
+        warn!("{}", warning);
     }
-}
-
-fn handle_get_request(request: &str) -> (String, String) {
-    match (
-        get_id(&request).parse::<i32>(),
-        Client::connect(DB_URL().as_str(), NoTls),
-    ) {
-        (Ok(id), Ok(mut client)) => {
-            println!("ID: {}", id);
-            match client.query_one("SELECT * FROM users WHERE id = $1", &[&id]) {
-                Ok(row) => {
-                    let user = User {
-                        id: row.get(0),
-                        name: row.get(1),
-                        email: row.get(2),
-                    };
-                    (
-                        OK_RESPONSE.to_string(),
-                        serde_json::to_string(&user).unwrap(),
-                    )
+    info!(
+        "rust_api {} ({}, built {})",
+        build_info::VERSION,
+        build_info::GIT_SHA,
+        build_info::BUILT_AT
+    );
+    info!("Profile: {}", config.profile.name());
+
+    match env::args().nth(1).as_deref() {
+        None | Some("serve") => match rust_api::run(config) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => failed(e),
+        },
+        Some("rotate-email-key") => match rust_api::rotate_email_key(&config) {
+            Ok(rows) => {
+                info!("Re-encrypted or re-indexed {} emails", rows);
+                ExitCode::SUCCESS
+            }
+            Err(e) => failed(format!("Key Rotation Error: {}", e)),
+        },
+        Some("backup") => match env::args().nth(2) {
+            Some(path) => match rust_api::backup(&config, Path::new(&path)) {
+                Ok(rows) => {
+                    info!("Backed up to {}: {:?}", path, rows);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => failed(e),
+            },
+            None => failed("Usage: backup <file>"),
+        },
+        Some("restore") => match env::args().nth(2) {
+            Some(path) => {
+                let replace = env::args().nth(3).as_deref() == Some("--replace");
+                match rust_api::restore(&config, Path::new(&path), replace) {
+                    Ok(rows) => {
+                        info!("Restored {}: {:?}", path, rows);
+                        ExitCode::SUCCESS
+                    }
+                    Err(e) => failed(e),
                 }
-                _ => (NOT_FOUND.to_string(), "User Not Found".to_string()),
+            }
+            None => failed("Usage: restore <file> [--replace]"),
+        },
+        Some("migrate") => {
+            let mut args: Vec<String> = env::args().skip(2).collect();
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            args.retain(|arg| arg != "--dry-run");
+            match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                [] | ["up"] => report(rust_api::migrate(&config, dry_run), dry_run),
+                ["down", count] => match count.parse() {
+                    Ok(count) => report(rust_api::migrate_down(&config, count, dry_run), dry_run),
+                    Err(_) => failed("Usage: migrate down <count> [--dry-run]"),
+                },
+                ["status"] => match rust_api::migration_status(&config) {
+                    Ok(migrations) => {
+                        for migration in migrations {
+                            println!("{}", migration);
+                        }
+                        ExitCode::SUCCESS
+                    }
+                    Err(e) => failed(e),
+                },
+                _ => failed("Usage: migrate [up | down <count> | status] [--dry-run]"),
             }
         }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Internal Server Error".to_string(),
-        ),
-    }
-}
-
-fn handle_get_all_request(request: &str) -> (String, String) {
-    match Client::connect(DB_URL().as_str(), NoTls) {
-        Ok(mut client) => {
-            let mut users = Vec::new();
-
-            for row in client.query("SELECT * FROM users", &[]).unwrap() {
-                users.push(User {
-                    id: row.get(0),
-                    name: row.get(1),
-                    email: row.get(2),
-                })
+        Some("bench") => {
+            let args: Vec<String> = env::args().skip(2).collect();
+            match bench::Options::parse(&args) {
+                Ok(options) => {
+                    info!(
+                        "Running {} workers against {}",
+                        options.concurrency, options.url
+                    );
+                    print!("{}", bench::run(&options));
+                    ExitCode::SUCCESS
+                }
+                Err(e) => failed(e),
             }
-
-            (
-                OK_RESPONSE.to_string(),
-                serde_json::to_string(&users).unwrap(),
-            )
         }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Internal Server Error".to_string(),
-        ),
+        Some(command) => failed(format!(
+            "Unknown command {:?}; expected serve, rotate-email-key, backup, restore, migrate or bench",
+            command
+        )),
     }
 }
 
-fn handle_put_request(request: &str) -> (String, String) {
-    match (
-        get_id(&request).parse::<i32>(),
-        get_user_request_body(&request),
-        Client::connect(DB_URL().as_str(), NoTls),
-    ) {
-        (Ok(id), Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE users SET name = $1, email = $2 WHERE id = $3",
-                    &[&user.name, &user.email, &id],
-                )
-                .unwrap();
-            (OK_RESPONSE.to_string(), "User Updated".to_string())
-        }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Internal Server Error".to_string(),
-        ),
-    }
+fn failed(e: impl Display) -> ExitCode {
+    error!("{}", e);
+    ExitCode::FAILURE
 }
 
-fn handle_delete_request(request: &str) -> (String, String) {
-    match (
-        get_id(&request).parse::<i32>(),
-        Client::connect(DB_URL().as_str(), NoTls),
-    ) {
-        (Ok(id), Ok(mut client)) => {
-            let rows_affected = client
-                .execute("DELETE FROM users WHERE id = $1", &[&id])
-                .unwrap();
-
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "User Not Found".to_string());
+/// Prints the SQL of a dry run, or logs the migrations that ran.
+fn report(steps: Result<Vec<Step>, CommandError>, dry_run: bool) -> ExitCode {
+    match steps {
+        Ok(steps) if steps.is_empty() => info!("Nothing to migrate"),
+        Ok(steps) if dry_run => {
+            for step in steps {
+                println!("{}\n", step);
+            }
+        }
+        Ok(steps) => {
+            for step in steps {
+                match step.down {
+                    false => info!("Applied migration {}", step.version),
+                    true => info!("Rolled back migration {}", step.version),
+                }
             }
-
-            (OK_RESPONSE.to_string(), "User Deleted".to_string())
         }
-        _ => (
-            INTERNAL_SERVER_ERROR.to_string(),
-            "Internal Server Error".to_string(),
-        ),
+        Err(e) => return failed(e),
     }
-}
-
-fn setup_database() -> Result<(), PostgresError> {
-    println!("Database URL: {}", DB_URL());
-    let mut client = Client::connect(DB_URL().as_str(), NoTls)?;
-
-    client.batch_execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            name VARCHAR NOT NULL,
-            email VARCHAR NOT NULL
-        )",
-    )?;
-    Ok(())
-}
-
-fn get_user_request_body(request: &str) -> Result<User, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
-}
-
-fn get_id(request: &str) -> &str {
-    request
-        .split("/")
-        .nth(4)
-        .unwrap_or_default()
-        .split_whitespace()
-        .next()
-        .unwrap_or_default()
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,85 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+/*
+*  Maintenance mode
+*
+*  While on, API requests are answered with 503 and a message for the client,
+*  so migrations or other work on the database can run without traffic.
+*  Admin routes keep working, which is how maintenance is ended. Unlike a
+*  drain, maintenance is expected to end and the process stays up throughout.
+*/
+
+pub const DEFAULT_MESSAGE: &str = "The service is down for maintenance; please try again later.";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Window {
+    /// Shown to clients in the 503 body.
+    pub message: String,
+    /// Sent as `Retry-After`.
+    pub retry_after: Duration,
+}
+
+pub struct Maintenance {
+    /// What a window started without details looks like.
+    defaults: Window,
+    window: RwLock<Option<Window>>,
+}
+
+impl Maintenance {
+    pub fn new(defaults: Window, on: bool) -> Self {
+        Maintenance {
+            window: RwLock::new(on.then(|| defaults.clone())),
+            defaults,
+        }
+    }
+
+    /// The ongoing maintenance, if any.
+    pub fn window(&self) -> Option<Window> {
+        self.window.read().unwrap().clone()
+    }
+
+    /// Starts maintenance, or changes the details of the ongoing one. Details
+    /// left out are taken from the configured defaults.
+    pub fn start(&self, message: Option<String>, retry_after: Option<Duration>) -> Window {
+        let window = Window {
+            message: message.unwrap_or_else(|| self.defaults.message.clone()),
+            retry_after: retry_after.unwrap_or(self.defaults.retry_after),
+        };
+        *self.window.write().unwrap() = Some(window.clone());
+        window
+    }
+
+    /// Ends maintenance. Returns whether it was on.
+    pub fn end(&self) -> bool {
+        self.window.write().unwrap().take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_fall_back_to_the_defaults() {
+        let defaults = Window {
+            message: DEFAULT_MESSAGE.to_string(),
+            retry_after: Duration::from_secs(300),
+        };
+        let maintenance = Maintenance::new(defaults.clone(), false);
+        assert_eq!(maintenance.window(), None);
+        assert!(!maintenance.end());
+
+        let window = maintenance.start(Some("Upgrading".to_string()), None);
+        assert_eq!(window.message, "Upgrading");
+        assert_eq!(window.retry_after, defaults.retry_after);
+        assert_eq!(maintenance.window(), Some(window));
+        assert!(maintenance.end());
+        assert_eq!(maintenance.window(), None);
+
+        assert_eq!(
+            Maintenance::new(defaults.clone(), true).window(),
+            Some(defaults)
+        );
+    }
+}
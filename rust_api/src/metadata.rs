@@ -0,0 +1,87 @@
+use crate::schema::Violation;
+use serde_json::Value;
+
+/*
+*  User metadata
+*
+*  Free-form attributes integrators attach to a user, stored as one JSON
+*  object. Updates either replace it or apply a JSON Merge Patch (RFC 7396).
+*/
+
+/// Largest metadata document accepted, serialized.
+pub const MAX_BYTES: usize = 16 * 1024;
+/// Deepest nesting of objects and arrays below the top-level object.
+pub const MAX_DEPTH: usize = 5;
+
+/// Checks the limits on a metadata document.
+pub fn validate(metadata: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if !metadata.is_object() {
+        violations.push(Violation {
+            pointer: String::new(),
+            code: "invalid_type",
+            message: "must be an object".to_string(),
+        });
+        return violations;
+    }
+    let size = metadata.to_string().len();
+    if size > MAX_BYTES {
+        violations.push(Violation {
+            pointer: String::new(),
+            code: "too_large",
+            message: format!("must be at most {} bytes, is {}", MAX_BYTES, size),
+        });
+    }
+    if let Some(pointer) = too_deep(metadata, String::new(), 0) {
+        violations.push(Violation {
+            pointer,
+            code: "too_deep",
+            message: format!("nests deeper than {} levels", MAX_DEPTH),
+        });
+    }
+    violations
+}
+
+/// Pointer to the first value nested deeper than `MAX_DEPTH`.
+fn too_deep(value: &Value, pointer: String, depth: usize) -> Option<String> {
+    let children: Vec<(String, &Value)> = match value {
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, value)| (key.replace('~', "~0").replace('/', "~1"), value))
+            .collect(),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (index.to_string(), value))
+            .collect(),
+        _ => return None,
+    };
+    if depth > MAX_DEPTH {
+        return Some(pointer);
+    }
+    children
+        .into_iter()
+        .find_map(|(token, child)| too_deep(child, format!("{}/{}", pointer, token), depth + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn limits_size_and_depth() {
+        assert!(validate(&json!({ "plan": "pro", "tags": ["a"] })).is_empty());
+        assert_eq!(validate(&json!([1]))[0].message, "must be an object");
+
+        let big = json!({ "blob": "x".repeat(MAX_BYTES) });
+        assert_eq!(validate(&big).len(), 1);
+
+        let deep = json!({ "a": { "b": { "c": { "d": { "e": { "f": { "g": 1 } } } } } } });
+        let violations = validate(&deep);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].pointer, "/a/b/c/d/e/f");
+        let deepest_allowed = json!({ "a": { "b": { "c": { "d": { "e": { "f": 1 } } } } } });
+        assert!(validate(&deepest_allowed).is_empty());
+    }
+}
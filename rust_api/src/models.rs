@@ -0,0 +1,224 @@
+use crate::crud::CrudModel;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+// Model
+#[derive(Serialize, Deserialize, Clone, CrudModel)]
+#[crud(table = "users")]
+pub struct User {
+    #[crud(id)]
+    pub id: Option<i32>,
+    #[crud(length(min = 1, max = 255))]
+    pub name: String,
+    #[crud(unique, email, length(max = 255))]
+    pub email: String,
+    /// Set by the storage on insert; ignored in request bodies.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[crud(generated)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Set by the storage whenever the name or email is written; ignored in
+    /// request bodies.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[crud(generated)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Request body schema shared by user create and update.
+pub fn user_schema() -> Value {
+    User::schema()
+}
+
+#[derive(Serialize, Deserialize, Clone, CrudModel)]
+#[crud(table = "addresses")]
+pub struct Address {
+    #[crud(id)]
+    pub id: Option<i32>,
+    /// Taken from the path; ignored in request bodies.
+    #[serde(skip_deserializing)]
+    #[crud(parent)]
+    pub user_id: Option<i32>,
+    #[crud(length(min = 1, max = 255))]
+    pub line1: String,
+    #[serde(default)]
+    #[crud(length(max = 255))]
+    pub line2: Option<String>,
+    #[crud(length(min = 1, max = 255))]
+    pub city: String,
+    #[crud(length(min = 1, max = 16))]
+    pub postal_code: String,
+    /// ISO 3166-1 alpha-2 code, e.g. `DE`.
+    #[crud(one_of = COUNTRY_CODES)]
+    pub country: String,
+}
+
+/// ISO 3166-1 alpha-2 country codes.
+const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+/// Request body schema shared by address create and update.
+pub fn address_schema() -> Value {
+    Address::schema()
+}
+
+/// Request body schema for replacing or patching user metadata. Its limits
+/// are checked once the result is known.
+pub fn metadata_schema() -> Value {
+    json!({ "type": "object" })
+}
+
+/// Request body schema for `PATCH /users/:id`: a JSON Patch list of
+/// operations or a JSON Merge Patch object. What each operation needs besides
+/// `op` and `path`, and what the merged user must look like, is checked once
+/// the patch is applied.
+pub fn user_patch_schema() -> Value {
+    json!({
+        "type": ["array", "object"],
+        "items": {
+            "type": "object",
+            "required": ["op", "path"],
+            "properties": {
+                "op": { "enum": ["add", "remove", "replace", "move", "copy", "test"] },
+                "path": { "type": "string" },
+                "from": { "type": "string" }
+            }
+        }
+    })
+}
+
+/// Most ids a single lookup may ask for.
+pub const MAX_LOOKUP_IDS: usize = 1000;
+
+/// Request body schema for `POST /users/lookup`: a list of ids.
+pub fn lookup_schema() -> Value {
+    json!({
+        "type": "array",
+        "items": { "type": "integer", "minimum": 1, "maximum": i32::MAX },
+        "maxItems": MAX_LOOKUP_IDS
+    })
+}
+
+/// Request body schema for the upsert, whose email comes from the path.
+pub fn upsert_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["name"],
+        "properties": {
+            "name": { "type": "string", "minLength": 1, "maxLength": 255 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Longest a log level override may last, in seconds.
+pub const MAX_LOG_OVERRIDE_SECS: u64 = 24 * 60 * 60;
+
+/// Request body schema for `PUT /admin/maintenance`; fields left out take the
+/// configured defaults.
+pub fn maintenance_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "message": { "type": "string", "minLength": 1, "maxLength": 500 },
+            "retry_after_secs": { "type": "integer", "minimum": 1, "maximum": 86400 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `POST /admin/users/:id/emails`. Every template but
+/// `welcome` also needs a `link`.
+pub fn email_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["template"],
+        "properties": {
+            "template": { "enum": ["welcome", "verification", "password_reset"] },
+            "link": { "type": "string", "minLength": 1, "maxLength": 2000 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `POST /admin/passwords/check`: the password and,
+/// to refuse passwords made of them, the user's name and email address.
+pub fn password_check_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["password"],
+        "properties": {
+            "password": { "type": "string", "maxLength": 1000 },
+            "name": { "type": "string", "maxLength": 255 },
+            "email": { "type": "string", "maxLength": 255 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `POST /admin/session`; `code` is the two-factor
+/// or recovery code, once two-factor login is enabled.
+pub fn session_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["token"],
+        "properties": {
+            "token": { "type": "string", "maxLength": 1000 },
+            "code": { "type": "string", "maxLength": 64 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `POST /admin/totp/confirm`.
+pub fn totp_confirm_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["code"],
+        "properties": {
+            "code": { "type": "string", "maxLength": 64 }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `PUT /admin/flags/:name`.
+pub fn flag_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["enabled"],
+        "properties": {
+            "enabled": { "type": "boolean" }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// Request body schema for `PUT /admin/log-level`. Without a `target` the
+/// level applies to every module, without `duration_secs` until cleared.
+pub fn log_level_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["level"],
+        "properties": {
+            "level": { "enum": ["off", "error", "warn", "info", "debug", "trace"] },
+            "target": { "type": "string", "maxLength": 255 },
+            "duration_secs": { "type": "integer", "minimum": 1, "maximum": MAX_LOG_OVERRIDE_SECS }
+        },
+        "additionalProperties": false
+    })
+}
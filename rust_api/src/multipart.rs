@@ -0,0 +1,328 @@
+use std::fmt;
+
+/*
+*  Multipart bodies
+*
+*  `multipart/form-data` (RFC 7578) is how browsers send forms with files, and
+*  what `fetch` sends for a `FormData`. The body is a run of parts, each with
+*  its own headers, between lines of `--<boundary>`; the last boundary is
+*  followed by `--`. Whatever comes before the first boundary or after the last
+*  is ignored, as RFC 2046 asks. Parts are borrowed from the body, so parsing
+*  copies nothing but the headers.
+*/
+
+/// Longest boundary RFC 2046 allows.
+const MAX_BOUNDARY: usize = 70;
+
+/// How much a multipart body may hold.
+pub struct Limits {
+    pub max_parts: usize,
+    /// Largest part content, in bytes.
+    pub max_part_size: usize,
+    /// Largest part headers, in bytes.
+    pub max_head_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_parts: 64,
+            max_part_size: 1024 * 1024,
+            max_head_size: 8 * 1024,
+        }
+    }
+}
+
+pub struct Part<'a> {
+    /// The form field, from `Content-Disposition`.
+    pub name: String,
+    /// The name of the file the browser sent, for file inputs.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// Every header of the part, `Content-Disposition` included.
+    pub headers: Vec<(String, String)>,
+    pub body: &'a [u8],
+}
+
+impl Part<'_> {
+    /// First value of the part header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MultipartError {
+    /// The `Content-Type` is not `multipart/form-data` with a usable boundary.
+    NotMultipart,
+    /// The body breaks the format; says where.
+    Malformed(&'static str),
+    TooManyParts,
+    /// Named for the part whose headers or content are over the limit.
+    PartTooLarge(String),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "not a multipart/form-data body"),
+            MultipartError::Malformed(problem) => {
+                write!(f, "malformed multipart body: {}", problem)
+            }
+            MultipartError::TooManyParts => write!(f, "too many parts"),
+            MultipartError::PartTooLarge(name) => write!(f, "part {:?} is too large", name),
+        }
+    }
+}
+
+/// Whether `content_type` is `multipart/form-data`, parameters aside.
+pub fn is_form_data(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("multipart/form-data")
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    if !is_form_data(content_type) {
+        return None;
+    }
+    let (_, params) = content_type.split_once(';')?;
+    parameters(params)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)
+        .filter(|boundary| (1..=MAX_BOUNDARY).contains(&boundary.len()))
+}
+
+/// Splits `body` into its parts.
+pub fn parse<'a>(
+    body: &'a [u8],
+    boundary: &str,
+    limits: &Limits,
+) -> Result<Vec<Part<'a>>, MultipartError> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // The first boundary may open the body, without a line break before it.
+    let mut rest = match body.strip_prefix(&delimiter[2..]) {
+        Some(rest) => rest,
+        None => {
+            let start = find(body, &delimiter).ok_or(MultipartError::Malformed("no boundary"))?;
+            &body[start + delimiter.len()..]
+        }
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n").ok_or(MultipartError::Malformed(
+            "boundary not followed by a line break",
+        ))?;
+        if parts.len() == limits.max_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+
+        // A part without headers starts with the blank line.
+        let (head, content) = match rest.strip_prefix(b"\r\n") {
+            Some(content) => (&b""[..], content),
+            None => {
+                let end = find(rest, b"\r\n\r\n")
+                    .ok_or(MultipartError::Malformed("part headers not terminated"))?;
+                (&rest[..end], &rest[end + 4..])
+            }
+        };
+        let headers = parse_headers(head);
+        let disposition = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Disposition"))
+            .map(|(_, value)| value.as_str())
+            .ok_or(MultipartError::Malformed(
+                "part without Content-Disposition",
+            ))?;
+        let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+        if !kind.trim().eq_ignore_ascii_case("form-data") {
+            return Err(MultipartError::Malformed("part is not form-data"));
+        }
+        let params = parameters(params);
+        let param = |wanted: &str| {
+            params
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value.clone())
+        };
+        let name = param("name").ok_or(MultipartError::Malformed("part without a name"))?;
+        if head.len() > limits.max_head_size {
+            return Err(MultipartError::PartTooLarge(name));
+        }
+
+        let end =
+            find(content, &delimiter).ok_or(MultipartError::Malformed("unterminated part"))?;
+        if end > limits.max_part_size {
+            return Err(MultipartError::PartTooLarge(name));
+        }
+        let content_type = headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.clone());
+        parts.push(Part {
+            filename: param("filename"),
+            name,
+            content_type,
+            headers,
+            body: &content[..end],
+        });
+        rest = &content[end + delimiter.len()..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Part headers as `Name: value` lines. Browsers send field names and
+/// filenames as raw UTF-8; anything else is replaced.
+fn parse_headers(head: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(head)
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// `; name=value` parameters, values optionally quoted with `\` escapes, so
+/// a quoted filename may hold `;` and `"`.
+fn parameters(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.next_if(|c| *c == ';' || c.is_whitespace()).is_some() {}
+        let name: String =
+            std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ';')).collect();
+        if name.is_empty() {
+            return parsed;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'=').is_some() {
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                value = std::iter::from_fn(|| chars.next_if(|c| *c != ';')).collect();
+            }
+        }
+        parsed.push((name.trim().to_string(), value.trim_end().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"name\"\r\n\
+        \r\n\
+        Ada Lovelace\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"avatar\"; filename=\"a;b \\\"c\\\".csv\"\r\n\
+        Content-Type: text/csv\r\n\
+        \r\n\
+        name,email\r\n--Xy not yet\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    #[test]
+    fn reads_the_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=XyZ").as_deref(),
+            Some("XyZ")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b;c\"").as_deref(),
+            Some("a b;c")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=XyZ"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(
+            boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))),
+            None
+        );
+    }
+
+    #[test]
+    fn splits_fields_and_files() {
+        let parts = parse(BODY, "XyZ", &Limits::default()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "name");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].body, b"Ada Lovelace");
+
+        assert_eq!(parts[1].name, "avatar");
+        assert_eq!(parts[1].filename.as_deref(), Some("a;b \"c\".csv"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("text/csv"));
+        assert_eq!(parts[1].header("content-type"), Some("text/csv"));
+        assert_eq!(parts[1].body, b"name,email\r\n--Xy not yet");
+    }
+
+    #[test]
+    fn enforces_the_limits() {
+        let limits = Limits {
+            max_parts: 1,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse(BODY, "XyZ", &limits).err(),
+            Some(MultipartError::TooManyParts)
+        );
+        let limits = Limits {
+            max_part_size: 12,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse(BODY, "XyZ", &limits).err(),
+            Some(MultipartError::PartTooLarge("avatar".to_string()))
+        );
+        let limits = Limits {
+            max_head_size: 50,
+            ..Limits::default()
+        };
+        assert_eq!(
+            parse(BODY, "XyZ", &limits).err(),
+            Some(MultipartError::PartTooLarge("avatar".to_string()))
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_bodies() {
+        for body in [
+            &b"no boundary at all"[..],
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nnever ends",
+            b"--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--",
+            b"--XyZ\r\nContent-Disposition: attachment; name=\"a\"\r\n\r\nx\r\n--XyZ--",
+            b"--XyZ\r\nContent-Disposition: form-data\r\n\r\nx\r\n--XyZ--",
+            b"--XyZjunk\r\n",
+        ] {
+            assert!(
+                matches!(
+                    parse(body, "XyZ", &Limits::default()),
+                    Err(MultipartError::Malformed(_))
+                ),
+                "{:?}",
+                String::from_utf8_lossy(body)
+            );
+        }
+        assert!(parse(b"--XyZ--", "XyZ", &Limits::default())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::http::Request;
+use crate::repository::{Comparison, Condition, Expression, Literal, Order, UserField, UserFilter};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/*
+*  OData query options
+*
+*  BI tools query collections through OData
+*  (https://docs.oasis-open.org/odata/odata/v4.01/odata-v4.01-part2-url-conventions.html),
+*  so `GET /users` understands a subset of its system query options:
+*
+*  - `$filter`: comparisons (`eq`, `ne`, `gt`, `ge`, `lt`, `le`) and the
+*    `contains`, `startswith` and `endswith` functions on text fields, joined
+*    with `and`, e.g. `name eq 'Ada' and created_at ge 2024-01-01T00:00:00Z`
+*  - `$orderby`: fields, each optionally followed by `asc` or `desc`
+*  - `$top` and `$skip`: the page
+*  - `$select`: the fields to send
+*
+*  Options are parsed into a `UserFilter`, so each backend runs them its own
+*  way; PostgreSQL gets them as conditions with parameters. Anything beyond
+*  the subset is refused rather than ignored.
+*/
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidQuery {
+    option: &'static str,
+    reason: String,
+}
+
+impl fmt::Display for InvalidQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.option, self.reason)
+    }
+}
+
+/// Adds the `$filter`, `$orderby`, `$top` and `$skip` of `request` to
+/// `filter`.
+pub fn apply(request: &Request, filter: &mut UserFilter) -> Result<(), InvalidQuery> {
+    if let Some(expression) = request.query("$filter") {
+        let conditions = parse_filter(expression).map_err(|reason| InvalidQuery {
+            option: "$filter",
+            reason,
+        })?;
+        filter
+            .conditions
+            .extend(conditions.into_iter().map(Expression::Condition));
+    }
+    if let Some(order) = request.query("$orderby") {
+        filter.order = parse_orderby(order).map_err(|reason| InvalidQuery {
+            option: "$orderby",
+            reason,
+        })?;
+    }
+    let count = |option: &'static str| {
+        request
+            .query(option)
+            .map(|value| {
+                value.trim().parse::<u64>().map_err(|_| InvalidQuery {
+                    option,
+                    reason: "expected a whole number".to_string(),
+                })
+            })
+            .transpose()
+    };
+    filter.top = count("$top")?;
+    filter.skip = count("$skip")?.unwrap_or(0);
+    Ok(())
+}
+
+/// The fields `$select` asks for, `None` for all of them.
+pub fn selection(request: &Request) -> Result<Option<Vec<UserField>>, InvalidQuery> {
+    let Some(select) = request.query("$select") else {
+        return Ok(None);
+    };
+    if select.trim() == "*" {
+        return Ok(None);
+    }
+    select
+        .split(',')
+        .map(|name| field(name.trim()))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(|reason| InvalidQuery {
+            option: "$select",
+            reason,
+        })
+}
+
+/// Drops the fields of a serialized user that were not selected.
+pub fn project(user: Value, fields: &[UserField]) -> Value {
+    match user {
+        Value::Object(mut object) => Value::Object(
+            fields
+                .iter()
+                .filter_map(|field| object.remove_entry(field.name()))
+                .collect::<Map<_, _>>(),
+        ),
+        other => other,
+    }
+}
+
+fn field(name: &str) -> Result<UserField, String> {
+    UserField::named(name).ok_or_else(|| format!("unknown field {:?}", name))
+}
+
+fn parse_orderby(order: &str) -> Result<Vec<Order>, String> {
+    order
+        .split(',')
+        .map(|item| {
+            let mut words = item.split_whitespace();
+            let field = field(words.next().unwrap_or_default())?;
+            let descending = match words.next() {
+                None | Some("asc") => false,
+                Some("desc") => true,
+                Some(other) => return Err(format!("expected asc or desc, found {:?}", other)),
+            };
+            match words.next() {
+                None => Ok(Order { field, descending }),
+                Some(other) => Err(format!("unexpected {:?}", other)),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Open,
+    Close,
+    Comma,
+}
+
+/// As written in the expression, for error messages.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Text(text) => write!(f, "'{}'", text.replace('\'', "''")),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                });
+            }
+            // Quotes inside a string are doubled.
+            '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            text.push('\'');
+                        }
+                        Some('\'') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | ',' | '\'') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// `condition (and condition)*`, where a condition is `field op literal` or
+/// `function(field, 'text')`.
+fn parse_filter(expression: &str) -> Result<Vec<Condition>, String> {
+    let mut tokens = tokenize(expression)?.into_iter();
+    let mut conditions = Vec::new();
+    loop {
+        conditions.push(parse_condition(&mut tokens)?);
+        match tokens.next() {
+            None => return Ok(conditions),
+            Some(Token::Word(word)) if word == "and" => {}
+            Some(Token::Word(word)) if word == "or" || word == "not" => {
+                return Err(format!("{} is not supported", word));
+            }
+            Some(other) => return Err(format!("expected and, found {}", other)),
+        }
+    }
+}
+
+fn parse_condition(tokens: &mut impl Iterator<Item = Token>) -> Result<Condition, String> {
+    let Some(Token::Word(first)) = tokens.next() else {
+        return Err("expected a field or function".to_string());
+    };
+    let function = match first.as_str() {
+        "contains" => Some(Comparison::Contains),
+        "startswith" => Some(Comparison::StartsWith),
+        "endswith" => Some(Comparison::EndsWith),
+        _ => None,
+    };
+    if let Some(comparison) = function {
+        let (Some(Token::Open), Some(Token::Word(name)), Some(Token::Comma), Some(value)) =
+            (tokens.next(), tokens.next(), tokens.next(), tokens.next())
+        else {
+            return Err(format!("expected {}(field, 'text')", first));
+        };
+        if tokens.next() != Some(Token::Close) {
+            return Err(format!("expected ) after the arguments of {}", first));
+        }
+        let field = field(&name)?;
+        return match (literal(field, value)?, field) {
+            (value @ Literal::Text(_), UserField::Name | UserField::Email) => Ok(Condition {
+                field,
+                comparison,
+                value,
+            }),
+            _ => Err(format!("{} takes a text field and a string", first)),
+        };
+    }
+
+    let field = field(&first)?;
+    let comparison = match tokens.next() {
+        Some(Token::Word(op)) => match op.as_str() {
+            "eq" => Comparison::Eq,
+            "ne" => Comparison::Ne,
+            "gt" => Comparison::Gt,
+            "ge" => Comparison::Ge,
+            "lt" => Comparison::Lt,
+            "le" => Comparison::Le,
+            _ => return Err(format!("unknown operator {:?}", op)),
+        },
+        _ => return Err(format!("expected an operator after {}", first)),
+    };
+    let value = tokens
+        .next()
+        .ok_or_else(|| format!("expected a value to compare {} with", first))?;
+    Ok(Condition {
+        field,
+        comparison,
+        value: literal(field, value)?,
+    })
+}
+
+/// A literal of the field's type: integers for `id`, strings for text and
+/// RFC 3339 timestamps, quoted or not, for times.
+fn literal(field: UserField, token: Token) -> Result<Literal, String> {
+    match (field, token) {
+        (UserField::Id, Token::Word(number)) => number
+            .parse()
+            .map(Literal::Integer)
+            .map_err(|_| format!("{:?} is not an id", number)),
+        (UserField::Name | UserField::Email, Token::Text(text)) => Ok(Literal::Text(text)),
+        (UserField::CreatedAt | UserField::UpdatedAt, Token::Word(time) | Token::Text(time)) => {
+            DateTime::parse_from_rfc3339(&time)
+                .map(|time| Literal::Timestamp(time.with_timezone(&Utc)))
+                .map_err(|_| format!("{:?} is not an RFC 3339 timestamp", time))
+        }
+        (field, token) => Err(format!(
+            "{} cannot be compared with {}",
+            token,
+            field.name()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn get(target: &str) -> Request {
+        Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            parse_filter("name eq 'O''Brien' and created_at ge 2024-01-01T00:00:00Z"),
+            Ok(vec![
+                Condition {
+                    field: UserField::Name,
+                    comparison: Comparison::Eq,
+                    value: Literal::Text("O'Brien".to_string()),
+                },
+                Condition {
+                    field: UserField::CreatedAt,
+                    comparison: Comparison::Ge,
+                    value: Literal::Timestamp(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+                },
+            ])
+        );
+        assert_eq!(
+            parse_filter("contains(email,'@example.com') and id gt 10")
+                .unwrap()
+                .iter()
+                .map(|condition| condition.comparison)
+                .collect::<Vec<_>>(),
+            [Comparison::Contains, Comparison::Gt]
+        );
+
+        for invalid in [
+            "name eq 'Ada' or name eq 'Grace'",
+            "name eq 1",
+            "id eq 'one'",
+            "password eq 'x'",
+            "contains(id, '1')",
+            "name eq 'Ada",
+            "name like 'A'",
+            "name eq 'Ada' name",
+        ] {
+            assert!(parse_filter(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn applies_options_to_the_filter() {
+        let request = get("/users?$orderby=name%20desc,id&$top=5&$skip=10&$filter=id%20le%203");
+        let mut filter = UserFilter::default();
+        apply(&request, &mut filter).unwrap();
+        assert_eq!(
+            filter.order,
+            [
+                Order {
+                    field: UserField::Name,
+                    descending: true
+                },
+                Order {
+                    field: UserField::Id,
+                    descending: false
+                },
+            ]
+        );
+        assert_eq!((filter.top, filter.skip), (Some(5), 10));
+        assert_eq!(filter.conditions.len(), 1);
+
+        let error = apply(&get("/users?$top=-1"), &mut UserFilter::default()).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid $top: expected a whole number");
+        assert!(apply(
+            &get("/users?$orderby=name%20up"),
+            &mut UserFilter::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn selects_fields() {
+        let fields = selection(&get("/users?$select=id,email")).unwrap().unwrap();
+        let user = serde_json::json!({ "id": 1, "name": "Ada", "email": "ada@example.com" });
+        assert_eq!(
+            project(user, &fields),
+            serde_json::json!({ "id": 1, "email": "ada@example.com" })
+        );
+        assert_eq!(selection(&get("/users?$select=*")), Ok(None));
+        assert!(selection(&get("/users?$select=password")).is_err());
+    }
+}
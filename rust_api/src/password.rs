@@ -0,0 +1,239 @@
+use crate::https;
+use crate::schema::Violation;
+use crate::statsd;
+use log::warn;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/*
+*  Password policy
+*
+*  rust-crud keeps no passwords itself. The services that own registration and
+*  password resets ask it whether a new password is acceptable, so they all
+*  enforce the same rules. A password has to be long enough and must not
+*  contain the user's name or email address. It must not be a known breached
+*  password either: one of the common passwords shipped with the binary, or,
+*  with `PASSWORD_BREACH_CHECK=api`, one that Have I Been Pwned lists. That API
+*  is asked by k-anonymity, so only the first five hex digits of the
+*  password's SHA-1 leave the process; when it cannot be reached the password
+*  is let through rather than holding up every registration.
+*  https://haveibeenpwned.com/API/v3#PwnedPasswords
+*/
+
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shorter parts of a name or email address are too common to refuse.
+const MIN_USER_DATA: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreachCheck {
+    Off,
+    /// Against the common passwords shipped with the binary.
+    List,
+    /// Against the list, then the Have I Been Pwned range API.
+    Api,
+}
+
+impl FromStr for BreachCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(BreachCheck::Off),
+            "list" => Ok(BreachCheck::List),
+            "api" => Ok(BreachCheck::Api),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct PasswordPolicy {
+    min_length: usize,
+    breach_check: BreachCheck,
+    /// `<PASSWORD_BREACH_API_URL>/range/`
+    range_url: String,
+    agent: ureq::Agent,
+}
+
+impl PasswordPolicy {
+    pub fn new(min_length: usize, breach_check: BreachCheck, api_url: &str) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length,
+            breach_check,
+            range_url: format!("{}/range/", api_url.trim_end_matches('/')),
+            agent: https::agent(API_TIMEOUT),
+        }
+    }
+
+    /// Every rule `password` breaks, pointing at `/password`. `user_data` are
+    /// the name and email address of the user it is for, when known.
+    pub fn check(&self, password: &str, user_data: &[&str]) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let mut violation = |code, message: String| {
+            violations.push(Violation {
+                pointer: "/password".to_string(),
+                code,
+                message,
+            })
+        };
+
+        if password.chars().count() < self.min_length {
+            violation(
+                "too_short",
+                format!("Must be at least {} characters long", self.min_length),
+            );
+        }
+        let lowercase = password.to_lowercase();
+        if user_data
+            .iter()
+            .flat_map(|data| data.split(|c: char| !c.is_alphanumeric()))
+            .filter(|part| part.chars().count() >= MIN_USER_DATA)
+            .any(|part| lowercase.contains(&part.to_lowercase()))
+        {
+            violation(
+                "contains_user_data",
+                "Must not contain the user's name or email address".to_string(),
+            );
+        }
+        if self.breach_check == BreachCheck::Off {
+            return violations;
+        }
+        if common_passwords().contains(lowercase.as_str()) {
+            violation("common", "Is one of the most common passwords".to_string());
+        } else if self.breach_check == BreachCheck::Api {
+            match self.times_breached(password) {
+                Ok(0) => {}
+                Ok(count) => violation(
+                    "breached",
+                    format!("Appeared in {} known data breaches", count),
+                ),
+                Err(e) => {
+                    statsd::count("password.breach_check_failed", 1, &[]);
+                    warn!("Breached password check skipped: {}", e);
+                }
+            }
+        }
+        violations
+    }
+
+    fn times_breached(&self, password: &str) -> Result<u64, String> {
+        let hash = sha1_hex(password);
+        let (prefix, suffix) = hash.split_at(5);
+        let mut response = self
+            .agent
+            .get(format!("{}{}", self.range_url, prefix))
+            // Hides from the network how many hashes share the prefix.
+            .header("Add-Padding", "true")
+            .call()
+            .map_err(|e| e.to_string())?;
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| e.to_string())?;
+        Ok(times_listed(&body, suffix))
+    }
+}
+
+/// Uppercase, as the range API lists them.
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+/// The count next to `suffix` in a range API response of `<suffix>:<count>`
+/// lines; padding lines have a count of 0.
+fn times_listed(range: &str, suffix: &str) -> u64 {
+    range
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(listed, _)| listed.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, count)| count.parse().ok())
+        .unwrap_or(0)
+}
+
+fn common_passwords() -> &'static HashSet<&'static str> {
+    static LIST: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    LIST.get_or_init(|| {
+        include_str!("../assets/common-passwords.txt")
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(violations: Vec<Violation>) -> Vec<&'static str> {
+        violations
+            .into_iter()
+            .map(|violation| violation.code)
+            .collect()
+    }
+
+    #[test]
+    fn names_every_broken_rule() {
+        let policy = PasswordPolicy::new(12, BreachCheck::List, "http://127.0.0.1:9");
+        assert!(policy
+            .check(
+                "correct horse battery",
+                &["Ada Lovelace", "ada@example.com"]
+            )
+            .is_empty());
+        assert_eq!(codes(policy.check("Tr0ub4dor&3", &[])), ["too_short"]);
+        assert_eq!(
+            codes(policy.check("lovelace-rocks-2024", &["Ada Lovelace"])),
+            ["contains_user_data"]
+        );
+        assert_eq!(
+            codes(policy.check("ada-rules-2024!", &["Ada"])),
+            [] as [&str; 0]
+        );
+        assert_eq!(
+            codes(policy.check("Password1234", &["ada@example.com"])),
+            ["common"]
+        );
+        assert_eq!(
+            codes(policy.check("qwerty12", &[])),
+            ["too_short", "common"]
+        );
+        assert!(PasswordPolicy::new(12, BreachCheck::Off, "")
+            .check("password1234", &[])
+            .is_empty());
+    }
+
+    #[test]
+    fn lets_passwords_through_when_the_api_is_down() {
+        let policy = PasswordPolicy::new(12, BreachCheck::Api, "http://127.0.0.1:9");
+        assert!(policy.check("correct horse battery", &[]).is_empty());
+    }
+
+    #[test]
+    fn reads_range_responses() {
+        assert_eq!(
+            sha1_hex("password"),
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8"
+        );
+        let range = "1D2DA4053E34E76F6576ED1DA63134B5E2A:2\r\n\
+                     1E4C9B93F3F0682250B6CF8331B7EE68FD8:9659365\r\n\
+                     1E4D2CCE0A21B3DE02B6E8C2FD2E8E0C3A5:0\r\n";
+        assert_eq!(
+            times_listed(range, "1E4C9B93F3F0682250B6CF8331B7EE68FD8"),
+            9659365
+        );
+        assert_eq!(
+            times_listed(range, "1e4c9b93f3f0682250b6cf8331b7ee68fd8"),
+            9659365
+        );
+        assert_eq!(
+            times_listed(range, "1E4D2CCE0A21B3DE02B6E8C2FD2E8E0C3A5"),
+            0
+        );
+        assert_eq!(times_listed(range, "FFFFF"), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::statsd;
+use postgres::Error as PostgresError;
+use postgres::{Client, NoTls};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+*  Connection pool
+*
+*  How long each checkout waited goes to StatsD; `stats` is sampled for the
+*  gauges.
+*/
+
+pub struct Pool {
+    database_url: String,
+    /// Applied to every new connection; zero leaves the server default.
+    statement_timeout: Duration,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    idle: Vec<Client>,
+    /// Idle plus checked-out connections.
+    open: usize,
+    max_size: usize,
+    /// Callers blocked until a connection is released.
+    waiting: usize,
+}
+
+/// A snapshot of the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub max_size: usize,
+    pub open: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    pub waiting: usize,
+}
+
+/// A connection borrowed from the pool; it goes back when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    client: Option<Client>,
+}
+
+impl Pool {
+    pub fn new(database_url: String, max_size: usize, statement_timeout: Duration) -> Self {
+        Pool {
+            database_url,
+            statement_timeout,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+                max_size,
+                waiting: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for an idle connection or opens a new one while below `max_size`.
+    pub fn get(&self) -> Result<PooledClient<'_>, PostgresError> {
+        let started = Instant::now();
+        let client = self.checkout();
+        statsd::timing("db.pool.checkout_wait", started.elapsed(), &[]);
+        client
+    }
+
+    fn checkout(&self) -> Result<PooledClient<'_>, PostgresError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                if client.is_closed() {
+                    state.open -= 1;
+                    continue;
+                }
+                return Ok(PooledClient {
+                    pool: self,
+                    client: Some(client),
+                });
+            }
+
+            if state.open < state.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(client) => Ok(PooledClient {
+                        pool: self,
+                        client: Some(client),
+                    }),
+                    Err(e) => {
+                        self.state.lock().unwrap().open -= 1;
+                        self.released.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+
+            state.waiting += 1;
+            state = self.released.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.state.lock().unwrap();
+        PoolStats {
+            max_size: state.max_size,
+            open: state.open,
+            idle: state.idle.len(),
+            in_use: state.open - state.idle.len(),
+            waiting: state.waiting,
+        }
+    }
+
+    fn connect(&self) -> Result<Client, PostgresError> {
+        let mut client = Client::connect(&self.database_url, NoTls)?;
+        if !self.statement_timeout.is_zero() {
+            client.batch_execute(&format!(
+                "SET statement_timeout = {}",
+                self.statement_timeout.as_millis()
+            ))?;
+        }
+        Ok(client)
+    }
+
+    /// Changes the connection cap. Surplus connections are closed as they are
+    /// returned rather than interrupted mid-query.
+    pub fn resize(&self, max_size: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_size = max_size;
+        while state.open > state.max_size && state.idle.pop().is_some() {
+            state.open -= 1;
+        }
+        self.released.notify_all();
+    }
+
+    fn release(&self, client: Client) {
+        let mut state = self.state.lock().unwrap();
+        if client.is_closed() || state.open > state.max_size {
+            state.open -= 1;
+        } else {
+            state.idle.push(client);
+        }
+        self.released.notify_one();
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.release(client);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::cidr::Cidr;
+use crate::http::Request;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/*
+*  Proxy awareness
+*
+*  The client address starts out as the socket peer (or the source announced in
+*  a PROXY protocol header). While that address belongs to a trusted proxy, the
+*  right-most remaining `Forwarded`/`X-Forwarded-For` hop replaces it. The first
+*  untrusted hop is the client; anything left of it could have been forged.
+*/
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// Bytes taken up by the header, which the HTTP request follows.
+    pub length: usize,
+    /// The original client, absent for `LOCAL`/`UNKNOWN` connections.
+    pub source: Option<IpAddr>,
+}
+
+/// The longest v1 header, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+/// The longest v2 header accepted. Addresses take at most 216 bytes; the rest
+/// leaves room for the TLVs proxies append.
+const V2_MAX_LENGTH: usize = 536;
+
+/// Reads from `stream` until `buffer` starts with a complete PROXY header and
+/// parses it, like `http::read_head` does for the request head. Bytes already
+/// in `buffer` count. `None` if the peer closed the connection first or the
+/// header is invalid.
+pub fn read_proxy_header(
+    stream: &mut dyn Read,
+    buffer: &mut Vec<u8>,
+) -> io::Result<Option<ProxyHeader>> {
+    loop {
+        if let Some(header) = parse_proxy_header(buffer) {
+            return Ok(Some(header));
+        }
+        if !is_incomplete(buffer) {
+            return Ok(None);
+        }
+        let mut chunk = [0; 512];
+        match stream.read(&mut chunk)? {
+            0 => return Ok(None),
+            size => buffer.extend_from_slice(&chunk[..size]),
+        }
+    }
+}
+
+/// Whether `buffer` is the start of a header that more bytes could complete.
+fn is_incomplete(buffer: &[u8]) -> bool {
+    let shorter = buffer.len().min(V2_SIGNATURE.len());
+    if buffer[..shorter] == V2_SIGNATURE[..shorter] {
+        return match buffer.get(14..16) {
+            Some(length) => {
+                let length = 16 + u16::from_be_bytes([length[0], length[1]]) as usize;
+                length <= V2_MAX_LENGTH && buffer.len() < length
+            }
+            None => true,
+        };
+    }
+    let shorter = buffer.len().min(6);
+    buffer[..shorter] == b"PROXY "[..shorter]
+        && buffer.len() < V1_MAX_LENGTH
+        && !buffer.windows(2).any(|pair| pair == b"\r\n")
+}
+
+/// Parses a PROXY protocol v1 or v2 header at the start of `buffer`.
+pub fn parse_proxy_header(buffer: &[u8]) -> Option<ProxyHeader> {
+    if buffer.starts_with(b"PROXY ") {
+        parse_v1(buffer)
+    } else if buffer.starts_with(V2_SIGNATURE) {
+        parse_v2(buffer)
+    } else {
+        None
+    }
+}
+
+fn parse_v1(buffer: &[u8]) -> Option<ProxyHeader> {
+    let end = buffer
+        .windows(2)
+        .take(V1_MAX_LENGTH - 1)
+        .position(|pair| pair == b"\r\n")?;
+    let line = std::str::from_utf8(&buffer[..end]).ok()?;
+    let mut fields = line.split(' ').skip(1);
+
+    let source = match fields.next()? {
+        "TCP4" | "TCP6" => Some(fields.next()?.parse().ok()?),
+        "UNKNOWN" => None,
+        _ => return None,
+    };
+
+    Some(ProxyHeader {
+        length: end + 2,
+        source,
+    })
+}
+
+fn parse_v2(buffer: &[u8]) -> Option<ProxyHeader> {
+    let header = buffer.get(..16)?;
+    let version_command = header[12];
+    let family = header[13];
+    let address_length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if 16 + address_length > V2_MAX_LENGTH {
+        return None;
+    }
+    let addresses = buffer.get(16..16 + address_length)?;
+
+    if version_command >> 4 != 2 {
+        return None;
+    }
+
+    let source = match (version_command & 0x0f, family >> 4) {
+        // LOCAL: health checks from the proxy itself.
+        (0x0, _) => None,
+        (0x1, 0x1) => {
+            let octets: [u8; 4] = addresses.get(..4)?.try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        (0x1, 0x2) => {
+            let octets: [u8; 16] = addresses.get(..16)?.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        (0x1, _) => None,
+        _ => return None,
+    };
+
+    Some(ProxyHeader {
+        length: 16 + address_length,
+        source,
+    })
+}
+
+/// Resolves the client address for `request` as seen through trusted proxies.
+/// `peer` is `None` for Unix socket connections, which can only come from the
+/// local host and are therefore trusted.
+pub fn client_addr(peer: Option<IpAddr>, request: &Request, trusted: &[Cidr]) -> Option<IpAddr> {
+    let is_trusted =
+        |ip: Option<IpAddr>| ip.is_none_or(|ip| trusted.iter().any(|net| net.contains(ip)));
+
+    let hops = forwarded_hops(request);
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        if !is_trusted(client) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = Some(*ip),
+            None => break,
+        }
+    }
+    client
+}
+
+/// Hops listed by the `Forwarded` header, or `X-Forwarded-For` when it is
+/// absent, from the original client to the nearest proxy. Obfuscated or
+/// unparsable entries are kept as `None`.
+fn forwarded_hops(request: &Request) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = request
+        .header_values("Forwarded")
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value.trim_matches('"')))
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    request
+        .header_values("X-Forwarded-For")
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` and `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out its bytes one at a time, like a slow network.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn reads_proxy_headers_split_across_reads() {
+        let v1 = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\nGET / HTTP/1.1\r\n\r\n";
+        let mut buffer = b"PRO".to_vec();
+        let header = read_proxy_header(&mut Trickle(&v1[3..]), &mut buffer).unwrap();
+        assert_eq!(
+            header,
+            Some(ProxyHeader {
+                length: 44,
+                source: Some("203.0.113.7".parse().unwrap()),
+            })
+        );
+        assert_eq!(&buffer[..44], &v1[..44]);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 198, 51, 100, 9, 10, 0, 0, 1, 0, 80, 0, 80,
+        ]);
+        let mut buffer = Vec::new();
+        let header = read_proxy_header(&mut Trickle(&v2), &mut buffer).unwrap();
+        assert_eq!(
+            header.unwrap().source,
+            Some("198.51.100.9".parse().unwrap())
+        );
+
+        // Gives up as soon as it cannot be a header, or when the peer leaves.
+        let mut buffer = Vec::new();
+        let mut request = Trickle(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(read_proxy_header(&mut request, &mut buffer).unwrap(), None);
+        assert_eq!(buffer, b"G");
+        let mut buffer = Vec::new();
+        let mut cut_short = Trickle(b"PROXY TCP4");
+        assert_eq!(
+            read_proxy_header(&mut cut_short, &mut buffer).unwrap(),
+            None
+        );
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        Request::parse(format!("GET / HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap()
+    }
+
+    fn ip(text: &str) -> Option<IpAddr> {
+        Some(text.parse().unwrap())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn walks_forwarded_hops_until_the_first_untrusted_one() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let forwarded = request(&[
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.7"),
+            ("X-Forwarded-For", "10.0.0.3"),
+        ]);
+        // 10.0.0.3 is a proxy too, so the client is the hop left of it;
+        // 198.51.100.1 was named by the untrusted client and is ignored.
+        assert_eq!(
+            client_addr(ip("10.0.0.2"), &forwarded, &trusted),
+            ip("203.0.113.7")
+        );
+        // An untrusted peer could have written any of it.
+        assert_eq!(
+            client_addr(ip("192.0.2.9"), &forwarded, &trusted),
+            ip("192.0.2.9")
+        );
+        // Unix socket peers are local and trusted.
+        assert_eq!(client_addr(None, &forwarded, &trusted), ip("203.0.113.7"));
+        // A hop that is not an address stops the walk at the proxy naming it.
+        let obfuscated = request(&[("X-Forwarded-For", "unknown")]);
+        assert_eq!(
+            client_addr(ip("10.0.0.2"), &obfuscated, &trusted),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn reads_forwarded_before_x_forwarded_for() {
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let request = request(&[
+            (
+                "Forwarded",
+                r#"for=192.0.2.60;proto=https, For="[2001:db8:cafe::17]:4711""#,
+            ),
+            ("X-Forwarded-For", "198.51.100.1"),
+        ]);
+        assert_eq!(
+            forwarded_hops(&request),
+            vec![ip("192.0.2.60"), ip("2001:db8:cafe::17")]
+        );
+        assert_eq!(
+            client_addr(ip("10.0.0.2"), &request, &trusted),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(parse_node("192.0.2.60:8080"), ip("192.0.2.60"));
+        assert_eq!(parse_node("2001:db8::1"), ip("2001:db8::1"));
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        assert_eq!(
+            parse_proxy_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n"),
+            Some(ProxyHeader {
+                length: 45,
+                source: ip("2001:db8::1"),
+            })
+        );
+        assert_eq!(
+            parse_proxy_header(b"PROXY UNKNOWN\r\n"),
+            Some(ProxyHeader {
+                length: 15,
+                source: None,
+            })
+        );
+        for line in [
+            &b"PROXY TCP4 not-an-address 10.0.0.1 1 2\r\n"[..],
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 1 2",
+        ] {
+            assert_eq!(parse_proxy_header(line), None, "{:?}", line);
+        }
+        // No CRLF within the longest possible line.
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(200, b'x');
+        long.extend_from_slice(b"\r\n");
+        assert_eq!(parse_proxy_header(&long), None);
+        assert!(!is_incomplete(&long[..V1_MAX_LENGTH]));
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_v2_headers() {
+        let header = v2(0x1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0, 80, 0, 80]);
+        assert_eq!(
+            parse_proxy_header(&header).unwrap().source,
+            ip("203.0.113.7")
+        );
+        for end in [13, 16, header.len() - 1] {
+            assert_eq!(parse_proxy_header(&header[..end]), None);
+            assert!(is_incomplete(&header[..end]));
+        }
+        // Shorter than the address family needs.
+        assert_eq!(parse_proxy_header(&v2(0x1, 0x21, &[0; 12])), None);
+        // Version 1 in the binary format.
+        let mut version_1 = header.clone();
+        version_1[12] = 0x11;
+        assert_eq!(parse_proxy_header(&version_1), None);
+
+        let oversized = v2(0x1, 0x11, &vec![0; V2_MAX_LENGTH]);
+        assert_eq!(parse_proxy_header(&oversized), None);
+        assert!(!is_incomplete(&oversized[..16]));
+    }
+
+    #[test]
+    fn v2_local_connections_have_no_source() {
+        let local = v2(0x0, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0, 80, 0, 80]);
+        assert_eq!(
+            parse_proxy_header(&local),
+            Some(ProxyHeader {
+                length: 28,
+                source: None,
+            })
+        );
+        let proxy = v2(
+            0x1,
+            0x21,
+            &[[0x20, 0x01, 0x0d, 0xb8], [0; 4], [0; 4], [0, 0, 0, 1]].concat(),
+        );
+        assert_eq!(
+            parse_proxy_header(&proxy).unwrap().source,
+            ip("2001:db8::1")
+        );
+        // Unix sockets and unspecified families carry no address to use.
+        assert_eq!(
+            parse_proxy_header(&v2(0x1, 0x31, &[0; 216]))
+                .unwrap()
+                .source,
+            None
+        );
+        // Commands other than LOCAL and PROXY are invalid.
+        assert_eq!(parse_proxy_header(&v2(0x2, 0x11, &[0; 12])), None);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::config::Config;
+use log::{info, warn};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/*
+*  Hot reload
+*
+*  A background thread re-reads the configuration on SIGHUP or whenever one of
+*  the profile's config files changes, and hands the result to `apply`. Only
+*  tunables should be applied; settings such as the storage backend keep their
+*  startup values until the process restarts.
+*/
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub fn watch<F>(config: Config, apply: F) -> std::io::Result<()>
+where
+    F: Fn(&Config) + Send + 'static,
+{
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::Builder::new()
+        .name("config-reload".to_string())
+        .spawn(move || {
+            let files = config.files();
+            let mut stamps = modified_times(&files);
+            let mut current = config;
+
+            loop {
+                thread::sleep(POLL_INTERVAL);
+
+                let hangup = signals.pending().count() > 0;
+                let latest = modified_times(&files);
+                if !hangup && latest == stamps {
+                    continue;
+                }
+                stamps = latest;
+
+                match current.reload() {
+                    Ok(next) => {
+                        for warning in &next.warnings {
+                            warn!("{}", warning);
+                        }
+                        warn_on_restart_only_changes(&current, &next);
+                        apply(&next);
+                        info!("Configuration reloaded");
+                        current = next;
+                    }
+                    Err(e) => warn!("Config Reload Error: {}, keeping previous settings", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+fn warn_on_restart_only_changes(current: &Config, next: &Config) {
+    if current.storage != next.storage || current.database_url != next.database_url {
+        warn!("Storage settings changed; restart to apply them");
+    }
+    if current.listen != next.listen {
+        warn!("LISTEN changed; restart to apply it");
+    }
+    if current.base_path != next.base_path {
+        warn!("BASE_PATH changed; restart to apply it");
+    }
+}
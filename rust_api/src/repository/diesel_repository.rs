@@ -0,0 +1,1045 @@
+use super::migrations::{self, HISTORY, MIGRATION_LOCK};
+use super::postgres_repository::outbox_payload;
+use super::{
+    slow_query, AddressRepository, Comparison, Expression, FlagRepository, Literal, Order,
+    RepositoryError, TotpRepository, Upserted, UserField, UserFilter, UserRepository,
+};
+use crate::crypto::{BlindIndex, FieldCipher};
+use crate::events::{Event, EventKind};
+use crate::models::{Address, User};
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use diesel::connection::{Instrumentation, InstrumentationEvent, SimpleConnection};
+use diesel::dsl::{max, now, sql, AsSelect, SqlTypeOf};
+use diesel::pg::{Pg, PgRowByRowLoadingMode};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PoolError, PooledConnection};
+use diesel::result::{DatabaseErrorKind, Error as QueryError};
+use diesel::sql_types::{BigInt, Bool, Integer, Text};
+use diesel::upsert::excluded;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/*
+*  Diesel backend
+*
+*  The same tables as `PostgresUserRepository`, mapped with Diesel's `table!`
+*  below, for `STORAGE=diesel` in builds with the `diesel` feature. Queries
+*  are built with Diesel's DSL, so they are type checked against that mapping
+*  but not against the database: the mapping has to follow every migration.
+*  Connections come from Diesel's r2d2 pool and go through libpq.
+*
+*  Migrations apply the same scripts, recorded in the same
+*  `schema_migrations`, so a database can move between the backends. Emails
+*  are encrypted and indexed alike, and with the outbox on user changes queue
+*  their event in the same transaction, for `PostgresUserRepository` to relay.
+*/
+
+mod schema {
+    diesel::table! {
+        users (id) {
+            id -> Integer,
+            name -> Varchar,
+            email -> Varchar,
+            created_at -> Timestamptz,
+            metadata -> Jsonb,
+            updated_at -> Timestamptz,
+            email_index -> Nullable<Varchar>,
+        }
+    }
+
+    diesel::table! {
+        addresses (id) {
+            id -> Integer,
+            user_id -> Integer,
+            line1 -> Varchar,
+            line2 -> Nullable<Varchar>,
+            city -> Varchar,
+            postal_code -> Varchar,
+            country -> Bpchar,
+        }
+    }
+
+    diesel::table! {
+        feature_flags (name) {
+            name -> Varchar,
+            enabled -> Bool,
+            updated_at -> Timestamptz,
+        }
+    }
+
+    diesel::table! {
+        totp (account) {
+            account -> Varchar,
+            secret -> Varchar,
+            enabled -> Bool,
+            recovery_codes -> Array<Text>,
+            last_step -> BigInt,
+        }
+    }
+
+    diesel::table! {
+        outbox (id) {
+            id -> BigInt,
+            user_id -> Integer,
+            payload -> Jsonb,
+            created_at -> Timestamptz,
+            sent_at -> Nullable<Timestamptz>,
+        }
+    }
+
+    diesel::table! {
+        schema_migrations (version) {
+            version -> Integer,
+            applied_at -> Timestamptz,
+        }
+    }
+}
+
+use schema::{addresses, feature_flags, outbox, schema_migrations, totp, users};
+
+diesel::define_sql_function!(fn strpos(string: Text, substring: Text) -> Integer);
+diesel::define_sql_function!(fn starts_with(string: Text, prefix: Text) -> Bool);
+diesel::define_sql_function!(fn right(string: Text, n: Integer) -> Text);
+diesel::define_sql_function!(fn char_length(string: Text) -> Integer);
+
+#[derive(Debug)]
+pub enum DieselError {
+    Query(QueryError),
+    /// No connection could be had from the pool.
+    Pool(PoolError),
+}
+
+impl fmt::Display for DieselError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DieselError::Query(e) => write!(f, "{}", e),
+            DieselError::Pool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<QueryError> for RepositoryError {
+    fn from(e: QueryError) -> Self {
+        match &e {
+            QueryError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                RepositoryError::Conflict
+            }
+            // libpq's errors only carry the message.
+            QueryError::DatabaseError(_, info)
+                if info
+                    .message()
+                    .starts_with("canceling statement due to statement timeout") =>
+            {
+                RepositoryError::Timeout
+            }
+            _ => RepositoryError::Diesel(DieselError::Query(e)),
+        }
+    }
+}
+
+impl From<PoolError> for RepositoryError {
+    fn from(e: PoolError) -> Self {
+        RepositoryError::Diesel(DieselError::Pool(e))
+    }
+}
+
+/// Sets up each new connection.
+#[derive(Debug)]
+struct Session {
+    statement_timeout: Duration,
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for Session {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        connection.set_instrumentation(SlowQueries::default());
+        if !self.statement_timeout.is_zero() {
+            connection
+                .batch_execute(&format!(
+                    "SET statement_timeout = {}",
+                    self.statement_timeout.as_millis()
+                ))
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Feeds every statement's duration to the slow query log.
+#[derive(Default)]
+struct SlowQueries {
+    started: Option<Instant>,
+}
+
+impl Instrumentation for SlowQueries {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { .. } => self.started = Some(Instant::now()),
+            InstrumentationEvent::FinishQuery { query, error, .. } => {
+                let Some(started) = self.started.take() else {
+                    return;
+                };
+                slow_query::record(started.elapsed(), error.is_none(), || {
+                    // Diesel shows the parameters after the SQL, as one list.
+                    let query = query.to_string();
+                    match query.split_once(" -- binds: ") {
+                        Some((sql, binds)) => {
+                            let binds = binds.trim_start_matches('[').trim_end_matches(']');
+                            (sql.to_string(), vec![binds.to_string()])
+                        }
+                        None => (query, Vec::new()),
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = users, check_for_backend(Pg))]
+struct UserRow {
+    id: i32,
+    name: String,
+    email: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = users)]
+struct NewUser<'a> {
+    name: &'a str,
+    email: &'a str,
+    email_index: Option<&'a str>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = addresses, check_for_backend(Pg))]
+struct AddressRow {
+    id: i32,
+    user_id: i32,
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    postal_code: String,
+    country: String,
+}
+
+impl From<AddressRow> for Address {
+    fn from(row: AddressRow) -> Self {
+        Address {
+            id: Some(row.id),
+            user_id: Some(row.user_id),
+            line1: row.line1,
+            line2: row.line2,
+            city: row.city,
+            postal_code: row.postal_code,
+            country: row.country,
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = addresses)]
+struct NewAddress<'a> {
+    user_id: i32,
+    line1: &'a str,
+    line2: Option<&'a str>,
+    city: &'a str,
+    postal_code: &'a str,
+    country: &'a str,
+}
+
+type Pooled = PooledConnection<ConnectionManager<PgConnection>>;
+/// A condition on users, as Diesel boxes it.
+type Condition = Box<dyn BoxableExpression<users::table, Pg, SqlType = Bool>>;
+
+pub struct DieselRepository {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// Encrypts the email column, and two-factor secrets, when set.
+    cipher: Option<FieldCipher>,
+    /// Indexes encrypted emails for lookups and uniqueness.
+    email_index: Option<BlindIndex>,
+    /// Queue an event in the `outbox` table with every user change.
+    outbox: bool,
+}
+
+impl DieselRepository {
+    /// Connects on first use, at most `max_connections` at a time, each
+    /// cancelling statements after `statement_timeout` unless it is zero.
+    pub fn new(
+        database_url: &str,
+        max_connections: usize,
+        statement_timeout: Duration,
+        cipher: Option<FieldCipher>,
+    ) -> Self {
+        let pool = Pool::builder()
+            .max_size(u32::try_from(max_connections).unwrap_or(u32::MAX))
+            .min_idle(Some(0))
+            .connection_customizer(Box::new(Session { statement_timeout }))
+            .build_unchecked(ConnectionManager::new(database_url));
+        DieselRepository {
+            pool,
+            cipher,
+            email_index: None,
+            outbox: false,
+        }
+    }
+
+    /// Keeps a blind index of encrypted emails in `email_index`. Without
+    /// encryption the plaintext column serves and the index is not used.
+    pub fn with_email_index(mut self, index: BlindIndex) -> Self {
+        self.email_index = Some(index);
+        self
+    }
+
+    /// Writes change events to the outbox, in the transaction of the change.
+    pub fn with_outbox(mut self) -> Self {
+        self.outbox = true;
+        self
+    }
+
+    fn connect(&self) -> Result<Pooled, RepositoryError> {
+        Ok(self.pool.get()?)
+    }
+
+    /// The blind index stored with `email`, when emails are encrypted and
+    /// indexed.
+    fn index_of(&self, email: &str) -> Option<String> {
+        self.cipher.as_ref()?;
+        self.email_index.as_ref().map(|index| index.of(email))
+    }
+
+    /// Encrypted emails cannot be compared or sorted by in SQL.
+    fn check_readable(&self, filter: &UserFilter) -> Result<(), RepositoryError> {
+        if self.cipher.is_some() && filter.reads_email() {
+            return Err(RepositoryError::Unsupported(
+                "Emails are encrypted and cannot be filtered or sorted by",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encrypts `value` for storage when a cipher is configured.
+    fn seal(&self, value: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(value),
+            None => value.to_string(),
+        }
+    }
+
+    fn unseal(&self, value: String) -> Result<String, RepositoryError> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.decrypt(&value)?),
+            None => Ok(value),
+        }
+    }
+
+    /// Queues `event` with the change `connection` is making, when the outbox
+    /// is on.
+    fn queue_event(
+        &self,
+        connection: &mut PgConnection,
+        event: Event,
+    ) -> Result<(), RepositoryError> {
+        if !self.outbox {
+            return Ok(());
+        }
+        diesel::insert_into(outbox::table)
+            .values((
+                outbox::user_id.eq(event.user_id),
+                outbox::payload.eq(outbox_payload(&event, self.cipher.as_ref())),
+            ))
+            .execute(connection)?;
+        Ok(())
+    }
+
+    fn user(&self, row: UserRow) -> Result<User, RepositoryError> {
+        Ok(User {
+            id: Some(row.id),
+            name: row.name,
+            email: self.unseal(row.email)?,
+            created_at: Some(row.created_at),
+            updated_at: Some(row.updated_at),
+        })
+    }
+
+    /// Without a blind index, encrypted emails never collide in the unique
+    /// index, so the match has to be found by decrypting every row. The table
+    /// lock keeps a concurrent upsert of the same email from inserting it
+    /// twice.
+    fn upsert_sealed(
+        &self,
+        cipher: &FieldCipher,
+        user: &User,
+    ) -> Result<Upserted, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            connection.batch_execute("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")?;
+            let (upserted, event) = match find_sealed(connection, cipher, &user.email)? {
+                Some(row) => {
+                    diesel::update(users::table.find(row.id))
+                        .set((users::name.eq(&user.name), users::updated_at.eq(now)))
+                        .execute(connection)?;
+                    let event = Event::new(EventKind::Updated, row.id, Some(user));
+                    (Upserted::Updated(row.id), event)
+                }
+                None => {
+                    let id = diesel::insert_into(users::table)
+                        .values((
+                            users::name.eq(&user.name),
+                            users::email.eq(cipher.encrypt(&user.email)),
+                        ))
+                        .returning(users::id)
+                        .get_result(connection)?;
+                    (
+                        Upserted::Created(id),
+                        Event::new(EventKind::Created, id, Some(user)),
+                    )
+                }
+            };
+            self.queue_event(connection, event)?;
+            Ok(upserted)
+        })
+    }
+}
+
+/// Looks a user up by email when emails are encrypted without a blind index,
+/// which means decrypting them one by one.
+fn find_sealed(
+    connection: &mut PgConnection,
+    cipher: &FieldCipher,
+    email: &str,
+) -> Result<Option<UserRow>, RepositoryError> {
+    let rows = users::table.select(UserRow::as_select()).load(connection)?;
+    for row in rows {
+        if cipher.decrypt(&row.email)? == email {
+            return Ok(Some(row));
+        }
+    }
+    Ok(None)
+}
+
+/// What `filter` asks of a user, bar the order and the page.
+fn conditions(filter: &UserFilter) -> Condition {
+    let mut all: Vec<Condition> = Vec::new();
+    if let Some(after) = filter.created_after {
+        all.push(Box::new(users::created_at.gt(after)));
+    }
+    if let Some(before) = filter.created_before {
+        all.push(Box::new(users::created_at.lt(before)));
+    }
+    all.extend(filter.conditions.iter().map(condition));
+    joined(all, true)
+}
+
+/// `operands` joined with `and`, or with `or`, `TRUE` or `FALSE` when empty.
+fn joined(operands: Vec<Condition>, all: bool) -> Condition {
+    operands
+        .into_iter()
+        .reduce(|joined, operand| match all {
+            true => Box::new(joined.and(operand)),
+            false => Box::new(joined.or(operand)),
+        })
+        .unwrap_or_else(|| Box::new(sql::<Bool>(if all { "TRUE" } else { "FALSE" })))
+}
+
+/// Compares `$column` with `$value` in any way their type allows.
+macro_rules! compare {
+    ($column:expr, $comparison:expr, $value:expr) => {
+        match $comparison {
+            Comparison::Eq => Box::new($column.eq($value)) as Condition,
+            Comparison::Ne => Box::new($column.ne($value)),
+            Comparison::Gt => Box::new($column.gt($value)),
+            Comparison::Ge => Box::new($column.ge($value)),
+            Comparison::Lt => Box::new($column.lt($value)),
+            Comparison::Le => Box::new($column.le($value)),
+            // Text matches on other types hold for nobody, as in `Condition`.
+            Comparison::Contains | Comparison::StartsWith | Comparison::EndsWith => {
+                Box::new(sql::<Bool>("FALSE"))
+            }
+        }
+    };
+}
+
+/// Like `compare!`, for text, which can also be matched in part. The functions
+/// spare escaping `%` and `_` for LIKE.
+macro_rules! compare_text {
+    ($column:expr, $comparison:expr, $value:expr) => {
+        match $comparison {
+            Comparison::Contains => Box::new(strpos($column, $value.clone()).gt(0)) as Condition,
+            Comparison::StartsWith => Box::new(starts_with($column, $value.clone())),
+            Comparison::EndsWith => {
+                Box::new(right($column, char_length($value.clone())).eq($value.clone()))
+            }
+            comparison => compare!($column, comparison, $value.clone()),
+        }
+    };
+}
+
+fn condition(expression: &Expression) -> Condition {
+    let condition = match expression {
+        Expression::Condition(condition) => condition,
+        Expression::All(operands) => {
+            return joined(operands.iter().map(self::condition).collect(), true)
+        }
+        Expression::Any(operands) => {
+            return joined(operands.iter().map(self::condition).collect(), false)
+        }
+    };
+    let comparison = condition.comparison;
+    match (condition.field, &condition.value) {
+        (UserField::Id, Literal::Integer(value)) => compare!(users::id, comparison, *value),
+        (UserField::Name, Literal::Text(value)) => compare_text!(users::name, comparison, value),
+        (UserField::Email, Literal::Text(value)) => {
+            compare_text!(users::email, comparison, value)
+        }
+        (UserField::CreatedAt, Literal::Timestamp(value)) => {
+            compare!(users::created_at, comparison, *value)
+        }
+        (UserField::UpdatedAt, Literal::Timestamp(value)) => {
+            compare!(users::updated_at, comparison, *value)
+        }
+        // Parsed conditions always compare a field with a value of its type.
+        _ => Box::new(sql::<Bool>("FALSE")),
+    }
+}
+
+/// The users `filter` matches, in the order and the page it asks for.
+fn list_users(
+    filter: &UserFilter,
+) -> users::BoxedQuery<'static, Pg, SqlTypeOf<AsSelect<UserRow, Pg>>> {
+    let mut query = users::table
+        .select(UserRow::as_select())
+        .filter(conditions(filter))
+        .into_boxed();
+    for &Order { field, descending } in &filter.order {
+        query = match (field, descending) {
+            (UserField::Id, false) => query.then_order_by(users::id.asc()),
+            (UserField::Id, true) => query.then_order_by(users::id.desc()),
+            (UserField::Name, false) => query.then_order_by(users::name.asc()),
+            (UserField::Name, true) => query.then_order_by(users::name.desc()),
+            (UserField::Email, false) => query.then_order_by(users::email.asc()),
+            (UserField::Email, true) => query.then_order_by(users::email.desc()),
+            (UserField::CreatedAt, false) => query.then_order_by(users::created_at.asc()),
+            (UserField::CreatedAt, true) => query.then_order_by(users::created_at.desc()),
+            (UserField::UpdatedAt, false) => query.then_order_by(users::updated_at.asc()),
+            (UserField::UpdatedAt, true) => query.then_order_by(users::updated_at.desc()),
+        };
+    }
+    query = query
+        .then_order_by(users::id.asc())
+        .offset(i64::try_from(filter.skip).unwrap_or(i64::MAX));
+    match filter.top {
+        Some(top) => query.limit(i64::try_from(top).unwrap_or(i64::MAX)),
+        None => query,
+    }
+}
+
+impl UserRepository for DieselRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+                .bind::<BigInt, _>(MIGRATION_LOCK)
+                .execute(connection)?;
+            connection.batch_execute(HISTORY)?;
+            let version: Option<i32> = schema_migrations::table
+                .select(max(schema_migrations::version))
+                .first(connection)?;
+            for step in migrations::pending(version.unwrap_or(0)) {
+                connection.batch_execute(step.sql)?;
+                diesel::insert_into(schema_migrations::table)
+                    .values(schema_migrations::version.eq(step.version))
+                    .execute(connection)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        let outbox = diesel::delete(outbox::table.filter(outbox::sent_at.lt(before)))
+            .execute(&mut self.connect()?)?;
+        Ok(BTreeMap::from([("outbox", outbox as u64)]))
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        let email = self.seal(&user.email);
+        let index = self.index_of(&user.email);
+        self.connect()?.transaction(|connection| {
+            let id = diesel::insert_into(users::table)
+                .values(NewUser {
+                    name: &user.name,
+                    email: &email,
+                    email_index: index.as_deref(),
+                })
+                .returning(users::id)
+                .get_result(connection)?;
+            self.queue_event(connection, Event::new(EventKind::Created, id, Some(user)))?;
+            Ok(id)
+        })
+    }
+
+    /// One statement for the whole batch. Rows come back without their
+    /// position, so each is matched up with the first unassigned user whose
+    /// stored email it carries.
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        if users.is_empty() {
+            return Ok(Vec::new());
+        }
+        let emails: Vec<String> = users.iter().map(|user| self.seal(&user.email)).collect();
+        let indexes: Vec<Option<String>> = users
+            .iter()
+            .map(|user| self.index_of(&user.email))
+            .collect();
+        self.connect()?.transaction(|connection| {
+            let rows: Vec<(i32, String)> = diesel::insert_into(users::table)
+                .values(
+                    users
+                        .iter()
+                        .zip(&emails)
+                        .zip(&indexes)
+                        .map(|((user, email), index)| NewUser {
+                            name: &user.name,
+                            email,
+                            email_index: index.as_deref(),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .on_conflict_do_nothing()
+                .returning((users::id, users::email))
+                .get_results(connection)?;
+            let mut ids = vec![None; users.len()];
+            for (id, email) in rows {
+                if let Some(slot) =
+                    (0..users.len()).find(|&i| ids[i].is_none() && emails[i] == email)
+                {
+                    ids[slot] = Some(id);
+                    let event = Event::new(EventKind::Created, id, Some(&users[slot]));
+                    self.queue_event(connection, event)?;
+                }
+            }
+            Ok(ids)
+        })
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        let row = users::table
+            .find(id)
+            .select(UserRow::as_select())
+            .first(&mut self.connect()?)
+            .optional()?;
+        row.map(|row| self.user(row)).transpose()
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        let rows = users::table
+            .filter(users::id.eq_any(ids))
+            .select(UserRow::as_select())
+            .load(&mut self.connect()?)?;
+        rows.into_iter().map(|row| self.user(row)).collect()
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let mut connection = self.connect()?;
+        let row = match (&self.cipher, self.index_of(email)) {
+            (Some(_), Some(index)) => users::table
+                .filter(users::email_index.eq(index))
+                .select(UserRow::as_select())
+                .first(&mut connection)
+                .optional()?,
+            (Some(cipher), None) => find_sealed(&mut connection, cipher, email)?,
+            (None, _) => users::table
+                .filter(users::email.eq(email))
+                .select(UserRow::as_select())
+                .first(&mut connection)
+                .optional()?,
+        };
+        row.map(|row| self.user(row)).transpose()
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.check_readable(filter)?;
+        let rows: Vec<UserRow> = list_users(filter).load(&mut self.connect()?)?;
+        rows.into_iter().map(|row| self.user(row)).collect()
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        self.check_readable(filter)?;
+        let count: i64 = users::table
+            .filter(conditions(filter))
+            .count()
+            .get_result(&mut self.connect()?)?;
+        Ok(count as u64)
+    }
+
+    /// Rows are read one at a time as the server sends them.
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        self.check_readable(filter)?;
+        let mut connection = self.connect()?;
+        let rows =
+            list_users(filter).load_iter::<UserRow, PgRowByRowLoadingMode>(&mut connection)?;
+        for row in rows {
+            if !each(self.user(row?)?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            let updated = diesel::update(users::table.find(id))
+                .set((
+                    users::name.eq(&user.name),
+                    users::email.eq(self.seal(&user.email)),
+                    users::email_index.eq(self.index_of(&user.email)),
+                    users::updated_at.eq(now),
+                ))
+                .execute(connection)?;
+            if updated > 0 {
+                self.queue_event(connection, Event::new(EventKind::Updated, id, Some(user)))?;
+            }
+            Ok(updated as u64)
+        })
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            let row = users::table
+                .find(id)
+                .select(UserRow::as_select())
+                .for_update()
+                .first(connection)
+                .optional()?;
+            let current = match row {
+                Some(row) => self.user(row)?,
+                None => return Ok(None),
+            };
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(Some(current));
+            }
+            let row = diesel::update(users::table.find(id))
+                .set((
+                    users::name.eq(&changed.name),
+                    users::email.eq(self.seal(&changed.email)),
+                    users::email_index.eq(self.index_of(&changed.email)),
+                    users::updated_at.eq(now),
+                ))
+                .returning(UserRow::as_returning())
+                .get_result(connection)?;
+            let stored = self.user(row)?;
+            self.queue_event(
+                connection,
+                Event::new(EventKind::Updated, id, Some(&stored)),
+            )?;
+            Ok(Some(stored))
+        })
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        let index = self.index_of(&user.email);
+        let email = self.seal(&user.email);
+        let row = NewUser {
+            name: &user.name,
+            email: &email,
+            email_index: index.as_deref(),
+        };
+        let update = (
+            users::name.eq(excluded(users::name)),
+            users::updated_at.eq(now),
+        );
+        // `xmax` is only zero for a freshly inserted row version.
+        let returning = (users::id, sql::<Bool>("xmax = 0"));
+        if let (Some(cipher), None) = (&self.cipher, &index) {
+            return self.upsert_sealed(cipher, user);
+        }
+        self.connect()?.transaction(|connection| {
+            let (id, created): (i32, bool) = match index {
+                Some(_) => diesel::insert_into(users::table)
+                    .values(row)
+                    .on_conflict(users::email_index)
+                    .do_update()
+                    .set(update)
+                    .returning(returning)
+                    .get_result(connection)?,
+                None => diesel::insert_into(users::table)
+                    .values(row)
+                    .on_conflict(users::email)
+                    .do_update()
+                    .set(update)
+                    .returning(returning)
+                    .get_result(connection)?,
+            };
+            let (upserted, kind) = match created {
+                true => (Upserted::Created(id), EventKind::Created),
+                false => (Upserted::Updated(id), EventKind::Updated),
+            };
+            self.queue_event(connection, Event::new(kind, id, Some(user)))?;
+            Ok(upserted)
+        })
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            let deleted = diesel::delete(users::table.find(id)).execute(connection)?;
+            if deleted > 0 {
+                self.queue_event(connection, Event::new(EventKind::Deleted, id, None))?;
+            }
+            Ok(deleted as u64)
+        })
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        Ok(users::table
+            .find(id)
+            .select(users::metadata)
+            .first(&mut self.connect()?)
+            .optional()?)
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            let row: Option<Value> = users::table
+                .find(id)
+                .select(users::metadata)
+                .for_update()
+                .first(connection)
+                .optional()?;
+            let Some(mut metadata) = row else {
+                return Ok(None);
+            };
+            let current = metadata.clone();
+            if !change(&mut metadata) {
+                return Ok(Some(current));
+            }
+            diesel::update(users::table.find(id))
+                .set(users::metadata.eq(&metadata))
+                .execute(connection)?;
+            Ok(Some(metadata))
+        })
+    }
+}
+
+impl AddressRepository for DieselRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        let rows = addresses::table
+            .filter(addresses::user_id.eq(user_id))
+            .order_by(addresses::id)
+            .select(AddressRow::as_select())
+            .load(&mut self.connect()?)?;
+        Ok(rows.into_iter().map(Address::from).collect())
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        let count: i64 = addresses::table
+            .filter(addresses::user_id.eq(user_id))
+            .count()
+            .get_result(&mut self.connect()?)?;
+        Ok(count as u64)
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        let row = diesel::insert_into(addresses::table)
+            .values(NewAddress {
+                user_id,
+                line1: &address.line1,
+                line2: address.line2.as_deref(),
+                city: &address.city,
+                postal_code: &address.postal_code,
+                country: &address.country,
+            })
+            .returning(AddressRow::as_returning())
+            .get_result(&mut self.connect()?)?;
+        Ok(Address::from(row))
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        let row = addresses::table
+            .find(id)
+            .filter(addresses::user_id.eq(user_id))
+            .select(AddressRow::as_select())
+            .first(&mut self.connect()?)
+            .optional()?;
+        Ok(row.map(Address::from))
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        let updated = diesel::update(
+            addresses::table
+                .find(id)
+                .filter(addresses::user_id.eq(user_id)),
+        )
+        .set((
+            addresses::line1.eq(&address.line1),
+            addresses::line2.eq(&address.line2),
+            addresses::city.eq(&address.city),
+            addresses::postal_code.eq(&address.postal_code),
+            addresses::country.eq(&address.country),
+        ))
+        .execute(&mut self.connect()?)?;
+        Ok(updated as u64)
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        let owned = addresses::table.filter(addresses::user_id.eq(user_id));
+        let deleted = match id {
+            Some(id) => {
+                diesel::delete(owned.filter(addresses::id.eq(id))).execute(&mut self.connect()?)?
+            }
+            None => diesel::delete(owned).execute(&mut self.connect()?)?,
+        };
+        Ok(deleted as u64)
+    }
+}
+
+impl FlagRepository for DieselRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        let rows: Vec<(String, bool)> = feature_flags::table
+            .select((feature_flags::name, feature_flags::enabled))
+            .load(&mut self.connect()?)?;
+        Ok(rows.into_iter().collect())
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        diesel::insert_into(feature_flags::table)
+            .values((
+                feature_flags::name.eq(name),
+                feature_flags::enabled.eq(enabled),
+            ))
+            .on_conflict(feature_flags::name)
+            .do_update()
+            .set((
+                feature_flags::enabled.eq(excluded(feature_flags::enabled)),
+                feature_flags::updated_at.eq(now),
+            ))
+            .execute(&mut self.connect()?)?;
+        Ok(())
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        let deleted =
+            diesel::delete(feature_flags::table.find(name)).execute(&mut self.connect()?)?;
+        Ok(deleted as u64)
+    }
+}
+
+impl TotpRepository for DieselRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        self.connect()?.transaction(|connection| {
+            let row: Option<(String, bool, Vec<String>, i64)> = totp::table
+                .find(account)
+                .select((
+                    totp::secret,
+                    totp::enabled,
+                    totp::recovery_codes,
+                    totp::last_step,
+                ))
+                .for_update()
+                .first(connection)
+                .optional()?;
+            let mut enrollment = match row {
+                Some((secret, enabled, recovery_codes, last_step)) => Some(Totp {
+                    secret: self.unseal(secret)?,
+                    enabled,
+                    recovery_codes,
+                    last_step: last_step as u64,
+                }),
+                None => None,
+            };
+            let current = enrollment.clone();
+            if !change(&mut enrollment) {
+                return Ok(current);
+            }
+            match &enrollment {
+                Some(enrollment) => diesel::insert_into(totp::table)
+                    .values((
+                        totp::account.eq(account),
+                        totp::secret.eq(self.seal(&enrollment.secret)),
+                        totp::enabled.eq(enrollment.enabled),
+                        totp::recovery_codes.eq(&enrollment.recovery_codes),
+                        totp::last_step.eq(enrollment.last_step as i64),
+                    ))
+                    .on_conflict(totp::account)
+                    .do_update()
+                    .set((
+                        totp::secret.eq(excluded(totp::secret)),
+                        totp::enabled.eq(excluded(totp::enabled)),
+                        totp::recovery_codes.eq(excluded(totp::recovery_codes)),
+                        totp::last_step.eq(excluded(totp::last_step)),
+                    ))
+                    .execute(connection)?,
+                None => diesel::delete(totp::table.find(account)).execute(connection)?,
+            };
+            Ok(enrollment)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::Condition as Compared;
+    use diesel::debug_query;
+
+    #[test]
+    fn lists_with_the_conditions_order_and_page_asked_for() {
+        let filter = UserFilter {
+            created_after: Some(DateTime::<Utc>::UNIX_EPOCH),
+            conditions: vec![Expression::Any(vec![
+                Expression::Condition(Compared {
+                    field: UserField::Name,
+                    comparison: Comparison::EndsWith,
+                    value: Literal::Text("son".to_string()),
+                }),
+                Expression::All(Vec::new()),
+                Expression::Condition(Compared {
+                    field: UserField::Id,
+                    comparison: Comparison::Ge,
+                    value: Literal::Integer(3),
+                }),
+            ])],
+            order: vec![Order {
+                field: UserField::Name,
+                descending: true,
+            }],
+            skip: 20,
+            top: Some(10),
+            ..UserFilter::default()
+        };
+        let query = list_users(&filter);
+        let sql = debug_query::<Pg, _>(&query).to_string();
+        assert_eq!(
+            sql.split(" -- binds: ").next().unwrap(),
+            "SELECT \"users\".\"id\", \"users\".\"name\", \"users\".\"email\", \
+             \"users\".\"created_at\", \"users\".\"updated_at\" FROM \"users\" \
+             WHERE ((\"users\".\"created_at\" > $1) AND \
+             (((right(\"users\".\"name\", char_length($2)) = $3) OR TRUE) OR \
+             (\"users\".\"id\" >= $4))) \
+             ORDER BY \"users\".\"name\" DESC, \"users\".\"id\" ASC LIMIT $5 OFFSET $6"
+        );
+    }
+}
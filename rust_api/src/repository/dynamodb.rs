@@ -0,0 +1,1141 @@
+use super::{
+    AddressRepository, FlagRepository, RepositoryError, TotpRepository, Upserted, UserFilter,
+    UserRepository,
+};
+use crate::aws::{self, Credentials, SignedRequest};
+use crate::https;
+use crate::models::{Address, User};
+use crate::totp::Totp;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/*
+*  DynamoDB storage
+*
+*  Everything lives in one table keyed by `PK` and `SK`:
+*
+*  | Item      | PK              | SK               |
+*  | --------- | --------------- | ---------------- |
+*  | user      | `USER#<id>`     | `PROFILE`        |
+*  | address   | `USER#<id>`     | `ADDRESS#<id>`   |
+*  | email     | `EMAIL#<email>` | `EMAIL`          |
+*  | flag      | `FLAG`          | `<name>`         |
+*  | 2FA       | `TOTP`          | `<account>`      |
+*  | id counter| `COUNTER`       | `users`, ...     |
+*
+*  Users carry their `email` as an attribute, indexed by the `email-index`
+*  GSI for lookups. The email items play the part of the unique index: they are
+*  written in the same transaction as the user and the write fails when one
+*  exists already. Ids come from atomic counters, so they stay numeric like in
+*  Postgres. Timestamps are RFC 3339 strings in UTC, which sort and compare
+*  like the times they stand for.
+*/
+
+const USER_SK: &str = "PROFILE";
+const EMAIL_INDEX: &str = "email-index";
+/// Largest `BatchGetItem` request DynamoDB accepts.
+const BATCH_GET_SIZE: usize = 100;
+/// Attempts at a user, metadata or two-factor change before giving up on
+/// concurrent writers.
+const CHANGE_ATTEMPTS: usize = 10;
+/// How long `migrate` waits for a new table to become usable.
+const TABLE_CREATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DynamoDbSettings {
+    pub table: String,
+    pub region: String,
+    /// `https://dynamodb.<region>.amazonaws.com`, or another endpoint such as
+    /// DynamoDB Local's.
+    pub endpoint: String,
+    pub credentials: Credentials,
+}
+
+/// A request DynamoDB refused, or that did not reach it.
+#[derive(Debug)]
+pub struct DynamoDbError {
+    /// The exception name, e.g. `ConditionalCheckFailedException`.
+    pub kind: String,
+    pub message: String,
+    /// For a cancelled transaction, why each of its items failed (`None` for
+    /// those that did not).
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for DynamoDbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DynamoDB {}: {}", self.kind, self.message)
+    }
+}
+
+impl DynamoDbError {
+    fn new(kind: &str, message: impl Into<String>) -> Self {
+        DynamoDbError {
+            kind: kind.to_string(),
+            message: message.into(),
+            reasons: Vec::new(),
+        }
+    }
+
+    /// Whether a condition on a single item write did not hold.
+    fn check_failed(&self) -> bool {
+        self.kind == "ConditionalCheckFailedException"
+    }
+
+    /// Whether DynamoDB refused the request for going over the table's
+    /// capacity or the account's request rate.
+    pub fn throttled(&self) -> bool {
+        matches!(
+            self.kind.as_str(),
+            "ProvisionedThroughputExceededException"
+                | "ThrottlingException"
+                | "RequestLimitExceeded"
+        )
+    }
+
+    /// Whether the condition on the `index`th item of a transaction did not
+    /// hold.
+    fn check_failed_at(&self, index: usize) -> bool {
+        self.kind == "TransactionCanceledException"
+            && self.reasons.get(index).map(String::as_str) == Some("ConditionalCheckFailed")
+    }
+
+    /// Reads the error out of a response body.
+    fn from_body(body: &str) -> Self {
+        let body: Value = serde_json::from_str(body).unwrap_or_default();
+        let kind = body["__type"].as_str().unwrap_or("UnknownError");
+        let message = body["message"]
+            .as_str()
+            .or_else(|| body["Message"].as_str())
+            .unwrap_or_default();
+        let reasons = body["CancellationReasons"]
+            .as_array()
+            .map(|reasons| {
+                reasons
+                    .iter()
+                    .map(|reason| reason["Code"].as_str().unwrap_or("None").to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        DynamoDbError {
+            // `com.amazonaws.dynamodb.v20120810#ResourceNotFoundException`
+            kind: kind.rsplit('#').next().unwrap_or(kind).to_string(),
+            message: message.to_string(),
+            reasons,
+        }
+    }
+}
+
+impl From<DynamoDbError> for RepositoryError {
+    fn from(e: DynamoDbError) -> Self {
+        RepositoryError::DynamoDb(e)
+    }
+}
+
+pub struct DynamoDbRepository {
+    settings: DynamoDbSettings,
+    /// `host[:port]` of the endpoint, which is signed.
+    host: String,
+    agent: ureq::Agent,
+}
+
+impl DynamoDbRepository {
+    /// `timeout` bounds each request; zero means the default of 30 seconds.
+    pub fn new(settings: DynamoDbSettings, timeout: Duration) -> Self {
+        let host = settings
+            .endpoint
+            .split_once("://")
+            .map_or(settings.endpoint.as_str(), |(_, rest)| rest)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let timeout = if timeout.is_zero() {
+            Duration::from_secs(30)
+        } else {
+            timeout
+        };
+        DynamoDbRepository {
+            settings,
+            host,
+            agent: https::agent(timeout),
+        }
+    }
+
+    /// Sends one API call, e.g. `GetItem`, with `TableName` filled in unless
+    /// the request holds several tables' items already.
+    fn call(&self, action: &str, mut request: Value) -> Result<Value, RepositoryError> {
+        let batch = request.get("TransactItems").is_some() || request.get("RequestItems").is_some();
+        if request.get("TableName").is_none() && !batch {
+            request["TableName"] = json!(self.settings.table);
+        }
+        let body = request.to_string();
+        let mut signed = SignedRequest {
+            method: "POST",
+            host: &self.host,
+            path: "/",
+            headers: vec![
+                ("content-type", "application/x-amz-json-1.0".to_string()),
+                ("x-amz-target", format!("DynamoDB_20120810.{}", action)),
+            ],
+            body: body.as_bytes(),
+        };
+        aws::sign(
+            &mut signed,
+            &self.settings.credentials,
+            &self.settings.region,
+            "dynamodb",
+            Utc::now(),
+        );
+
+        let mut post = self
+            .agent
+            .post(format!("{}/", self.settings.endpoint.trim_end_matches('/')));
+        for (name, value) in signed.headers.iter().filter(|(name, _)| *name != "host") {
+            post = post.header(*name, value);
+        }
+        let mut response = match post.send(body.as_bytes()) {
+            Ok(response) => response,
+            Err(ureq::Error::Timeout(_)) => return Err(RepositoryError::Timeout),
+            Err(e) => return Err(DynamoDbError::new("RequestFailed", e.to_string()).into()),
+        };
+        let text = response
+            .body_mut()
+            .read_to_string()
+            .map_err(|e| DynamoDbError::new("RequestFailed", e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(DynamoDbError::from_body(&text).into());
+        }
+        serde_json::from_str(&text)
+            .map_err(|e| DynamoDbError::new("InvalidResponse", e.to_string()).into())
+    }
+
+    /// Runs a `Scan` or `Query`, feeding each page of items to `page` until it
+    /// returns `false` or the results run out.
+    fn pages(
+        &self,
+        action: &str,
+        mut request: Value,
+        page: &mut dyn FnMut(&Value) -> Result<bool, RepositoryError>,
+    ) -> Result<(), RepositoryError> {
+        loop {
+            let response = self.call(action, request.clone())?;
+            if !page(&response)? {
+                return Ok(());
+            }
+            match response.get("LastEvaluatedKey") {
+                Some(key) => request["ExclusiveStartKey"] = key.clone(),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn get(&self, key: Value) -> Result<Option<Value>, RepositoryError> {
+        let response = self.call("GetItem", json!({ "Key": key, "ConsistentRead": true }))?;
+        Ok(response.get("Item").cloned())
+    }
+
+    /// Deletes the item, returning whether there was one.
+    fn delete_item(&self, key: Value) -> Result<bool, RepositoryError> {
+        let response = self.call(
+            "DeleteItem",
+            json!({ "Key": key, "ReturnValues": "ALL_OLD" }),
+        )?;
+        Ok(response.get("Attributes").is_some())
+    }
+
+    /// Writes all items or none, see `DynamoDbError::check_failed_at`.
+    fn transact(&self, items: Vec<Value>) -> Result<(), RepositoryError> {
+        let table = &self.settings.table;
+        let items: Vec<Value> = items
+            .into_iter()
+            .map(|mut item| {
+                if let Some(operation) = item.as_object_mut().and_then(|o| o.values_mut().next()) {
+                    operation["TableName"] = json!(table);
+                }
+                item
+            })
+            .collect();
+        self.call("TransactWriteItems", json!({ "TransactItems": items }))?;
+        Ok(())
+    }
+
+    /// The next value of an id sequence, starting at 1.
+    fn next_id(&self, sequence: &str) -> Result<i32, RepositoryError> {
+        let response = self.call(
+            "UpdateItem",
+            json!({
+                "Key": { "PK": s("COUNTER"), "SK": s(sequence) },
+                "UpdateExpression": "ADD #value :one",
+                "ExpressionAttributeNames": { "#value": "value" },
+                "ExpressionAttributeValues": { ":one": n(1) },
+                "ReturnValues": "UPDATED_NEW",
+            }),
+        )?;
+        number(&response["Attributes"], "value")
+    }
+
+    /// Inserts the user with its email item, returning the new id.
+    fn insert(&self, user: &User) -> Result<i32, RepositoryError> {
+        let id = self.next_id("users")?;
+        let now = timestamp(Utc::now());
+        let mut item = user_item(id, user);
+        item["created_at"] = s(&now);
+        item["updated_at"] = s(&now);
+        let result = self.transact(vec![
+            json!({ "Put": {
+                "Item": item,
+                "ConditionExpression": "attribute_not_exists(PK)",
+            }}),
+            json!({ "Put": {
+                "Item": email_item(&user.email, id),
+                "ConditionExpression": "attribute_not_exists(PK)",
+            }}),
+        ]);
+        match result {
+            Err(RepositoryError::DynamoDb(e)) if e.check_failed_at(1) => {
+                Err(RepositoryError::Conflict)
+            }
+            result => result.map(|()| id),
+        }
+    }
+
+    /// The id of the user holding `email`, read consistently unlike the GSI.
+    fn owner_of(&self, email: &str) -> Result<Option<i32>, RepositoryError> {
+        self.get(email_key(email))?
+            .map(|item| number(&item, "user_id"))
+            .transpose()
+    }
+
+    fn scan_users(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        let mut request = user_filter(filter);
+        request["ConsistentRead"] = json!(true);
+        self.pages("Scan", request, &mut |page| {
+            for item in items(page) {
+                let user = user_from_item(item)?;
+                // `conditions` are not part of the filter expression.
+                if filter.matches(&user) && !each(user) {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// Writes the name and email of `user` over those of `existing`, moving
+    /// the email item along. With `guard`, the item `existing` was read from,
+    /// nothing is written if the user was written since.
+    fn replace_user(
+        &self,
+        id: i32,
+        existing: &User,
+        user: &User,
+        guard: Option<&Value>,
+    ) -> Result<u64, RepositoryError> {
+        let mut update = json!({
+            "Key": user_key(id),
+            "UpdateExpression": "SET #name = :name, #email = :email, #updated_at = :now",
+            "ConditionExpression": "#email = :old_email",
+            "ExpressionAttributeNames": {
+                "#name": "name",
+                "#email": "email",
+                "#updated_at": "updated_at",
+            },
+            "ExpressionAttributeValues": {
+                ":name": s(&user.name),
+                ":email": s(&user.email),
+                ":old_email": s(&existing.email),
+                ":now": s(&timestamp(Utc::now())),
+            },
+        });
+        if let Some(item) = guard {
+            update["ConditionExpression"] = json!(match item.get("updated_at") {
+                Some(_) => "#email = :old_email AND #updated_at = :old_updated_at",
+                None => "#email = :old_email AND attribute_not_exists(#updated_at)",
+            });
+            if let Some(updated_at) = item.get("updated_at") {
+                update["ExpressionAttributeValues"][":old_updated_at"] = updated_at.clone();
+            }
+        }
+        if existing.email == user.email {
+            return match self.call("UpdateItem", update) {
+                Err(RepositoryError::DynamoDb(e)) if e.check_failed() => Ok(0),
+                result => result.map(|_| 1),
+            };
+        }
+
+        // The email moves: its old item goes and the new one must be free.
+        let result = self.transact(vec![
+            json!({ "Update": update }),
+            json!({ "Delete": {
+                "Key": email_key(&existing.email),
+                "ConditionExpression": "user_id = :id",
+                "ExpressionAttributeValues": { ":id": n(id) },
+            }}),
+            json!({ "Put": {
+                "Item": email_item(&user.email, id),
+                "ConditionExpression": "attribute_not_exists(PK)",
+            }}),
+        ]);
+        match result {
+            Err(RepositoryError::DynamoDb(e)) if e.check_failed_at(2) => {
+                Err(RepositoryError::Conflict)
+            }
+            // Deleted or changed in the meantime.
+            Err(RepositoryError::DynamoDb(e)) if e.check_failed_at(0) => Ok(0),
+            result => result.map(|()| 1),
+        }
+    }
+
+    /// Every address item of the user.
+    fn address_items(&self, user_id: i32) -> Result<Vec<Value>, RepositoryError> {
+        let mut found = Vec::new();
+        self.pages("Query", address_query(user_id), &mut |page| {
+            found.extend(items(page).cloned());
+            Ok(true)
+        })?;
+        Ok(found)
+    }
+}
+
+impl UserRepository for DynamoDbRepository {
+    /// Changes are published without an outbox, so nothing piles up.
+    fn purge(&self, _: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        Ok(BTreeMap::new())
+    }
+
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        match self.call("DescribeTable", json!({})) {
+            Ok(_) => return Ok(()),
+            Err(RepositoryError::DynamoDb(e)) if e.kind == "ResourceNotFoundException" => {}
+            Err(e) => return Err(e),
+        }
+
+        info!("Creating DynamoDB table {}", self.settings.table);
+        self.call(
+            "CreateTable",
+            json!({
+                "AttributeDefinitions": [
+                    { "AttributeName": "PK", "AttributeType": "S" },
+                    { "AttributeName": "SK", "AttributeType": "S" },
+                    { "AttributeName": "email", "AttributeType": "S" },
+                ],
+                "KeySchema": [
+                    { "AttributeName": "PK", "KeyType": "HASH" },
+                    { "AttributeName": "SK", "KeyType": "RANGE" },
+                ],
+                "GlobalSecondaryIndexes": [{
+                    "IndexName": EMAIL_INDEX,
+                    "KeySchema": [{ "AttributeName": "email", "KeyType": "HASH" }],
+                    "Projection": { "ProjectionType": "ALL" },
+                }],
+                "BillingMode": "PAY_PER_REQUEST",
+            }),
+        )?;
+
+        let mut waited = Duration::ZERO;
+        while waited < TABLE_CREATION_TIMEOUT {
+            let table = self.call("DescribeTable", json!({}))?;
+            if table["Table"]["TableStatus"] == "ACTIVE" {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_secs(1));
+            waited += Duration::from_secs(1);
+        }
+        Err(DynamoDbError::new("TableNotActive", "table still being created").into())
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        self.insert(user)
+    }
+
+    /// One insert per user, since a transaction would fail them all for one
+    /// taken email.
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        users
+            .iter()
+            .map(|user| match self.insert(user) {
+                Ok(id) => Ok(Some(id)),
+                Err(RepositoryError::Conflict) => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        self.get(user_key(id))?
+            .map(|item| user_from_item(&item))
+            .transpose()
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        let mut users = Vec::new();
+        for chunk in ids.chunks(BATCH_GET_SIZE) {
+            let keys: Vec<Value> = chunk.iter().map(|&id| user_key(id)).collect();
+            let mut request = json!({});
+            request[&self.settings.table] = json!({ "Keys": keys, "ConsistentRead": true });
+            // Keys DynamoDB did not get to under load come back to be retried.
+            while request.as_object().is_some_and(|tables| !tables.is_empty()) {
+                let response = self.call("BatchGetItem", json!({ "RequestItems": request }))?;
+                for item in response["Responses"][&self.settings.table]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                {
+                    users.push(user_from_item(item)?);
+                }
+                request = response["UnprocessedKeys"].clone();
+            }
+        }
+        Ok(users)
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let response = self.call(
+            "Query",
+            json!({
+                "IndexName": EMAIL_INDEX,
+                "KeyConditionExpression": "#email = :email",
+                "ExpressionAttributeNames": { "#email": "email" },
+                "ExpressionAttributeValues": { ":email": s(email) },
+            }),
+        )?;
+        let user = items(&response).next().map(user_from_item).transpose();
+        user
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        let mut users = Vec::new();
+        self.scan_users(filter, &mut |user| {
+            users.push(user);
+            true
+        })?;
+        filter.arrange(&mut users);
+        Ok(users)
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        if !filter.conditions.is_empty() {
+            let mut count = 0;
+            self.scan_users(filter, &mut |_| {
+                count += 1;
+                true
+            })?;
+            return Ok(count);
+        }
+        let mut request = user_filter(filter);
+        request["Select"] = json!("COUNT");
+        let mut count = 0;
+        self.pages("Scan", request, &mut |page| {
+            count += page["Count"].as_u64().unwrap_or_default();
+            Ok(true)
+        })?;
+        Ok(count)
+    }
+
+    /// Users come in table order rather than by id, unless `filter` asks for
+    /// an order or a page, which takes reading them all first.
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        if filter.order.is_empty() && !filter.is_paged() {
+            return self.scan_users(filter, each);
+        }
+        for user in self.list(filter)? {
+            if !each(user) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        let existing = match self.find(id)? {
+            Some(existing) => existing,
+            None => return Ok(0),
+        };
+        self.replace_user(id, &existing, user, None)
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        for _ in 0..CHANGE_ATTEMPTS {
+            let item = match self.get(user_key(id))? {
+                Some(item) => item,
+                None => return Ok(None),
+            };
+            let current = user_from_item(&item)?;
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(Some(current));
+            }
+            // Nothing is written when the user changed since it was read.
+            if self.replace_user(id, &current, &changed, Some(&item))? > 0 {
+                return self.find(id);
+            }
+        }
+        Err(DynamoDbError::new("TooManyConflicts", "user kept changing").into())
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        // A user deleted between the failed insert and the rename is retried.
+        for _ in 0..3 {
+            match self.insert(user) {
+                Ok(id) => return Ok(Upserted::Created(id)),
+                Err(RepositoryError::Conflict) => {}
+                Err(e) => return Err(e),
+            }
+            let id = match self.owner_of(&user.email)? {
+                Some(id) => id,
+                None => continue,
+            };
+            let renamed = self.call(
+                "UpdateItem",
+                json!({
+                    "Key": user_key(id),
+                    "UpdateExpression": "SET #name = :name, #updated_at = :now",
+                    "ConditionExpression": "#email = :email",
+                    "ExpressionAttributeNames": {
+                        "#name": "name",
+                        "#email": "email",
+                        "#updated_at": "updated_at",
+                    },
+                    "ExpressionAttributeValues": {
+                        ":name": s(&user.name),
+                        ":email": s(&user.email),
+                        ":now": s(&timestamp(Utc::now())),
+                    },
+                }),
+            );
+            match renamed {
+                Ok(_) => return Ok(Upserted::Updated(id)),
+                Err(RepositoryError::DynamoDb(e)) if e.check_failed() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(RepositoryError::Conflict)
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        let user = match self.find(id)? {
+            Some(user) => user,
+            None => return Ok(0),
+        };
+        // Addresses first, like the cascading foreign key in Postgres.
+        self.delete_addresses(id, None)?;
+        let result = self.transact(vec![
+            json!({ "Delete": {
+                "Key": user_key(id),
+                "ConditionExpression": "attribute_exists(PK)",
+            }}),
+            json!({ "Delete": {
+                "Key": email_key(&user.email),
+                "ConditionExpression": "user_id = :id",
+                "ExpressionAttributeValues": { ":id": n(id) },
+            }}),
+        ]);
+        match result {
+            Err(RepositoryError::DynamoDb(e)) if e.check_failed_at(0) => Ok(0),
+            result => result.map(|()| 1),
+        }
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        self.get(user_key(id))?
+            .map(|item| metadata_from_item(&item))
+            .transpose()
+    }
+
+    /// Optimistic: the change is written only if the metadata is still what it
+    /// was read as, and rerun on the new value otherwise.
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        for _ in 0..CHANGE_ATTEMPTS {
+            let item = match self.get(user_key(id))? {
+                Some(item) => item,
+                None => return Ok(None),
+            };
+            let current = metadata_from_item(&item)?;
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(Some(current));
+            }
+
+            let mut update = json!({
+                "Key": user_key(id),
+                "UpdateExpression": "SET #metadata = :new",
+                "ExpressionAttributeNames": { "#metadata": "metadata" },
+                "ExpressionAttributeValues": { ":new": s(&changed.to_string()) },
+            });
+            match item.get("metadata") {
+                Some(stored) => {
+                    update["ConditionExpression"] = json!("#metadata = :old");
+                    update["ExpressionAttributeValues"][":old"] = stored.clone();
+                }
+                None => {
+                    update["ConditionExpression"] =
+                        json!("attribute_exists(PK) AND attribute_not_exists(#metadata)");
+                }
+            }
+            match self.call("UpdateItem", update) {
+                Ok(_) => return Ok(Some(changed)),
+                Err(RepositoryError::DynamoDb(e)) if e.check_failed() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DynamoDbError::new("TooManyConflicts", "metadata kept changing").into())
+    }
+}
+
+impl AddressRepository for DynamoDbRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        self.address_items(user_id)?
+            .iter()
+            .map(address_from_item)
+            .collect()
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        let mut request = address_query(user_id);
+        request["Select"] = json!("COUNT");
+        let mut count = 0;
+        self.pages("Query", request, &mut |page| {
+            count += page["Count"].as_u64().unwrap_or_default();
+            Ok(true)
+        })?;
+        Ok(count)
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        let id = self.next_id("addresses")?;
+        let address = Address {
+            id: Some(id),
+            user_id: Some(user_id),
+            ..address.clone()
+        };
+        self.call("PutItem", json!({ "Item": address_item(&address) }))?;
+        Ok(address)
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        self.get(address_key(user_id, id))?
+            .map(|item| address_from_item(&item))
+            .transpose()
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        let address = Address {
+            id: Some(id),
+            user_id: Some(user_id),
+            ..address.clone()
+        };
+        let written = self.call(
+            "PutItem",
+            json!({
+                "Item": address_item(&address),
+                "ConditionExpression": "attribute_exists(PK)",
+            }),
+        );
+        match written {
+            Err(RepositoryError::DynamoDb(e)) if e.check_failed() => Ok(0),
+            written => written.map(|_| 1),
+        }
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        if let Some(id) = id {
+            return Ok(self.delete_item(address_key(user_id, id))? as u64);
+        }
+        let mut deleted = 0;
+        for item in self.address_items(user_id)? {
+            let key = json!({ "PK": item["PK"], "SK": item["SK"] });
+            deleted += self.delete_item(key)? as u64;
+        }
+        Ok(deleted)
+    }
+}
+
+impl FlagRepository for DynamoDbRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        let mut flags = BTreeMap::new();
+        let request = json!({
+            "KeyConditionExpression": "PK = :flag",
+            "ExpressionAttributeValues": { ":flag": s("FLAG") },
+            "ConsistentRead": true,
+        });
+        self.pages("Query", request, &mut |page| {
+            for item in items(page) {
+                if let (Some(name), Some(enabled)) =
+                    (item["SK"]["S"].as_str(), item["enabled"]["BOOL"].as_bool())
+                {
+                    flags.insert(name.to_string(), enabled);
+                }
+            }
+            Ok(true)
+        })?;
+        Ok(flags)
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        let mut item = flag_key(name);
+        item["enabled"] = json!({ "BOOL": enabled });
+        item["updated_at"] = s(&timestamp(Utc::now()));
+        self.call("PutItem", json!({ "Item": item }))?;
+        Ok(())
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        Ok(self.delete_item(flag_key(name))? as u64)
+    }
+}
+
+impl TotpRepository for DynamoDbRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        for _ in 0..CHANGE_ATTEMPTS {
+            let item = self.get(totp_key(account))?;
+            let current = item.as_ref().map(totp_from_item).transpose()?;
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(current);
+            }
+
+            // Written only while the item is still as read.
+            let (version, condition) = match &item {
+                Some(item) => (number::<u64>(item, "version")?, "version = :version"),
+                None => (0, "attribute_not_exists(PK)"),
+            };
+            let (action, mut request) = match &changed {
+                Some(totp) => {
+                    let mut item = totp_key(account);
+                    item["secret"] = s(&totp.secret);
+                    item["enabled"] = json!({ "BOOL": totp.enabled });
+                    item["recovery_codes"] = json!({ "L": totp.recovery_codes.iter().map(|code| s(code)).collect::<Vec<_>>() });
+                    item["last_step"] = json!({ "N": totp.last_step.to_string() });
+                    item["version"] = json!({ "N": (version + 1).to_string() });
+                    ("PutItem", json!({ "Item": item }))
+                }
+                None => ("DeleteItem", json!({ "Key": totp_key(account) })),
+            };
+            request["ConditionExpression"] = json!(condition);
+            if item.is_some() {
+                request["ExpressionAttributeValues"] =
+                    json!({ ":version": { "N": version.to_string() } });
+            }
+            match self.call(action, request) {
+                Ok(_) => return Ok(changed),
+                Err(RepositoryError::DynamoDb(e)) if e.check_failed() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DynamoDbError::new("TooManyConflicts", "two-factor enrollment kept changing").into())
+    }
+}
+
+fn s(value: &str) -> Value {
+    json!({ "S": value })
+}
+
+fn n(value: i32) -> Value {
+    json!({ "N": value.to_string() })
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn user_key(id: i32) -> Value {
+    json!({ "PK": s(&format!("USER#{}", id)), "SK": s(USER_SK) })
+}
+
+fn email_key(email: &str) -> Value {
+    json!({ "PK": s(&format!("EMAIL#{}", email)), "SK": s("EMAIL") })
+}
+
+/// Zero-padded so addresses sort by id.
+fn address_key(user_id: i32, id: i32) -> Value {
+    json!({
+        "PK": s(&format!("USER#{}", user_id)),
+        "SK": s(&format!("ADDRESS#{:010}", id)),
+    })
+}
+
+fn flag_key(name: &str) -> Value {
+    json!({ "PK": s("FLAG"), "SK": s(name) })
+}
+
+fn totp_key(account: &str) -> Value {
+    json!({ "PK": s("TOTP"), "SK": s(account) })
+}
+
+fn email_item(email: &str, user_id: i32) -> Value {
+    let mut item = email_key(email);
+    item["user_id"] = n(user_id);
+    item
+}
+
+/// The user's attributes, without timestamps or metadata.
+fn user_item(id: i32, user: &User) -> Value {
+    let mut item = user_key(id);
+    item["id"] = n(id);
+    item["name"] = s(&user.name);
+    item["email"] = s(&user.email);
+    item
+}
+
+fn address_item(address: &Address) -> Value {
+    let mut item = address_key(
+        address.user_id.unwrap_or_default(),
+        address.id.unwrap_or_default(),
+    );
+    item["id"] = n(address.id.unwrap_or_default());
+    item["user_id"] = n(address.user_id.unwrap_or_default());
+    item["line1"] = s(&address.line1);
+    if let Some(line2) = &address.line2 {
+        item["line2"] = s(line2);
+    }
+    item["city"] = s(&address.city);
+    item["postal_code"] = s(&address.postal_code);
+    item["country"] = s(&address.country);
+    item
+}
+
+/// The items of a `Scan` or `Query` page.
+fn items(page: &Value) -> impl Iterator<Item = &Value> {
+    page["Items"].as_array().into_iter().flatten()
+}
+
+/// A `Scan` request for the users `filter` matches. Only names and values
+/// the expression uses may be sent.
+fn user_filter(filter: &UserFilter) -> Value {
+    let mut conditions = vec!["SK = :profile".to_string()];
+    let mut values = Map::new();
+    values.insert(":profile".to_string(), s(USER_SK));
+    if let Some(after) = &filter.created_after {
+        conditions.push("created_at > :after".to_string());
+        values.insert(":after".to_string(), s(&timestamp(*after)));
+    }
+    if let Some(before) = &filter.created_before {
+        conditions.push("created_at < :before".to_string());
+        values.insert(":before".to_string(), s(&timestamp(*before)));
+    }
+    json!({
+        "FilterExpression": conditions.join(" AND "),
+        "ExpressionAttributeValues": values,
+    })
+}
+
+fn address_query(user_id: i32) -> Value {
+    json!({
+        "KeyConditionExpression": "PK = :pk AND begins_with(SK, :prefix)",
+        "ExpressionAttributeValues": {
+            ":pk": s(&format!("USER#{}", user_id)),
+            ":prefix": s("ADDRESS#"),
+        },
+        "ConsistentRead": true,
+    })
+}
+
+fn invalid_item(attribute: &str) -> RepositoryError {
+    DynamoDbError::new(
+        "InvalidItem",
+        format!("missing or malformed attribute {}", attribute),
+    )
+    .into()
+}
+
+fn string(item: &Value, attribute: &str) -> Result<String, RepositoryError> {
+    item[attribute]["S"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid_item(attribute))
+}
+
+fn number<T: FromStr>(item: &Value, attribute: &str) -> Result<T, RepositoryError> {
+    item[attribute]["N"]
+        .as_str()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid_item(attribute))
+}
+
+fn time(item: &Value, attribute: &str) -> Result<Option<DateTime<Utc>>, RepositoryError> {
+    match item[attribute]["S"].as_str() {
+        None => Ok(None),
+        Some(value) => DateTime::parse_from_rfc3339(value)
+            .map(|time| Some(time.with_timezone(&Utc)))
+            .map_err(|_| invalid_item(attribute)),
+    }
+}
+
+fn user_from_item(item: &Value) -> Result<User, RepositoryError> {
+    Ok(User {
+        id: Some(number(item, "id")?),
+        name: string(item, "name")?,
+        email: string(item, "email")?,
+        created_at: time(item, "created_at")?,
+        updated_at: time(item, "updated_at")?,
+    })
+}
+
+/// An empty object until the metadata was first set.
+fn metadata_from_item(item: &Value) -> Result<Value, RepositoryError> {
+    match item["metadata"]["S"].as_str() {
+        None => Ok(Value::Object(Map::new())),
+        Some(text) => serde_json::from_str(text).map_err(|_| invalid_item("metadata")),
+    }
+}
+
+fn totp_from_item(item: &Value) -> Result<Totp, RepositoryError> {
+    Ok(Totp {
+        secret: string(item, "secret")?,
+        enabled: item["enabled"]["BOOL"]
+            .as_bool()
+            .ok_or_else(|| invalid_item("enabled"))?,
+        recovery_codes: item["recovery_codes"]["L"]
+            .as_array()
+            .ok_or_else(|| invalid_item("recovery_codes"))?
+            .iter()
+            .map(|code| code["S"].as_str().map(str::to_string))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid_item("recovery_codes"))?,
+        last_step: number(item, "last_step")?,
+    })
+}
+
+fn address_from_item(item: &Value) -> Result<Address, RepositoryError> {
+    Ok(Address {
+        id: Some(number(item, "id")?),
+        user_id: Some(number(item, "user_id")?),
+        line1: string(item, "line1")?,
+        line2: item["line2"]["S"].as_str().map(str::to_string),
+        city: string(item, "city")?,
+        postal_code: string(item, "postal_code")?,
+        country: string(item, "country")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn users_and_addresses_survive_a_round_trip() {
+        let user = User {
+            id: None,
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: None,
+            updated_at: None,
+        };
+        let mut item = user_item(7, &user);
+        let created = Utc.with_ymd_and_hms(2024, 3, 4, 5, 6, 7).unwrap();
+        item["created_at"] = s(&timestamp(created));
+
+        let read = user_from_item(&item).unwrap();
+        assert_eq!(read.id, Some(7));
+        assert_eq!(read.email, "ada@example.com");
+        assert_eq!(read.created_at, Some(created));
+        assert_eq!(read.updated_at, None);
+        assert_eq!(item["PK"], s("USER#7"));
+
+        let address = Address {
+            id: Some(3),
+            user_id: Some(7),
+            line1: "1 Main St".to_string(),
+            line2: None,
+            city: "Berlin".to_string(),
+            postal_code: "10115".to_string(),
+            country: "DE".to_string(),
+        };
+        let item = address_item(&address);
+        assert_eq!(item["SK"], s("ADDRESS#0000000003"));
+        let read = address_from_item(&item).unwrap();
+        assert_eq!((read.id, read.user_id), (Some(3), Some(7)));
+        assert_eq!((read.line2, read.country), (None, "DE".to_string()));
+        assert!(metadata_from_item(&item)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn filters_only_send_the_values_they_use() {
+        let request = user_filter(&UserFilter::default());
+        assert_eq!(request["FilterExpression"], "SK = :profile");
+        assert_eq!(
+            request["ExpressionAttributeValues"]
+                .as_object()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let after = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let request = user_filter(&UserFilter {
+            created_after: Some(after),
+            ..UserFilter::default()
+        });
+        assert_eq!(
+            request["FilterExpression"],
+            "SK = :profile AND created_at > :after"
+        );
+        assert_eq!(
+            request["ExpressionAttributeValues"][":after"],
+            s("2024-01-01T00:00:00.000000Z")
+        );
+    }
+
+    #[test]
+    fn reads_cancelled_transactions() {
+        let e = DynamoDbError::from_body(
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#TransactionCanceledException",
+                "Message":"Transaction cancelled",
+                "CancellationReasons":[{"Code":"None"},{"Code":"ConditionalCheckFailed"}]}"#,
+        );
+        assert_eq!(e.kind, "TransactionCanceledException");
+        assert!(!e.check_failed_at(0));
+        assert!(e.check_failed_at(1));
+        assert_eq!(
+            e.to_string(),
+            "DynamoDB TransactionCanceledException: Transaction cancelled"
+        );
+    }
+
+    #[test]
+    fn recognizes_throttling() {
+        let e = DynamoDbError::from_body(
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException",
+                "message":"The level of configured provisioned throughput for the table was exceeded"}"#,
+        );
+        assert!(e.throttled());
+        assert!(!DynamoDbError::new("ResourceNotFoundException", "no table").throttled());
+    }
+}
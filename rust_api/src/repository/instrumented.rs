@@ -0,0 +1,458 @@
+use super::{
+    AddressRepository, FlagRepository, Repository, RepositoryError, TotpRepository, Upserted,
+    UserFilter, UserRepository,
+};
+use crate::models::{Address, User};
+use crate::statsd;
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+*  Repository instrumentation
+*
+*  Every call into the storage backend is timed under a query name such as
+*  `users.find`, with the rows it returned or wrote and, when it failed, the
+*  class of the error. Calls are pushed to StatsD, logged at debug level (the
+*  JSON log ties each one to the request and trace it ran in) and summed up per
+*  query for `GET /metrics`.
+*/
+
+/// Totals for one query name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryStats {
+    pub calls: u64,
+    pub rows: u64,
+    pub duration: Duration,
+    pub max_duration: Duration,
+    /// Failed calls by error class, see `RepositoryError::class`.
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+/// The most recent call the database failed, for `GET /status`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DatabaseError {
+    pub query: &'static str,
+    pub class: &'static str,
+    pub message: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Per-query totals since startup.
+#[derive(Default)]
+pub struct QueryMetrics {
+    queries: Mutex<BTreeMap<&'static str, QueryStats>>,
+    last_error: Mutex<Option<DatabaseError>>,
+}
+
+impl QueryMetrics {
+    pub fn record(
+        &self,
+        query: &'static str,
+        duration: Duration,
+        rows: u64,
+        error: Option<&RepositoryError>,
+    ) {
+        let mut queries = self.queries.lock().unwrap();
+        let stats = queries.entry(query).or_default();
+        stats.calls += 1;
+        stats.rows += rows;
+        stats.duration += duration;
+        stats.max_duration = stats.max_duration.max(duration);
+        if let Some(e) = error {
+            *stats.errors.entry(e.class()).or_default() += 1;
+            // Conflicts and encryption errors are the data's fault, not the
+            // database's.
+            if matches!(e.class(), "database" | "timeout") {
+                *self.last_error.lock().unwrap() = Some(DatabaseError {
+                    query,
+                    class: e.class(),
+                    message: e.to_string(),
+                    at: Utc::now(),
+                });
+            }
+        }
+    }
+
+    pub fn last_error(&self) -> Option<DatabaseError> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, QueryStats> {
+        self.queries.lock().unwrap().clone()
+    }
+
+    /// The totals in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let queries = self.snapshot();
+        let mut out = String::new();
+        let mut family =
+            |name: &str, kind: &str, help: &str, value: &dyn Fn(&QueryStats) -> String| {
+                writeln!(out, "# HELP {} {}", name, help).unwrap();
+                writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+                for (query, stats) in &queries {
+                    writeln!(out, "{}{{query=\"{}\"}} {}", name, query, value(stats)).unwrap();
+                }
+            };
+        family(
+            "repository_calls_total",
+            "counter",
+            "Repository calls by query.",
+            &|stats| stats.calls.to_string(),
+        );
+        family(
+            "repository_rows_total",
+            "counter",
+            "Rows returned or written by query.",
+            &|stats| stats.rows.to_string(),
+        );
+        family(
+            "repository_call_duration_seconds_total",
+            "counter",
+            "Time spent in repository calls by query.",
+            &|stats| stats.duration.as_secs_f64().to_string(),
+        );
+        family(
+            "repository_call_duration_seconds_max",
+            "gauge",
+            "Slowest repository call by query.",
+            &|stats| stats.max_duration.as_secs_f64().to_string(),
+        );
+
+        writeln!(
+            out,
+            "# HELP repository_errors_total Failed repository calls by query and error class."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE repository_errors_total counter").unwrap();
+        for (query, stats) in &queries {
+            for (class, count) in &stats.errors {
+                writeln!(
+                    out,
+                    "repository_errors_total{{query=\"{}\",class=\"{}\"}} {}",
+                    query, class, count
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+/// Wraps a backend, recording every call into `metrics`.
+pub struct InstrumentedRepository {
+    inner: Arc<dyn Repository>,
+    metrics: Arc<QueryMetrics>,
+}
+
+impl InstrumentedRepository {
+    pub fn new(inner: Arc<dyn Repository>, metrics: Arc<QueryMetrics>) -> Self {
+        InstrumentedRepository { inner, metrics }
+    }
+
+    /// Runs `call` as `query`; `rows` tells how many rows its result stands for.
+    fn call<T>(
+        &self,
+        query: &'static str,
+        rows: impl FnOnce(&T) -> u64,
+        call: impl FnOnce() -> Result<T, RepositoryError>,
+    ) -> Result<T, RepositoryError> {
+        let started = Instant::now();
+        let result = call();
+        let elapsed = started.elapsed();
+        let (rows, error) = match &result {
+            Ok(value) => (rows(value), None),
+            Err(e) => (0, Some(e)),
+        };
+
+        self.metrics.record(query, elapsed, rows, error);
+        let outcome = error.map_or("ok", RepositoryError::class);
+        statsd::timing(
+            "repository.call_duration",
+            elapsed,
+            &[("query", query), ("outcome", outcome)],
+        );
+        statsd::count("repository.rows", rows, &[("query", query)]);
+        debug!(
+            "Query {} took {:.3} ms: {} rows, {}",
+            query,
+            elapsed.as_secs_f64() * 1000.0,
+            rows,
+            outcome
+        );
+        result
+    }
+}
+
+/// How many rows a result stands for: those returned, or those affected for
+/// a count of updated or deleted rows.
+trait Rows {
+    fn rows(&self) -> u64;
+}
+
+impl<T> Rows for Option<T> {
+    fn rows(&self) -> u64 {
+        self.is_some() as u64
+    }
+}
+
+impl<T> Rows for Vec<T> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<K, V> Rows for BTreeMap<K, V> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl Rows for u64 {
+    fn rows(&self) -> u64 {
+        *self
+    }
+}
+
+/// For calls that write or count a single row.
+fn one<T>(_: &T) -> u64 {
+    1
+}
+
+impl UserRepository for InstrumentedRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        self.call("migrate", |_| 0, || self.inner.migrate())
+    }
+
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        self.call(
+            "purge",
+            |purged: &BTreeMap<_, u64>| purged.values().sum(),
+            || self.inner.purge(before),
+        )
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        self.call("users.create", one, || self.inner.create(user))
+    }
+
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        self.call(
+            "users.create_many",
+            |ids: &Vec<Option<i32>>| ids.iter().flatten().count() as u64,
+            || self.inner.create_many(users),
+        )
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        self.call("users.find", Rows::rows, || self.inner.find(id))
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        self.call("users.find_many", Rows::rows, || self.inner.find_many(ids))
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.call("users.find_by_email", Rows::rows, || {
+            self.inner.find_by_email(email)
+        })
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.call("users.list", Rows::rows, || self.inner.list(filter))
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        self.call("users.count", one, || self.inner.count(filter))
+    }
+
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        self.call("users.stream_all", Rows::rows, || {
+            let mut streamed = 0;
+            self.inner
+                .stream_all(filter, &mut |user| {
+                    streamed += 1;
+                    each(user)
+                })
+                .map(|()| streamed)
+        })
+        .map(|_| ())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        self.call("users.update", Rows::rows, || self.inner.update(id, user))
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.call("users.change", Rows::rows, || {
+            self.inner.change_user(id, change)
+        })
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        self.call("users.upsert", one, || self.inner.upsert(user))
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        self.call("users.delete", Rows::rows, || self.inner.delete(id))
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        self.call("users.metadata", Rows::rows, || self.inner.metadata(id))
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        self.call("users.change_metadata", Rows::rows, || {
+            self.inner.change_metadata(id, change)
+        })
+    }
+}
+
+impl AddressRepository for InstrumentedRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        self.call("addresses.list", Rows::rows, || {
+            self.inner.list_addresses(user_id)
+        })
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        self.call("addresses.count", one, || {
+            self.inner.count_addresses(user_id)
+        })
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        self.call("addresses.create", one, || {
+            self.inner.create_address(user_id, address)
+        })
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        self.call("addresses.find", Rows::rows, || {
+            self.inner.find_address(user_id, id)
+        })
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        self.call("addresses.update", Rows::rows, || {
+            self.inner.update_address(user_id, id, address)
+        })
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        self.call("addresses.delete", Rows::rows, || {
+            self.inner.delete_addresses(user_id, id)
+        })
+    }
+}
+
+impl FlagRepository for InstrumentedRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        self.call("flags.list", Rows::rows, || self.inner.list_flags())
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        self.call("flags.set", one, || self.inner.set_flag(name, enabled))
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        self.call("flags.delete", Rows::rows, || self.inner.delete_flag(name))
+    }
+}
+
+impl TotpRepository for InstrumentedRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        self.call("totp.change", Rows::rows, || {
+            self.inner.change_totp(account, change)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    fn user(email: &str) -> User {
+        User {
+            id: None,
+            name: "Ada".to_string(),
+            email: email.to_string(),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn records_calls_rows_and_errors_per_query() {
+        let metrics = Arc::new(QueryMetrics::default());
+        let repository =
+            InstrumentedRepository::new(Arc::new(MemoryUserRepository::new()), metrics.clone());
+        repository.create(&user("ada@example.com")).unwrap();
+        repository.create(&user("bob@example.com")).unwrap();
+        assert!(repository.create(&user("ada@example.com")).is_err());
+        repository.list(&UserFilter::default()).unwrap();
+        repository.find(42).unwrap();
+
+        let queries = metrics.snapshot();
+        let create = &queries["users.create"];
+        assert_eq!((create.calls, create.rows), (3, 2));
+        assert_eq!(create.errors, BTreeMap::from([("conflict", 1)]));
+        assert_eq!(queries["users.list"].rows, 2);
+        assert_eq!(queries["users.find"].rows, 0);
+
+        let text = metrics.render();
+        assert!(text.contains("repository_calls_total{query=\"users.create\"} 3\n"));
+        assert!(text.contains("repository_rows_total{query=\"users.list\"} 2\n"));
+        assert!(
+            text.contains("repository_errors_total{query=\"users.create\",class=\"conflict\"} 1\n")
+        );
+        assert!(text.contains("# TYPE repository_call_duration_seconds_max gauge\n"));
+        assert_eq!(metrics.last_error(), None);
+    }
+
+    #[test]
+    fn remembers_the_last_database_failure() {
+        let metrics = QueryMetrics::default();
+        metrics.record(
+            "users.find",
+            Duration::ZERO,
+            0,
+            Some(&RepositoryError::Timeout),
+        );
+        metrics.record(
+            "users.create",
+            Duration::ZERO,
+            0,
+            Some(&RepositoryError::Conflict),
+        );
+        let last = metrics.last_error().unwrap();
+        assert_eq!((last.query, last.class), ("users.find", "timeout"));
+    }
+}
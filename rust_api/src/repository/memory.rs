@@ -0,0 +1,365 @@
+use super::{
+    AddressRepository, FlagRepository, RepositoryError, TotpRepository, Upserted, UserFilter,
+    UserRepository,
+};
+use crate::models::{Address, User};
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Process-local storage used by the test profile. Data is lost on restart.
+#[derive(Default)]
+pub struct MemoryUserRepository {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: i32,
+    users: BTreeMap<i32, User>,
+    next_address_id: i32,
+    addresses: BTreeMap<i32, Address>,
+    /// Only users whose metadata was set have an entry.
+    metadata: BTreeMap<i32, Value>,
+    flags: BTreeMap<String, bool>,
+    totp: BTreeMap<String, Totp>,
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl State {
+    /// Mirrors the unique index on `users.email` in Postgres.
+    fn email_taken(&self, email: &str, except: Option<i32>) -> bool {
+        self.users
+            .values()
+            .any(|user| user.email == email && user.id != except)
+    }
+
+    fn address_mut(&mut self, user_id: i32, id: i32) -> Option<&mut Address> {
+        self.addresses
+            .get_mut(&id)
+            .filter(|address| address.user_id == Some(user_id))
+    }
+}
+
+impl UserRepository for MemoryUserRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    fn purge(&self, _: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        Ok(BTreeMap::new())
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        if state.email_taken(&user.email, None) {
+            return Err(RepositoryError::Conflict);
+        }
+        state.next_id += 1;
+        let id = state.next_id;
+        let now = Utc::now();
+        state.users.insert(
+            id,
+            User {
+                id: Some(id),
+                created_at: Some(now),
+                updated_at: Some(now),
+                ..user.clone()
+            },
+        );
+        Ok(id)
+    }
+
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        users
+            .iter()
+            .map(|user| match self.create(user) {
+                Ok(id) => Ok(Some(id)),
+                Err(RepositoryError::Conflict) => Ok(None),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        Ok(self.state.lock().unwrap().users.get(&id).cloned())
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.users.get(id).cloned())
+            .collect())
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .values()
+            .find(|user| user.email == email)
+            .cloned())
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        let mut users: Vec<User> = state
+            .users
+            .values()
+            .filter(|user| filter.matches(user))
+            .cloned()
+            .collect();
+        filter.arrange(&mut users);
+        Ok(users)
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .values()
+            .filter(|user| filter.matches(user))
+            .count() as u64)
+    }
+
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        // Snapshot first so a slow consumer does not hold the lock.
+        for user in self.list(filter)? {
+            if !each(user) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.contains_key(&id) && state.email_taken(&user.email, Some(id)) {
+            return Err(RepositoryError::Conflict);
+        }
+        match state.users.get_mut(&id) {
+            Some(existing) => {
+                existing.name = user.name.clone();
+                existing.email = user.email.clone();
+                existing.updated_at = Some(Utc::now());
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(current) = state.users.get(&id).cloned() else {
+            return Ok(None);
+        };
+        let mut changed = current.clone();
+        if !change(&mut changed) {
+            return Ok(Some(current));
+        }
+        if state.email_taken(&changed.email, Some(id)) {
+            return Err(RepositoryError::Conflict);
+        }
+        let stored = User {
+            name: changed.name,
+            email: changed.email,
+            updated_at: Some(Utc::now()),
+            ..current
+        };
+        state.users.insert(id, stored.clone());
+        Ok(Some(stored))
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(existing) = state
+            .users
+            .values_mut()
+            .find(|existing| existing.email == user.email)
+        {
+            existing.name = user.name.clone();
+            existing.updated_at = Some(Utc::now());
+            return Ok(Upserted::Updated(existing.id.unwrap_or_default()));
+        }
+        state.next_id += 1;
+        let id = state.next_id;
+        let now = Utc::now();
+        state.users.insert(
+            id,
+            User {
+                id: Some(id),
+                created_at: Some(now),
+                updated_at: Some(now),
+                ..user.clone()
+            },
+        );
+        Ok(Upserted::Created(id))
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        // Cascades like the foreign key in Postgres.
+        state
+            .addresses
+            .retain(|_, address| address.user_id != Some(id));
+        state.metadata.remove(&id);
+        Ok(state.users.remove(&id).map_or(0, |_| 1))
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        if !state.users.contains_key(&id) {
+            return Ok(None);
+        }
+        Ok(Some(
+            state
+                .metadata
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(empty_object),
+        ))
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.users.contains_key(&id) {
+            return Ok(None);
+        }
+        let current = state
+            .metadata
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(empty_object);
+        let mut changed = current.clone();
+        if !change(&mut changed) {
+            return Ok(Some(current));
+        }
+        state.metadata.insert(id, changed.clone());
+        Ok(Some(changed))
+    }
+}
+
+fn empty_object() -> Value {
+    Value::Object(Default::default())
+}
+
+impl AddressRepository for MemoryUserRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .addresses
+            .values()
+            .filter(|address| address.user_id == Some(user_id))
+            .cloned()
+            .collect())
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        Ok(self.list_addresses(user_id)?.len() as u64)
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.next_address_id += 1;
+        let id = state.next_address_id;
+        let address = Address {
+            id: Some(id),
+            user_id: Some(user_id),
+            ..address.clone()
+        };
+        state.addresses.insert(id, address.clone());
+        Ok(address)
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state
+            .address_mut(user_id, id)
+            .map(|address| address.clone()))
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        match state.address_mut(user_id, id) {
+            Some(existing) => {
+                *existing = Address {
+                    id: Some(id),
+                    user_id: Some(user_id),
+                    ..address.clone()
+                };
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.addresses.len();
+        state.addresses.retain(|address_id, address| {
+            address.user_id != Some(user_id) || id.is_some_and(|id| id != *address_id)
+        });
+        Ok((before - state.addresses.len()) as u64)
+    }
+}
+
+impl FlagRepository for MemoryUserRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        Ok(self.state.lock().unwrap().flags.clone())
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.flags.insert(name.to_string(), enabled);
+        Ok(())
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        Ok(u64::from(state.flags.remove(name).is_some()))
+    }
+}
+
+impl TotpRepository for MemoryUserRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        let mut totp = state.totp.get(account).cloned();
+        if !change(&mut totp) {
+            return Ok(state.totp.get(account).cloned());
+        }
+        match &totp {
+            Some(totp) => state.totp.insert(account.to_string(), totp.clone()),
+            None => state.totp.remove(account),
+        };
+        Ok(totp)
+    }
+}
@@ -0,0 +1,277 @@
+use super::RepositoryError;
+use chrono::{DateTime, Utc};
+use postgres::{Client, GenericClient, Transaction};
+use std::fmt;
+
+/*
+*  Migrations
+*
+*  Each migration is a pair of scripts: `up` changes the schema and `down`
+*  takes the change back. They are applied in order, each once, and recorded
+*  in `schema_migrations`; rolling one back deletes its record, so the next
+*  `up` applies it again. Both directions run in one transaction holding
+*  `MIGRATION_LOCK`, so instances starting together take turns and a script
+*  that fails leaves the schema as it was.
+*
+*  A dry run goes through the same steps without running any script, and
+*  returns the SQL it would have run so the change can be reviewed first.
+*/
+
+/// Held while migrating, so instances starting together take turns.
+pub(super) const MIGRATION_LOCK: i64 = 0x736368656d61;
+/// Records which migrations were applied.
+pub(super) const HISTORY: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version INTEGER PRIMARY KEY,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)";
+
+struct Migration {
+    up: &'static str,
+    down: &'static str,
+}
+
+/// Applied in order. Append new ones; never change one that has shipped. The
+/// ones from before versioning are idempotent since they ran on databases
+/// that already had some of them. Rolling back the first drops the users.
+const MIGRATIONS: [Migration; 9] = [
+    Migration {
+        up: "CREATE TABLE IF NOT EXISTS users (
+        id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        email VARCHAR NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS users_email_key ON users (email)",
+        down: "DROP TABLE IF EXISTS users",
+    },
+    Migration {
+        up: "ALTER TABLE users ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+    CREATE INDEX IF NOT EXISTS users_created_at_idx ON users (created_at)",
+        down: "ALTER TABLE users DROP COLUMN IF EXISTS created_at",
+    },
+    Migration {
+        up: "CREATE TABLE IF NOT EXISTS addresses (
+        id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        line1 VARCHAR NOT NULL,
+        line2 VARCHAR,
+        city VARCHAR NOT NULL,
+        postal_code VARCHAR NOT NULL,
+        country CHAR(2) NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS addresses_user_id_idx ON addresses (user_id)",
+        down: "DROP TABLE IF EXISTS addresses",
+    },
+    Migration {
+        up: "ALTER TABLE users ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}'",
+        down: "ALTER TABLE users DROP COLUMN IF EXISTS metadata",
+    },
+    Migration {
+        up: "ALTER TABLE users ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now()",
+        down: "ALTER TABLE users DROP COLUMN IF EXISTS updated_at",
+    },
+    Migration {
+        up: "CREATE TABLE IF NOT EXISTS feature_flags (
+        name VARCHAR PRIMARY KEY,
+        enabled BOOLEAN NOT NULL,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+    )",
+        down: "DROP TABLE IF EXISTS feature_flags",
+    },
+    Migration {
+        up: "CREATE TABLE IF NOT EXISTS outbox (
+        id BIGSERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        payload JSONB NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        sent_at TIMESTAMPTZ
+    );
+    CREATE INDEX IF NOT EXISTS outbox_unsent_idx ON outbox (id) WHERE sent_at IS NULL",
+        down: "DROP TABLE IF EXISTS outbox",
+    },
+    Migration {
+        up: "CREATE TABLE IF NOT EXISTS totp (
+        account VARCHAR PRIMARY KEY,
+        secret VARCHAR NOT NULL,
+        enabled BOOLEAN NOT NULL,
+        recovery_codes VARCHAR[] NOT NULL,
+        last_step BIGINT NOT NULL
+    )",
+        down: "DROP TABLE IF EXISTS totp",
+    },
+    Migration {
+        up: "ALTER TABLE users ADD COLUMN IF NOT EXISTS email_index VARCHAR;
+    CREATE UNIQUE INDEX IF NOT EXISTS users_email_index_key ON users (email_index)",
+        down: "ALTER TABLE users DROP COLUMN IF EXISTS email_index",
+    },
+];
+
+/// The version `migrate` brings the schema to.
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// A script run, or about to run in a dry run.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Step {
+    pub version: i32,
+    /// Whether it rolls the migration back.
+    pub down: bool,
+    pub sql: &'static str,
+}
+
+/// As it would be fed to `psql`.
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = if self.down { "down" } else { "up" };
+        write!(f, "-- {} {}\n{};", self.version, direction, self.sql)
+    }
+}
+
+/// Whether a migration this build knows, or one it does not, was applied.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i32,
+    /// `None` while it is pending.
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+impl fmt::Display for MigrationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.applied_at {
+            Some(_) if self.version > SCHEMA_VERSION => {
+                write!(f, "{:>4}  applied, unknown to this build", self.version)
+            }
+            Some(at) => write!(f, "{:>4}  applied {}", self.version, at.to_rfc3339()),
+            None => write!(f, "{:>4}  pending", self.version),
+        }
+    }
+}
+
+/// Every migration this build knows, and any newer one that was applied.
+pub fn status(client: &mut Client) -> Result<Vec<MigrationStatus>, RepositoryError> {
+    let applied = applied(client)?;
+    let last = applied.last().map_or(0, |(version, _)| *version);
+    Ok((1..=SCHEMA_VERSION.max(last))
+        .map(|version| MigrationStatus {
+            version,
+            applied_at: applied
+                .iter()
+                .find(|(applied, _)| *applied == version)
+                .map(|(_, at)| *at),
+        })
+        .collect())
+}
+
+/// Applies the pending migrations.
+pub fn up(client: &mut Client, dry_run: bool) -> Result<Vec<Step>, RepositoryError> {
+    let mut transaction = locked(client)?;
+    if !dry_run {
+        transaction.batch_execute(HISTORY)?;
+    }
+    let steps = pending(current(&mut transaction)?);
+    if !dry_run {
+        for step in &steps {
+            transaction.batch_execute(step.sql)?;
+            transaction.execute(
+                "INSERT INTO schema_migrations (version) VALUES ($1)",
+                &[&step.version],
+            )?;
+        }
+        transaction.commit()?;
+    }
+    Ok(steps)
+}
+
+/// The migrations to apply after `version`, in order.
+pub(super) fn pending(version: i32) -> Vec<Step> {
+    (1..)
+        .zip(&MIGRATIONS)
+        .skip(version.max(0) as usize)
+        .map(|(version, migration)| Step {
+            version,
+            down: false,
+            sql: migration.up,
+        })
+        .collect()
+}
+
+/// Rolls back the last `count` migrations applied, newest first.
+pub fn down(client: &mut Client, count: u32, dry_run: bool) -> Result<Vec<Step>, RepositoryError> {
+    let mut transaction = locked(client)?;
+    let version = current(&mut transaction)?;
+    if version > SCHEMA_VERSION {
+        return Err(RepositoryError::Unsupported(
+            "The database has migrations this build cannot roll back",
+        ));
+    }
+    let steps: Vec<Step> = (1..=version)
+        .rev()
+        .take(count as usize)
+        .map(|version| Step {
+            version,
+            down: true,
+            sql: MIGRATIONS[version as usize - 1].down,
+        })
+        .collect();
+    if !dry_run {
+        for step in &steps {
+            transaction.batch_execute(step.sql)?;
+            transaction.execute(
+                "DELETE FROM schema_migrations WHERE version = $1",
+                &[&step.version],
+            )?;
+        }
+        transaction.commit()?;
+    }
+    Ok(steps)
+}
+
+fn locked(client: &mut Client) -> Result<Transaction<'_>, RepositoryError> {
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+    Ok(transaction)
+}
+
+/// The last migration applied, 0 for none.
+fn current(transaction: &mut impl GenericClient) -> Result<i32, RepositoryError> {
+    Ok(applied(transaction)?
+        .last()
+        .map_or(0, |(version, _)| *version))
+}
+
+/// The versions recorded, in order; none before the first migration.
+fn applied(client: &mut impl GenericClient) -> Result<Vec<(i32, DateTime<Utc>)>, RepositoryError> {
+    let exists: bool = client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])?
+        .get(0);
+    if !exists {
+        return Ok(Vec::new());
+    }
+    Ok(client
+        .query(
+            "SELECT version, applied_at FROM schema_migrations ORDER BY version",
+            &[],
+        )?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_migration_can_be_rolled_back() {
+        for migration in &MIGRATIONS {
+            assert!(!migration.down.trim().is_empty(), "{}", migration.up);
+        }
+        let step = Step {
+            version: 4,
+            down: true,
+            sql: MIGRATIONS[3].down,
+        };
+        assert_eq!(
+            step.to_string(),
+            "-- 4 down\nALTER TABLE users DROP COLUMN IF EXISTS metadata;"
+        );
+    }
+}
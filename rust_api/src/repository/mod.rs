@@ -0,0 +1,536 @@
+use crate::config::{Config, Storage};
+use crate::crypto::{CryptoError, FieldCipher};
+use crate::events::{self, EventBus, Publisher};
+use crate::models::{Address, User};
+use crate::pool::Pool;
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "diesel")]
+mod diesel_repository;
+mod dynamodb;
+mod instrumented;
+mod memory;
+pub mod migrations;
+mod postgres_repository;
+mod publishing;
+mod query;
+pub mod slow_query;
+#[cfg(feature = "sqlx")]
+mod sqlx_repository;
+
+#[cfg(feature = "diesel")]
+use diesel_repository::DieselError;
+#[cfg(feature = "diesel")]
+pub use diesel_repository::DieselRepository;
+use dynamodb::DynamoDbError;
+pub use dynamodb::{DynamoDbRepository, DynamoDbSettings};
+pub use instrumented::{InstrumentedRepository, QueryMetrics};
+pub use memory::MemoryUserRepository;
+pub use migrations::SCHEMA_VERSION;
+pub use postgres_repository::PostgresUserRepository;
+pub use publishing::PublishingRepository;
+#[cfg(feature = "sqlx")]
+pub use sqlx_repository::SqlxRepository;
+
+/*
+*  Repository
+*/
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Database(PostgresError),
+    /// The statement was cancelled by `statement_timeout`.
+    Timeout,
+    /// Another user already has the email address.
+    Conflict,
+    /// A stored value could not be decrypted.
+    Encryption(CryptoError),
+    DynamoDb(DynamoDbError),
+    #[cfg(feature = "sqlx")]
+    Sqlx(sqlx::Error),
+    #[cfg(feature = "diesel")]
+    Diesel(DieselError),
+    /// The backend cannot answer the query as asked.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepositoryError::Database(e) => write!(f, "{}", e),
+            RepositoryError::Timeout => write!(f, "statement timeout"),
+            RepositoryError::Conflict => write!(f, "email already exists"),
+            RepositoryError::Encryption(e) => write!(f, "{}", e),
+            RepositoryError::DynamoDb(e) => write!(f, "{}", e),
+            #[cfg(feature = "sqlx")]
+            RepositoryError::Sqlx(e) => write!(f, "{}", e),
+            #[cfg(feature = "diesel")]
+            RepositoryError::Diesel(e) => write!(f, "{}", e),
+            RepositoryError::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl RepositoryError {
+    /// A short name for the kind of failure, for metrics.
+    pub fn class(&self) -> &'static str {
+        match self {
+            RepositoryError::Database(_) | RepositoryError::DynamoDb(_) => "database",
+            #[cfg(feature = "sqlx")]
+            RepositoryError::Sqlx(_) => "database",
+            #[cfg(feature = "diesel")]
+            RepositoryError::Diesel(_) => "database",
+            RepositoryError::Timeout => "timeout",
+            RepositoryError::Conflict => "conflict",
+            RepositoryError::Encryption(_) => "encryption",
+            RepositoryError::Unsupported(_) => "unsupported",
+        }
+    }
+}
+
+impl From<CryptoError> for RepositoryError {
+    fn from(e: CryptoError) -> Self {
+        RepositoryError::Encryption(e)
+    }
+}
+
+impl From<PostgresError> for RepositoryError {
+    fn from(e: PostgresError) -> Self {
+        match e.code() {
+            Some(&SqlState::QUERY_CANCELED) => RepositoryError::Timeout,
+            Some(&SqlState::UNIQUE_VIOLATION) => RepositoryError::Conflict,
+            _ => RepositoryError::Database(e),
+        }
+    }
+}
+
+/// Narrows down a listing; the default matches every user. Bounds are
+/// exclusive. `order`, `skip` and `top` shape the page of users returned, but
+/// not what `count` counts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserFilter {
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// Further conditions, all of which must hold.
+    pub conditions: Vec<Expression>,
+    /// Sort keys, most significant first; ties are broken by id.
+    pub order: Vec<Order>,
+    pub skip: u64,
+    pub top: Option<u64>,
+}
+
+impl UserFilter {
+    pub fn matches(&self, user: &User) -> bool {
+        if !self
+            .conditions
+            .iter()
+            .all(|expression| expression.holds(user))
+        {
+            return false;
+        }
+        let created_at = match user.created_at {
+            Some(created_at) => created_at,
+            None => return self.created_after.is_none() && self.created_before.is_none(),
+        };
+        self.created_after.is_none_or(|after| created_at > after)
+            && self.created_before.is_none_or(|before| created_at < before)
+    }
+
+    /// Whether only some of the matching users make up the listing.
+    pub fn is_paged(&self) -> bool {
+        self.skip > 0 || self.top.is_some()
+    }
+
+    /// Sorts matching users, by id unless `order` says otherwise, and cuts
+    /// out the page, for backends that cannot do it themselves.
+    pub fn arrange(&self, users: &mut Vec<User>) {
+        users.sort_by(|a, b| {
+            self.order
+                .iter()
+                .map(|order| order.compare(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.id.cmp(&b.id))
+        });
+        let skip = usize::try_from(self.skip).unwrap_or(usize::MAX);
+        users.drain(..skip.min(users.len()));
+        if let Some(top) = self.top {
+            users.truncate(usize::try_from(top).unwrap_or(usize::MAX));
+        }
+    }
+
+    /// Whether a condition or the order reads the email.
+    pub fn reads_email(&self) -> bool {
+        self.conditions
+            .iter()
+            .any(|expression| expression.reads(UserField::Email))
+            || self
+                .order
+                .iter()
+                .any(|order| order.field == UserField::Email)
+    }
+}
+
+/// A user field that listings can be filtered and sorted by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserField {
+    Id,
+    Name,
+    Email,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl UserField {
+    pub const ALL: [UserField; 5] = [
+        UserField::Id,
+        UserField::Name,
+        UserField::Email,
+        UserField::CreatedAt,
+        UserField::UpdatedAt,
+    ];
+
+    /// The field called `name`, if listings may use it.
+    pub fn named(name: &str) -> Option<UserField> {
+        UserField::ALL
+            .into_iter()
+            .find(|field| field.name() == name)
+    }
+
+    /// The name of the field in JSON, which is also its column.
+    pub fn name(self) -> &'static str {
+        match self {
+            UserField::Id => "id",
+            UserField::Name => "name",
+            UserField::Email => "email",
+            UserField::CreatedAt => "created_at",
+            UserField::UpdatedAt => "updated_at",
+        }
+    }
+
+    fn value(self, user: &User) -> Option<Literal> {
+        match self {
+            UserField::Id => user.id.map(Literal::Integer),
+            UserField::Name => Some(Literal::Text(user.name.clone())),
+            UserField::Email => Some(Literal::Text(user.email.clone())),
+            UserField::CreatedAt => user.created_at.map(Literal::Timestamp),
+            UserField::UpdatedAt => user.updated_at.map(Literal::Timestamp),
+        }
+    }
+}
+
+/// A value to compare a field with, of the field's type.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub enum Literal {
+    Integer(i32),
+    Text(String),
+    Timestamp(DateTime<Utc>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    /// Case-sensitive substring match, for text fields.
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+/// `field <comparison> value`. Like in SQL, a field without a value, such as
+/// a user that was never updated, satisfies no condition on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub field: UserField,
+    pub comparison: Comparison,
+    pub value: Literal,
+}
+
+impl Condition {
+    fn holds(&self, user: &User) -> bool {
+        let Some(actual) = self.field.value(user) else {
+            return false;
+        };
+        match (self.comparison, &actual, &self.value) {
+            (Comparison::Contains, Literal::Text(actual), Literal::Text(part)) => {
+                actual.contains(part.as_str())
+            }
+            (Comparison::StartsWith, Literal::Text(actual), Literal::Text(part)) => {
+                actual.starts_with(part.as_str())
+            }
+            (Comparison::EndsWith, Literal::Text(actual), Literal::Text(part)) => {
+                actual.ends_with(part.as_str())
+            }
+            (Comparison::Contains | Comparison::StartsWith | Comparison::EndsWith, _, _) => false,
+            (comparison, actual, value) => match actual.partial_cmp(value) {
+                Some(ordering) => match comparison {
+                    Comparison::Eq => ordering.is_eq(),
+                    Comparison::Ne => ordering.is_ne(),
+                    Comparison::Gt => ordering.is_gt(),
+                    Comparison::Ge => ordering.is_ge(),
+                    Comparison::Lt => ordering.is_lt(),
+                    _ => ordering.is_le(),
+                },
+                None => false,
+            },
+        }
+    }
+}
+
+/// Conditions combined with `and` and `or`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    Condition(Condition),
+    /// Holds when every operand does.
+    All(Vec<Expression>),
+    /// Holds when any operand does.
+    Any(Vec<Expression>),
+}
+
+impl Expression {
+    fn holds(&self, user: &User) -> bool {
+        match self {
+            Expression::Condition(condition) => condition.holds(user),
+            Expression::All(operands) => operands.iter().all(|operand| operand.holds(user)),
+            Expression::Any(operands) => operands.iter().any(|operand| operand.holds(user)),
+        }
+    }
+
+    fn reads(&self, field: UserField) -> bool {
+        match self {
+            Expression::Condition(condition) => condition.field == field,
+            Expression::All(operands) | Expression::Any(operands) => {
+                operands.iter().any(|operand| operand.reads(field))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Order {
+    pub field: UserField,
+    pub descending: bool,
+}
+
+impl Order {
+    /// Missing values sort last when ascending and first when descending,
+    /// as PostgreSQL sorts NULL.
+    fn compare(&self, a: &User, b: &User) -> std::cmp::Ordering {
+        let ordering = match (self.field.value(a), self.field.value(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+            (None, None) => std::cmp::Ordering::Equal,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some(_), None) => std::cmp::Ordering::Less,
+        };
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// What an upsert did, with the id of the row it wrote.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Upserted {
+    Created(i32),
+    Updated(i32),
+}
+
+pub trait UserRepository: Send + Sync {
+    /// Creates the schema if it does not exist yet.
+    fn migrate(&self) -> Result<(), RepositoryError>;
+    /// Permanently deletes rows that were done with before `before`, such as
+    /// relayed outbox events. Returns the rows deleted per table.
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError>;
+    /// Returns the new user's id.
+    fn create(&self, user: &User) -> Result<i32, RepositoryError>;
+    /// Creates every user whose email is free, in as few round trips as the
+    /// backend allows. Returns the new ids in the order of `users`, `None`
+    /// where the email was taken, also by an earlier one of `users`.
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError>;
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError>;
+    /// The users among `ids` that exist, in no particular order.
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError>;
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError>;
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError>;
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError>;
+    /// Feeds every matching user to `each` without collecting them first,
+    /// stopping early once `each` returns `false`.
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError>;
+    /// Returns the number of rows updated.
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError>;
+    /// Runs `change` on the stored user and writes its name and email back,
+    /// unless `change` returns `false`. No concurrent write can interleave,
+    /// and `change` must not use the repository itself. Returns the user as
+    /// left, or `None` when there is no such user.
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError>;
+    /// Creates the user, or renames the one that already has its email.
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError>;
+    /// Returns the number of rows deleted.
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError>;
+    /// The user's metadata object, or `None` when there is no such user.
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError>;
+    /// Runs `change` on the user's metadata and stores the result, unless
+    /// `change` returns `false`. No concurrent change can interleave. Returns
+    /// the metadata as left, or `None` when there is no such user.
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError>;
+}
+
+/// Addresses belong to a user; every call is scoped to one, so an address id
+/// from another user is treated as missing.
+pub trait AddressRepository: Send + Sync {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError>;
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError>;
+    /// Returns the stored address, with its id.
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError>;
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError>;
+    /// Returns the number of rows updated.
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError>;
+    /// Removes one address, or every address of the user when `id` is `None`.
+    /// Returns the number of rows deleted.
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError>;
+}
+
+/// Feature flags by name, see `crate::flags`.
+pub trait FlagRepository: Send + Sync {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError>;
+    /// Creates the flag or changes its state.
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError>;
+    /// Returns the number of rows deleted.
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError>;
+}
+
+/// Two-factor enrollments by account, see `crate::totp`.
+pub trait TotpRepository: Send + Sync {
+    /// Runs `change` on the account's enrollment, `None` when it has none, and
+    /// stores what it leaves unless `change` returns `false`. No concurrent
+    /// change can interleave. Returns the enrollment as left.
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError>;
+}
+
+/// A storage backend, holding users and everything attached to them.
+pub trait Repository: UserRepository + AddressRepository + FlagRepository + TotpRepository {}
+
+impl<T: UserRepository + AddressRepository + FlagRepository + TotpRepository> Repository for T {}
+
+/// Builds the configured backend, publishing user changes to `publishers`.
+/// Backends on the Postgres database need the pool created from the same
+/// config, and relay their events through the outbox; other backends publish
+/// them after the change. Fails when the backend cannot be set up, such as for a malformed
+/// `DATABASE_URL`.
+pub fn from_config(
+    config: &Config,
+    pool: Option<Arc<Pool>>,
+    publishers: Vec<Box<dyn Publisher>>,
+) -> Result<Arc<dyn Repository>, RepositoryError> {
+    let backend: Arc<dyn Repository> = match (config.storage, pool) {
+        (Storage::Postgres, Some(pool)) => {
+            let mut repository = PostgresUserRepository::new(
+                pool,
+                i32::try_from(config.fetch_size.get()).unwrap_or(i32::MAX),
+                FieldCipher::new(&config.email_keys),
+            );
+            if let Some(index) = &config.email_index_key {
+                repository = repository.with_email_index(index.clone());
+            }
+            if publishers.is_empty() {
+                return Ok(Arc::new(repository));
+            }
+            let repository = Arc::new(repository.with_outbox());
+            events::relay_outbox(repository.clone(), publishers, config.outbox_poll);
+            return Ok(repository);
+        }
+        #[cfg(feature = "sqlx")]
+        (Storage::Sqlx, pool) => {
+            let url = config.database_url.as_deref().unwrap_or_default();
+            let mut repository = SqlxRepository::new(
+                url,
+                config.pool_size.get(),
+                config.statement_timeout,
+                config.slow_query_threshold,
+                FieldCipher::new(&config.email_keys),
+            )?;
+            if let Some(index) = &config.email_index_key {
+                repository = repository.with_email_index(index.clone());
+            }
+            if let (Some(pool), false) = (pool, publishers.is_empty()) {
+                relay_outbox(config, pool, publishers);
+                return Ok(Arc::new(repository.with_outbox()));
+            }
+            Arc::new(repository)
+        }
+        #[cfg(feature = "diesel")]
+        (Storage::Diesel, pool) => {
+            let url = config.database_url.as_deref().unwrap_or_default();
+            let mut repository = DieselRepository::new(
+                url,
+                config.pool_size.get(),
+                config.statement_timeout,
+                FieldCipher::new(&config.email_keys),
+            );
+            if let Some(index) = &config.email_index_key {
+                repository = repository.with_email_index(index.clone());
+            }
+            if let (Some(pool), false) = (pool, publishers.is_empty()) {
+                relay_outbox(config, pool, publishers);
+                return Ok(Arc::new(repository.with_outbox()));
+            }
+            Arc::new(repository)
+        }
+        (Storage::DynamoDb, _) => match &config.dynamodb {
+            Some(settings) => Arc::new(DynamoDbRepository::new(
+                settings.clone(),
+                config.statement_timeout,
+            )),
+            None => Arc::new(MemoryUserRepository::new()),
+        },
+        _ => Arc::new(MemoryUserRepository::new()),
+    };
+    if publishers.is_empty() {
+        return Ok(backend);
+    }
+    Ok(Arc::new(PublishingRepository::new(
+        backend,
+        Arc::new(EventBus::start(publishers)),
+    )))
+}
+
+/// Relays the outbox of a backend other than `PostgresUserRepository` on the
+/// same database, which the postgres crate reads like its own.
+#[cfg(any(feature = "sqlx", feature = "diesel"))]
+fn relay_outbox(config: &Config, pool: Arc<Pool>, publishers: Vec<Box<dyn Publisher>>) {
+    let relay = PostgresUserRepository::new(pool, 1, FieldCipher::new(&config.email_keys));
+    events::relay_outbox(Arc::new(relay), publishers, config.outbox_poll);
+}
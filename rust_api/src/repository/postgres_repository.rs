@@ -0,0 +1,783 @@
+use super::migrations;
+use super::query::{Op, Param, Predicate, Select};
+use super::{
+    slow_query, AddressRepository, Comparison, Expression, FlagRepository, Literal,
+    RepositoryError, TotpRepository, Upserted, UserFilter, UserRepository,
+};
+use crate::crud::CrudModel;
+use crate::crypto::{BlindIndex, FieldCipher};
+use crate::events::{Event, EventKind};
+use crate::models::{Address, User};
+use crate::pool::{Pool, PooledClient};
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use log::error;
+use postgres::types::ToSql;
+use postgres::{GenericClient, Row, Transaction};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Column order `user_from_row` expects.
+const COLUMNS: &str = User::COLUMNS;
+/// Held by the instance relaying the outbox, so events leave in order.
+const OUTBOX_LOCK: i64 = 0x6f7574626f78;
+
+pub struct PostgresUserRepository {
+    pool: Arc<Pool>,
+    /// Rows fetched from the cursor per round trip when streaming.
+    fetch_size: i32,
+    /// Encrypts the email column, and two-factor secrets, when set.
+    cipher: Option<FieldCipher>,
+    /// Indexes encrypted emails for lookups and uniqueness.
+    email_index: Option<BlindIndex>,
+    /// Queue an event in the `outbox` table with every user change.
+    outbox: bool,
+}
+
+impl PostgresUserRepository {
+    pub fn new(pool: Arc<Pool>, fetch_size: i32, cipher: Option<FieldCipher>) -> Self {
+        PostgresUserRepository {
+            pool,
+            fetch_size,
+            cipher,
+            email_index: None,
+            outbox: false,
+        }
+    }
+
+    /// Keeps a blind index of encrypted emails in `email_index`. Without
+    /// encryption the plaintext column serves and the index is not used.
+    pub fn with_email_index(mut self, index: BlindIndex) -> Self {
+        self.email_index = Some(index);
+        self
+    }
+
+    /// Writes change events to the outbox, in the transaction of the change.
+    pub fn with_outbox(mut self) -> Self {
+        self.outbox = true;
+        self
+    }
+
+    /// Hands up to `limit` queued events, oldest first, to `publish` and marks
+    /// those it took as sent. Stops at the first one `publish` fails on, which
+    /// stays queued. Only one instance relays at a time; the others get 0.
+    pub fn relay_outbox(
+        &self,
+        limit: i64,
+        publish: &mut dyn FnMut(&Event) -> Result<(), String>,
+    ) -> Result<u64, RepositoryError> {
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let locked =
+            transaction.query_one("SELECT pg_try_advisory_xact_lock($1)", &[&OUTBOX_LOCK])?;
+        if !locked.get::<_, bool>(0) {
+            return Ok(0);
+        }
+        let rows = transaction.query(
+            "SELECT id, payload FROM outbox WHERE sent_at IS NULL ORDER BY id LIMIT $1",
+            &[&limit],
+        )?;
+        let mut sent = Vec::new();
+        for row in &rows {
+            let id: i64 = row.get(0);
+            match self.event_from_outbox(row.get(1)) {
+                Some(event) => {
+                    if publish(&event).is_err() {
+                        break;
+                    }
+                }
+                // Not worth blocking every later event for.
+                None => error!("Outbox row {} is not a valid event, skipped", id),
+            }
+            sent.push(id);
+        }
+        transaction.execute(
+            "UPDATE outbox SET sent_at = now() WHERE id = ANY($1)",
+            &[&sent],
+        )?;
+        transaction.commit()?;
+        Ok(sent.len() as u64)
+    }
+
+    /// Runs a statement that changes a user and, with the outbox on, queues
+    /// the event `changed` derives from its rows in the same transaction.
+    fn write<T>(
+        &self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+        changed: impl FnOnce(&[Row]) -> (T, Option<Event>),
+    ) -> Result<T, RepositoryError> {
+        let mut client = self.connect()?;
+        if !self.outbox {
+            let rows = slow_query::timed(sql, params, || client.query(sql, params))?;
+            return Ok(changed(&rows).0);
+        }
+        let mut transaction = client.transaction()?;
+        let rows = slow_query::timed(sql, params, || transaction.query(sql, params))?;
+        let (result, event) = changed(&rows);
+        if let Some(event) = event {
+            self.queue_event(&mut transaction, &event)?;
+        }
+        transaction.commit()?;
+        Ok(result)
+    }
+
+    fn queue_event(
+        &self,
+        transaction: &mut Transaction,
+        event: &Event,
+    ) -> Result<(), RepositoryError> {
+        transaction.execute(
+            "INSERT INTO outbox (user_id, payload) VALUES ($1, $2)",
+            &[&event.user_id, &outbox_payload(event, self.cipher.as_ref())],
+        )?;
+        Ok(())
+    }
+
+    fn event_from_outbox(&self, mut payload: Value) -> Option<Event> {
+        if let (Some(email), Some(cipher)) = (payload["data"]["email"].as_str(), &self.cipher) {
+            payload["data"]["email"] = json!(cipher.decrypt(email).ok()?);
+        }
+        Event::from_json(&payload)
+    }
+
+    /// Re-encrypts every email not yet sealed with the current key, including
+    /// plaintext ones, and brings each row's blind index up to date, in
+    /// batches of `fetch_size`. Returns the rows rewritten. Safe to interrupt
+    /// and run again.
+    pub fn rotate_email_key(&self) -> Result<u64, RepositoryError> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(0),
+        };
+
+        let mut rewritten = 0;
+        let mut last_id = 0;
+        loop {
+            let mut client = self.connect()?;
+            let mut transaction = client.transaction()?;
+            let batch = Select::new("users", "id, email, email_index")
+                .filter("id", Op::Gt, &last_id)
+                .order_by("id")
+                .limit(self.fetch_size as u64)
+                .for_update();
+            let rows = transaction.query(&batch.sql(), batch.params())?;
+            let last = match rows.last() {
+                Some(row) => row.get(0),
+                None => break,
+            };
+
+            for row in &rows {
+                let stored: String = row.get(1);
+                let email = cipher.decrypt(&stored)?;
+                let index = self.index_of(&email);
+                if cipher.is_current(&stored) && row.get::<_, Option<String>>(2) == index {
+                    continue;
+                }
+                let sealed = match cipher.is_current(&stored) {
+                    true => stored,
+                    false => cipher.encrypt(&email),
+                };
+                let id: i32 = row.get(0);
+                transaction.execute(
+                    "UPDATE users SET email = $1, email_index = $2 WHERE id = $3",
+                    &[&sealed, &index, &id],
+                )?;
+                rewritten += 1;
+            }
+            transaction.commit()?;
+            last_id = last;
+        }
+        Ok(rewritten)
+    }
+
+    /// Without a blind index, encrypted emails never collide in the unique
+    /// index, so the match has to be found by decrypting every row. The table lock keeps a concurrent
+    /// upsert of the same email from inserting it twice.
+    fn upsert_sealed(
+        &self,
+        cipher: &FieldCipher,
+        user: &User,
+    ) -> Result<Upserted, RepositoryError> {
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")?;
+
+        let existing = find_sealed(&mut transaction, cipher, &user.email)?;
+        let upserted = match existing.map(|row| row.get(0)) {
+            Some(id) => {
+                transaction.execute(
+                    "UPDATE users SET name = $1, updated_at = now() WHERE id = $2",
+                    &[&user.name, &id],
+                )?;
+                Upserted::Updated(id)
+            }
+            None => {
+                let row = transaction.query_one(
+                    "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id",
+                    &[&user.name, &cipher.encrypt(&user.email)],
+                )?;
+                Upserted::Created(row.get(0))
+            }
+        };
+        if self.outbox {
+            let event = match upserted {
+                Upserted::Created(id) => Event::new(EventKind::Created, id, Some(user)),
+                Upserted::Updated(id) => Event::new(EventKind::Updated, id, Some(user)),
+            };
+            self.queue_event(&mut transaction, &event)?;
+        }
+        transaction.commit()?;
+        Ok(upserted)
+    }
+
+    /// The blind index stored with `email`, when emails are encrypted and
+    /// indexed.
+    fn index_of(&self, email: &str) -> Option<String> {
+        self.cipher.as_ref()?;
+        self.email_index.as_ref().map(|index| index.of(email))
+    }
+
+    /// Encrypted emails cannot be compared or sorted by in SQL.
+    fn check_readable(&self, filter: &UserFilter) -> Result<(), RepositoryError> {
+        if self.cipher.is_some() && filter.reads_email() {
+            return Err(RepositoryError::Unsupported(
+                "Emails are encrypted and cannot be filtered or sorted by",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encrypts `value` for storage when a cipher is configured.
+    fn seal(&self, value: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(value),
+            None => value.to_string(),
+        }
+    }
+
+    fn user_from_row(&self, row: &Row) -> Result<User, RepositoryError> {
+        let email: String = row.get(2);
+        Ok(User {
+            id: row.get(0),
+            name: row.get(1),
+            email: match &self.cipher {
+                Some(cipher) => cipher.decrypt(&email)?,
+                None => email,
+            },
+            created_at: row.get(3),
+            updated_at: row.get(4),
+        })
+    }
+
+    fn connect(&self) -> Result<PooledClient<'_>, RepositoryError> {
+        Ok(self.pool.get()?)
+    }
+
+    fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, RepositoryError> {
+        let mut client = self.connect()?;
+        Ok(slow_query::timed(sql, params, || {
+            client.execute(sql, params)
+        })?)
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, RepositoryError> {
+        let mut client = self.connect()?;
+        Ok(slow_query::timed(sql, params, || {
+            client.query(sql, params)
+        })?)
+    }
+
+    fn query_opt(
+        &self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, RepositoryError> {
+        let mut client = self.connect()?;
+        Ok(slow_query::timed(sql, params, || {
+            client.query_opt(sql, params)
+        })?)
+    }
+}
+
+/// What the outbox keeps of `event`. Emails are encrypted like those in
+/// `users`; every backend on this database queues events in this form.
+pub(super) fn outbox_payload(event: &Event, cipher: Option<&FieldCipher>) -> Value {
+    let mut payload = event.to_json();
+    if let (Some(email), Some(cipher)) = (payload["data"]["email"].as_str(), cipher) {
+        payload["data"]["email"] = json!(cipher.encrypt(email));
+    }
+    payload
+}
+
+/// Looks a user up by email when emails are encrypted without a blind index,
+/// which means decrypting them one by one.
+fn find_sealed(
+    client: &mut impl GenericClient,
+    cipher: &FieldCipher,
+    email: &str,
+) -> Result<Option<Row>, RepositoryError> {
+    for row in client.query(&format!("SELECT {} FROM users", COLUMNS), &[])? {
+        if cipher.decrypt(row.get(2))? == email {
+            return Ok(Some(row));
+        }
+    }
+    Ok(None)
+}
+
+/// Selects `columns` from the users `filter` matches.
+fn select_users<'a>(columns: &'static str, filter: &'a UserFilter) -> Select<'a> {
+    let mut query = Select::new("users", columns)
+        .filter_opt("created_at", Op::Gt, &filter.created_after)
+        .filter_opt("created_at", Op::Lt, &filter.created_before);
+    for expression in &filter.conditions {
+        query = query.filter_by(predicate(expression));
+    }
+    query
+}
+
+fn predicate(expression: &Expression) -> Predicate<'_> {
+    let condition = match expression {
+        Expression::Condition(condition) => condition,
+        Expression::All(operands) => {
+            return Predicate::All(operands.iter().map(predicate).collect())
+        }
+        Expression::Any(operands) => {
+            return Predicate::Any(operands.iter().map(predicate).collect())
+        }
+    };
+    let op = match condition.comparison {
+        Comparison::Eq => Op::Eq,
+        Comparison::Ne => Op::Ne,
+        Comparison::Gt => Op::Gt,
+        Comparison::Ge => Op::Ge,
+        Comparison::Lt => Op::Lt,
+        Comparison::Le => Op::Le,
+        Comparison::Contains => Op::Contains,
+        Comparison::StartsWith => Op::StartsWith,
+        Comparison::EndsWith => Op::EndsWith,
+    };
+    let value: Param = match &condition.value {
+        Literal::Integer(value) => value,
+        Literal::Text(value) => value,
+        Literal::Timestamp(value) => value,
+    };
+    Predicate::Compare(condition.field.name(), op, value)
+}
+
+/// Like `select_users`, in the order and the page `filter` asks for.
+fn list_users<'a>(columns: &'static str, filter: &'a UserFilter) -> Select<'a> {
+    let mut query = select_users(columns, filter);
+    for order in &filter.order {
+        query = if order.descending {
+            query.order_by_desc(order.field.name())
+        } else {
+            query.order_by(order.field.name())
+        };
+    }
+    query = query.order_by("id").offset(filter.skip);
+    match filter.top {
+        Some(top) => query.limit(top),
+        None => query,
+    }
+}
+
+impl UserRepository for PostgresUserRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        migrations::up(&mut *self.connect()?, false).map(|_| ())
+    }
+
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        let outbox = self.execute("DELETE FROM outbox WHERE sent_at < $1", &[&before])?;
+        Ok(BTreeMap::from([("outbox", outbox)]))
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        self.write(
+            "INSERT INTO users (name, email, email_index) VALUES ($1, $2, $3) RETURNING id",
+            &[
+                &user.name,
+                &self.seal(&user.email),
+                &self.index_of(&user.email),
+            ],
+            |rows| {
+                let id = rows[0].get(0);
+                (id, Some(Event::new(EventKind::Created, id, Some(user))))
+            },
+        )
+    }
+
+    /// One statement for the whole batch. Rows come back without their
+    /// position, so each is matched up with the first unassigned user whose
+    /// stored email it carries.
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        let names: Vec<&str> = users.iter().map(|user| user.name.as_str()).collect();
+        let emails: Vec<String> = users.iter().map(|user| self.seal(&user.email)).collect();
+        let indexes: Vec<Option<String>> = users
+            .iter()
+            .map(|user| self.index_of(&user.email))
+            .collect();
+        let sql = "INSERT INTO users (name, email, email_index)
+            SELECT name, email, email_index
+            FROM unnest($1::varchar[], $2::varchar[], $3::varchar[])
+                WITH ORDINALITY AS batch (name, email, email_index, position)
+            ORDER BY position
+            ON CONFLICT DO NOTHING
+            RETURNING id, email";
+        let params: [&(dyn ToSql + Sync); 3] = [&names, &emails, &indexes];
+
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let rows = slow_query::timed(sql, &params, || transaction.query(sql, &params))?;
+        let mut ids = vec![None; users.len()];
+        for row in &rows {
+            let email: String = row.get(1);
+            if let Some(slot) = (0..users.len()).find(|&i| ids[i].is_none() && emails[i] == email) {
+                ids[slot] = Some(row.get(0));
+            }
+        }
+        if self.outbox {
+            for (user, id) in users.iter().zip(&ids) {
+                if let Some(id) = *id {
+                    let event = Event::new(EventKind::Created, id, Some(user));
+                    self.queue_event(&mut transaction, &event)?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(ids)
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        let query = Select::new("users", COLUMNS).filter("id", Op::Eq, &id);
+        let row = self.query_opt(&query.sql(), query.params())?;
+        row.map(|row| self.user_from_row(&row)).transpose()
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        let query = Select::new("users", COLUMNS).filter("id", Op::Any, &ids);
+        let rows = self.query(&query.sql(), query.params())?;
+        rows.iter().map(|row| self.user_from_row(row)).collect()
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let row = match (&self.cipher, self.index_of(email)) {
+            (Some(_), Some(index)) => {
+                let query = Select::new("users", COLUMNS).filter("email_index", Op::Eq, &index);
+                self.query_opt(&query.sql(), query.params())?
+            }
+            (Some(cipher), None) => find_sealed(&mut *self.connect()?, cipher, email)?,
+            (None, _) => {
+                let query = Select::new("users", COLUMNS).filter("email", Op::Eq, &email);
+                self.query_opt(&query.sql(), query.params())?
+            }
+        };
+        row.map(|row| self.user_from_row(&row)).transpose()
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.check_readable(filter)?;
+        let query = list_users(COLUMNS, filter);
+        let rows = self.query(&query.sql(), query.params())?;
+        rows.iter().map(|row| self.user_from_row(row)).collect()
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        self.check_readable(filter)?;
+        let query = select_users("COUNT(*)", filter);
+        let rows = self.query(&query.sql(), query.params())?;
+        Ok(rows[0].get::<_, i64>(0) as u64)
+    }
+
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        self.check_readable(filter)?;
+        let query = list_users(COLUMNS, filter);
+        let (sql, params) = (&query.sql(), query.params());
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let portal = transaction.bind(sql, params)?;
+
+        loop {
+            let rows = slow_query::timed(sql, params, || {
+                transaction.query_portal(&portal, self.fetch_size)
+            })?;
+            if rows.is_empty() {
+                break;
+            }
+            for row in &rows {
+                if !each(self.user_from_row(row)?) {
+                    return Ok(());
+                }
+            }
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        self.write(
+            "UPDATE users SET name = $1, email = $2, email_index = $3, updated_at = now()
+             WHERE id = $4 RETURNING id",
+            &[
+                &user.name,
+                &self.seal(&user.email),
+                &self.index_of(&user.email),
+                &id,
+            ],
+            |rows| {
+                let event =
+                    (!rows.is_empty()).then(|| Event::new(EventKind::Updated, id, Some(user)));
+                (rows.len() as u64, event)
+            },
+        )
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let query = Select::new("users", COLUMNS)
+            .filter("id", Op::Eq, &id)
+            .for_update();
+        let current = match transaction.query_opt(&query.sql(), query.params())? {
+            Some(row) => self.user_from_row(&row)?,
+            None => return Ok(None),
+        };
+        let mut changed = current.clone();
+        if !change(&mut changed) {
+            return Ok(Some(current));
+        }
+        let row = transaction.query_one(
+            &format!(
+                "UPDATE users SET name = $1, email = $2, email_index = $3, updated_at = now()
+                 WHERE id = $4 RETURNING {}",
+                COLUMNS
+            ),
+            &[
+                &changed.name,
+                &self.seal(&changed.email),
+                &self.index_of(&changed.email),
+                &id,
+            ],
+        )?;
+        let stored = self.user_from_row(&row)?;
+        if self.outbox {
+            let event = Event::new(EventKind::Updated, id, Some(&stored));
+            self.queue_event(&mut transaction, &event)?;
+        }
+        transaction.commit()?;
+        Ok(Some(stored))
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        let index = self.index_of(&user.email);
+        let conflict = match (&self.cipher, &index) {
+            (Some(cipher), None) => return self.upsert_sealed(cipher, user),
+            (Some(_), Some(_)) => "email_index",
+            (None, _) => User::UNIQUE[0],
+        };
+        // `xmax` is only zero for a freshly inserted row version.
+        self.write(
+            &format!(
+                "INSERT INTO users (name, email, email_index) VALUES ($1, $2, $3)
+                 ON CONFLICT ({}) DO UPDATE SET name = EXCLUDED.name, updated_at = now()
+                 RETURNING id, xmax = 0",
+                conflict
+            ),
+            &[&user.name, &self.seal(&user.email), &index],
+            |rows| {
+                let id = rows[0].get(0);
+                if rows[0].get(1) {
+                    let event = Event::new(EventKind::Created, id, Some(user));
+                    (Upserted::Created(id), Some(event))
+                } else {
+                    let event = Event::new(EventKind::Updated, id, Some(user));
+                    (Upserted::Updated(id), Some(event))
+                }
+            },
+        )
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        self.write(
+            "DELETE FROM users WHERE id = $1 RETURNING id",
+            &[&id],
+            |rows| {
+                let event = (!rows.is_empty()).then(|| Event::new(EventKind::Deleted, id, None));
+                (rows.len() as u64, event)
+            },
+        )
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        let row = self.query_opt("SELECT metadata FROM users WHERE id = $1", &[&id])?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let row = transaction.query_opt(
+            "SELECT metadata FROM users WHERE id = $1 FOR UPDATE",
+            &[&id],
+        )?;
+        let mut metadata: Value = match row {
+            Some(row) => row.get(0),
+            None => return Ok(None),
+        };
+        let current = metadata.clone();
+        if !change(&mut metadata) {
+            return Ok(Some(current));
+        }
+        transaction.execute(
+            "UPDATE users SET metadata = $1 WHERE id = $2",
+            &[&metadata, &id],
+        )?;
+        transaction.commit()?;
+        Ok(Some(metadata))
+    }
+}
+
+impl AddressRepository for PostgresUserRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        let query = Select::new(Address::TABLE, Address::COLUMNS)
+            .filter("user_id", Op::Eq, &user_id)
+            .order_by("id");
+        let rows = self.query(&query.sql(), query.params())?;
+        Ok(rows.iter().map(Address::from_row).collect())
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        let rows = self.query(
+            "SELECT COUNT(*) FROM addresses WHERE user_id = $1",
+            &[&user_id],
+        )?;
+        Ok(rows[0].get::<_, i64>(0) as u64)
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        let address = Address {
+            user_id: Some(user_id),
+            ..address.clone()
+        };
+        let rows = self.query(Address::INSERT, &address.insert_params())?;
+        Ok(Address::from_row(&rows[0]))
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        let query = Select::new(Address::TABLE, Address::COLUMNS)
+            .filter("id", Op::Eq, &id)
+            .filter("user_id", Op::Eq, &user_id);
+        let row = self.query_opt(&query.sql(), query.params())?;
+        Ok(row.as_ref().map(Address::from_row))
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        let mut params = address.update_params();
+        params.extend([&id as &(dyn ToSql + Sync), &user_id]);
+        self.execute(Address::UPDATE, &params)
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        match id {
+            Some(id) => self.execute(Address::DELETE, &[&id, &user_id]),
+            None => self.execute("DELETE FROM addresses WHERE user_id = $1", &[&user_id]),
+        }
+    }
+}
+
+impl FlagRepository for PostgresUserRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        let rows = self.query("SELECT name, enabled FROM feature_flags", &[])?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        self.execute(
+            "INSERT INTO feature_flags (name, enabled) VALUES ($1, $2)
+             ON CONFLICT (name) DO UPDATE SET enabled = EXCLUDED.enabled, updated_at = now()",
+            &[&name, &enabled],
+        )?;
+        Ok(())
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        self.execute("DELETE FROM feature_flags WHERE name = $1", &[&name])
+    }
+}
+
+impl TotpRepository for PostgresUserRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        let mut client = self.connect()?;
+        let mut transaction = client.transaction()?;
+        let row = transaction.query_opt(
+            "SELECT secret, enabled, recovery_codes, last_step FROM totp
+             WHERE account = $1 FOR UPDATE",
+            &[&account],
+        )?;
+        let mut totp = match row {
+            Some(row) => {
+                let secret: String = row.get(0);
+                Some(Totp {
+                    secret: match &self.cipher {
+                        Some(cipher) => cipher.decrypt(&secret)?,
+                        None => secret,
+                    },
+                    enabled: row.get(1),
+                    recovery_codes: row.get(2),
+                    last_step: row.get::<_, i64>(3) as u64,
+                })
+            }
+            None => None,
+        };
+        let current = totp.clone();
+        if !change(&mut totp) {
+            return Ok(current);
+        }
+        match &totp {
+            Some(totp) => transaction.execute(
+                "INSERT INTO totp (account, secret, enabled, recovery_codes, last_step)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (account) DO UPDATE SET secret = EXCLUDED.secret,
+                     enabled = EXCLUDED.enabled, recovery_codes = EXCLUDED.recovery_codes,
+                     last_step = EXCLUDED.last_step",
+                &[
+                    &account,
+                    &self.seal(&totp.secret),
+                    &totp.enabled,
+                    &totp.recovery_codes,
+                    &(totp.last_step as i64),
+                ],
+            )?,
+            None => transaction.execute("DELETE FROM totp WHERE account = $1", &[&account])?,
+        };
+        transaction.commit()?;
+        Ok(totp)
+    }
+}
@@ -0,0 +1,253 @@
+use super::{
+    AddressRepository, FlagRepository, Repository, RepositoryError, TotpRepository, Upserted,
+    UserFilter, UserRepository,
+};
+use crate::events::{Event, EventBus, EventKind};
+use crate::models::{Address, User};
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/*
+*  Change events
+*
+*  Wraps a storage backend and announces every user create, update and delete
+*  it committed on the event bus. Calls that did not change a row, and every
+*  failed call, publish nothing. Metadata and addresses are not part of the
+*  user event.
+*/
+
+pub struct PublishingRepository {
+    inner: Arc<dyn Repository>,
+    events: Arc<EventBus>,
+}
+
+impl PublishingRepository {
+    pub fn new(inner: Arc<dyn Repository>, events: Arc<EventBus>) -> Self {
+        PublishingRepository { inner, events }
+    }
+
+    fn publish(&self, kind: EventKind, id: i32, user: Option<&User>) {
+        self.events.publish(Event::new(kind, id, user));
+    }
+}
+
+impl UserRepository for PublishingRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        self.inner.migrate()
+    }
+
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        self.inner.purge(before)
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        let id = self.inner.create(user)?;
+        self.publish(EventKind::Created, id, Some(user));
+        Ok(id)
+    }
+
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        let ids = self.inner.create_many(users)?;
+        for (user, id) in users.iter().zip(&ids) {
+            if let Some(id) = *id {
+                self.publish(EventKind::Created, id, Some(user));
+            }
+        }
+        Ok(ids)
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        self.inner.find(id)
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        self.inner.find_many(ids)
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        self.inner.find_by_email(email)
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.inner.list(filter)
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        self.inner.count(filter)
+    }
+
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        self.inner.stream_all(filter, each)
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        let updated = self.inner.update(id, user)?;
+        if updated > 0 {
+            self.publish(EventKind::Updated, id, Some(user));
+        }
+        Ok(updated)
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        let mut changed = false;
+        let user = self.inner.change_user(id, &mut |user| {
+            changed = change(user);
+            changed
+        })?;
+        if let (true, Some(user)) = (changed, &user) {
+            self.publish(EventKind::Updated, id, Some(user));
+        }
+        Ok(user)
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        let upserted = self.inner.upsert(user)?;
+        match upserted {
+            Upserted::Created(id) => self.publish(EventKind::Created, id, Some(user)),
+            Upserted::Updated(id) => self.publish(EventKind::Updated, id, Some(user)),
+        }
+        Ok(upserted)
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        let deleted = self.inner.delete(id)?;
+        if deleted > 0 {
+            self.publish(EventKind::Deleted, id, None);
+        }
+        Ok(deleted)
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        self.inner.metadata(id)
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        self.inner.change_metadata(id, change)
+    }
+}
+
+impl AddressRepository for PublishingRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        self.inner.list_addresses(user_id)
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        self.inner.count_addresses(user_id)
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        self.inner.create_address(user_id, address)
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        self.inner.find_address(user_id, id)
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        self.inner.update_address(user_id, id, address)
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        self.inner.delete_addresses(user_id, id)
+    }
+}
+
+impl FlagRepository for PublishingRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        self.inner.list_flags()
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        self.inner.set_flag(name, enabled)
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        self.inner.delete_flag(name)
+    }
+}
+
+impl TotpRepository for PublishingRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        self.inner.change_totp(account, change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Publisher;
+    use crate::repository::MemoryUserRepository;
+    use std::sync::mpsc::{self, Sender};
+    use std::time::Duration;
+
+    struct Collect(Sender<(EventKind, i32)>);
+
+    impl Publisher for Collect {
+        fn name(&self) -> &'static str {
+            "collect"
+        }
+
+        fn publish(&mut self, event: &Event) -> Result<(), String> {
+            self.0.send((event.kind, event.user_id)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn publishes_committed_changes_only() {
+        let (sent, received) = mpsc::channel();
+        let events = Arc::new(EventBus::start(vec![Box::new(Collect(sent))]));
+        let repository = PublishingRepository::new(Arc::new(MemoryUserRepository::new()), events);
+        let user = User {
+            id: None,
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: None,
+            updated_at: None,
+        };
+
+        let id = repository.create(&user).unwrap();
+        assert!(repository.create(&user).is_err());
+        repository.update(id, &user).unwrap();
+        repository.update(id + 1, &user).unwrap();
+        repository.delete(id).unwrap();
+        repository.delete(id).unwrap();
+
+        let published: Vec<_> = (0..3)
+            .map(|_| received.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                (EventKind::Created, id),
+                (EventKind::Updated, id),
+                (EventKind::Deleted, id)
+            ]
+        );
+        assert!(received.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
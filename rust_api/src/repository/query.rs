@@ -0,0 +1,291 @@
+use postgres::types::ToSql;
+
+/*
+*  Query builder
+*
+*  Assembles `SELECT` statements whose conditions, ordering and paging depend
+*  on the request. Table and column names are `&'static str`, so only names
+*  written in the code end up in the SQL; values always travel as positional
+*  parameters, numbered in the order they are added.
+*/
+
+pub type Param<'a> = &'a (dyn ToSql + Sync);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The column holds the parameter as a substring.
+    Contains,
+    StartsWith,
+    EndsWith,
+    /// The column equals one of the elements of an array parameter.
+    Any,
+}
+
+/// A condition on the selected rows.
+pub enum Predicate<'a> {
+    /// `column <op> $n`.
+    Compare(&'static str, Op, Param<'a>),
+    /// Holds when every operand does.
+    All(Vec<Predicate<'a>>),
+    /// Holds when any operand does.
+    Any(Vec<Predicate<'a>>),
+}
+
+impl<'a> Predicate<'a> {
+    /// Appends the parameters in the order `sql` numbers them.
+    fn collect_params(&self, params: &mut Vec<Param<'a>>) {
+        match self {
+            Predicate::Compare(_, _, value) => params.push(*value),
+            Predicate::All(operands) | Predicate::Any(operands) => {
+                for operand in operands {
+                    operand.collect_params(params);
+                }
+            }
+        }
+    }
+
+    /// `placeholder` is the number of the last parameter written so far.
+    fn sql(&self, placeholder: &mut usize) -> String {
+        let (operands, joint, empty) = match self {
+            Predicate::Compare(column, op, _) => {
+                *placeholder += 1;
+                return compare(column, *op, *placeholder);
+            }
+            Predicate::All(operands) => (operands, " AND ", "TRUE"),
+            Predicate::Any(operands) => (operands, " OR ", "FALSE"),
+        };
+        if operands.is_empty() {
+            return empty.to_string();
+        }
+        let operands: Vec<String> = operands
+            .iter()
+            .map(|operand| operand.sql(placeholder))
+            .collect();
+        format!("({})", operands.join(joint))
+    }
+}
+
+fn compare(column: &str, op: Op, placeholder: usize) -> String {
+    match op {
+        Op::Eq => format!("{} = ${}", column, placeholder),
+        Op::Ne => format!("{} <> ${}", column, placeholder),
+        Op::Lt => format!("{} < ${}", column, placeholder),
+        Op::Le => format!("{} <= ${}", column, placeholder),
+        Op::Gt => format!("{} > ${}", column, placeholder),
+        Op::Ge => format!("{} >= ${}", column, placeholder),
+        // Rather than LIKE, which would need `%` and `_` escaped.
+        Op::Contains => format!("strpos({}, ${}) > 0", column, placeholder),
+        Op::StartsWith => format!("starts_with({}, ${})", column, placeholder),
+        Op::EndsWith => format!(
+            "right({}, char_length(${p})) = ${p}",
+            column,
+            p = placeholder
+        ),
+        Op::Any => format!("{} = ANY(${})", column, placeholder),
+    }
+}
+
+pub struct Select<'a> {
+    table: &'static str,
+    columns: &'static str,
+    conditions: Vec<Predicate<'a>>,
+    params: Vec<Param<'a>>,
+    /// With whether it is descending.
+    order: Vec<(&'static str, bool)>,
+    limit: Option<u64>,
+    offset: u64,
+    for_update: bool,
+}
+
+impl<'a> Select<'a> {
+    /// `columns` is the select list as written, e.g. `"id, name"` or
+    /// `"COUNT(*)"`.
+    pub fn new(table: &'static str, columns: &'static str) -> Self {
+        Select {
+            table,
+            columns,
+            conditions: Vec::new(),
+            params: Vec::new(),
+            order: Vec::new(),
+            limit: None,
+            offset: 0,
+            for_update: false,
+        }
+    }
+
+    /// Adds `column <op> $n`; conditions are joined with `AND`.
+    pub fn filter(self, column: &'static str, op: Op, value: Param<'a>) -> Self {
+        self.filter_by(Predicate::Compare(column, op, value))
+    }
+
+    /// Adds a condition that may combine several with `AND` and `OR`.
+    pub fn filter_by(mut self, predicate: Predicate<'a>) -> Self {
+        predicate.collect_params(&mut self.params);
+        self.conditions.push(predicate);
+        self
+    }
+
+    /// Like `filter`, doing nothing when `value` is `None`.
+    pub fn filter_opt<T: ToSql + Sync>(
+        self,
+        column: &'static str,
+        op: Op,
+        value: &'a Option<T>,
+    ) -> Self {
+        match value {
+            Some(value) => self.filter(column, op, value),
+            None => self,
+        }
+    }
+
+    pub fn order_by(mut self, column: &'static str) -> Self {
+        self.order.push((column, false));
+        self
+    }
+
+    pub fn order_by_desc(mut self, column: &'static str) -> Self {
+        self.order.push((column, true));
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Locks the rows read until the transaction ends.
+    pub fn for_update(mut self) -> Self {
+        self.for_update = true;
+        self
+    }
+
+    pub fn sql(&self) -> String {
+        let mut sql = format!("SELECT {} FROM {}", self.columns, self.table);
+        let mut placeholder = 0;
+        for (n, condition) in self.conditions.iter().enumerate() {
+            let keyword = if n == 0 { "WHERE" } else { "AND" };
+            sql.push_str(&format!(" {} {}", keyword, condition.sql(&mut placeholder)));
+        }
+        if !self.order.is_empty() {
+            let order: Vec<String> = self
+                .order
+                .iter()
+                .map(|&(column, descending)| {
+                    if descending {
+                        format!("{} DESC", column)
+                    } else {
+                        column.to_string()
+                    }
+                })
+                .collect();
+            sql.push_str(&format!(" ORDER BY {}", order.join(", ")));
+        }
+        // Numbers, so they are safe to inline.
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        if self.offset > 0 {
+            sql.push_str(&format!(" OFFSET {}", self.offset));
+        }
+        if self.for_update {
+            sql.push_str(" FOR UPDATE");
+        }
+        sql
+    }
+
+    /// The values for the placeholders in `sql`, in order.
+    pub fn params(&self) -> &[Param<'a>] {
+        &self.params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_placeholders_in_order() {
+        let after = Some(1);
+        let before: Option<i32> = None;
+        let name = "Ada";
+        let query = Select::new("users", "id, name")
+            .filter_opt("id", Op::Gt, &after)
+            .filter_opt("id", Op::Lt, &before)
+            .filter("name", Op::Eq, &name)
+            .order_by("created_at")
+            .order_by("id")
+            .limit(20)
+            .for_update();
+
+        assert_eq!(
+            query.sql(),
+            "SELECT id, name FROM users WHERE id > $1 AND name = $2 \
+             ORDER BY created_at, id LIMIT 20 FOR UPDATE"
+        );
+        assert_eq!(query.params().len(), 2);
+    }
+
+    #[test]
+    fn builds_a_bare_select() {
+        let ids = vec![1, 2];
+        assert_eq!(
+            Select::new("users", "COUNT(*)").sql(),
+            "SELECT COUNT(*) FROM users"
+        );
+        assert_eq!(
+            Select::new("users", "id").filter("id", Op::Any, &ids).sql(),
+            "SELECT id FROM users WHERE id = ANY($1)"
+        );
+    }
+
+    #[test]
+    fn nests_conditions_in_parentheses() {
+        let (ada, bob, id) = ("Ada", "Bob", 3);
+        let query = Select::new("users", "id")
+            .filter("id", Op::Gt, &id)
+            .filter_by(Predicate::Any(vec![
+                Predicate::Compare("name", Op::Eq, &ada),
+                Predicate::All(vec![
+                    Predicate::Compare("name", Op::StartsWith, &bob),
+                    Predicate::Compare("id", Op::Lt, &id),
+                ]),
+            ]))
+            .filter_by(Predicate::Any(Vec::new()));
+        assert_eq!(
+            query.sql(),
+            "SELECT id FROM users WHERE id > $1 \
+             AND (name = $2 OR (starts_with(name, $3) AND id < $4)) AND FALSE"
+        );
+        assert_eq!(query.params().len(), 4);
+    }
+
+    #[test]
+    fn matches_text_without_patterns() {
+        let part = "a_b";
+        let query = Select::new("users", "id")
+            .filter("name", Op::Contains, &part)
+            .filter("email", Op::EndsWith, &part)
+            .filter("id", Op::Ge, &part)
+            .order_by_desc("name")
+            .order_by("id")
+            .limit(10)
+            .offset(20);
+        assert_eq!(
+            query.sql(),
+            "SELECT id FROM users WHERE strpos(name, $1) > 0 \
+             AND right(email, char_length($2)) = $2 AND id >= $3 \
+             ORDER BY name DESC, id LIMIT 10 OFFSET 20"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use crate::context;
+use crate::statsd;
+use log::warn;
+use postgres::types::ToSql;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/*
+*  Slow query log
+*
+*  Every query's duration also goes to StatsD, when configured.
+*/
+
+/// Threshold in milliseconds; 0 disables the log.
+static THRESHOLD_MS: AtomicU64 = AtomicU64::new(0);
+static REDACT_PARAMS: AtomicBool = AtomicBool::new(true);
+
+pub fn configure(threshold: Duration, redact_params: bool) {
+    THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+    REDACT_PARAMS.store(redact_params, Ordering::Relaxed);
+}
+
+/// Runs `query` and logs it when it takes longer than the configured threshold.
+pub fn timed<T, E>(
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+    query: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    let started = Instant::now();
+    let result = query();
+    record(started.elapsed(), result.is_ok(), || {
+        let params = params.iter().map(|p| format!("{:?}", p)).collect();
+        (sql.to_string(), params)
+    });
+    result
+}
+
+/// Counts a query that took `elapsed`, for drivers that time their own, and
+/// logs it when slow. `describe` gives its SQL and parameters, and is only
+/// called then.
+pub fn record(elapsed: Duration, ok: bool, describe: impl FnOnce() -> (String, Vec<String>)) {
+    let outcome = if ok { "ok" } else { "error" };
+    statsd::timing("db.query_duration", elapsed, &[("outcome", outcome)]);
+
+    let threshold = THRESHOLD_MS.load(Ordering::Relaxed);
+    if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+        let (sql, mut params) = describe();
+        if REDACT_PARAMS.load(Ordering::Relaxed) {
+            params.fill("<redacted>".to_string());
+        }
+        warn!(
+            "Slow query ({} ms, request {}): {} params=[{}]",
+            elapsed.as_millis(),
+            context::request_id().as_deref().unwrap_or("-"),
+            sql.split_whitespace().collect::<Vec<_>>().join(" "),
+            params.join(", ")
+        );
+    }
+}
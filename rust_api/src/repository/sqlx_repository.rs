@@ -0,0 +1,1000 @@
+use super::migrations::{self, HISTORY, MIGRATION_LOCK};
+use super::postgres_repository::outbox_payload;
+use super::{
+    AddressRepository, Comparison, Expression, FlagRepository, Literal, RepositoryError,
+    TotpRepository, Upserted, UserFilter, UserRepository,
+};
+use crate::crypto::{BlindIndex, FieldCipher};
+use crate::events::{Event, EventKind};
+use crate::models::{Address, User};
+use crate::totp::Totp;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use log::LevelFilter;
+use serde_json::Value;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use sqlx::{ConnectOptions, PgConnection, Postgres, QueryBuilder};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/*
+*  sqlx backend
+*
+*  The same tables as `PostgresUserRepository`, reached through sqlx, for
+*  `STORAGE=sqlx` in builds with the `sqlx` feature. Statements of a fixed
+*  shape go through `query!` and friends, which check them against the schema
+*  when compiling: with `DATABASE_URL` pointing at a migrated database, or
+*  else from the query data in `.sqlx`, which `cargo sqlx prepare` refreshes
+*  after a statement changes. Listings are assembled at run time with
+*  `QueryBuilder`, like `Select` does for the other backend.
+*
+*  sqlx is async and the repository is not, so the backend keeps a small
+*  Tokio runtime of its own and blocks on it for each call. Migrations apply
+*  the same scripts, recorded in the same `schema_migrations`, so a database
+*  can move between the two backends. Emails are encrypted and indexed alike,
+*  and with the outbox on user changes queue their event in the same
+*  transaction, for `PostgresUserRepository` to relay.
+*/
+
+/// Threads driving the connections; the callers wait on them.
+const RUNTIME_THREADS: usize = 2;
+
+/// A row of `users`, as the statements below select it.
+struct UserRow {
+    id: i32,
+    name: String,
+    email: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(e: sqlx::Error) -> Self {
+        let code = e.as_database_error().and_then(|e| e.code());
+        match code.as_deref() {
+            Some("57014") => RepositoryError::Timeout,
+            Some("23505") => RepositoryError::Conflict,
+            _ => RepositoryError::Sqlx(e),
+        }
+    }
+}
+
+pub struct SqlxRepository {
+    runtime: Runtime,
+    pool: PgPool,
+    /// Encrypts the email column, and two-factor secrets, when set.
+    cipher: Option<FieldCipher>,
+    /// Indexes encrypted emails for lookups and uniqueness.
+    email_index: Option<BlindIndex>,
+    /// Queue an event in the `outbox` table with every user change.
+    outbox: bool,
+}
+
+impl SqlxRepository {
+    /// Connects on first use, at most `max_connections` at a time, each
+    /// cancelling statements after `statement_timeout` and logging those
+    /// slower than `slow_query` at `warn`, unless they are zero.
+    pub fn new(
+        database_url: &str,
+        max_connections: usize,
+        statement_timeout: Duration,
+        slow_query: Duration,
+        cipher: Option<FieldCipher>,
+    ) -> Result<Self, RepositoryError> {
+        // Statements are logged by sqlx, without their parameters.
+        let mut options = PgConnectOptions::from_str(database_url)?
+            .log_statements(LevelFilter::Off)
+            .log_slow_statements(
+                match slow_query.is_zero() {
+                    true => LevelFilter::Off,
+                    false => LevelFilter::Warn,
+                },
+                slow_query,
+            );
+        if !statement_timeout.is_zero() {
+            options = options.options([(
+                "statement_timeout",
+                statement_timeout.as_millis().to_string(),
+            )]);
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(RUNTIME_THREADS)
+            .thread_name("sqlx")
+            .enable_all()
+            .build()
+            .map_err(sqlx::Error::Io)?;
+        // The pool's upkeep runs on the runtime.
+        let pool = {
+            let _entered = runtime.enter();
+            PgPoolOptions::new()
+                .max_connections(u32::try_from(max_connections).unwrap_or(u32::MAX))
+                .connect_lazy_with(options)
+        };
+        Ok(SqlxRepository {
+            runtime,
+            pool,
+            cipher,
+            email_index: None,
+            outbox: false,
+        })
+    }
+
+    /// Keeps a blind index of encrypted emails in `email_index`. Without
+    /// encryption the plaintext column serves and the index is not used.
+    pub fn with_email_index(mut self, index: BlindIndex) -> Self {
+        self.email_index = Some(index);
+        self
+    }
+
+    /// Writes change events to the outbox, in the transaction of the change.
+    pub fn with_outbox(mut self) -> Self {
+        self.outbox = true;
+        self
+    }
+
+    fn run<T>(
+        &self,
+        work: impl Future<Output = Result<T, RepositoryError>>,
+    ) -> Result<T, RepositoryError> {
+        self.runtime.block_on(work)
+    }
+
+    /// The blind index stored with `email`, when emails are encrypted and
+    /// indexed.
+    fn index_of(&self, email: &str) -> Option<String> {
+        self.cipher.as_ref()?;
+        self.email_index.as_ref().map(|index| index.of(email))
+    }
+
+    /// Encrypted emails cannot be compared or sorted by in SQL.
+    fn check_readable(&self, filter: &UserFilter) -> Result<(), RepositoryError> {
+        if self.cipher.is_some() && filter.reads_email() {
+            return Err(RepositoryError::Unsupported(
+                "Emails are encrypted and cannot be filtered or sorted by",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encrypts `value` for storage when a cipher is configured.
+    fn seal(&self, value: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(value),
+            None => value.to_string(),
+        }
+    }
+
+    fn unseal(&self, value: String) -> Result<String, RepositoryError> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.decrypt(&value)?),
+            None => Ok(value),
+        }
+    }
+
+    fn user(&self, row: UserRow) -> Result<User, RepositoryError> {
+        Ok(User {
+            id: Some(row.id),
+            name: row.name,
+            email: self.unseal(row.email)?,
+            created_at: Some(row.created_at),
+            updated_at: Some(row.updated_at),
+        })
+    }
+
+    /// Queues `event` with the change `connection` is making, when the outbox
+    /// is on.
+    async fn queue_event(
+        &self,
+        connection: &mut PgConnection,
+        event: Event,
+    ) -> Result<(), RepositoryError> {
+        if !self.outbox {
+            return Ok(());
+        }
+        sqlx::query!(
+            "INSERT INTO outbox (user_id, payload) VALUES ($1, $2)",
+            event.user_id,
+            outbox_payload(&event, self.cipher.as_ref())
+        )
+        .execute(connection)
+        .await?;
+        Ok(())
+    }
+
+    /// Looks a user up by email when emails are encrypted without a blind
+    /// index, which means decrypting them one by one.
+    async fn find_sealed(
+        &self,
+        connection: &mut PgConnection,
+        cipher: &FieldCipher,
+        email: &str,
+    ) -> Result<Option<UserRow>, RepositoryError> {
+        let rows = sqlx::query_as!(
+            UserRow,
+            "SELECT id, name, email, created_at, updated_at FROM users"
+        )
+        .fetch_all(connection)
+        .await?;
+        for row in rows {
+            if cipher.decrypt(&row.email)? == email {
+                return Ok(Some(row));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Without a blind index, encrypted emails never collide in the unique
+    /// index, so the match has to be found by decrypting every row. The table
+    /// lock keeps a concurrent upsert of the same email from inserting it
+    /// twice.
+    async fn upsert_sealed(
+        &self,
+        cipher: &FieldCipher,
+        user: &User,
+    ) -> Result<Upserted, RepositoryError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::raw_sql("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *transaction)
+            .await?;
+        let existing = self
+            .find_sealed(&mut transaction, cipher, &user.email)
+            .await?;
+        let upserted = match existing {
+            Some(row) => {
+                sqlx::query!(
+                    "UPDATE users SET name = $1, updated_at = now() WHERE id = $2",
+                    user.name,
+                    row.id
+                )
+                .execute(&mut *transaction)
+                .await?;
+                Upserted::Updated(row.id)
+            }
+            None => {
+                let id = sqlx::query_scalar!(
+                    "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id",
+                    user.name,
+                    cipher.encrypt(&user.email)
+                )
+                .fetch_one(&mut *transaction)
+                .await?;
+                Upserted::Created(id)
+            }
+        };
+        let event = match upserted {
+            Upserted::Created(id) => Event::new(EventKind::Created, id, Some(user)),
+            Upserted::Updated(id) => Event::new(EventKind::Updated, id, Some(user)),
+        };
+        self.queue_event(&mut transaction, event).await?;
+        transaction.commit().await?;
+        Ok(upserted)
+    }
+}
+
+/// `SELECT <columns> FROM users` and the conditions of `filter`.
+fn select_users<'a>(columns: &str, filter: &UserFilter) -> QueryBuilder<'a, Postgres> {
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM users WHERE TRUE", columns));
+    if let Some(after) = filter.created_after {
+        query.push(" AND created_at > ").push_bind(after);
+    }
+    if let Some(before) = filter.created_before {
+        query.push(" AND created_at < ").push_bind(before);
+    }
+    for expression in &filter.conditions {
+        query.push(" AND ");
+        push_expression(&mut query, expression);
+    }
+    query
+}
+
+/// Like `select_users`, in the order and the page `filter` asks for.
+fn list_users<'a>(filter: &UserFilter) -> QueryBuilder<'a, Postgres> {
+    let mut query = select_users("id, name, email, created_at, updated_at", filter);
+    query.push(" ORDER BY ");
+    for order in &filter.order {
+        query.push(order.field.name());
+        query.push(if order.descending { " DESC, " } else { ", " });
+    }
+    // Numbers, so they are safe to inline.
+    query.push(format!("id OFFSET {}", filter.skip));
+    if let Some(top) = filter.top {
+        query.push(format!(" LIMIT {}", top));
+    }
+    query
+}
+
+fn push_expression(query: &mut QueryBuilder<'_, Postgres>, expression: &Expression) {
+    let (operands, joint, empty) = match expression {
+        Expression::Condition(condition) => {
+            let column = condition.field.name();
+            let operator = match condition.comparison {
+                Comparison::Eq => " = ",
+                Comparison::Ne => " <> ",
+                Comparison::Gt => " > ",
+                Comparison::Ge => " >= ",
+                Comparison::Lt => " < ",
+                Comparison::Le => " <= ",
+                // Rather than LIKE, which would need `%` and `_` escaped.
+                Comparison::Contains => {
+                    query.push(format!("strpos({}, ", column));
+                    push_literal(query, &condition.value);
+                    query.push(") > 0");
+                    return;
+                }
+                Comparison::StartsWith => {
+                    query.push(format!("starts_with({}, ", column));
+                    push_literal(query, &condition.value);
+                    query.push(")");
+                    return;
+                }
+                Comparison::EndsWith => {
+                    query.push(format!("right({}, char_length(", column));
+                    push_literal(query, &condition.value);
+                    query.push(")) = ");
+                    push_literal(query, &condition.value);
+                    return;
+                }
+            };
+            query.push(column).push(operator);
+            push_literal(query, &condition.value);
+            return;
+        }
+        Expression::All(operands) => (operands, " AND ", "TRUE"),
+        Expression::Any(operands) => (operands, " OR ", "FALSE"),
+    };
+    if operands.is_empty() {
+        query.push(empty);
+        return;
+    }
+    query.push("(");
+    for (n, operand) in operands.iter().enumerate() {
+        if n > 0 {
+            query.push(joint);
+        }
+        push_expression(query, operand);
+    }
+    query.push(")");
+}
+
+fn push_literal(query: &mut QueryBuilder<'_, Postgres>, value: &Literal) {
+    match value {
+        Literal::Integer(value) => query.push_bind(*value),
+        Literal::Text(value) => query.push_bind(value.clone()),
+        Literal::Timestamp(value) => query.push_bind(*value),
+    };
+}
+
+impl UserRepository for SqlxRepository {
+    fn migrate(&self) -> Result<(), RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            sqlx::query!("SELECT pg_advisory_xact_lock($1)", MIGRATION_LOCK)
+                .execute(&mut *transaction)
+                .await?;
+            sqlx::raw_sql(HISTORY).execute(&mut *transaction).await?;
+            let version =
+                sqlx::query_scalar!(r#"SELECT MAX(version) AS "version" FROM schema_migrations"#)
+                    .fetch_one(&mut *transaction)
+                    .await?;
+            for step in migrations::pending(version.unwrap_or(0)) {
+                sqlx::raw_sql(step.sql).execute(&mut *transaction).await?;
+                sqlx::query!(
+                    "INSERT INTO schema_migrations (version) VALUES ($1)",
+                    step.version
+                )
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn purge(&self, before: DateTime<Utc>) -> Result<BTreeMap<&'static str, u64>, RepositoryError> {
+        self.run(async {
+            let outbox = sqlx::query!("DELETE FROM outbox WHERE sent_at < $1", before)
+                .execute(&self.pool)
+                .await?;
+            Ok(BTreeMap::from([("outbox", outbox.rows_affected())]))
+        })
+    }
+
+    fn create(&self, user: &User) -> Result<i32, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let id = sqlx::query_scalar!(
+                "INSERT INTO users (name, email, email_index) VALUES ($1, $2, $3) RETURNING id",
+                user.name,
+                self.seal(&user.email),
+                self.index_of(&user.email)
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            self.queue_event(
+                &mut transaction,
+                Event::new(EventKind::Created, id, Some(user)),
+            )
+            .await?;
+            transaction.commit().await?;
+            Ok(id)
+        })
+    }
+
+    /// One statement for the whole batch. Rows come back without their
+    /// position, so each is matched up with the first unassigned user whose
+    /// stored email it carries.
+    fn create_many(&self, users: &[User]) -> Result<Vec<Option<i32>>, RepositoryError> {
+        let names: Vec<String> = users.iter().map(|user| user.name.clone()).collect();
+        let emails: Vec<String> = users.iter().map(|user| self.seal(&user.email)).collect();
+        let indexes: Vec<Option<String>> = users
+            .iter()
+            .map(|user| self.index_of(&user.email))
+            .collect();
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let rows = sqlx::query!(
+                "INSERT INTO users (name, email, email_index)
+                 SELECT name, email, email_index
+                 FROM unnest($1::varchar[], $2::varchar[], $3::varchar[])
+                     WITH ORDINALITY AS batch (name, email, email_index, position)
+                 ORDER BY position
+                 ON CONFLICT DO NOTHING
+                 RETURNING id, email",
+                &names,
+                &emails,
+                &indexes as &[Option<String>]
+            )
+            .fetch_all(&mut *transaction)
+            .await?;
+            let mut ids = vec![None; users.len()];
+            for row in rows {
+                if let Some(slot) =
+                    (0..users.len()).find(|&i| ids[i].is_none() && emails[i] == row.email)
+                {
+                    ids[slot] = Some(row.id);
+                    let event = Event::new(EventKind::Created, row.id, Some(&users[slot]));
+                    self.queue_event(&mut transaction, event).await?;
+                }
+            }
+            transaction.commit().await?;
+            Ok(ids)
+        })
+    }
+
+    fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        let row = self.run(async {
+            Ok(sqlx::query_as!(
+                UserRow,
+                "SELECT id, name, email, created_at, updated_at FROM users WHERE id = $1",
+                id
+            )
+            .fetch_optional(&self.pool)
+            .await?)
+        })?;
+        row.map(|row| self.user(row)).transpose()
+    }
+
+    fn find_many(&self, ids: &[i32]) -> Result<Vec<User>, RepositoryError> {
+        let rows = self.run(async {
+            Ok(sqlx::query_as!(
+                UserRow,
+                "SELECT id, name, email, created_at, updated_at FROM users WHERE id = ANY($1)",
+                ids
+            )
+            .fetch_all(&self.pool)
+            .await?)
+        })?;
+        rows.into_iter().map(|row| self.user(row)).collect()
+    }
+
+    fn find_by_email(&self, email: &str) -> Result<Option<User>, RepositoryError> {
+        let row = self.run(async {
+            match (&self.cipher, self.index_of(email)) {
+                (Some(_), Some(index)) => Ok(sqlx::query_as!(
+                    UserRow,
+                    "SELECT id, name, email, created_at, updated_at FROM users
+                     WHERE email_index = $1",
+                    index
+                )
+                .fetch_optional(&self.pool)
+                .await?),
+                (Some(cipher), None) => {
+                    let mut connection = self.pool.acquire().await?;
+                    self.find_sealed(&mut connection, cipher, email).await
+                }
+                (None, _) => Ok(sqlx::query_as!(
+                    UserRow,
+                    "SELECT id, name, email, created_at, updated_at FROM users WHERE email = $1",
+                    email
+                )
+                .fetch_optional(&self.pool)
+                .await?),
+            }
+        })?;
+        row.map(|row| self.user(row)).transpose()
+    }
+
+    fn list(&self, filter: &UserFilter) -> Result<Vec<User>, RepositoryError> {
+        self.check_readable(filter)?;
+        let rows = self.run(async {
+            Ok(list_users(filter)
+                .build_query_as::<(i32, String, String, DateTime<Utc>, DateTime<Utc>)>()
+                .fetch_all(&self.pool)
+                .await?)
+        })?;
+        rows.into_iter()
+            .map(|(id, name, email, created_at, updated_at)| {
+                self.user(UserRow {
+                    id,
+                    name,
+                    email,
+                    created_at,
+                    updated_at,
+                })
+            })
+            .collect()
+    }
+
+    fn count(&self, filter: &UserFilter) -> Result<u64, RepositoryError> {
+        self.check_readable(filter)?;
+        self.run(async {
+            let count: i64 = select_users("COUNT(*)", filter)
+                .build_query_scalar()
+                .fetch_one(&self.pool)
+                .await?;
+            Ok(count as u64)
+        })
+    }
+
+    fn stream_all(
+        &self,
+        filter: &UserFilter,
+        each: &mut dyn FnMut(User) -> bool,
+    ) -> Result<(), RepositoryError> {
+        self.check_readable(filter)?;
+        self.run(async {
+            let mut query = list_users(filter);
+            let mut rows = query
+                .build_query_as::<(i32, String, String, DateTime<Utc>, DateTime<Utc>)>()
+                .fetch(&self.pool);
+            while let Some((id, name, email, created_at, updated_at)) = rows.try_next().await? {
+                let user = self.user(UserRow {
+                    id,
+                    name,
+                    email,
+                    created_at,
+                    updated_at,
+                })?;
+                if !each(user) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let done = sqlx::query!(
+                "UPDATE users SET name = $1, email = $2, email_index = $3, updated_at = now()
+                 WHERE id = $4",
+                user.name,
+                self.seal(&user.email),
+                self.index_of(&user.email),
+                id
+            )
+            .execute(&mut *transaction)
+            .await?;
+            if done.rows_affected() > 0 {
+                self.queue_event(
+                    &mut transaction,
+                    Event::new(EventKind::Updated, id, Some(user)),
+                )
+                .await?;
+            }
+            transaction.commit().await?;
+            Ok(done.rows_affected())
+        })
+    }
+
+    fn change_user(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut User) -> bool,
+    ) -> Result<Option<User>, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let row = sqlx::query_as!(
+                UserRow,
+                "SELECT id, name, email, created_at, updated_at FROM users
+                 WHERE id = $1 FOR UPDATE",
+                id
+            )
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let current = match row {
+                Some(row) => self.user(row)?,
+                None => return Ok(None),
+            };
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(Some(current));
+            }
+            let row = sqlx::query_as!(
+                UserRow,
+                "UPDATE users SET name = $1, email = $2, email_index = $3, updated_at = now()
+                 WHERE id = $4 RETURNING id, name, email, created_at, updated_at",
+                changed.name,
+                self.seal(&changed.email),
+                self.index_of(&changed.email),
+                id
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            let stored = self.user(row)?;
+            self.queue_event(
+                &mut transaction,
+                Event::new(EventKind::Updated, id, Some(&stored)),
+            )
+            .await?;
+            transaction.commit().await?;
+            Ok(Some(stored))
+        })
+    }
+
+    fn upsert(&self, user: &User) -> Result<Upserted, RepositoryError> {
+        let index = self.index_of(&user.email);
+        let email = self.seal(&user.email);
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            // `xmax` is only zero for a freshly inserted row version.
+            let row = match (&self.cipher, index) {
+                (Some(cipher), None) => return self.upsert_sealed(cipher, user).await,
+                (Some(_), Some(index)) => {
+                    sqlx::query_as!(
+                        UpsertedRow,
+                        r#"INSERT INTO users (name, email, email_index) VALUES ($1, $2, $3)
+                       ON CONFLICT (email_index) DO UPDATE
+                       SET name = EXCLUDED.name, updated_at = now()
+                       RETURNING id, xmax = 0 AS "created!""#,
+                        user.name,
+                        email,
+                        index
+                    )
+                    .fetch_one(&mut *transaction)
+                    .await?
+                }
+                (None, _) => {
+                    sqlx::query_as!(
+                        UpsertedRow,
+                        r#"INSERT INTO users (name, email) VALUES ($1, $2)
+                       ON CONFLICT (email) DO UPDATE
+                       SET name = EXCLUDED.name, updated_at = now()
+                       RETURNING id, xmax = 0 AS "created!""#,
+                        user.name,
+                        email
+                    )
+                    .fetch_one(&mut *transaction)
+                    .await?
+                }
+            };
+            let (upserted, kind) = match row.created {
+                true => (Upserted::Created(row.id), EventKind::Created),
+                false => (Upserted::Updated(row.id), EventKind::Updated),
+            };
+            self.queue_event(&mut transaction, Event::new(kind, row.id, Some(user)))
+                .await?;
+            transaction.commit().await?;
+            Ok(upserted)
+        })
+    }
+
+    fn delete(&self, id: i32) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let done = sqlx::query!("DELETE FROM users WHERE id = $1", id)
+                .execute(&mut *transaction)
+                .await?;
+            if done.rows_affected() > 0 {
+                self.queue_event(&mut transaction, Event::new(EventKind::Deleted, id, None))
+                    .await?;
+            }
+            transaction.commit().await?;
+            Ok(done.rows_affected())
+        })
+    }
+
+    fn metadata(&self, id: i32) -> Result<Option<Value>, RepositoryError> {
+        self.run(async {
+            Ok(
+                sqlx::query_scalar!("SELECT metadata FROM users WHERE id = $1", id)
+                    .fetch_optional(&self.pool)
+                    .await?,
+            )
+        })
+    }
+
+    fn change_metadata(
+        &self,
+        id: i32,
+        change: &mut dyn FnMut(&mut Value) -> bool,
+    ) -> Result<Option<Value>, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let row =
+                sqlx::query_scalar!("SELECT metadata FROM users WHERE id = $1 FOR UPDATE", id)
+                    .fetch_optional(&mut *transaction)
+                    .await?;
+            let Some(mut metadata) = row else {
+                return Ok(None);
+            };
+            let current = metadata.clone();
+            if !change(&mut metadata) {
+                return Ok(Some(current));
+            }
+            sqlx::query!("UPDATE users SET metadata = $1 WHERE id = $2", metadata, id)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            Ok(Some(metadata))
+        })
+    }
+}
+
+/// What an upsert returns.
+struct UpsertedRow {
+    id: i32,
+    created: bool,
+}
+
+impl AddressRepository for SqlxRepository {
+    fn list_addresses(&self, user_id: i32) -> Result<Vec<Address>, RepositoryError> {
+        self.run(async {
+            Ok(sqlx::query_as!(
+                Address,
+                r#"SELECT id AS "id?", user_id AS "user_id?", line1, line2, city, postal_code,
+                       country
+                   FROM addresses WHERE user_id = $1 ORDER BY id"#,
+                user_id
+            )
+            .fetch_all(&self.pool)
+            .await?)
+        })
+    }
+
+    fn count_addresses(&self, user_id: i32) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let count = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM addresses WHERE user_id = $1"#,
+                user_id
+            )
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(count as u64)
+        })
+    }
+
+    fn create_address(&self, user_id: i32, address: &Address) -> Result<Address, RepositoryError> {
+        self.run(async {
+            Ok(sqlx::query_as!(
+                Address,
+                r#"INSERT INTO addresses (user_id, line1, line2, city, postal_code, country)
+                   VALUES ($1, $2, $3, $4, $5, $6)
+                   RETURNING id AS "id?", user_id AS "user_id?", line1, line2, city,
+                       postal_code, country"#,
+                user_id,
+                address.line1,
+                address.line2,
+                address.city,
+                address.postal_code,
+                address.country
+            )
+            .fetch_one(&self.pool)
+            .await?)
+        })
+    }
+
+    fn find_address(&self, user_id: i32, id: i32) -> Result<Option<Address>, RepositoryError> {
+        self.run(async {
+            Ok(sqlx::query_as!(
+                Address,
+                r#"SELECT id AS "id?", user_id AS "user_id?", line1, line2, city, postal_code,
+                       country
+                   FROM addresses WHERE id = $1 AND user_id = $2"#,
+                id,
+                user_id
+            )
+            .fetch_optional(&self.pool)
+            .await?)
+        })
+    }
+
+    fn update_address(
+        &self,
+        user_id: i32,
+        id: i32,
+        address: &Address,
+    ) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let done = sqlx::query!(
+                "UPDATE addresses SET line1 = $1, line2 = $2, city = $3, postal_code = $4,
+                     country = $5
+                 WHERE id = $6 AND user_id = $7",
+                address.line1,
+                address.line2,
+                address.city,
+                address.postal_code,
+                address.country,
+                id,
+                user_id
+            )
+            .execute(&self.pool)
+            .await?;
+            Ok(done.rows_affected())
+        })
+    }
+
+    fn delete_addresses(&self, user_id: i32, id: Option<i32>) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let done = match id {
+                Some(id) => {
+                    sqlx::query!(
+                        "DELETE FROM addresses WHERE id = $1 AND user_id = $2",
+                        id,
+                        user_id
+                    )
+                    .execute(&self.pool)
+                    .await?
+                }
+                None => {
+                    sqlx::query!("DELETE FROM addresses WHERE user_id = $1", user_id)
+                        .execute(&self.pool)
+                        .await?
+                }
+            };
+            Ok(done.rows_affected())
+        })
+    }
+}
+
+impl FlagRepository for SqlxRepository {
+    fn list_flags(&self) -> Result<BTreeMap<String, bool>, RepositoryError> {
+        self.run(async {
+            let rows = sqlx::query!("SELECT name, enabled FROM feature_flags")
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| (row.name, row.enabled))
+                .collect())
+        })
+    }
+
+    fn set_flag(&self, name: &str, enabled: bool) -> Result<(), RepositoryError> {
+        self.run(async {
+            sqlx::query!(
+                "INSERT INTO feature_flags (name, enabled) VALUES ($1, $2)
+                 ON CONFLICT (name) DO UPDATE SET enabled = EXCLUDED.enabled, updated_at = now()",
+                name,
+                enabled
+            )
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_flag(&self, name: &str) -> Result<u64, RepositoryError> {
+        self.run(async {
+            let done = sqlx::query!("DELETE FROM feature_flags WHERE name = $1", name)
+                .execute(&self.pool)
+                .await?;
+            Ok(done.rows_affected())
+        })
+    }
+}
+
+impl TotpRepository for SqlxRepository {
+    fn change_totp(
+        &self,
+        account: &str,
+        change: &mut dyn FnMut(&mut Option<Totp>) -> bool,
+    ) -> Result<Option<Totp>, RepositoryError> {
+        self.run(async {
+            let mut transaction = self.pool.begin().await?;
+            let row = sqlx::query!(
+                "SELECT secret, enabled, recovery_codes, last_step FROM totp
+                 WHERE account = $1 FOR UPDATE",
+                account
+            )
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let mut totp = match row {
+                Some(row) => Some(Totp {
+                    secret: self.unseal(row.secret)?,
+                    enabled: row.enabled,
+                    recovery_codes: row.recovery_codes,
+                    last_step: row.last_step as u64,
+                }),
+                None => None,
+            };
+            let current = totp.clone();
+            if !change(&mut totp) {
+                return Ok(current);
+            }
+            match &totp {
+                Some(totp) => {
+                    sqlx::query!(
+                        "INSERT INTO totp (account, secret, enabled, recovery_codes, last_step)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (account) DO UPDATE SET secret = EXCLUDED.secret,
+                         enabled = EXCLUDED.enabled, recovery_codes = EXCLUDED.recovery_codes,
+                         last_step = EXCLUDED.last_step",
+                        account,
+                        self.seal(&totp.secret),
+                        totp.enabled,
+                        &totp.recovery_codes,
+                        totp.last_step as i64
+                    )
+                    .execute(&mut *transaction)
+                    .await?
+                }
+                None => {
+                    sqlx::query!("DELETE FROM totp WHERE account = $1", account)
+                        .execute(&mut *transaction)
+                        .await?
+                }
+            };
+            transaction.commit().await?;
+            Ok(totp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Condition, Order, UserField};
+
+    #[test]
+    fn lists_with_the_conditions_order_and_page_asked_for() {
+        let filter = UserFilter {
+            created_after: Some(DateTime::<Utc>::UNIX_EPOCH),
+            conditions: vec![Expression::Any(vec![
+                Expression::Condition(Condition {
+                    field: UserField::Name,
+                    comparison: Comparison::EndsWith,
+                    value: Literal::Text("son".to_string()),
+                }),
+                Expression::All(Vec::new()),
+                Expression::Condition(Condition {
+                    field: UserField::Id,
+                    comparison: Comparison::Ge,
+                    value: Literal::Integer(3),
+                }),
+            ])],
+            order: vec![Order {
+                field: UserField::Name,
+                descending: true,
+            }],
+            skip: 20,
+            top: Some(10),
+            ..UserFilter::default()
+        };
+        assert_eq!(
+            list_users(&filter).sql(),
+            "SELECT id, name, email, created_at, updated_at FROM users WHERE TRUE \
+             AND created_at > $1 \
+             AND (right(name, char_length($2)) = $3 OR TRUE OR id >= $4) \
+             ORDER BY name DESC, id OFFSET 20 LIMIT 10"
+        );
+        assert_eq!(
+            select_users("COUNT(*)", &UserFilter::default()).sql(),
+            "SELECT COUNT(*) FROM users WHERE TRUE"
+        );
+    }
+}
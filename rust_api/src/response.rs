@@ -0,0 +1,214 @@
+use std::fmt;
+
+/*
+*  Response heads
+*
+*  Headers whose values are not constants are added through `ResponseHead`
+*  instead of being formatted into the head. A carriage return or line feed in
+*  a value would end its header early and let whoever controls the value add
+*  headers, or a body, of their own; the builder refuses values with control
+*  characters rather than writing them. Values taken from a request are best
+*  passed through `quoted` or `encode_segment` first, which turn anything into
+*  a valid value.
+*/
+
+/// A header value with characters a header may not contain.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidHeader {
+    pub name: &'static str,
+}
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid value for header {}", self.name)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+pub struct ResponseHead {
+    head: String,
+    /// The first header refused.
+    invalid: Option<&'static str>,
+}
+
+impl ResponseHead {
+    /// `status` is the code and reason phrase, e.g. `200 OK`.
+    pub fn new(status: &str) -> ResponseHead {
+        ResponseHead {
+            head: format!("HTTP/1.1 {}\r\n", status),
+            invalid: None,
+        }
+    }
+
+    /// Continues a complete head, such as one of the status constants.
+    pub fn extend(head: &str) -> ResponseHead {
+        ResponseHead {
+            head: head.strip_suffix("\r\n").unwrap_or(head).to_string(),
+            invalid: None,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl fmt::Display) -> ResponseHead {
+        debug_assert!(is_token(name), "invalid header name {:?}", name);
+        let value = value.to_string();
+        if !is_valid_value(&value) {
+            self.invalid.get_or_insert(name);
+            return self;
+        }
+        self.head.push_str(name);
+        self.head.push_str(": ");
+        self.head.push_str(&value);
+        self.head.push_str("\r\n");
+        self
+    }
+
+    /// The head including the blank line that ends it, unless a header was
+    /// refused.
+    pub fn build(self) -> Result<String, InvalidHeader> {
+        match self.invalid {
+            Some(name) => Err(InvalidHeader { name }),
+            None => Ok(self.head + "\r\n"),
+        }
+    }
+}
+
+/// Printable ASCII, spaces and tabs. Other bytes are allowed by RFC 9110 as
+/// obsolete text, but clients disagree on how to read them.
+fn is_valid_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b'\t' || b == b' ' || b.is_ascii_graphic())
+}
+
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// The status code of a complete head, 500 if it has none.
+pub fn status_code(head: &str) -> u16 {
+    head.split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(500)
+}
+
+/// The first value of header `name` in a complete head.
+pub fn header_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Replaces the `Content-Type` of a complete head, or adds one after the
+/// status line.
+pub fn set_content_type(head: &str, content_type: &str) -> String {
+    let (status_line, headers) = head.split_once("\r\n").unwrap_or((head, "\r\n"));
+    let mut replaced = format!("{}\r\nContent-Type: {}\r\n", status_line, content_type);
+    for line in headers.split_inclusive("\r\n") {
+        let is_content_type = line
+            .split_once(':')
+            .is_some_and(|(name, _)| name.eq_ignore_ascii_case("Content-Type"));
+        if !is_content_type {
+            replaced.push_str(line);
+        }
+    }
+    replaced
+}
+
+/// `value` as a quoted string, e.g. for a `Content-Disposition` filename.
+/// Characters a header cannot carry become `_`.
+pub fn quoted(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            ' ' | '\t' => quoted.push(c),
+            _ if c.is_ascii_graphic() => quoted.push(c),
+            _ => quoted.push('_'),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Percent-encodes a path segment for a URL sent back in `Location` or
+/// `Link`, the inverse of the decoding requests get.
+pub fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_rewrites_complete_heads() {
+        let head = "HTTP/1.1 404 NOT FOUND\r\ncontent-type: text/plain\r\nX-Total-Count: 3\r\n\r\n";
+        assert_eq!(status_code(head), 404);
+        assert_eq!(header_value(head, "X-Total-Count"), Some("3"));
+        assert_eq!(header_value(head, "Location"), None);
+        assert_eq!(
+            set_content_type(head, "application/json"),
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\nX-Total-Count: 3\r\n\r\n"
+        );
+        assert_eq!(
+            set_content_type("HTTP/1.1 200 OK\r\n\r\n", "application/json"),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n"
+        );
+        assert_eq!(status_code("garbage"), 500);
+    }
+
+    #[test]
+    fn refuses_values_that_would_split_the_head() {
+        assert_eq!(
+            ResponseHead::new("201 CREATED")
+                .header("Location", "/users/7")
+                .header("X-Total-Count", 3)
+                .build(),
+            Ok(
+                "HTTP/1.1 201 CREATED\r\nLocation: /users/7\r\nX-Total-Count: 3\r\n\r\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            ResponseHead::extend("HTTP/1.1 404 NOT FOUND\r\n\r\n")
+                .header("Link", "</users\r\nSet-Cookie: session=stolen>")
+                .header("Retry-After", "1\n")
+                .build(),
+            Err(InvalidHeader { name: "Link" })
+        );
+        assert!(ResponseHead::new("200 OK")
+            .header("Content-Disposition", "attachment; filename=\"caf\u{e9}\"")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn escapes_what_comes_from_requests() {
+        assert_eq!(quoted("a \"b\"\\c\r\nd"), r#""a \"b\"\\c__d""#);
+        assert_eq!(quoted("caf\u{e9}"), "\"caf_\"");
+        assert_eq!(encode_segment("7"), "7");
+        assert_eq!(
+            encode_segment("a b/c\r\n%\u{e9}"),
+            "a%20b%2Fc%0D%0A%25%C3%A9"
+        );
+    }
+}
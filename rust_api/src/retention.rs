@@ -0,0 +1,259 @@
+use crate::models::User;
+use crate::repository::{AddressRepository, RepositoryError, UserFilter, UserRepository};
+use crate::statsd;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/*
+*  Data retention
+*
+*  `RETENTION_RULES` says what happens to users nobody has touched for a
+*  while, e.g. `users:anonymize:3y,users:delete:7y`. A user is inactive since
+*  it was last changed, its `updated_at`. Anonymizing does what the erasure
+*  endpoint does and keeps the row; deleting removes it with its addresses.
+*  A user covered by several rules gets the one with the longest age, so the
+*  example anonymizes after three years and deletes after seven. Users that
+*  were already anonymized are left alone by anonymizing rules.
+*
+*  The rules are enforced by the `retention` job, and `GET /admin/retention`
+*  reports what they would do right now without doing it. Changes go through
+*  the repository like any other, so they are published as events.
+*/
+
+/// Name erased users are left with.
+pub const ERASED_NAME: &str = "Erased User";
+/// Most ids listed per rule in a report.
+pub const REPORT_IDS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Anonymize,
+    Delete,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Anonymize => "anonymize",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// `users:<action>:<age>`, the age in days (`90d`) or years of 365 days
+/// (`3y`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub action: Action,
+    pub age: Duration,
+}
+
+impl FromStr for RetentionRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ':');
+        if parts.next() != Some("users") {
+            return Err(());
+        }
+        let action = match parts.next() {
+            Some("anonymize") => Action::Anonymize,
+            Some("delete") => Action::Delete,
+            _ => return Err(()),
+        };
+        let age = parts.next().ok_or(())?;
+        let days = match age.split_at(age.len().saturating_sub(1)) {
+            (days, "d") => days.parse::<u64>().map_err(|_| ())?,
+            (years, "y") => years.parse::<u64>().map_err(|_| ())? * 365,
+            _ => return Err(()),
+        };
+        if days == 0 {
+            return Err(());
+        }
+        Ok(RetentionRule {
+            action,
+            age: Duration::from_secs(days * 24 * 60 * 60),
+        })
+    }
+}
+
+impl fmt::Display for RetentionRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days = self.age.as_secs() / (24 * 60 * 60);
+        write!(f, "users:{}:{}d", self.action.as_str(), days)
+    }
+}
+
+impl RetentionRule {
+    /// Users last changed before this are covered.
+    pub fn cutoff(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now - chrono::Duration::from_std(self.age).unwrap_or(chrono::Duration::MAX)
+    }
+
+    fn covers(&self, user: &User, now: DateTime<Utc>) -> bool {
+        let inactive = user
+            .updated_at
+            .is_some_and(|updated_at| updated_at < self.cutoff(now));
+        inactive && !(self.action == Action::Anonymize && is_erased(user))
+    }
+}
+
+/// The users one rule covers.
+#[derive(Debug)]
+pub struct Affected<'a> {
+    pub rule: &'a RetentionRule,
+    pub cutoff: DateTime<Utc>,
+    pub ids: Vec<i32>,
+}
+
+/// What each rule covers as of `now`, in the order of `rules`.
+pub fn affected<'a>(
+    rules: &'a [RetentionRule],
+    users: &dyn UserRepository,
+    now: DateTime<Utc>,
+) -> Result<Vec<Affected<'a>>, RepositoryError> {
+    let mut affected: Vec<Affected> = rules
+        .iter()
+        .map(|rule| Affected {
+            rule,
+            cutoff: rule.cutoff(now),
+            ids: Vec::new(),
+        })
+        .collect();
+    users.stream_all(&UserFilter::default(), &mut |user| {
+        let strictest = affected
+            .iter_mut()
+            .filter(|affected| affected.rule.covers(&user, now))
+            .max_by_key(|affected| affected.rule.age);
+        if let (Some(affected), Some(id)) = (strictest, user.id) {
+            affected.ids.push(id);
+        }
+        true
+    })?;
+    Ok(affected)
+}
+
+/// Applies the rules as of now. Returns a summary for the scheduler log.
+pub fn enforce(
+    rules: &[RetentionRule],
+    users: &dyn UserRepository,
+    addresses: &dyn AddressRepository,
+) -> Result<String, String> {
+    let affected = affected(rules, users, Utc::now()).map_err(|e| e.to_string())?;
+    let mut report = Vec::new();
+    for affected in affected {
+        let mut changed = 0;
+        for &id in &affected.ids {
+            let result = match affected.rule.action {
+                Action::Anonymize => erase(users, addresses, id),
+                Action::Delete => users.delete(id),
+            };
+            changed += result.map_err(|e| e.to_string())?;
+        }
+        let action = affected.rule.action.as_str();
+        statsd::count("retention.users", changed, &[("action", action)]);
+        report.push(format!("{} {}", action, changed));
+    }
+    Ok(format!("retention {}", report.join(", ")))
+}
+
+/// Overwrites the user's name and email with placeholders that cannot be
+/// traced back and drops everything attached, keeping the row and its id.
+/// Returns the number of users changed.
+pub fn erase(
+    users: &dyn UserRepository,
+    addresses: &dyn AddressRepository,
+    id: i32,
+) -> Result<u64, RepositoryError> {
+    let placeholder = User {
+        id: None,
+        name: ERASED_NAME.to_string(),
+        // Random so the unique email index still holds.
+        email: format!("erased-{:016x}@invalid", rand::random::<u64>()),
+        created_at: None,
+        updated_at: None,
+    };
+    let updated = users.update(id, &placeholder)?;
+    if updated > 0 {
+        addresses.delete_addresses(id, None)?;
+        users.change_metadata(id, &mut |metadata| {
+            *metadata = serde_json::json!({});
+            true
+        })?;
+    }
+    Ok(updated)
+}
+
+fn is_erased(user: &User) -> bool {
+    user.name == ERASED_NAME
+        && user.email.starts_with("erased-")
+        && user.email.ends_with("@invalid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    fn user(name: &str, email: &str) -> User {
+        User {
+            id: None,
+            name: name.to_string(),
+            email: email.to_string(),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: RetentionRule = "users:anonymize:3y".parse().unwrap();
+        assert_eq!(rule.action, Action::Anonymize);
+        assert_eq!(rule.to_string(), "users:anonymize:1095d");
+        assert_eq!(
+            "users:delete:90d".parse::<RetentionRule>().unwrap().age,
+            Duration::from_secs(90 * 24 * 60 * 60)
+        );
+        for invalid in ["users:anonymize", "users:forget:1y", "audit:delete:1y"] {
+            assert!(invalid.parse::<RetentionRule>().is_err(), "{}", invalid);
+        }
+        assert!("users:delete:0d".parse::<RetentionRule>().is_err());
+        assert!("users:delete:3m".parse::<RetentionRule>().is_err());
+    }
+
+    #[test]
+    fn applies_the_longest_rule_covering_each_user() {
+        let repository = MemoryUserRepository::new();
+        let ada = repository.create(&user("Ada", "ada@example.com")).unwrap();
+        let bob = repository.create(&user("Bob", "bob@example.com")).unwrap();
+        let rules: Vec<RetentionRule> = vec![
+            "users:anonymize:30d".parse().unwrap(),
+            "users:delete:365d".parse().unwrap(),
+        ];
+
+        let now = Utc::now();
+        let today = affected(&rules, &repository, now).unwrap();
+        assert!(today.iter().all(|affected| affected.ids.is_empty()));
+
+        let in_two_months = now + chrono::Duration::days(60);
+        let later = affected(&rules, &repository, in_two_months).unwrap();
+        assert_eq!(later[0].ids, vec![ada, bob]);
+        assert!(later[1].ids.is_empty());
+
+        erase(&repository, &repository, ada).unwrap();
+        let later = affected(
+            &rules,
+            &repository,
+            in_two_months + chrono::Duration::days(60),
+        )
+        .unwrap();
+        assert_eq!(later[0].ids, vec![bob]);
+
+        let in_two_years = now + chrono::Duration::days(730);
+        let much_later = affected(&rules, &repository, in_two_years).unwrap();
+        assert!(much_later[0].ids.is_empty());
+        assert_eq!(much_later[1].ids, vec![ada, bob]);
+    }
+}
@@ -0,0 +1,518 @@
+use crate::access::{Grant, Scope};
+use crate::codec;
+use crate::context;
+use crate::extract::{Parts, Respond};
+use crate::http::Request;
+use crate::response::{encode_segment, ResponseHead};
+use crate::schema::{self, Violation};
+use crate::services::Services;
+use chrono::{NaiveDate, NaiveTime};
+use log::warn;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+
+/*
+*  Router
+*
+*  Routes are tried in registration order and the first whose method and path
+*  match wins. A path pattern matches segment by segment: literal segments must
+*  be equal and `:name` segments take any single value, so `/users/:id` matches
+*  `/users/5` but neither `/users` nor `/users/5/export`. A route may carry a
+*  JSON Schema; the request body is checked against it before the handler
+*  runs, after making sure it was sent as a media type we can read. A route
+*  whose body only makes sense in formats of its own, like a JSON Patch, names
+*  them instead, and nothing else is accepted there.
+*
+*  A route may also name the scope a request needs, see `crate::access`;
+*  requests without it never reach the handler.
+*
+*  Handlers take the request, or declare the parts of it they need as
+*  extractors (see `crate::extract`), which are parsed once the checks above
+*  passed.
+*
+*  An upload route gets the body as a reader instead, before any of it was
+*  buffered, so it can take bodies far larger than `MAX_BODY_BYTES` with
+*  bounded memory. It cannot carry a schema, and signatures, which cover the
+*  whole body, cannot be checked for it.
+*
+*  A route marked deprecated keeps working, but its responses announce the
+*  sunset date and the replacement, and every call is logged so we can tell
+*  who still has to move.
+*/
+
+/// A handler of any signature `Respond` takes, ready to run.
+type Responder = Box<dyn Fn(&Parts) -> (String, String) + Send + Sync>;
+
+/// Writes the whole response straight to the client, for bodies too large to
+/// buffer. Returns the status code it sent.
+pub type StreamHandler = fn(&Request, &Services, &mut dyn Write) -> io::Result<u16>;
+
+/// Reads the request body from `body` as it arrives.
+pub type UploadHandler = fn(&Request, &Services, &mut dyn Read) -> (String, String);
+
+enum Action {
+    Respond(Responder),
+    Stream(StreamHandler),
+    Upload(UploadHandler),
+}
+
+pub enum Outcome {
+    Response(String, String),
+    /// The handler still has to run against the connection.
+    Stream(StreamHandler),
+    /// The handler still has to run against the unread body.
+    Upload(UploadHandler),
+}
+
+const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\nContent-Type: application/json\r\n\r\n";
+const UNSUPPORTED_MEDIA_TYPE: &str =
+    "HTTP/1.1 415 UNSUPPORTED MEDIA TYPE\r\nContent-Type: application/json\r\n";
+pub const UNAUTHORIZED: &str = "HTTP/1.1 401 UNAUTHORIZED\r\nContent-Type: application/json\r\nWWW-Authenticate: Bearer realm=\"api\"\r\n\r\n";
+pub const UNPROCESSABLE_ENTITY: &str =
+    "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\nContent-Type: application/json\r\n\r\n";
+
+/// The `400` response for a request that cannot be read as the route needs.
+pub fn bad_request(error: String) -> (String, String) {
+    (
+        BAD_REQUEST.to_string(),
+        serde_json::json!({ "error": error }).to_string(),
+    )
+}
+
+/// The `422` response for a body that broke its rules. `violations` keeps the
+/// pointer form; `details` names each field the way a client form would.
+pub fn validation_failed(violations: &[Violation]) -> (String, String) {
+    let details: Vec<Value> = violations
+        .iter()
+        .map(|violation| {
+            serde_json::json!({
+                "field": violation.field(),
+                "code": violation.code,
+                "message": violation.message,
+            })
+        })
+        .collect();
+    (
+        UNPROCESSABLE_ENTITY.to_string(),
+        serde_json::json!({
+            "error": "Validation Failed",
+            "violations": violations,
+            "details": details,
+        })
+        .to_string(),
+    )
+}
+
+/// When a route stopped being recommended, when it goes away, and the path
+/// pattern that replaces its own. Parameters of the successor are filled in
+/// from the parameters of the same name in the deprecated path.
+pub struct Deprecation {
+    pub since: NaiveDate,
+    pub sunset: NaiveDate,
+    pub successor: &'static str,
+}
+
+pub struct Route {
+    method: &'static str,
+    path: &'static str,
+    action: Action,
+    schema: Option<Value>,
+    /// Replaces `codec::BODY_TYPES` for this route.
+    body_types: Option<&'static [&'static str]>,
+    /// The handler takes a `Json` extractor.
+    reads_body: bool,
+    scope: Option<Scope>,
+    deprecation: Option<Deprecation>,
+}
+
+impl Route {
+    pub fn new<Args>(
+        method: &'static str,
+        path: &'static str,
+        handler: impl Respond<Args>,
+    ) -> Self {
+        Route {
+            method,
+            path,
+            reads_body: handler.reads_body(),
+            action: Action::Respond(Box::new(move |parts| handler.respond(parts))),
+            schema: None,
+            body_types: None,
+            scope: None,
+            deprecation: None,
+        }
+    }
+
+    pub fn stream(method: &'static str, path: &'static str, handler: StreamHandler) -> Self {
+        Route {
+            method,
+            path,
+            action: Action::Stream(handler),
+            schema: None,
+            body_types: None,
+            reads_body: false,
+            scope: None,
+            deprecation: None,
+        }
+    }
+
+    pub fn upload(method: &'static str, path: &'static str, handler: UploadHandler) -> Self {
+        Route {
+            method,
+            path,
+            action: Action::Upload(handler),
+            schema: None,
+            body_types: None,
+            reads_body: false,
+            scope: None,
+            deprecation: None,
+        }
+    }
+
+    pub fn with_schema(mut self, schema: Value) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Takes bodies of the given media types only, which are read as JSON.
+    pub fn accepting(mut self, body_types: &'static [&'static str]) -> Self {
+        self.body_types = Some(body_types);
+        self
+    }
+
+    /// Refuses requests whose token lacks `scope`.
+    pub fn requires(mut self, scope: Scope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    pub fn deprecated(mut self, deprecation: Deprecation) -> Self {
+        self.deprecation = Some(deprecation);
+        self
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        self.matches_segments(&request.method, request.segments())
+    }
+
+    fn matches_segments(&self, method: &str, segments: &[String]) -> bool {
+        method == self.method
+            && pattern(self.path).count() == segments.len()
+            && pattern(self.path)
+                .zip(segments)
+                .all(|(expected, segment)| expected.starts_with(':') || expected == segment)
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Whether the route the request will reach once `prefix` is stripped
+    /// off reads its body as it arrives, so it must not be buffered first.
+    pub fn takes_upload(&self, request: &Request, prefix: &str) -> bool {
+        let Some(segments) = request.segments_under(prefix) else {
+            return false;
+        };
+        self.routes
+            .iter()
+            .find(|route| route.matches_segments(&request.method, segments))
+            .is_some_and(|route| matches!(route.action, Action::Upload(_)))
+    }
+
+    /// Runs the matching route, or returns `None` when nothing matches.
+    /// `grant` is what the request's token or admin login allows.
+    pub fn dispatch(
+        &self,
+        request: &Request,
+        services: &Services,
+        grant: Option<&Grant>,
+    ) -> Option<Outcome> {
+        if !request.has_valid_path() {
+            return Some(Outcome::Response(
+                BAD_REQUEST.to_string(),
+                serde_json::json!({ "error": "Invalid path encoding" }).to_string(),
+            ));
+        }
+
+        let route = self.routes.iter().find(|route| route.matches(request))?;
+        context::set_route(route.path);
+        if let Some(refused) = route
+            .scope
+            .and_then(|scope| refuse(scope, grant, services.tokens.required()))
+        {
+            return Some(refused);
+        }
+        let outcome = run(route, request, services, grant);
+        let Some(deprecation) = &route.deprecation else {
+            return Some(outcome);
+        };
+
+        warn!(
+            "Deprecated route {} {} called by {}",
+            request.method,
+            request.path(),
+            request.header("User-Agent").unwrap_or("-")
+        );
+        Some(match outcome {
+            Outcome::Response(status_line, content) => {
+                let successor = format!(
+                    "{}{}",
+                    services.base_path,
+                    fill(deprecation.successor, route.path, request)
+                );
+                Outcome::Response(announce(status_line, deprecation, &successor), content)
+            }
+            // Streamed responses write their own headers, and uploads are
+            // not answered yet; the call is still logged.
+            outcome => outcome,
+        })
+    }
+}
+
+/// The response for a request without `scope`. Requests without any token
+/// only need one once tokens are configured.
+fn refuse(scope: Scope, grant: Option<&Grant>, tokens_required: bool) -> Option<Outcome> {
+    match grant {
+        Some(grant) if grant.allows(scope) => None,
+        Some(grant) => {
+            warn!(
+                "{} lacks the {} scope for this route",
+                grant.subject,
+                scope.as_str()
+            );
+            Some(Outcome::Response(
+                format!(
+                    "HTTP/1.1 403 FORBIDDEN\r\nContent-Type: application/json\r\nWWW-Authenticate: Bearer error=\"insufficient_scope\", scope=\"{}\"\r\n\r\n",
+                    scope.as_str()
+                ),
+                serde_json::json!({ "error": "Insufficient Scope", "scope": scope.as_str() })
+                    .to_string(),
+            ))
+        }
+        None if tokens_required => Some(Outcome::Response(
+            UNAUTHORIZED.to_string(),
+            serde_json::json!({ "error": "Unauthorized" }).to_string(),
+        )),
+        None => None,
+    }
+}
+
+fn run(route: &Route, request: &Request, services: &Services, grant: Option<&Grant>) -> Outcome {
+    if route.schema.is_some() || route.body_types.is_some() || route.reads_body {
+        let accepted = match route.body_types {
+            Some(types) => request.header("Content-Type").is_some_and(|content_type| {
+                let essence = content_type.split(';').next().unwrap_or_default().trim();
+                types.iter().any(|t| essence.eq_ignore_ascii_case(t))
+            }),
+            None => codec::has_supported_body(request),
+        };
+        if !accepted {
+            let supported = route.body_types.unwrap_or(&codec::BODY_TYPES).join(", ");
+            return Outcome::Response(
+                format!("{}Accept: {}\r\n\r\n", UNSUPPORTED_MEDIA_TYPE, supported),
+                serde_json::json!({
+                    "error": format!("Unsupported Content-Type, expected one of: {}", supported),
+                })
+                .to_string(),
+            );
+        }
+    }
+    if let Some(schema) = &route.schema {
+        let body: Value = match serde_json::from_slice(&request.body) {
+            Ok(body) => body,
+            Err(e) => {
+                let (status_line, content) = bad_request(format!("Invalid JSON: {}", e));
+                return Outcome::Response(status_line, content);
+            }
+        };
+        let violations = schema::validate(schema, &body);
+        if !violations.is_empty() {
+            let (status_line, content) = validation_failed(&violations);
+            return Outcome::Response(status_line, content);
+        }
+    }
+
+    match &route.action {
+        Action::Respond(handler) => {
+            let (status_line, content) = handler(&Parts {
+                request,
+                services,
+                grant,
+                route: route.path,
+            });
+            Outcome::Response(status_line, content)
+        }
+        Action::Stream(handler) => Outcome::Stream(*handler),
+        Action::Upload(handler) => Outcome::Upload(*handler),
+    }
+}
+
+/// The segments of a path pattern.
+fn pattern(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Turns `target` into a path by replacing each `:name` with the request's
+/// segment where `source` has the same parameter.
+fn fill(target: &str, source: &str, request: &Request) -> String {
+    let segments: Vec<String> = pattern(target)
+        .map(|segment| {
+            if !segment.starts_with(':') {
+                return segment.to_string();
+            }
+            pattern(source)
+                .position(|name| name == segment)
+                .and_then(|index| request.segment(index))
+                .map(encode_segment)
+                .unwrap_or_else(|| segment.to_string())
+        })
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// Adds the `Deprecation` (RFC 9745), `Sunset` (RFC 8594) and successor `Link`
+/// headers to a status line.
+fn announce(status_line: String, deprecation: &Deprecation, successor: &str) -> String {
+    let since = deprecation.since.and_time(NaiveTime::MIN).and_utc();
+    let sunset = deprecation.sunset.format("%a, %d %b %Y 00:00:00 GMT");
+    ResponseHead::extend(&status_line)
+        .header("Deprecation", format!("@{}", since.timestamp()))
+        .header("Sunset", sunset)
+        .header(
+            "Link",
+            format!("<{}>; rel=\"successor-version\"", successor),
+        )
+        .build()
+        .unwrap_or(status_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::Json;
+    use crate::repository::MemoryUserRepository;
+
+    fn request(method: &str, target: &str) -> Request {
+        Request::parse(format!("{} {} HTTP/1.1\r\n\r\n", method, target).as_bytes()).unwrap()
+    }
+
+    fn ok(_: &Request, _: &Services) -> (String, String) {
+        (String::new(), String::new())
+    }
+
+    #[test]
+    fn matches_whole_segments_only() {
+        let route = Route::new("GET", "/users/:id/export", ok);
+
+        assert!(route.matches(&request("GET", "/users/5/export")));
+        assert!(route.matches(&request("GET", "//users/5/export/")));
+        assert!(!route.matches(&request("POST", "/users/5/export")));
+        assert!(!route.matches(&request("GET", "/teamusers/5/export")));
+        assert!(!route.matches(&request("GET", "/users/5/exports")));
+        assert!(!route.matches(&request("GET", "/users/5")));
+        assert!(!route.matches(&request("GET", "/users/5/export/x")));
+        assert!(!route.matches(&request("GET", "/api/users/5/export")));
+
+        let root = Route::new("GET", "/", ok);
+        assert!(root.matches(&request("GET", "/")));
+        assert!(!root.matches(&request("GET", "/users")));
+    }
+
+    fn echo(_: &Services, Json(body): Json<Value>) -> (String, String) {
+        (String::new(), body.to_string())
+    }
+
+    fn status(outcome: Outcome) -> String {
+        match outcome {
+            Outcome::Response(status_line, _) => status_line,
+            _ => panic!("expected a response"),
+        }
+    }
+
+    /// Routes without a schema are held to their media types too, whether
+    /// they name them or take a `Json` body.
+    #[test]
+    fn refuses_unsupported_bodies_on_every_route_that_reads_one() {
+        let services = Services::for_tests(MemoryUserRepository::new());
+        let post = |content_type: &str| {
+            Request::parse(
+                format!(
+                    "POST /echo HTTP/1.1\r\nContent-Type: {}\r\n\r\n{{}}",
+                    content_type
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+
+        let json = Route::new("POST", "/echo", echo);
+        assert!(status(run(&json, &post("text/plain"), &services, None)).contains(" 415 "));
+        assert!(!status(run(&json, &post("application/json"), &services, None)).contains(" 415 "));
+
+        let patch = Route::new("POST", "/echo", ok).accepting(&["application/merge-patch+json"]);
+        assert!(status(run(&patch, &post("application/json"), &services, None)).contains(" 415 "));
+        assert!(!status(run(
+            &patch,
+            &post("application/merge-patch+json"),
+            &services,
+            None
+        ))
+        .contains(" 415 "));
+
+        let raw = Route::new("POST", "/echo", ok);
+        assert!(!status(run(&raw, &post("text/plain"), &services, None)).contains(" 415 "));
+    }
+
+    fn upload(_: &Request, _: &Services, _: &mut dyn Read) -> (String, String) {
+        (String::new(), String::new())
+    }
+
+    #[test]
+    fn finds_uploads_before_the_body_is_read() {
+        let router = Router::new()
+            .route(Route::new("POST", "/users/lookup", ok))
+            .route(Route::upload("POST", "/users/bulk", upload));
+
+        assert!(router.takes_upload(&request("POST", "/api/users/bulk"), "/api"));
+        assert!(!router.takes_upload(&request("POST", "/users/bulk"), "/api"));
+        assert!(!router.takes_upload(&request("PUT", "/api/users/bulk"), "/api"));
+        assert!(!router.takes_upload(&request("POST", "/api/users/lookup"), "/api"));
+        assert!(!router.takes_upload(&request("POST", "/api/users/%zz"), "/api"));
+    }
+
+    #[test]
+    fn fills_parameters_by_name() {
+        let request = request("GET", "/user/7/addresses/3");
+        assert_eq!(
+            fill(
+                "/users/:id/addresses/:address_id",
+                "/user/:id/addresses/:address_id",
+                &request
+            ),
+            "/users/7/addresses/3"
+        );
+    }
+
+    /// Decoded segments go back out encoded, so they cannot break the `Link`
+    /// header they end up in.
+    #[test]
+    fn fills_parameters_encoded() {
+        let request = request("GET", "/user/7%0D%0ASet-Cookie:%20a=b");
+        assert_eq!(
+            fill("/users/:id", "/user/:id", &request),
+            "/users/7%0D%0ASet-Cookie:%20a=b"
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use crate::repository::{Comparison, Condition, Expression, Literal, UserField};
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/*
+*  RSQL filters
+*
+*  `?filter=` takes an RSQL (FIQL) expression
+*  (https://github.com/jirutka/rsql-parser#grammar-and-semantic), which can
+*  combine conditions on any listing field without a query parameter for each:
+*
+*      name==Ali*;email==*@corp.com
+*      (name==Ada,name==Grace);created_at=ge=2024-01-01T00:00:00Z
+*
+*  `;` (or `and`) binds tighter than `,` (or `or`), and parentheses group.
+*  The operators are `==`, `!=`, `=lt=` / `<`, `=le=` / `<=`, `=gt=` / `>`,
+*  `=ge=` / `>=`, `=in=` and `=out=`, the last two with a parenthesized list
+*  of values. Values containing reserved characters are quoted with `'` or
+*  `"`, with `\` escaping. In `==` on text fields a `*` at the start or end
+*  of the value matches anything. Only the fields in `UserField` are known.
+*  Groups nest at most `MAX_DEPTH` deep.
+*/
+
+/// Deepest parentheses may nest; the parser recurses once per level.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidFilter(String);
+
+impl fmt::Display for InvalidFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid filter: {}", self.0)
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expression, InvalidFilter> {
+    let mut parser = Parser {
+        input,
+        position: 0,
+        depth: 0,
+    };
+    let expression = parser.or().map_err(InvalidFilter)?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(expression),
+        Some(c) => Err(InvalidFilter(format!(
+            "unexpected {:?} at {}",
+            c, parser.position
+        ))),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    /// Byte offset of the next character.
+    position: usize,
+    /// Groups open at the position.
+    depth: usize,
+}
+
+/// Characters that end an unquoted selector or value.
+const RESERVED: &str = "\"'();,=!~<> ";
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes `token`, after optional whitespace, if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes the keyword `and` or `or` surrounded by whitespace.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        let matched = self.input[..self.position].ends_with(char::is_whitespace)
+            && self.rest().starts_with(keyword)
+            && self.rest()[keyword.len()..].starts_with(char::is_whitespace);
+        if matched {
+            self.position += keyword.len();
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut operands = vec![self.and()?];
+        while self.eat(",") || self.eat_keyword("or") {
+            operands.push(self.and()?);
+        }
+        Ok(flatten(operands, Expression::Any))
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut operands = vec![self.constraint()?];
+        while self.eat(";") || self.eat_keyword("and") {
+            operands.push(self.constraint()?);
+        }
+        Ok(flatten(operands, Expression::All))
+    }
+
+    fn constraint(&mut self) -> Result<Expression, String> {
+        if self.eat("(") {
+            if self.depth == MAX_DEPTH {
+                return Err(format!("groups nested deeper than {}", MAX_DEPTH));
+            }
+            self.depth += 1;
+            let group = self.or()?;
+            self.depth -= 1;
+            if !self.eat(")") {
+                return Err(format!("expected ) at {}", self.position));
+            }
+            return Ok(group);
+        }
+        self.skip_whitespace();
+        let selector = self.unreserved();
+        if selector.is_empty() {
+            return Err(format!("expected a field at {}", self.position));
+        }
+        let field =
+            UserField::named(&selector).ok_or_else(|| format!("unknown field {:?}", selector))?;
+        let operator = self.operator()?;
+        let values = if self.eat("(") {
+            let mut values = vec![self.value()?];
+            while self.eat(",") {
+                values.push(self.value()?);
+            }
+            if !self.eat(")") {
+                return Err(format!("expected ) at {}", self.position));
+            }
+            values
+        } else {
+            vec![self.value()?]
+        };
+        comparison(field, &operator, values)
+    }
+
+    fn operator(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        for symbol in ["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(symbol) {
+                return Ok(symbol.to_string());
+            }
+        }
+        let rest = self.rest();
+        if let Some(name) = rest.strip_prefix('=') {
+            let length = name
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(name.len());
+            if name[length..].starts_with('=') {
+                self.position += length + 2;
+                return Ok(format!("={}=", &name[..length]));
+            }
+        }
+        Err(format!("expected an operator at {}", self.position))
+    }
+
+    fn unreserved(&mut self) -> String {
+        let rest = self.rest();
+        let length = rest
+            .find(|c: char| RESERVED.contains(c) || c.is_whitespace())
+            .unwrap_or(rest.len());
+        self.position += length;
+        rest[..length].to_string()
+    }
+
+    /// An unquoted or quoted value.
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        let Some(quote @ ('"' | '\'')) = self.peek() else {
+            let text = self.unreserved();
+            if text.is_empty() {
+                return Err(format!("expected a value at {}", self.position));
+            }
+            return Ok(Value {
+                text,
+                quoted: false,
+            });
+        };
+        let mut text = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => text.push(escaped),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.position += offset + c.len_utf8();
+                    return Ok(Value { text, quoted: true });
+                }
+                c => text.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
+
+struct Value {
+    text: String,
+    quoted: bool,
+}
+
+/// A single operand stands for itself.
+fn flatten(
+    mut operands: Vec<Expression>,
+    combine: fn(Vec<Expression>) -> Expression,
+) -> Expression {
+    if operands.len() == 1 {
+        operands.remove(0)
+    } else {
+        combine(operands)
+    }
+}
+
+fn comparison(field: UserField, operator: &str, values: Vec<Value>) -> Result<Expression, String> {
+    let comparison = match operator {
+        "=in=" | "=out=" => {
+            let (comparison, combine): (_, fn(_) -> _) = if operator == "=in=" {
+                (Comparison::Eq, Expression::Any)
+            } else {
+                (Comparison::Ne, Expression::All)
+            };
+            let conditions = values
+                .into_iter()
+                .map(|value| {
+                    Ok(Expression::Condition(Condition {
+                        field,
+                        comparison,
+                        value: literal(field, &value.text)?,
+                    }))
+                })
+                .collect::<Result<_, String>>()?;
+            return Ok(combine(conditions));
+        }
+        "==" => Comparison::Eq,
+        "!=" => Comparison::Ne,
+        "=lt=" | "<" => Comparison::Lt,
+        "=le=" | "<=" => Comparison::Le,
+        "=gt=" | ">" => Comparison::Gt,
+        "=ge=" | ">=" => Comparison::Ge,
+        other => return Err(format!("unknown operator {}", other)),
+    };
+    let [value] =
+        <[Value; 1]>::try_from(values).map_err(|_| format!("{} takes a single value", operator))?;
+    let text_field = matches!(field, UserField::Name | UserField::Email);
+    if comparison == Comparison::Eq && text_field && !value.quoted && value.text.contains('*') {
+        return wildcard(field, &value.text);
+    }
+    Ok(Expression::Condition(Condition {
+        field,
+        comparison,
+        value: literal(field, &value.text)?,
+    }))
+}
+
+/// `Ali*`, `*@corp.com` and `*ali*`.
+fn wildcard(field: UserField, pattern: &str) -> Result<Expression, String> {
+    let starts = pattern.starts_with('*');
+    let inner = pattern.strip_prefix('*').unwrap_or(pattern);
+    let ends = inner.ends_with('*');
+    let inner = inner.strip_suffix('*').unwrap_or(inner);
+    if inner.contains('*') {
+        return Err(format!(
+            "{:?} has a wildcard other than at the start or end",
+            pattern
+        ));
+    }
+    let comparison = match (starts, ends) {
+        (true, true) => Comparison::Contains,
+        (true, false) => Comparison::EndsWith,
+        (false, _) => Comparison::StartsWith,
+    };
+    Ok(Expression::Condition(Condition {
+        field,
+        comparison,
+        value: Literal::Text(inner.to_string()),
+    }))
+}
+
+/// Ids are integers and times RFC 3339 timestamps.
+fn literal(field: UserField, text: &str) -> Result<Literal, String> {
+    match field {
+        UserField::Id => text
+            .parse()
+            .map(Literal::Integer)
+            .map_err(|_| format!("{:?} is not an id", text)),
+        UserField::Name | UserField::Email => Ok(Literal::Text(text.to_string())),
+        UserField::CreatedAt | UserField::UpdatedAt => DateTime::parse_from_rfc3339(text)
+            .map(|time| Literal::Timestamp(time.with_timezone(&Utc)))
+            .map_err(|_| format!("{:?} is not an RFC 3339 timestamp", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(field: UserField, comparison: Comparison, value: Literal) -> Expression {
+        Expression::Condition(Condition {
+            field,
+            comparison,
+            value,
+        })
+    }
+
+    fn text(value: &str) -> Literal {
+        Literal::Text(value.to_string())
+    }
+
+    #[test]
+    fn parses_wildcards_and_precedence() {
+        assert_eq!(
+            parse("name==Ali*;email==*@corp.com,id=in=(1, 2)"),
+            Ok(Expression::Any(vec![
+                Expression::All(vec![
+                    condition(UserField::Name, Comparison::StartsWith, text("Ali")),
+                    condition(UserField::Email, Comparison::EndsWith, text("@corp.com")),
+                ]),
+                Expression::Any(vec![
+                    condition(UserField::Id, Comparison::Eq, Literal::Integer(1)),
+                    condition(UserField::Id, Comparison::Eq, Literal::Integer(2)),
+                ]),
+            ]))
+        );
+        assert_eq!(
+            parse("(name==Ada or name=='*') and id=gt=3"),
+            Ok(Expression::All(vec![
+                Expression::Any(vec![
+                    condition(UserField::Name, Comparison::Eq, text("Ada")),
+                    condition(UserField::Name, Comparison::Eq, text("*")),
+                ]),
+                condition(UserField::Id, Comparison::Gt, Literal::Integer(3)),
+            ]))
+        );
+        assert_eq!(
+            parse(r#"name=="O\"Brien""#),
+            Ok(condition(UserField::Name, Comparison::Eq, text("O\"Brien")))
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_does_not_know() {
+        for invalid in [
+            "password==x",
+            "name=like=A*",
+            "name==A*i",
+            "id==one",
+            "id<(1,2)",
+            "name==Ada;",
+            "(name==Ada",
+            "name=='Ada",
+            "created_at=ge=yesterday",
+        ] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            parse("nickname==Ada").unwrap_err().to_string(),
+            "Invalid filter: unknown field \"nickname\""
+        );
+    }
+
+    #[test]
+    fn limits_how_deep_groups_nest() {
+        let nested = |depth| format!("{}name==a{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(
+            parse(&nested(MAX_DEPTH)),
+            Ok(condition(UserField::Name, Comparison::Eq, text("a")))
+        );
+        assert_eq!(
+            parse(&nested(MAX_DEPTH + 1)).unwrap_err().to_string(),
+            "Invalid filter: groups nested deeper than 32"
+        );
+        // Returns instead of overflowing the stack.
+        assert!(parse(&nested(30000)).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use crate::statsd;
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, DurationRound, NaiveDate, Timelike, Utc,
+};
+use log::{error, info};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+*  Scheduler
+*
+*  Runs recurring maintenance jobs on cron schedules, evaluated in UTC. Every
+*  instance runs its own scheduler, so jobs must be safe to run concurrently
+*  on several instances. A run that is missed, because the previous run was
+*  still going or the process was down, is skipped rather than caught up.
+*/
+
+/// A schedule that cannot fire within this many years is rejected.
+const HORIZON_YEARS: i32 = 5;
+/// Sleeps at most this long between checks, so that a clock that jumps does
+/// not leave a job waiting much longer than planned.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// A cron expression: `minute hour day-of-month month day-of-week`, each a
+/// `*`, a value, a range `a-b` or a list of those, optionally stepped with
+/// `/n`. Day of week runs from 0 (Sunday) to 6, 7 also being Sunday. When
+/// both day fields are restricted a day matching either one fires, as in
+/// cron. `@hourly`, `@daily` and `@weekly` are accepted as well.
+#[derive(Clone)]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day-of-month and day-of-week were both restricted.
+    either_day: bool,
+}
+
+impl Schedule {
+    /// The first minute after `after` the schedule fires at, if any within
+    /// the horizon.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut at =
+            after.duration_trunc(ChronoDuration::minutes(1)).ok()? + ChronoDuration::minutes(1);
+        let end = after.year() + HORIZON_YEARS;
+        while at.year() <= end {
+            if !has(self.months, at.month()) {
+                let (year, month) = match at.month() {
+                    12 => (at.year() + 1, 1),
+                    month => (at.year(), month + 1),
+                };
+                at = NaiveDate::from_ymd_opt(year, month, 1)?
+                    .and_hms_opt(0, 0, 0)?
+                    .and_utc();
+            } else if !self.fires_on(at) {
+                at = (at.date_naive() + ChronoDuration::days(1))
+                    .and_hms_opt(0, 0, 0)?
+                    .and_utc();
+            } else if !has(self.hours, at.hour()) {
+                at = at.with_minute(0)? + ChronoDuration::hours(1);
+            } else if !has(self.minutes, at.minute()) {
+                at += ChronoDuration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    fn fires_on(&self, at: DateTime<Utc>) -> bool {
+        let day = has(self.days, at.day());
+        let weekday = has(self.weekdays, at.weekday().num_days_from_sunday());
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ();
+
+    fn from_str(expression: &str) -> Result<Schedule, ()> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(());
+        };
+        let weekday_bits = field(weekdays, 0, 7)?;
+        let schedule = Schedule {
+            expression: expression.trim().to_string(),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            // 7 is Sunday too.
+            weekdays: (weekday_bits | weekday_bits >> 7) & 0x7f,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        };
+        match schedule.next_after(Utc::now()) {
+            Some(_) => Ok(schedule),
+            None => Err(()),
+        }
+    }
+}
+
+impl fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.expression)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+/// The values one cron field allows, as a bit set.
+fn field(text: &str, min: u32, max: u32) -> Result<u64, ()> {
+    let mut bits = 0;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| ())?),
+            None => (item, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // `5/15` means from 5 on.
+            None if item.contains('/') => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn value(text: &str) -> Result<u32, ()> {
+    text.parse().map_err(|_| ())
+}
+
+type Task = Box<dyn FnMut() -> Result<String, String> + Send>;
+
+struct Job {
+    name: &'static str,
+    schedule: Schedule,
+    task: Task,
+    next: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    /// Adds a job. It reports what it did on success, which gets logged.
+    pub fn add(
+        &mut self,
+        name: &'static str,
+        schedule: Schedule,
+        task: impl FnMut() -> Result<String, String> + Send + 'static,
+    ) {
+        self.jobs.push(Job {
+            name,
+            schedule,
+            task: Box::new(task),
+            next: None,
+        });
+    }
+
+    /// Runs the jobs on a background thread; does nothing without jobs.
+    pub fn start(mut self) {
+        if self.jobs.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        for job in &mut self.jobs {
+            job.next = job.schedule.next_after(now);
+            info!("Job {} scheduled at {}", job.name, job.schedule);
+        }
+        thread::spawn(move || loop {
+            let Some(due) = self.jobs.iter().filter_map(|job| job.next).min() else {
+                return;
+            };
+            // Negative once due, which does not convert.
+            if let Ok(wait) = (due - Utc::now()).to_std() {
+                thread::sleep(wait.min(MAX_SLEEP));
+                continue;
+            }
+            let now = Utc::now();
+            for job in self.jobs.iter_mut() {
+                if job.next.is_some_and(|next| next <= now) {
+                    run(job);
+                    job.next = job.schedule.next_after(Utc::now());
+                }
+            }
+        });
+    }
+}
+
+fn run(job: &mut Job) {
+    let started = Instant::now();
+    let outcome = panic::catch_unwind(AssertUnwindSafe(&mut job.task))
+        .unwrap_or_else(|_| Err("panicked".to_string()));
+    let duration = started.elapsed();
+    let tags = [
+        ("job", job.name),
+        ("outcome", if outcome.is_ok() { "ok" } else { "error" }),
+    ];
+    statsd::count("scheduler.runs", 1, &tags);
+    statsd::timing("scheduler.job_duration", duration, &tags[..1]);
+    match outcome {
+        Ok(report) => info!(
+            "Job {} done in {}ms: {}",
+            job.name,
+            duration.as_millis(),
+            report
+        ),
+        Err(e) => error!("Job {} failed: {}", job.name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn schedule(expression: &str) -> Schedule {
+        expression.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cron_expressions() {
+        assert!("* * * * *".parse::<Schedule>().is_ok());
+        assert!("*/15 9-17 * * 1-5".parse::<Schedule>().is_ok());
+        assert!("0 0,12 1 */3 7".parse::<Schedule>().is_ok());
+        assert!("@daily".parse::<Schedule>().is_ok());
+        for invalid in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "@yearly",
+            // February never has 30 days.
+            "0 0 30 2 *",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn finds_the_next_run() {
+        let nightly = schedule("17 3 * * *");
+        assert_eq!(
+            nightly.next_after(at(2024, 5, 1, 3, 16)),
+            Some(at(2024, 5, 1, 3, 17))
+        );
+        // Strictly after, even when `after` is itself a run.
+        assert_eq!(
+            nightly.next_after(at(2024, 5, 1, 3, 17)),
+            Some(at(2024, 5, 2, 3, 17))
+        );
+        assert_eq!(
+            nightly.next_after(at(2024, 12, 31, 23, 0)),
+            Some(at(2025, 1, 1, 3, 17))
+        );
+
+        let quarter_hours = schedule("*/15 9-17 * * 1-5");
+        // Friday evening to Monday morning.
+        assert_eq!(
+            quarter_hours.next_after(at(2024, 5, 3, 17, 45)),
+            Some(at(2024, 5, 6, 9, 0))
+        );
+        assert_eq!(
+            quarter_hours.next_after(at(2024, 5, 6, 9, 1)),
+            Some(at(2024, 5, 6, 9, 15))
+        );
+
+        assert_eq!(
+            schedule("0 0 29 2 *").next_after(at(2024, 3, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        assert_eq!(
+            schedule("30 * * * *").next_after(at(2024, 5, 1, 10, 45)),
+            Some(at(2024, 5, 1, 11, 30))
+        );
+    }
+
+    #[test]
+    fn either_restricted_day_field_fires() {
+        // The 13th, and every Friday.
+        let friday_13th = schedule("0 0 13 * 5");
+        assert_eq!(
+            friday_13th.next_after(at(2024, 5, 1, 0, 0)),
+            Some(at(2024, 5, 3, 0, 0))
+        );
+        assert_eq!(
+            friday_13th.next_after(at(2024, 5, 10, 0, 0)),
+            Some(at(2024, 5, 13, 0, 0))
+        );
+        // Only Sundays, given as 7.
+        assert_eq!(
+            schedule("0 0 * * 7").next_after(at(2024, 5, 1, 0, 0)),
+            Some(at(2024, 5, 5, 0, 0))
+        );
+    }
+}
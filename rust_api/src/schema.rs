@@ -0,0 +1,397 @@
+use serde_json::Value;
+
+/*
+*  JSON Schema validation
+*
+*  Supports the subset of JSON Schema our request bodies need: `type`, `enum`,
+*  `const`, `properties`, `required`, `additionalProperties`, `items`,
+*  `minLength`/`maxLength`, `minimum`/`maximum`, `minItems`/`maxItems` and
+*  `format: email`. Unknown keywords are ignored, as the spec requires.
+*
+*  Each violation carries a stable `code` next to its human-readable message so
+*  clients can react to a failure without parsing English.
+*/
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// JSON pointer to the offending value; empty for the document itself.
+    pub pointer: String,
+    /// Machine-readable reason, such as `required` or `invalid_format`.
+    #[serde(skip)]
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Violation {
+    /// The pointer as a dotted field path (`addresses.0.city`), which is how
+    /// form libraries usually name their inputs.
+    pub fn field(&self) -> String {
+        self.pointer
+            .split('/')
+            .skip(1)
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+pub fn validate(schema: &Value, instance: &Value) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    check(schema, instance, String::new(), &mut violations);
+    violations
+}
+
+fn check(schema: &Value, instance: &Value, pointer: String, out: &mut Vec<Violation>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            out.push(violation(
+                pointer,
+                "not_allowed",
+                "is not allowed".to_string(),
+            ));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|name| has_type(instance, name)) {
+            out.push(violation(
+                pointer,
+                "invalid_type",
+                format!("must be of type {}", allowed.join(" or ")),
+            ));
+            // Further keywords would only repeat the same complaint.
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(instance) {
+            out.push(violation(
+                pointer.clone(),
+                "invalid_choice",
+                "is not one of the allowed values".to_string(),
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != instance {
+            out.push(violation(
+                pointer.clone(),
+                "invalid_value",
+                format!("must be {}", expected),
+            ));
+        }
+    }
+
+    match instance {
+        Value::String(s) => check_string(schema, s, &pointer, out),
+        Value::Number(n) => check_number(schema, n.as_f64().unwrap_or_default(), &pointer, out),
+        Value::Array(items) => check_array(schema, items, &pointer, out),
+        Value::Object(fields) => check_object(schema, fields, &pointer, out),
+        _ => {}
+    }
+}
+
+fn check_string(
+    schema: &serde_json::Map<String, Value>,
+    s: &str,
+    pointer: &str,
+    out: &mut Vec<Violation>,
+) {
+    let length = s.chars().count() as u64;
+    if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+        if length < min {
+            out.push(violation(
+                pointer.to_string(),
+                "too_short",
+                format!("must be at least {} characters long", min),
+            ));
+        }
+    }
+    if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+        if length > max {
+            out.push(violation(
+                pointer.to_string(),
+                "too_long",
+                format!("must be at most {} characters long", max),
+            ));
+        }
+    }
+    if schema.get("format").and_then(Value::as_str) == Some("email") && !is_email(s) {
+        out.push(violation(
+            pointer.to_string(),
+            "invalid_format",
+            "must be an email address".to_string(),
+        ));
+    }
+}
+
+fn check_number(
+    schema: &serde_json::Map<String, Value>,
+    n: f64,
+    pointer: &str,
+    out: &mut Vec<Violation>,
+) {
+    if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+        if n < min {
+            out.push(violation(
+                pointer.to_string(),
+                "too_small",
+                format!("must be at least {}", min),
+            ));
+        }
+    }
+    if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+        if n > max {
+            out.push(violation(
+                pointer.to_string(),
+                "too_large",
+                format!("must be at most {}", max),
+            ));
+        }
+    }
+}
+
+fn check_array(
+    schema: &serde_json::Map<String, Value>,
+    items: &[Value],
+    pointer: &str,
+    out: &mut Vec<Violation>,
+) {
+    let count = items.len() as u64;
+    if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+        if count < min {
+            out.push(violation(
+                pointer.to_string(),
+                "too_few_items",
+                format!("must have at least {} items", min),
+            ));
+        }
+    }
+    if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+        if count > max {
+            out.push(violation(
+                pointer.to_string(),
+                "too_many_items",
+                format!("must have at most {} items", max),
+            ));
+        }
+    }
+    if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+            check(item_schema, item, format!("{}/{}", pointer, index), out);
+        }
+    }
+}
+
+fn check_object(
+    schema: &serde_json::Map<String, Value>,
+    fields: &serde_json::Map<String, Value>,
+    pointer: &str,
+    out: &mut Vec<Violation>,
+) {
+    let properties = schema.get("properties").and_then(Value::as_object);
+
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !fields.contains_key(name) {
+                out.push(violation(
+                    child(pointer, name),
+                    "required",
+                    "is required".to_string(),
+                ));
+            }
+        }
+    }
+
+    for (name, value) in fields {
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property_schema) => check(property_schema, value, child(pointer, name), out),
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    check(additional, value, child(pointer, name), out);
+                }
+            }
+        }
+    }
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        // Whatever the notation, `1.0` is an integer to JSON Schema.
+        "integer" => instance.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "array" => instance.is_array(),
+        "object" => instance.is_object(),
+        _ => false,
+    }
+}
+
+fn is_email(s: &str) -> bool {
+    match s.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !s.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// Appends a reference token, escaping it as RFC 6901 requires.
+fn child(pointer: &str, token: &str) -> String {
+    format!(
+        "{}/{}",
+        pointer,
+        token.replace('~', "~0").replace('/', "~1")
+    )
+}
+
+fn violation(pointer: String, code: &'static str, message: String) -> Violation {
+    Violation {
+        pointer,
+        code,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn codes(schema: &Value, instance: &Value) -> Vec<(String, &'static str)> {
+        validate(schema, instance)
+            .into_iter()
+            .map(|violation| (violation.pointer, violation.code))
+            .collect()
+    }
+
+    #[test]
+    fn checks_types() {
+        let integer = json!({ "type": "integer" });
+        assert!(validate(&integer, &json!(1)).is_empty());
+        assert!(validate(&integer, &json!(1.0)).is_empty());
+        assert!(validate(&integer, &json!(u64::MAX)).is_empty());
+        assert_eq!(
+            codes(&integer, &json!(1.5)),
+            [(String::new(), "invalid_type")]
+        );
+        assert_eq!(
+            codes(&integer, &json!("1")),
+            [(String::new(), "invalid_type")]
+        );
+
+        let nullable = json!({ "type": ["string", "null"], "minLength": 2 });
+        assert!(validate(&nullable, &json!(null)).is_empty());
+        assert!(validate(&nullable, &json!("ab")).is_empty());
+        // Only the type is reported, not the length it cannot have.
+        let violations = validate(&nullable, &json!(true));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].message, "must be of type string or null");
+    }
+
+    #[test]
+    fn checks_required_and_additional_properties() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": { "name": { "type": "string" } },
+            "additionalProperties": false
+        });
+        assert!(validate(&schema, &json!({ "name": "Ada" })).is_empty());
+        assert_eq!(
+            codes(&schema, &json!({ "nickname": "A/B" })),
+            [
+                ("/name".to_string(), "required"),
+                ("/nickname".to_string(), "not_allowed")
+            ]
+        );
+
+        let open = json!({ "properties": {}, "additionalProperties": { "type": "integer" } });
+        assert!(validate(&open, &json!({ "a~b/c": 1 })).is_empty());
+        let violations = validate(&open, &json!({ "a~b/c": "x" }));
+        assert_eq!(violations[0].pointer, "/a~0b~1c");
+        assert_eq!(violations[0].field(), "a~b/c");
+    }
+
+    #[test]
+    fn checks_enums() {
+        let schema = json!({ "enum": ["DE", "FR", 1] });
+        assert!(validate(&schema, &json!("DE")).is_empty());
+        assert!(validate(&schema, &json!(1)).is_empty());
+        assert_eq!(
+            codes(&schema, &json!("XX")),
+            [(String::new(), "invalid_choice")]
+        );
+    }
+
+    #[test]
+    fn checks_string_lengths_in_characters() {
+        let schema = json!({ "minLength": 2, "maxLength": 3 });
+        assert!(validate(&schema, &json!("äöü")).is_empty());
+        assert_eq!(codes(&schema, &json!("a")), [(String::new(), "too_short")]);
+        assert_eq!(
+            codes(&schema, &json!("abcd")),
+            [(String::new(), "too_long")]
+        );
+        // Lengths only apply to strings.
+        assert!(validate(&schema, &json!(12345)).is_empty());
+    }
+
+    #[test]
+    fn checks_email_format() {
+        let schema = json!({ "format": "email" });
+        assert!(validate(&schema, &json!("ada@example.com")).is_empty());
+        for invalid in [
+            "ada",
+            "@example.com",
+            "ada@example",
+            "ada@.example.com",
+            "ada@example.com.",
+            "a@b@example.com",
+            "ada lovelace@example.com",
+        ] {
+            assert_eq!(
+                codes(&schema, &json!(invalid)),
+                [(String::new(), "invalid_format")],
+                "{}",
+                invalid
+            );
+        }
+        // Unknown formats are ignored.
+        assert!(validate(&json!({ "format": "uuid" }), &json!("x")).is_empty());
+    }
+
+    #[test]
+    fn checks_nested_items() {
+        let schema = json!({
+            "type": "array",
+            "maxItems": 2,
+            "items": { "type": "object", "required": ["city"] }
+        });
+        assert_eq!(
+            codes(&schema, &json!([{ "city": "Berlin" }, {}, {}])),
+            [
+                (String::new(), "too_many_items"),
+                ("/1/city".to_string(), "required"),
+                ("/2/city".to_string(), "required")
+            ]
+        );
+    }
+}
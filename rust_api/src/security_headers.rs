@@ -0,0 +1,159 @@
+use crate::http::has_header;
+use std::io::{self, Write};
+
+/*
+*  Security headers
+*
+*  Every response, errors and streamed ones included, leaves with the headers
+*  that keep browsers from sniffing content types, framing our pages or
+*  leaking URLs in referrers, and with a `Content-Security-Policy`. Responses
+*  on `tls:` listeners also carry `Strict-Transport-Security`; behind a
+*  TLS-terminating proxy that header is the proxy's business. A handler that
+*  sets one of these itself keeps its own, which is how the admin UI gets a
+*  policy allowing its script and styles.
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityHeaders {
+    /// Empty sends no `Content-Security-Policy`.
+    pub content_security_policy: String,
+    /// Empty sends no `Referrer-Policy`.
+    pub referrer_policy: String,
+    /// Only sent over TLS; empty never sends it.
+    pub strict_transport_security: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            content_security_policy: "default-src 'none'; frame-ancestors 'none'".to_string(),
+            referrer_policy: "no-referrer".to_string(),
+            strict_transport_security: "max-age=31536000".to_string(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// The headers for a response on a connection with or without TLS.
+    pub fn for_connection(&self, tls: bool) -> Vec<(&'static str, &str)> {
+        let mut headers = vec![
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", "DENY"),
+            ("Referrer-Policy", self.referrer_policy.as_str()),
+            (
+                "Content-Security-Policy",
+                self.content_security_policy.as_str(),
+            ),
+        ];
+        if tls {
+            headers.push((
+                "Strict-Transport-Security",
+                self.strict_transport_security.as_str(),
+            ));
+        }
+        headers.retain(|(_, value)| !value.is_empty());
+        headers
+    }
+}
+
+/// Writes one response through, adding each of `headers` its head does not
+/// set yet. Buffers only until the end of the head.
+pub struct Secured<'a> {
+    out: &'a mut dyn Write,
+    /// Taken once the head has gone out.
+    headers: Option<Vec<(&'static str, &'a str)>>,
+    head: Vec<u8>,
+}
+
+impl<'a> Secured<'a> {
+    pub fn new(out: &'a mut dyn Write, headers: Vec<(&'static str, &'a str)>) -> Self {
+        Secured {
+            out,
+            headers: Some(headers),
+            head: Vec::new(),
+        }
+    }
+}
+
+impl Write for Secured<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(headers) = &self.headers else {
+            return self.out.write(buf);
+        };
+
+        self.head.extend_from_slice(buf);
+        if let Some(end) = self.head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let buffered = std::mem::take(&mut self.head);
+            let (head, rest) = buffered.split_at(end + 2);
+            self.out.write_all(head)?;
+            for (name, value) in headers {
+                if !has_header(head, name) {
+                    write!(self.out, "{}: {}\r\n", name, value)?;
+                }
+            }
+            self.headers = None;
+            self.out.write_all(rest)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Sends whatever was held back, even if it never formed a full head.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.head.is_empty() {
+            self.headers = None;
+            let buffered = std::mem::take(&mut self.head);
+            self.out.write_all(&buffered)?;
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(headers: &SecurityHeaders, tls: bool, chunks: &[&str]) -> String {
+        let mut sink = Vec::new();
+        let mut out = Secured::new(&mut sink, headers.for_connection(tls));
+        for chunk in chunks {
+            out.write_all(chunk.as_bytes()).unwrap();
+        }
+        out.flush().unwrap();
+        String::from_utf8(sink).unwrap()
+    }
+
+    #[test]
+    fn adds_the_headers_a_response_lacks() {
+        let headers = SecurityHeaders::default();
+        assert_eq!(
+            written(&headers, false, &["HTTP/1.1 404 NOT FOUND\r\n", "\r\nUser Not Found"]),
+            "HTTP/1.1 404 NOT FOUND\r\nX-Content-Type-Options: nosniff\r\nX-Frame-Options: DENY\r\n\
+             Referrer-Policy: no-referrer\r\n\
+             Content-Security-Policy: default-src 'none'; frame-ancestors 'none'\r\n\r\nUser Not Found"
+        );
+        assert_eq!(
+            written(
+                &headers,
+                true,
+                &["HTTP/1.1 200 OK\r\ncontent-security-policy: script-src 'self'\r\n\r\n"]
+            ),
+            "HTTP/1.1 200 OK\r\ncontent-security-policy: script-src 'self'\r\n\
+             X-Content-Type-Options: nosniff\r\nX-Frame-Options: DENY\r\n\
+             Referrer-Policy: no-referrer\r\nStrict-Transport-Security: max-age=31536000\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn leaves_out_disabled_headers() {
+        let headers = SecurityHeaders {
+            content_security_policy: String::new(),
+            referrer_policy: "same-origin".to_string(),
+            strict_transport_security: String::new(),
+        };
+        assert_eq!(
+            written(&headers, true, &["HTTP/1.1 200 OK\r\n\r\n"]),
+            "HTTP/1.1 200 OK\r\nX-Content-Type-Options: nosniff\r\nX-Frame-Options: DENY\r\n\
+             Referrer-Policy: same-origin\r\n\r\n"
+        );
+    }
+}
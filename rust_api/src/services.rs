@@ -0,0 +1,102 @@
+use crate::access::AccessTokens;
+use crate::auth::AdminAuth;
+use crate::backup::Backup;
+use crate::database_schema::DatabaseSchema;
+use crate::email::Mailer;
+use crate::export::ExportStore;
+use crate::flags::FeatureFlags;
+use crate::jobs::JobQueue;
+use crate::lifecycle::Lifecycle;
+use crate::maintenance::Maintenance;
+use crate::password::PasswordPolicy;
+use crate::repository::{AddressRepository, QueryMetrics, TotpRepository, UserRepository};
+use crate::retention::RetentionRule;
+use crate::status::ServerStatus;
+use std::sync::Arc;
+
+/*
+*  Services
+*
+*  Everything a handler may use besides the request itself. Built once at
+*  startup and shared by every connection.
+*/
+
+pub struct Services {
+    pub repository: Arc<dyn UserRepository>,
+    pub addresses: Arc<dyn AddressRepository>,
+    /// Two-factor enrollments, see `crate::totp`.
+    pub totp: Arc<dyn TotpRepository>,
+    pub jobs: Arc<JobQueue>,
+    pub exports: Arc<ExportStore>,
+    /// Exports with at least this many records are built in the background.
+    pub export_async_threshold: u64,
+    /// Prefix for links handed to clients, see `Config::base_path`.
+    pub base_path: String,
+    pub lifecycle: Arc<Lifecycle>,
+    pub maintenance: Arc<Maintenance>,
+    pub flags: Arc<FeatureFlags>,
+    /// API keys and JWTs of integrations.
+    pub tokens: Arc<AccessTokens>,
+    /// Admin token and UI sessions.
+    pub admin: Arc<AdminAuth>,
+    /// Sends emails to users; `None` when email is not configured.
+    pub mailer: Option<Arc<Mailer>>,
+    pub passwords: Arc<PasswordPolicy>,
+    /// Reported on by `GET /admin/retention`.
+    pub retention: Vec<RetentionRule>,
+    /// Backs the database up; `None` unless storage is Postgres.
+    pub backup: Option<Arc<Backup>>,
+    /// Describes the database schema; `None` unless storage is Postgres.
+    pub schema: Option<Arc<DatabaseSchema>>,
+    /// Totals of every repository call, for `GET /metrics`.
+    pub queries: Arc<QueryMetrics>,
+    /// Uptime and request counts, for `GET /status`.
+    pub status: Arc<ServerStatus>,
+}
+
+#[cfg(test)]
+impl Services {
+    /// Services around `repository`, with nothing else configured.
+    pub fn for_tests(repository: impl crate::repository::Repository + 'static) -> Services {
+        use crate::access::AccessTokens;
+        use crate::auth::AdminAuth;
+        use crate::jobs::JobQueue;
+        use crate::lockout::LoginGuard;
+        use crate::maintenance::{self, Window};
+        use crate::password::BreachCheck;
+        use std::time::Duration;
+
+        let storage = Arc::new(repository);
+        Services {
+            repository: storage.clone(),
+            addresses: storage.clone(),
+            totp: storage.clone(),
+            jobs: Arc::new(JobQueue::new(1)),
+            exports: Arc::default(),
+            export_async_threshold: 1000,
+            base_path: String::new(),
+            lifecycle: Arc::default(),
+            maintenance: Arc::new(Maintenance::new(
+                Window {
+                    message: maintenance::DEFAULT_MESSAGE.to_string(),
+                    retry_after: Duration::from_secs(300),
+                },
+                false,
+            )),
+            flags: Arc::new(FeatureFlags::new(storage, Duration::ZERO)),
+            tokens: Arc::new(AccessTokens::new(Vec::new(), None)),
+            admin: Arc::new(AdminAuth::new(
+                None,
+                false,
+                Arc::new(LoginGuard::new(0, Duration::ZERO)),
+            )),
+            mailer: None,
+            passwords: Arc::new(PasswordPolicy::new(12, BreachCheck::List, "")),
+            retention: Vec::new(),
+            backup: None,
+            schema: None,
+            queries: Arc::default(),
+            status: Arc::default(),
+        }
+    }
+}
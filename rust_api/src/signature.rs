@@ -0,0 +1,222 @@
+use crate::access::{ApiKey, Grant, InvalidToken};
+use crate::http::Request;
+use chrono::Utc;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+*  Signed requests
+*
+*  Server-to-server callers that cannot present a TLS client certificate sign
+*  each request with a secret shared through `SIGNING_KEYS` instead:
+*
+*      X-Signature: key=<name>,timestamp=<unix seconds>,nonce=<random>,signature=<hex>
+*
+*  where the signature is the HMAC-SHA256 of
+*
+*      <timestamp>\n<nonce>\n<METHOD>\n<target>\n<body>
+*
+*  with the target exactly as sent, query string included, and the body
+*  before any decoding. A signature is only good while its timestamp is within
+*  `SIGNATURE_WINDOW_SECS` of our clock, and only once: nonces are remembered
+*  until their timestamp leaves the window. They are kept in memory, so with
+*  several instances a captured request can be replayed once per instance
+*  within the window.
+*/
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Longest nonce remembered.
+const MAX_NONCE: usize = 128;
+
+pub struct SignedRequests {
+    keys: Vec<ApiKey>,
+    window: Duration,
+    /// When each key's nonces may be forgotten, as unix seconds.
+    nonces: Mutex<HashMap<(String, String), i64>>,
+}
+
+struct Header<'a> {
+    key: &'a str,
+    timestamp: i64,
+    nonce: &'a str,
+    signature: Vec<u8>,
+}
+
+impl SignedRequests {
+    pub fn new(keys: Vec<ApiKey>, window: Duration) -> SignedRequests {
+        SignedRequests {
+            keys,
+            window,
+            nonces: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The grant of the key that signed the request, or `None` when it is not
+    /// signed.
+    pub fn verify(&self, request: &Request) -> Result<Option<Grant>, InvalidToken> {
+        let Some(header) = request.header(SIGNATURE_HEADER) else {
+            return Ok(None);
+        };
+        let header = parse(header).ok_or(InvalidToken::Malformed)?;
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.name == header.key)
+            .ok_or(InvalidToken::UnknownKey)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.secret().as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(
+            format!(
+                "{}\n{}\n{}\n{}\n",
+                header.timestamp, header.nonce, request.method, request.target
+            )
+            .as_bytes(),
+        );
+        mac.update(&request.body);
+        mac.verify_slice(&header.signature)
+            .map_err(|_| InvalidToken::Signature)?;
+
+        let now = Utc::now().timestamp();
+        let window = self.window.as_secs() as i64;
+        if (now - header.timestamp).abs() > window {
+            return Err(InvalidToken::Expired);
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        let seen = (key.name.clone(), header.nonce.to_string());
+        if nonces.get(&seen).is_some_and(|&until| until >= now) {
+            return Err(InvalidToken::Replayed);
+        }
+        nonces.insert(seen, header.timestamp + window);
+        Ok(Some(key.grant()))
+    }
+
+    /// Forgets nonces whose requests could no longer be replayed anyway.
+    pub fn purge_expired(&self) -> usize {
+        let mut nonces = self.nonces.lock().unwrap();
+        let before = nonces.len();
+        let now = Utc::now().timestamp();
+        nonces.retain(|_, until| *until >= now);
+        before - nonces.len()
+    }
+}
+
+fn parse(header: &str) -> Option<Header<'_>> {
+    let mut fields: HashMap<&str, &str> = HashMap::new();
+    for field in header.split(',') {
+        let (name, value) = field.split_once('=')?;
+        fields.insert(name.trim(), value.trim());
+    }
+    let nonce = *fields.get("nonce")?;
+    if nonce.is_empty() || nonce.len() > MAX_NONCE {
+        return None;
+    }
+    Some(Header {
+        key: fields.get("key")?,
+        timestamp: fields.get("timestamp")?.parse().ok()?,
+        nonce,
+        signature: decode_hex(fields.get("signature")?)?,
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::Scope;
+
+    fn signed(target: &str, body: &str, timestamp: i64, nonce: &str, secret: &str) -> Request {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\nPOST\n{}\n{}", timestamp, nonce, target, body).as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Request::parse(
+            format!(
+                "POST {} HTTP/1.1\r\nX-Signature: key=sync, timestamp={}, nonce={}, signature={}\r\n\r\n{}",
+                target, timestamp, nonce, signature, body
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn signatures() -> SignedRequests {
+        SignedRequests::new(
+            vec!["sync:users:write:s3cret".parse().unwrap()],
+            Duration::from_secs(300),
+        )
+    }
+
+    #[test]
+    fn accepts_each_signature_once() {
+        let signatures = signatures();
+        let now = Utc::now().timestamp();
+        let request = signed("/users?x=1", r#"{"name":"Ada"}"#, now, "n1", "s3cret");
+
+        let grant = signatures.verify(&request).unwrap().unwrap();
+        assert_eq!(grant.subject, "sync");
+        assert!(grant.allows(Scope::UsersWrite));
+        assert_eq!(signatures.verify(&request), Err(InvalidToken::Replayed));
+        assert!(signatures
+            .verify(&signed("/users?x=1", "{}", now, "n2", "s3cret"))
+            .unwrap()
+            .is_some());
+        assert_eq!(signatures.purge_expired(), 0);
+    }
+
+    #[test]
+    fn refuses_tampered_stale_and_foreign_requests() {
+        let signatures = signatures();
+        let now = Utc::now().timestamp();
+
+        let mut tampered = signed("/users", r#"{"name":"Ada"}"#, now, "n1", "s3cret");
+        tampered.body = br#"{"name":"Eve"}"#.to_vec();
+        assert_eq!(signatures.verify(&tampered), Err(InvalidToken::Signature));
+        let mut moved = signed("/users", "", now, "n2", "s3cret");
+        moved.target = "/users/1".to_string();
+        assert_eq!(signatures.verify(&moved), Err(InvalidToken::Signature));
+        assert_eq!(
+            signatures.verify(&signed("/users", "", now, "n3", "guess")),
+            Err(InvalidToken::Signature)
+        );
+        assert_eq!(
+            signatures.verify(&signed("/users", "", now - 301, "n4", "s3cret")),
+            Err(InvalidToken::Expired)
+        );
+        assert_eq!(
+            signatures.verify(&signed("/users", "", now + 301, "n5", "s3cret")),
+            Err(InvalidToken::Expired)
+        );
+
+        let unknown = Request::parse(
+            b"POST /users HTTP/1.1\r\nX-Signature: key=other,timestamp=1,nonce=n,signature=00\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(signatures.verify(&unknown), Err(InvalidToken::UnknownKey));
+        let malformed =
+            Request::parse(b"POST /users HTTP/1.1\r\nX-Signature: sha256=00\r\n\r\n").unwrap();
+        assert_eq!(signatures.verify(&malformed), Err(InvalidToken::Malformed));
+        let unsigned = Request::parse(b"POST /users HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(signatures.verify(&unsigned), Ok(None));
+    }
+}
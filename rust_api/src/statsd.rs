@@ -0,0 +1,157 @@
+use log::{info, warn};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/*
+*  StatsD metrics
+*
+*  With `STATSD_ADDR` set, request and database metrics are pushed over UDP
+*  in the DogStatsD format: `<prefix>.<name>:<value>|<type>|#<tags>`, which a
+*  Datadog agent and plain StatsD servers both accept (the latter ignore the
+*  tags). Sending never blocks or fails a request; lost packets are lost
+*  metrics.
+*/
+
+struct Client {
+    socket: UdpSocket,
+    prefix: String,
+    /// Sent with every metric, already joined: `env:prod,service:crud`.
+    tags: String,
+}
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Starts sending metrics to `addr`. Only the first call has an effect.
+pub fn init(addr: &str, prefix: &str, tags: &[String]) {
+    let client = match connect(addr) {
+        Ok(socket) => Client {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags: tags.join(","),
+        },
+        Err(e) => {
+            warn!("StatsD disabled, cannot reach {}: {}", addr, e);
+            return;
+        }
+    };
+    if CLIENT.set(client).is_ok() {
+        info!("Sending metrics to StatsD at {}", addr);
+    }
+}
+
+fn connect(addr: &str) -> std::io::Result<UdpSocket> {
+    let target: SocketAddr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"))?;
+    let local: SocketAddr = if target.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(target)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "c", tags);
+}
+
+pub fn gauge(name: &str, value: u64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "g", tags);
+}
+
+/// Whether metrics are being sent at all.
+pub fn enabled() -> bool {
+    CLIENT.get().is_some()
+}
+
+pub fn timing(name: &str, duration: Duration, tags: &[(&str, &str)]) {
+    let millis = format!("{:.3}", duration.as_secs_f64() * 1000.0);
+    send(name, &millis, "ms", tags);
+}
+
+fn send(name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+    if let Some(client) = CLIENT.get() {
+        let line = format_metric(&client.prefix, name, value, kind, &client.tags, tags);
+        client.socket.send(line.as_bytes()).ok();
+    }
+}
+
+fn format_metric(
+    prefix: &str,
+    name: &str,
+    value: &str,
+    kind: &str,
+    common_tags: &str,
+    tags: &[(&str, &str)],
+) -> String {
+    let mut line = if prefix.is_empty() {
+        format!("{}:{}|{}", name, value, kind)
+    } else {
+        format!("{}.{}:{}|{}", prefix, name, value, kind)
+    };
+    let tags: Vec<String> = (!common_tags.is_empty())
+        .then(|| common_tags.to_string())
+        .into_iter()
+        .chain(
+            tags.iter()
+                .map(|(key, value)| format!("{}:{}", key, sanitize(value))),
+        )
+        .collect();
+    if !tags.is_empty() {
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    line
+}
+
+/// Tag values cannot hold the characters that separate tags and fields.
+fn sanitize(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_dogstatsd_lines() {
+        assert_eq!(
+            format_metric(
+                "rust_crud",
+                "http.requests",
+                "1",
+                "c",
+                "env:prod",
+                &[("route", "/users/:id"), ("status", "200")]
+            ),
+            "rust_crud.http.requests:1|c|#env:prod,route:/users/:id,status:200"
+        );
+        assert_eq!(
+            format_metric("", "db.queries", "1", "c", "", &[]),
+            "db.queries:1|c"
+        );
+        assert_eq!(
+            format_metric("app", "x", "2.500", "ms", "", &[("tenant", "a,b|c")]),
+            "app.x:2.500|ms|#tenant:a_b_c"
+        );
+    }
+
+    #[test]
+    fn sends_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let socket = connect(&server.local_addr().unwrap().to_string()).unwrap();
+        socket.send(b"a:1|c").unwrap();
+
+        let mut buffer = [0; 64];
+        let length = server.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"a:1|c");
+    }
+}
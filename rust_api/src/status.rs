@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/*
+*  Server status
+*
+*  Counters for `GET /status`, a quick look at a running instance for when
+*  scraping `GET /metrics` is more than the question needs: how long it has
+*  been up and how many requests it has served and is serving.
+*/
+
+pub struct ServerStatus {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    served: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+/// Held while a request is handled; counts it as served when dropped.
+pub struct InFlight(Arc<ServerStatus>);
+
+impl Default for ServerStatus {
+    fn default() -> Self {
+        ServerStatus {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            served: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ServerStatus {
+    pub fn begin(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        InFlight(self.clone())
+    }
+
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Requests answered since startup.
+    pub fn served(&self) -> u64 {
+        self.served.load(Ordering::Acquire)
+    }
+
+    /// Requests being handled now.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.served.fetch_add(1, Ordering::AcqRel);
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_in_flight_and_served() {
+        let status = Arc::new(ServerStatus::default());
+        let first = status.begin();
+        let second = status.begin();
+        assert_eq!((status.in_flight(), status.served()), (2, 0));
+
+        drop(first);
+        assert_eq!((status.in_flight(), status.served()), (1, 1));
+        drop(second);
+        assert_eq!((status.in_flight(), status.served()), (0, 2));
+    }
+}
@@ -0,0 +1,196 @@
+use log::info;
+use std::env;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+
+/*
+*  systemd socket activation
+*
+*  With a `.socket` unit, systemd binds the listening sockets itself and
+*  starts the service with them open as file descriptors 3, 4, ..., saying so
+*  in `LISTEN_PID`, `LISTEN_FDS` and, when the unit names them with
+*  `FileDescriptorName=`, `LISTEN_FDNAMES` (the sd_listen_fds protocol,
+*  https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html).
+*
+*  `LISTEN=systemd:<name>` serves on the socket of that name, or at that
+*  position when the name is a number; `systemd` alone is the first one.
+*  `tls:systemd:<name>` serves HTTPS on it. Only listening sockets can be
+*  used (`Accept=no`, the default).
+*
+*  systemd keeps the sockets open while the service restarts, so connections
+*  arriving in between wait in the backlog instead of being refused, and the
+*  service can listen on a privileged port without running as root.
+*
+*  A server handing over to a new binary (see `crate::handoff`) passes its
+*  sockets the same way, minus `LISTEN_PID`: it cannot know the pid before
+*  the process exists. Those sockets are taken by the listeners bound to the
+*  same address; whatever no listener takes is closed once all are bound.
+*/
+
+/// The first descriptor passed, `SD_LISTEN_FDS_START`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A passed socket, `None` once taken.
+struct Passed {
+    name: String,
+    fd: Option<OwnedFd>,
+}
+
+/// Read from the environment on first use.
+static PASSED: Mutex<Option<Vec<Passed>>> = Mutex::new(None);
+
+/// Takes the socket passed as `name`, or at that position. Each one can be
+/// taken once.
+pub fn take(name: &str) -> io::Result<OwnedFd> {
+    let mut passed = PASSED.lock().unwrap_or_else(|e| e.into_inner());
+    let sockets = passed.get_or_insert_with(inherit);
+    let index = match sockets.iter().position(|socket| socket.name == name) {
+        Some(index) => Some(index),
+        None => name.parse::<usize>().ok(),
+    };
+    let socket = index
+        .and_then(|index| sockets.get_mut(index))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("systemd passed no socket {:?}", name),
+            )
+        })?;
+    let fd = socket
+        .fd
+        .take()
+        .ok_or_else(|| io::Error::other(format!("systemd socket {:?} is listed twice", name)))?;
+    if !is_listening(&fd) {
+        return Err(io::Error::other(format!(
+            "systemd socket {:?} is not a listening socket; the unit needs Accept=no",
+            name
+        )));
+    }
+    Ok(fd)
+}
+
+/// Takes the first socket passed that `wanted` picks, if any.
+pub fn take_matching(wanted: impl Fn(&OwnedFd) -> bool) -> Option<OwnedFd> {
+    let mut passed = PASSED.lock().unwrap_or_else(|e| e.into_inner());
+    passed
+        .get_or_insert_with(inherit)
+        .iter_mut()
+        .find(|socket| socket.fd.as_ref().is_some_and(&wanted))?
+        .fd
+        .take()
+}
+
+/// Closes the sockets passed that no listener took, so connections to them
+/// are refused rather than left waiting.
+pub fn close_unused() {
+    let mut passed = PASSED.lock().unwrap_or_else(|e| e.into_inner());
+    for socket in passed.get_or_insert_with(inherit) {
+        if socket.fd.take().is_some() {
+            info!(
+                "Closing socket {:?}, which LISTEN does not use",
+                socket.name
+            );
+        }
+    }
+}
+
+/// The sockets passed to this process, owned from now on.
+fn inherit() -> Vec<Passed> {
+    let var = |name| env::var(name).ok();
+    passed(
+        std::process::id(),
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+    )
+    .into_iter()
+    .map(|(name, fd)| {
+        // Keeps them out of any process started from here.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        Passed {
+            name,
+            // The protocol hands them over to whichever process LISTEN_PID
+            // names, which `passed` checked is this one.
+            fd: Some(unsafe { OwnedFd::from_raw_fd(fd) }),
+        }
+    })
+    .collect()
+}
+
+/// The name and descriptor of each socket the variables describe. None when
+/// they were meant for another process, such as the parent that started this
+/// one; without `LISTEN_PID` they were passed by a handoff.
+fn passed(
+    pid: u32,
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    names: Option<&str>,
+) -> Vec<(String, RawFd)> {
+    if listen_pid.is_some_and(|listen_pid| listen_pid.parse() != Ok(pid)) {
+        return Vec::new();
+    }
+    let count: RawFd = listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .map(|fd| {
+            // As systemd calls sockets without a name.
+            let name = names.next().filter(|name| !name.is_empty());
+            (name.unwrap_or("unknown").to_string(), fd)
+        })
+        .collect()
+}
+
+fn is_listening(fd: &OwnedFd) -> bool {
+    let mut accepting: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&mut accepting as *mut libc::c_int).cast(),
+            &mut length,
+        )
+    };
+    result == 0 && accepting != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn reads_the_passed_sockets() {
+        assert_eq!(
+            passed(42, Some("42"), Some("3"), Some("http:https")),
+            [
+                ("http".to_string(), 3),
+                ("https".to_string(), 4),
+                ("unknown".to_string(), 5)
+            ]
+        );
+        assert_eq!(
+            passed(42, Some("42"), Some("1"), None),
+            [("unknown".to_string(), 3)]
+        );
+        // Handed over.
+        assert_eq!(
+            passed(42, None, Some("1"), Some("https")),
+            [("https".to_string(), 3)]
+        );
+        // Meant for the parent, or not socket activated at all.
+        assert!(passed(42, Some("41"), Some("1"), None).is_empty());
+        assert!(passed(42, None, None, None).is_empty());
+        assert!(passed(42, Some("42"), Some("many"), None).is_empty());
+    }
+
+    #[test]
+    fn tells_listening_sockets_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(is_listening(&OwnedFd::from(listener)));
+        assert!(!is_listening(&OwnedFd::from(stream)));
+    }
+}
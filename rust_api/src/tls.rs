@@ -0,0 +1,317 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/*
+*  TLS listeners
+*
+*  `tls:<addr>` listeners serve HTTPS with the certificate chain and key from
+*  `TLS_CERT_FILE` and `TLS_KEY_FILE` (PEM). With `TLS_CLIENT_CA_FILE` they
+*  also ask for a client certificate and verify it against that CA (mutual
+*  TLS); connections without a valid one fail the handshake unless
+*  `TLS_CLIENT_AUTH=optional`. The subject of a verified certificate is handed
+*  to handlers on the request and tagged onto log entries, so calls can be
+*  told apart by caller.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuth {
+    Required,
+    /// Connections without a certificate are let through, just without a
+    /// subject; ones with an invalid certificate still fail.
+    Optional,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Verifies client certificates when set.
+    pub client_ca_file: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+}
+
+/// Loads the certificates and keys `settings` point to.
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certs = load_certs(&settings.cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&settings.key_file)
+        .map_err(|e| format!("{}: {}", settings.key_file.display(), e))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match &settings.client_ca_file {
+        None => builder.with_no_client_auth(),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match settings.client_auth {
+                ClientAuth::Required => verifier,
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| e.to_string())?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", path.display()));
+    }
+    Ok(certs)
+}
+
+/// The subject of a DER certificate as an RFC 4514 string, most specific
+/// attribute first, e.g. `CN=billing,O=Example`.
+pub fn subject(cert: &[u8]) -> Option<String> {
+    let (_, certificate, _) = tlv(cert)?;
+    let (_, mut tbs, _) = tlv(certificate)?;
+    if tbs.first() == Some(&0xa0) {
+        tbs = tlv(tbs)?.2; // version
+    }
+    // serial number, signature algorithm, issuer, validity
+    for _ in 0..4 {
+        tbs = tlv(tbs)?.2;
+    }
+    let (_, mut name, _) = tlv(tbs)?;
+
+    let mut rdns = Vec::new();
+    while !name.is_empty() {
+        let (_, mut set, rest) = tlv(name)?;
+        name = rest;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (_, attribute, rest) = tlv(set)?;
+            set = rest;
+            let (_, oid, value) = tlv(attribute)?;
+            let (tag, value, _) = tlv(value)?;
+            attributes.push(format!(
+                "{}={}",
+                attribute_type(oid),
+                escape(&text(tag, value))
+            ));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// Splits a DER value off `input`: its tag, its contents and what follows.
+fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let length = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let bytes = usize::from(first & 0x7f);
+        if bytes == 0 || bytes > 4 || input.len() < bytes {
+            return None;
+        }
+        let (length, rest) = input.split_at(bytes);
+        input = rest;
+        length
+            .iter()
+            .fold(0, |length, &byte| (length << 8) | usize::from(byte))
+    };
+    (input.len() >= length).then(|| (tag, &input[..length], &input[length..]))
+}
+
+/// The short name of an attribute type, or its dotted OID.
+fn attribute_type(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".to_string(),
+        [0x55, 0x04, 0x06] => "C".to_string(),
+        [0x55, 0x04, 0x07] => "L".to_string(),
+        [0x55, 0x04, 0x08] => "ST".to_string(),
+        [0x55, 0x04, 0x0a] => "O".to_string(),
+        [0x55, 0x04, 0x0b] => "OU".to_string(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC".to_string(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID".to_string(),
+        _ => {
+            let mut arcs = Vec::new();
+            let mut arc = 0u64;
+            for &byte in oid {
+                arc = (arc << 7) | u64::from(byte & 0x7f);
+                if byte & 0x80 == 0 {
+                    arcs.push(arc);
+                    arc = 0;
+                }
+            }
+            let Some(&first) = arcs.first() else {
+                return String::new();
+            };
+            let (x, y) = match first {
+                0..=39 => (0, first),
+                40..=79 => (1, first - 40),
+                _ => (2, first - 80),
+            };
+            std::iter::once(x)
+                .chain(std::iter::once(y))
+                .chain(arcs[1..].iter().copied())
+                .map(|arc| arc.to_string())
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    }
+}
+
+/// An attribute value as text; BMPString is UTF-16, the other string types
+/// in use are read as UTF-8.
+fn text(tag: u8, value: &[u8]) -> String {
+    match tag {
+        0x1e => char::decode_utf16(
+            value
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect(),
+        _ => String::from_utf8_lossy(value).into_owned(),
+    }
+}
+
+/// Escapes what RFC 4514 reserves, and control characters so a subject
+/// cannot break a log line.
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => escaped.push('\\'),
+            '#' | ' ' if i == 0 => escaped.push('\\'),
+            ' ' if i == last => escaped.push('\\'),
+            _ => {}
+        }
+        if c.is_control() {
+            escaped.push_str(&format!("\\{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::{ListenAddr, Listener};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls")).join(name)
+    }
+
+    fn settings(client_auth: ClientAuth) -> TlsSettings {
+        TlsSettings {
+            cert_file: fixture("server.pem"),
+            key_file: fixture("server-key.pem"),
+            client_ca_file: Some(fixture("ca.pem")),
+            client_auth,
+        }
+    }
+
+    /// Sends `ping` over TLS, with the client certificate when asked, and
+    /// returns the subject the server saw, or the error either side hit.
+    fn handshake(client_auth: ClientAuth, with_cert: bool) -> Result<Option<String>, String> {
+        let listener = Listener::bind(
+            &"tls:127.0.0.1:0".parse::<ListenAddr>().unwrap(),
+            Some(server_config(&settings(client_auth)).unwrap()),
+        )
+        .unwrap();
+        let ListenAddr::Tls(addr) = listener.local_addr().unwrap() else {
+            panic!("not a TLS listener");
+        };
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let mut ping = [0; 4];
+            stream
+                .read_exact(&mut ping)
+                .map(|()| stream.client_subject())
+                .map_err(|e| e.to_string())
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap())
+            .unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = match with_cert {
+            true => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_file(fixture("client.pem")).unwrap()],
+                    PrivateKeyDer::from_pem_file(fixture("client-key.pem")).unwrap(),
+                )
+                .unwrap(),
+            false => builder.with_no_client_auth(),
+        };
+        let connection =
+            ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+                .unwrap();
+        let mut client = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+        client.write_all(b"ping").and_then(|()| client.flush()).ok();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn verifies_client_certificates() {
+        assert_eq!(
+            handshake(ClientAuth::Required, true),
+            Ok(Some("CN=billing,O=Example".to_string()))
+        );
+        assert!(handshake(ClientAuth::Required, false).is_err());
+        assert_eq!(handshake(ClientAuth::Optional, false), Ok(None));
+    }
+
+    #[test]
+    fn formats_subjects() {
+        let server = CertificateDer::from_pem_file(fixture("server.pem")).unwrap();
+        assert_eq!(subject(&server).as_deref(), Some("CN=localhost"));
+        assert_eq!(escape("#1, \"Acme\" Inc "), "\\#1\\, \\\"Acme\\\" Inc\\ ");
+        assert_eq!(escape("a\nb"), "a\\0Ab");
+        // 1.2.840.113549.1.9.1, emailAddress
+        assert_eq!(
+            attribute_type(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01]),
+            "1.2.840.113549.1.9.1"
+        );
+        assert_eq!(subject(&[0x30, 0x05, 0x30]), None);
+    }
+}
@@ -0,0 +1,428 @@
+use crate::repository::{RepositoryError, TotpRepository};
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+*  Two-factor authentication
+*
+*  Time-based one-time passwords as authenticator apps generate them: six
+*  digits from HMAC-SHA1 over the number of 30 second steps since the epoch.
+*  An enrollment starts out pending and takes effect once a first code
+*  confirms the app has the secret. Codes from one step before or after are
+*  accepted to allow for clock drift, but never one from a step at or before
+*  the last accepted one, so an observed code cannot be replayed. Recovery
+*  codes stand in for a lost device; each works once and only its SHA-256 is
+*  stored.
+*  https://www.rfc-editor.org/rfc/rfc6238
+*/
+
+/// The account of the admin token, the only one that logs in.
+pub const ADMIN_ACCOUNT: &str = "admin";
+const ISSUER: &str = "rust-crud";
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps before and after the current one whose codes are accepted.
+const DRIFT_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// An account's enrollment as stored.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Totp {
+    /// Base32, as authenticator apps take it.
+    pub secret: String,
+    /// False until a first code is confirmed.
+    pub enabled: bool,
+    /// SHA-256 of each unused recovery code, in hex.
+    pub recovery_codes: Vec<String>,
+    /// Step of the last accepted code.
+    pub last_step: u64,
+}
+
+/// What an app and the account holder need to set up two-factor login.
+pub struct Enrollment {
+    pub secret: String,
+    /// `otpauth://` provisioning URI, the payload of the QR code apps scan.
+    pub uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Check {
+    /// Two-factor login is off for the account.
+    NotEnabled,
+    Accepted,
+    /// The account needs a code and none was sent.
+    Missing,
+    Rejected,
+}
+
+/// Starts a pending enrollment with a new secret and recovery codes,
+/// replacing any earlier pending one. `None` when it is enabled already.
+pub fn enroll(
+    repository: &dyn TotpRepository,
+    account: &str,
+) -> Result<Option<Enrollment>, RepositoryError> {
+    let secret = base32(&rand::random::<[u8; SECRET_BYTES]>());
+    let recovery_codes = recovery_codes();
+    let mut enrolled = false;
+    repository.change_totp(account, &mut |totp| {
+        if totp.as_ref().is_some_and(|totp| totp.enabled) {
+            return false;
+        }
+        *totp = Some(Totp {
+            secret: secret.clone(),
+            enabled: false,
+            recovery_codes: recovery_codes.iter().map(|code| digest(code)).collect(),
+            last_step: 0,
+        });
+        enrolled = true;
+        true
+    })?;
+    Ok(enrolled.then(|| Enrollment {
+        uri: format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+            issuer = ISSUER,
+        ),
+        secret,
+        recovery_codes,
+    }))
+}
+
+/// Enables a pending enrollment when `code` comes from its secret. `None`
+/// when the account has no enrollment.
+pub fn confirm(
+    repository: &dyn TotpRepository,
+    account: &str,
+    code: &str,
+) -> Result<Option<bool>, RepositoryError> {
+    confirm_at(repository, account, code, current_step())
+}
+
+/// Checks a login's code, or recovery code, against the account's enabled
+/// enrollment and uses it up.
+pub fn verify(
+    repository: &dyn TotpRepository,
+    account: &str,
+    code: Option<&str>,
+) -> Result<Check, RepositoryError> {
+    verify_at(repository, account, code, current_step())
+}
+
+/// Replaces the recovery codes of an enabled enrollment. `None` when two-factor
+/// login is off.
+pub fn renew_recovery_codes(
+    repository: &dyn TotpRepository,
+    account: &str,
+) -> Result<Option<Vec<String>>, RepositoryError> {
+    let codes = recovery_codes();
+    let totp = repository.change_totp(account, &mut |totp| match totp {
+        Some(totp) if totp.enabled => {
+            totp.recovery_codes = codes.iter().map(|code| digest(code)).collect();
+            true
+        }
+        _ => false,
+    })?;
+    Ok(totp.filter(|totp| totp.enabled).map(|_| codes))
+}
+
+/// Turns two-factor login off, returning whether it was enrolled.
+pub fn disable(repository: &dyn TotpRepository, account: &str) -> Result<bool, RepositoryError> {
+    let mut enrolled = false;
+    repository.change_totp(account, &mut |totp| {
+        enrolled = totp.take().is_some();
+        enrolled
+    })?;
+    Ok(enrolled)
+}
+
+pub fn status(
+    repository: &dyn TotpRepository,
+    account: &str,
+) -> Result<Option<Totp>, RepositoryError> {
+    repository.change_totp(account, &mut |_| false)
+}
+
+fn confirm_at(
+    repository: &dyn TotpRepository,
+    account: &str,
+    code: &str,
+    now: u64,
+) -> Result<Option<bool>, RepositoryError> {
+    let mut found = false;
+    let mut confirmed = false;
+    repository.change_totp(account, &mut |totp| {
+        let Some(totp) = totp else {
+            return false;
+        };
+        found = true;
+        match matching_step(totp, code, now) {
+            Some(step) => {
+                totp.enabled = true;
+                totp.last_step = step;
+                confirmed = true;
+            }
+            None => confirmed = false,
+        }
+        confirmed
+    })?;
+    Ok(found.then_some(confirmed))
+}
+
+fn verify_at(
+    repository: &dyn TotpRepository,
+    account: &str,
+    code: Option<&str>,
+    now: u64,
+) -> Result<Check, RepositoryError> {
+    let mut check = Check::NotEnabled;
+    repository.change_totp(account, &mut |totp| {
+        let Some(totp) = totp.as_mut().filter(|totp| totp.enabled) else {
+            check = Check::NotEnabled;
+            return false;
+        };
+        let Some(code) = code.filter(|code| !code.trim().is_empty()) else {
+            check = Check::Missing;
+            return false;
+        };
+        if let Some(step) = matching_step(totp, code, now) {
+            totp.last_step = step;
+            check = Check::Accepted;
+            return true;
+        }
+        let hash = digest(code);
+        match totp
+            .recovery_codes
+            .iter()
+            .position(|stored| *stored == hash)
+        {
+            Some(index) => {
+                totp.recovery_codes.remove(index);
+                check = Check::Accepted;
+                true
+            }
+            None => {
+                check = Check::Rejected;
+                false
+            }
+        }
+    })?;
+    Ok(check)
+}
+
+/// The step within the drift window whose code `code` is, when it is newer
+/// than the last accepted one.
+fn matching_step(totp: &Totp, code: &str, now: u64) -> Option<u64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let key = unbase32(&totp.secret)?;
+    (now.saturating_sub(DRIFT_STEPS)..=now + DRIFT_STEPS)
+        .filter(|step| *step > totp.last_step)
+        .find(|step| code_at(&key, *step) == code)
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / STEP_SECONDS
+}
+
+/// HOTP (RFC 4226) of `step`.
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let mac = mac.finalize().into_bytes();
+    let offset = usize::from(mac[mac.len() - 1] & 0x0f);
+    let truncated = u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Ten base32 characters in two groups, e.g. `k7qzm-4bx2p`.
+fn recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = base32(&rand::random::<[u8; 7]>()).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without case, spaces or dashes.
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn digest(code: &str) -> String {
+    Sha256::digest(normalize(code).as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// RFC 4648 base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(char::from(BASE32[(buffer >> bits) as usize & 31]));
+        }
+    }
+    if bits > 0 {
+        encoded.push(char::from(BASE32[(buffer << (5 - bits)) as usize & 31]));
+    }
+    encoded
+}
+
+fn unbase32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes().filter(|c| *c != b'=') {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryUserRepository;
+
+    #[test]
+    fn generates_the_rfc_6238_codes() {
+        let key = b"12345678901234567890";
+        assert_eq!(code_at(key, 59 / STEP_SECONDS), 287082);
+        assert_eq!(code_at(key, 1111111109 / STEP_SECONDS), 81804);
+        assert_eq!(code_at(key, 2000000000 / STEP_SECONDS), 279037);
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(unbase32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(unbase32(&base32(key)).unwrap(), key);
+    }
+
+    #[test]
+    fn enrolls_confirms_and_verifies_codes_once() {
+        let repository = MemoryUserRepository::new();
+        let now = 1_000_000;
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, None, now).unwrap(),
+            Check::NotEnabled
+        );
+
+        let enrollment = enroll(&repository, ADMIN_ACCOUNT).unwrap().unwrap();
+        assert!(enrollment
+            .uri
+            .starts_with("otpauth://totp/rust-crud:admin?secret="));
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        let key = unbase32(&enrollment.secret).unwrap();
+        let code = |step| format!("{:06}", code_at(&key, step));
+        // Pending enrollments do not apply to logins yet.
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, None, now).unwrap(),
+            Check::NotEnabled
+        );
+        assert_eq!(
+            confirm_at(&repository, ADMIN_ACCOUNT, &code(now - 5), now).unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            confirm_at(&repository, ADMIN_ACCOUNT, &code(now), now).unwrap(),
+            Some(true)
+        );
+        assert!(enroll(&repository, ADMIN_ACCOUNT).unwrap().is_none());
+
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, None, now + 1).unwrap(),
+            Check::Missing
+        );
+        // The confirming code is used up, as is any earlier one.
+        for step in [now - 1, now] {
+            assert_eq!(
+                verify_at(&repository, ADMIN_ACCOUNT, Some(&code(step)), now + 1).unwrap(),
+                Check::Rejected
+            );
+        }
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&code(now + 2)), now + 1).unwrap(),
+            Check::Accepted
+        );
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&code(now + 2)), now + 2).unwrap(),
+            Check::Rejected
+        );
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&code(now + 4)), now + 2).unwrap(),
+            Check::Rejected
+        );
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let repository = MemoryUserRepository::new();
+        let enrollment = enroll(&repository, ADMIN_ACCOUNT).unwrap().unwrap();
+        assert_eq!(enrollment.recovery_codes.len(), RECOVERY_CODES);
+        let key = unbase32(&enrollment.secret).unwrap();
+        let now = 1_000_000;
+        let code = format!("{:06}", code_at(&key, now));
+        confirm_at(&repository, ADMIN_ACCOUNT, &code, now).unwrap();
+
+        let recovery = enrollment.recovery_codes[3]
+            .to_uppercase()
+            .replace('-', " ");
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&recovery), now).unwrap(),
+            Check::Accepted
+        );
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&recovery), now).unwrap(),
+            Check::Rejected
+        );
+        assert_eq!(
+            status(&repository, ADMIN_ACCOUNT)
+                .unwrap()
+                .unwrap()
+                .recovery_codes
+                .len(),
+            RECOVERY_CODES - 1
+        );
+
+        let renewed = renew_recovery_codes(&repository, ADMIN_ACCOUNT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            verify_at(
+                &repository,
+                ADMIN_ACCOUNT,
+                Some(&enrollment.recovery_codes[4]),
+                now
+            )
+            .unwrap(),
+            Check::Rejected
+        );
+        assert_eq!(
+            verify_at(&repository, ADMIN_ACCOUNT, Some(&renewed[0]), now).unwrap(),
+            Check::Accepted
+        );
+
+        assert!(disable(&repository, ADMIN_ACCOUNT).unwrap());
+        assert!(!disable(&repository, ADMIN_ACCOUNT).unwrap());
+        assert!(renew_recovery_codes(&repository, ADMIN_ACCOUNT)
+            .unwrap()
+            .is_none());
+    }
+}
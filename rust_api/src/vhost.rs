@@ -0,0 +1,162 @@
+use crate::listener::RouteGroup;
+use std::str::FromStr;
+
+/*
+*  Virtual hosts
+*
+*  `VIRTUAL_HOSTS` binds route groups to the host names clients ask for, so
+*  one instance can front several of them:
+*
+*      VIRTUAL_HOSTS=api.example.com@api,admin.example.com@admin
+*
+*  Each entry is a host name, or `*.<domain>` for every name below that
+*  domain, optionally followed by `@group+group` as in `LISTEN`; without it
+*  the host serves every group. The `Host` header is matched without its
+*  port and case-insensitively, against the first entry that fits. Once any
+*  entry is configured, requests for other hosts are refused. Listeners
+*  restrict the groups further: a request is routed only if both its
+*  listener and its host serve the group.
+*/
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualHost {
+    /// Lowercase, without a trailing dot. Starts with `*.` for a wildcard.
+    name: String,
+    pub groups: Vec<RouteGroup>,
+}
+
+impl VirtualHost {
+    /// Whether `host`, already normalized, is this one.
+    fn matches(&self, host: &str) -> bool {
+        match self.name.strip_prefix('*') {
+            Some(domain) => host.len() > domain.len() && host.ends_with(domain),
+            None => host == self.name,
+        }
+    }
+}
+
+impl FromStr for VirtualHost {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, groups) = match s.rsplit_once('@') {
+            Some((name, groups)) => (
+                name,
+                groups
+                    .split('+')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => (s, RouteGroup::ALL.to_vec()),
+        };
+        let name = normalize(name);
+        let labels = name.strip_prefix("*.").unwrap_or(&name);
+        let valid = !labels.is_empty()
+            && labels.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            return Err(());
+        }
+        Ok(VirtualHost { name, groups })
+    }
+}
+
+/// The route groups served to requests for `host`, the value of their
+/// `Host` header. None for hosts not configured.
+pub fn groups<'a>(hosts: &'a [VirtualHost], host: &str) -> Option<&'a [RouteGroup]> {
+    let host = normalize(strip_port(host));
+    hosts
+        .iter()
+        .find(|virtual_host| virtual_host.matches(&host))
+        .map(|virtual_host| virtual_host.groups.as_slice())
+}
+
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// `example.com:8443` is `example.com`; IPv6 literals keep their brackets.
+fn strip_port(host: &str) -> &str {
+    let host = host.trim();
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(list: &[&str]) -> Vec<VirtualHost> {
+        list.iter().map(|host| host.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_hosts_and_their_groups() {
+        let host: VirtualHost = "Admin.Example.com.@admin".parse().unwrap();
+        assert_eq!(host.name, "admin.example.com");
+        assert_eq!(host.groups, [RouteGroup::Admin]);
+        let host: VirtualHost = "*.example.com".parse().unwrap();
+        assert_eq!(host.groups, RouteGroup::ALL);
+
+        for invalid in [
+            "",
+            "*",
+            "*.",
+            "api..example.com",
+            "api.example.com@web",
+            "a_b.com",
+            "api.example.com:8080",
+        ] {
+            assert!(invalid.parse::<VirtualHost>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn picks_the_groups_of_the_first_matching_host() {
+        let hosts = hosts(&[
+            "api.example.com@api",
+            "admin.example.com@admin",
+            "*.example.com@api+admin",
+        ]);
+        assert_eq!(
+            groups(&hosts, "api.example.com"),
+            Some(&[RouteGroup::Api][..])
+        );
+        assert_eq!(
+            groups(&hosts, "API.example.com.:8443"),
+            Some(&[RouteGroup::Api][..])
+        );
+        assert_eq!(
+            groups(&hosts, "admin.example.com:80"),
+            Some(&[RouteGroup::Admin][..])
+        );
+        assert_eq!(
+            groups(&hosts, "eu.api.example.com"),
+            Some(&RouteGroup::ALL[..])
+        );
+        assert_eq!(groups(&hosts, "example.com"), None);
+        assert_eq!(groups(&hosts, "api.example.org"), None);
+        assert_eq!(groups(&hosts, "evilexample.com"), None);
+        assert_eq!(groups(&hosts, ""), None);
+    }
+
+    #[test]
+    fn ignores_ports_but_not_addresses() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        let hosts = hosts(&["127.0.0.1@admin"]);
+        assert_eq!(
+            groups(&hosts, "127.0.0.1:8081"),
+            Some(&[RouteGroup::Admin][..])
+        );
+        assert_eq!(groups(&hosts, "127.0.0.2:8081"), None);
+    }
+}
@@ -0,0 +1,221 @@
+//! Property tests for what the server does with requests it cannot make sense
+//! of: malformed, truncated and oversized ones, sent to the real server on a
+//! random port with memory storage. Whatever arrives, no thread may panic and
+//! every request whose head arrived is answered with a valid status line.
+//! `fuzz/` has cargo-fuzz targets feeding the parser the same way, for longer.
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use rust_api::config::{Config, Profile};
+use rust_api::listener::ListenAddr;
+use rust_api::Server;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+const MAX_HEADER_BYTES: usize = 1024;
+const MAX_BODY_BYTES: usize = 256;
+
+static SERVER: OnceLock<SocketAddr> = OnceLock::new();
+/// Panics anywhere in the process since the server started, caught or not.
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+fn server() -> SocketAddr {
+    *SERVER.get_or_init(|| {
+        let max_header_bytes = MAX_HEADER_BYTES.to_string();
+        let max_body_bytes = MAX_BODY_BYTES.to_string();
+        let vars: HashMap<String, String> = [
+            ("LISTEN", "127.0.0.1:0"),
+            ("MAX_HEADER_BYTES", max_header_bytes.as_str()),
+            ("MAX_BODY_BYTES", max_body_bytes.as_str()),
+            ("READ_BUFFER_BYTES", "64"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let config = Config::from_vars(Profile::Test, &vars).unwrap();
+        let server = Server::bind(config).expect("start server");
+        let addr = match server.local_addrs()[0] {
+            ListenAddr::Tcp(addr) => addr,
+            ref other => panic!("unexpected listener {}", other),
+        };
+
+        // After the server's own hook, which it keeps calling.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::SeqCst);
+            hook(info);
+        }));
+        thread::spawn(move || server.run());
+        addr
+    })
+}
+
+/// Sends `raw`, closes the sending half so the server sees the end of it, and
+/// returns everything it answered.
+fn exchange(raw: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(server()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    // The server may answer and hang up before reading all of it.
+    stream.write_all(raw).ok();
+    stream.shutdown(Shutdown::Write).ok();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).ok();
+    response
+}
+
+/// The status code of a response starting with `HTTP/1.1 NNN Reason\r\n`.
+fn status_of(response: &[u8]) -> Option<u16> {
+    let end = response.windows(2).position(|w| w == b"\r\n")?;
+    let line = std::str::from_utf8(&response[..end]).ok()?;
+    let rest = line.strip_prefix("HTTP/1.1 ")?;
+    let (code, reason) = rest.split_once(' ')?;
+    let valid = code.len() == 3 && !reason.is_empty();
+    code.parse()
+        .ok()
+        .filter(|code| valid && (100..600).contains(code))
+}
+
+fn methods() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("GET"),
+        Just("POST"),
+        Just("PUT"),
+        Just("PATCH"),
+        Just("DELETE"),
+        Just("HEAD"),
+        Just("OPTIONS"),
+        Just("BREW"),
+        Just(""),
+    ]
+    .prop_map(str::to_string)
+}
+
+fn targets() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("/users".to_string()),
+        Just("/users/1".to_string()),
+        Just("/users/1/addresses".to_string()),
+        Just("/users/%ff%fe".to_string()),
+        Just("/users/..%2f..%2fadmin".to_string()),
+        Just("*".to_string()),
+        "/[ -~]{0,48}",
+        "[^\r\n]{0,48}",
+    ]
+}
+
+fn headers() -> impl Strategy<Value = Vec<(String, String)>> {
+    let name = prop_oneof![
+        Just("Content-Length"),
+        Just("Content-Type"),
+        Just("Content-Encoding"),
+        Just("Transfer-Encoding"),
+        Just("Expect"),
+        Just("Authorization"),
+        Just("Cookie"),
+        Just("Accept"),
+        Just("If-Match"),
+        Just("X-Forwarded-For"),
+    ]
+    .prop_map(str::to_string);
+    let value = prop_oneof![
+        Just("-1".to_string()),
+        Just("18446744073709551616".to_string()),
+        Just("application/json".to_string()),
+        Just("multipart/form-data; boundary=x".to_string()),
+        Just("gzip".to_string()),
+        Just("100-continue".to_string()),
+        "[0-9]{1,4}",
+        "[ -~]{0,32}",
+    ];
+    vec(
+        prop_oneof![(name, value), ("[ -~]{0,16}", "[^\r\n]{0,32}")],
+        0..6,
+    )
+}
+
+fn request(method: &str, target: &str, headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut raw = format!("{} {} HTTP/1.1\r\n", method, target);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str("\r\n");
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(body);
+    raw
+}
+
+fn assert_no_panics() -> Result<(), TestCaseError> {
+    prop_assert_eq!(PANICS.load(Ordering::SeqCst), 0, "the server panicked");
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn answers_any_bytes_ending_a_head(bytes in vec(any::<u8>(), 0..512)) {
+        let mut raw = bytes;
+        raw.extend_from_slice(b"\r\n\r\n");
+        let response = exchange(&raw);
+        prop_assert!(status_of(&response).is_some(), "{:?}", String::from_utf8_lossy(&response));
+        assert_no_panics()?;
+    }
+
+    #[test]
+    fn answers_malformed_requests(
+        method in methods(),
+        target in targets(),
+        headers in headers(),
+        body in vec(any::<u8>(), 0..128),
+    ) {
+        let response = exchange(&request(&method, &target, &headers, &body));
+        prop_assert!(status_of(&response).is_some(), "{:?}", String::from_utf8_lossy(&response));
+        assert_no_panics()?;
+    }
+
+    #[test]
+    fn answers_or_hangs_up_on_truncated_requests(
+        method in methods(),
+        target in targets(),
+        headers in headers(),
+        body in vec(any::<u8>(), 0..128),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let raw = request(&method, &target, &headers, &body);
+        let cut = cut.index(raw.len());
+        let head_sent = raw[..cut].windows(4).any(|w| w == b"\r\n\r\n");
+        let response = exchange(&raw[..cut]);
+        // Without a whole head there is nothing to answer.
+        if head_sent || !response.is_empty() {
+            prop_assert!(status_of(&response).is_some(), "{:?}", String::from_utf8_lossy(&response));
+        }
+        assert_no_panics()?;
+    }
+
+    #[test]
+    fn refuses_oversized_requests(
+        padding in MAX_HEADER_BYTES..4 * MAX_HEADER_BYTES,
+        length in MAX_BODY_BYTES + 1..1 << 20,
+    ) {
+        let headers = [("X-Padding".to_string(), "a".repeat(padding))];
+        let response = exchange(&request("GET", "/users", &headers, b""));
+        prop_assert_eq!(status_of(&response), Some(431));
+
+        let headers = [
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), length.to_string()),
+        ];
+        let body = vec![b'{'; length.min(4 * MAX_BODY_BYTES)];
+        let response = exchange(&request("POST", "/users", &headers, &body));
+        prop_assert_eq!(status_of(&response), Some(413));
+        assert_no_panics()?;
+    }
+}